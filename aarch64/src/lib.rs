@@ -10,8 +10,8 @@ use std::mem::size_of;
 use std::sync::Arc;
 
 use arch::{
-    get_serial_cmdline, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware, SerialParameters,
-    VmComponents, VmImage,
+    get_serial_cmdline, CpuTopology, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware,
+    SerialParameters, VmComponents, VmImage,
 };
 use base::Event;
 use devices::{Bus, BusError, IrqChip, IrqChipAArch64, PciConfigMmio, PciDevice, ProtectionType};
@@ -150,6 +150,7 @@ pub enum Error {
     CreateVcpu(base::Error),
     CreateVm(Box<dyn StdError>),
     DowncastVcpu,
+    GetReg(base::Error),
     GetPsciVersion(base::Error),
     GetSerialCmdline(GetSerialCmdlineError),
     InitrdLoadFailure(arch::LoadImageError),
@@ -186,6 +187,7 @@ impl Display for Error {
             CreateVm(e) => write!(f, "failed to create vm: {}", e),
             DowncastVcpu => write!(f, "vm created wrong kind of vcpu"),
             GetPsciVersion(e) => write!(f, "failed to get PSCI version: {}", e),
+            GetReg(e) => write!(f, "failed to get register: {}", e),
             GetSerialCmdline(e) => write!(f, "failed to get serial cmdline: {}", e),
             InitrdLoadFailure(e) => write!(f, "initrd could not be loaded: {}", e),
             KernelLoadFailure(e) => write!(f, "kernel could not be loaded: {}", e),
@@ -420,6 +422,7 @@ impl arch::LinuxArch for AArch64 {
             vcpus: Some(vcpus),
             vcpu_affinity: components.vcpu_affinity,
             no_smt: components.no_smt,
+            cpu_topology: components.cpu_topology,
             irq_chip,
             has_bios,
             io_bus,
@@ -440,10 +443,38 @@ impl arch::LinuxArch for AArch64 {
         _num_cpus: usize,
         _has_bios: bool,
         _no_smt: bool,
+        _cpu_topology: Option<CpuTopology>,
     ) -> std::result::Result<(), Self::Error> {
         // AArch64 doesn't configure vcpus on the vcpu thread, so nothing to do here.
         Ok(())
     }
+
+    fn dump_vcpu_registers<T: VcpuAArch64>(vcpu: &T) -> std::result::Result<String, Self::Error> {
+        let mut dump = String::new();
+        // x0-x30 are the first 31 entries of `user_pt_regs::regs`.
+        for i in 0..31 {
+            let reg_id = KVM_REG_ARM64
+                | KVM_REG_SIZE_U64
+                | KVM_REG_ARM_CORE
+                | (((offset__of!(kvm_sys::user_pt_regs, regs) + i * size_of::<u64>()) / 4) as u64);
+            let value = vcpu.get_one_reg(reg_id).map_err(Error::GetReg)?;
+            dump.push_str(&format!("x{}: {:#x}\n", i, value));
+        }
+        let sp = vcpu
+            .get_one_reg(arm64_core_reg!(sp))
+            .map_err(Error::GetReg)?;
+        let pc = vcpu
+            .get_one_reg(arm64_core_reg!(pc))
+            .map_err(Error::GetReg)?;
+        let pstate = vcpu
+            .get_one_reg(arm64_core_reg!(pstate))
+            .map_err(Error::GetReg)?;
+        dump.push_str(&format!(
+            "sp: {:#x}\npc: {:#x}\npstate: {:#x}\n",
+            sp, pc, pstate
+        ));
+        Ok(dump)
+    }
 }
 
 impl AArch64 {
@@ -1537,6 +1537,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pkg_length_boundaries() {
+        // The largest one byte encoding, then the first payload that needs two bytes.
+        assert_eq!(create_pkg_length(&[0u8; 62], true), vec![63]);
+        assert_eq!(create_pkg_length(&[0u8; 63], true), vec![1 << 6 | 1, 4]);
+        assert_eq!(create_pkg_length(&[0u8; 63], false), vec![1 << 6 | 0xf, 3]);
+        assert_eq!(create_pkg_length(&[0u8; 64], false), vec![1 << 6, 4]);
+        // The largest two byte encoding, then the first that needs three bytes.
+        assert_eq!(
+            create_pkg_length(&[0u8; 4093], true),
+            vec![1 << 6 | 0xf, 0xff]
+        );
+        assert_eq!(
+            create_pkg_length(&[0u8; 4094], true),
+            vec![2 << 6 | 1, 0, 1]
+        );
+        // The first payload that needs four bytes.
+        assert_eq!(
+            create_pkg_length(&vec![0u8; (1 << 20) - 3], true),
+            vec![3 << 6 | 1, 0, 0, 1]
+        );
+    }
+
     #[test]
     fn test_package() {
         /*
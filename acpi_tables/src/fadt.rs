@@ -0,0 +1,123 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::sdt::{OemFields, SDT};
+
+// FADT
+pub const FADT_LEN: u32 = 276;
+pub const FADT_REVISION: u8 = 6;
+pub const FADT_MINOR_REVISION: u8 = 3;
+// FADT flags
+pub const FADT_POWER_BUTTON: u32 = 1 << 4;
+pub const FADT_SLEEP_BUTTON: u32 = 1 << 5;
+// FADT fields offset
+const FADT_FIELD_FACS_ADDR32: usize = 36;
+const FADT_FIELD_DSDT_ADDR32: usize = 40;
+const FADT_FIELD_SCI_INTERRUPT: usize = 46;
+const FADT_FIELD_PM1A_EVENT_BLK_ADDR: usize = 56;
+const FADT_FIELD_PM1A_CONTROL_BLK_ADDR: usize = 64;
+const FADT_FIELD_PM_TIMER_BLK_ADDR: usize = 76;
+const FADT_FIELD_PM1A_EVENT_BLK_LEN: usize = 88;
+const FADT_FIELD_PM1A_CONTROL_BLK_LEN: usize = 89;
+const FADT_FIELD_PM_TIMER_BLK_LEN: usize = 91;
+const FADT_FIELD_FLAGS: usize = 112;
+const FADT_FIELD_MINOR_REVISION: usize = 131;
+const FADT_FIELD_FACS_ADDR: usize = 132;
+const FADT_FIELD_DSDT_ADDR: usize = 140;
+const FADT_FIELD_HYPERVISOR_ID: usize = 268;
+
+/// Builder for the Fixed ACPI Description Table (signature "FACP").
+///
+/// Fields left at zero are omitted from the table. The FACS and DSDT
+/// addresses are written to the 64-bit X_ fields only, as the 32-bit
+/// fields must be zero when the X_ fields are in use.
+#[derive(Clone, Default)]
+pub struct Fadt {
+    pub oem: OemFields,
+    pub firmware_ctrl: u64,
+    pub dsdt: u64,
+    pub sci_interrupt: u16,
+    pub pm1a_event_blk: u32,
+    pub pm1a_event_blk_len: u8,
+    pub pm1a_control_blk: u32,
+    pub pm1a_control_blk_len: u8,
+    pub pm_timer_blk: u32,
+    pub pm_timer_blk_len: u8,
+    pub flags: u32,
+    pub hypervisor_id: [u8; 8],
+}
+
+impl Fadt {
+    pub fn new(oem_id: [u8; 6], oem_table: [u8; 8], oem_revision: u32) -> Self {
+        Fadt {
+            oem: OemFields {
+                oem_id,
+                oem_table_id: oem_table,
+                oem_revision,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Sets the OEM and creator fields of the table header.
+    pub fn set_oem(&mut self, oem: OemFields) -> &mut Self {
+        self.oem = oem;
+        self
+    }
+
+    /// Lays out the table, including the SDT header and checksum.
+    pub fn to_sdt(&self) -> SDT {
+        let mut fadt = SDT::with_oem(*b"FACP", FADT_LEN, FADT_REVISION, &self.oem);
+
+        fadt.write(FADT_FIELD_FACS_ADDR32, 0u32);
+        fadt.write(FADT_FIELD_DSDT_ADDR32, 0u32);
+        fadt.write(FADT_FIELD_SCI_INTERRUPT, self.sci_interrupt);
+        fadt.write(FADT_FIELD_PM1A_EVENT_BLK_ADDR, self.pm1a_event_blk);
+        fadt.write(FADT_FIELD_PM1A_CONTROL_BLK_ADDR, self.pm1a_control_blk);
+        fadt.write(FADT_FIELD_PM_TIMER_BLK_ADDR, self.pm_timer_blk);
+        fadt.write(FADT_FIELD_PM1A_EVENT_BLK_LEN, self.pm1a_event_blk_len);
+        fadt.write(FADT_FIELD_PM1A_CONTROL_BLK_LEN, self.pm1a_control_blk_len);
+        fadt.write(FADT_FIELD_PM_TIMER_BLK_LEN, self.pm_timer_blk_len);
+        fadt.write(FADT_FIELD_FLAGS, self.flags);
+        fadt.write(FADT_FIELD_MINOR_REVISION, FADT_MINOR_REVISION);
+        fadt.write(FADT_FIELD_FACS_ADDR, self.firmware_ctrl);
+        fadt.write(FADT_FIELD_DSDT_ADDR, self.dsdt);
+        fadt.write(FADT_FIELD_HYPERVISOR_ID, self.hypervisor_id);
+
+        fadt
+    }
+
+    /// Returns the serialized table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_sdt().as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fadt() {
+        let mut fadt = Fadt::new(*b"CROSVM", *b"CROSVMDT", 1);
+        fadt.firmware_ctrl = 0x1000;
+        fadt.dsdt = 0x2000;
+        fadt.sci_interrupt = 9;
+        fadt.pm1a_event_blk = 0x600;
+        fadt.pm1a_event_blk_len = 4;
+        fadt.pm1a_control_blk = 0x604;
+        fadt.pm1a_control_blk_len = 2;
+        fadt.flags = FADT_POWER_BUTTON | FADT_SLEEP_BUTTON;
+        fadt.hypervisor_id = *b"CROSVM\0\0";
+
+        let bytes = fadt.to_bytes();
+        assert_eq!(bytes.len(), FADT_LEN as usize);
+        assert_eq!(&bytes[0..4], b"FACP");
+        assert_eq!(&bytes[46..48], &9u16.to_le_bytes());
+        assert_eq!(&bytes[140..148], &0x2000u64.to_le_bytes());
+        let sum = bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+        assert_eq!(sum, 0);
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::sdt::{OemFields, SDT};
+
+// GTDT
+pub const GTDT_LEN: u32 = 104;
+pub const GTDT_REVISION: u8 = 3;
+// GTDT timer flags
+pub const GTDT_TIMER_EDGE_TRIGGERED: u32 = 1 << 0;
+pub const GTDT_TIMER_ACTIVE_LOW: u32 = 1 << 1;
+pub const GTDT_TIMER_ALWAYS_ON: u32 = 1 << 2;
+// GTDT fields offset
+const GTDT_FIELD_CNT_CONTROL_BASE: usize = 36;
+const GTDT_FIELD_SECURE_EL1_TIMER: usize = 48;
+const GTDT_FIELD_NON_SECURE_EL1_TIMER: usize = 56;
+const GTDT_FIELD_VIRTUAL_EL1_TIMER: usize = 64;
+const GTDT_FIELD_EL2_TIMER: usize = 72;
+const GTDT_FIELD_CNT_READ_BASE: usize = 80;
+const GTDT_FIELD_VIRTUAL_EL2_TIMER: usize = 96;
+
+/// Interrupt and flags of one of the per-processor timers.
+#[derive(Clone, Copy, Default)]
+struct GtdtTimer {
+    gsiv: u32,
+    flags: u32,
+}
+
+/// Builder for the Generic Timer Description Table (signature "GTDT").
+///
+/// Timers that are never set are reported with a GSIV of zero. No platform
+/// timers are described, and the counter read base is reported as absent.
+#[derive(Clone)]
+pub struct Gtdt {
+    oem: OemFields,
+    cnt_control_base: u64,
+    secure_el1: GtdtTimer,
+    non_secure_el1: GtdtTimer,
+    virtual_el1: GtdtTimer,
+    el2: GtdtTimer,
+    virtual_el2: GtdtTimer,
+}
+
+impl Gtdt {
+    pub fn new() -> Self {
+        Gtdt {
+            oem: OemFields::default(),
+            // All ones means the memory mapped counter control block isn't provided.
+            cnt_control_base: u64::MAX,
+            secure_el1: GtdtTimer::default(),
+            non_secure_el1: GtdtTimer::default(),
+            virtual_el1: GtdtTimer::default(),
+            el2: GtdtTimer::default(),
+            virtual_el2: GtdtTimer::default(),
+        }
+    }
+
+    /// Sets the OEM and creator fields of the table header.
+    pub fn set_oem(&mut self, oem: OemFields) -> &mut Self {
+        self.oem = oem;
+        self
+    }
+
+    /// Sets the physical address of the memory mapped counter control block.
+    pub fn set_cnt_control_base(&mut self, base: u64) -> &mut Self {
+        self.cnt_control_base = base;
+        self
+    }
+
+    pub fn set_secure_el1_timer(&mut self, gsiv: u32, flags: u32) -> &mut Self {
+        self.secure_el1 = GtdtTimer { gsiv, flags };
+        self
+    }
+
+    pub fn set_non_secure_el1_timer(&mut self, gsiv: u32, flags: u32) -> &mut Self {
+        self.non_secure_el1 = GtdtTimer { gsiv, flags };
+        self
+    }
+
+    pub fn set_virtual_el1_timer(&mut self, gsiv: u32, flags: u32) -> &mut Self {
+        self.virtual_el1 = GtdtTimer { gsiv, flags };
+        self
+    }
+
+    pub fn set_el2_timer(&mut self, gsiv: u32, flags: u32) -> &mut Self {
+        self.el2 = GtdtTimer { gsiv, flags };
+        self
+    }
+
+    pub fn set_virtual_el2_timer(&mut self, gsiv: u32, flags: u32) -> &mut Self {
+        self.virtual_el2 = GtdtTimer { gsiv, flags };
+        self
+    }
+
+    /// Lays out the table, including the SDT header and checksum.
+    pub fn to_sdt(&self) -> SDT {
+        let mut gtdt = SDT::with_oem(*b"GTDT", GTDT_LEN, GTDT_REVISION, &self.oem);
+
+        gtdt.write(GTDT_FIELD_CNT_CONTROL_BASE, self.cnt_control_base);
+        for (offset, timer) in &[
+            (GTDT_FIELD_SECURE_EL1_TIMER, self.secure_el1),
+            (GTDT_FIELD_NON_SECURE_EL1_TIMER, self.non_secure_el1),
+            (GTDT_FIELD_VIRTUAL_EL1_TIMER, self.virtual_el1),
+            (GTDT_FIELD_EL2_TIMER, self.el2),
+            (GTDT_FIELD_VIRTUAL_EL2_TIMER, self.virtual_el2),
+        ] {
+            gtdt.write(*offset, timer.gsiv);
+            gtdt.write(*offset + 4, timer.flags);
+        }
+        gtdt.write(GTDT_FIELD_CNT_READ_BASE, u64::MAX);
+
+        gtdt
+    }
+
+    /// Returns the serialized table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_sdt().as_slice().to_vec()
+    }
+}
+
+impl Default for Gtdt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify_checksum;
+
+    #[test]
+    fn test_gtdt() {
+        let mut gtdt = Gtdt::new();
+        gtdt.set_secure_el1_timer(29, GTDT_TIMER_ACTIVE_LOW)
+            .set_non_secure_el1_timer(30, GTDT_TIMER_ACTIVE_LOW)
+            .set_virtual_el1_timer(27, GTDT_TIMER_ACTIVE_LOW)
+            .set_el2_timer(26, GTDT_TIMER_ACTIVE_LOW);
+        let bytes = gtdt.to_bytes();
+        assert_eq!(bytes.len(), 104);
+        assert_eq!(&bytes[0..4], b"GTDT");
+        assert_eq!(&bytes[4..8], &104u32.to_le_bytes());
+        assert_eq!(&bytes[36..44], &u64::MAX.to_le_bytes());
+        assert_eq!(&bytes[56..60], &30u32.to_le_bytes());
+        assert_eq!(&bytes[60..64], &GTDT_TIMER_ACTIVE_LOW.to_le_bytes());
+        assert_eq!(&bytes[64..68], &27u32.to_le_bytes());
+        assert_eq!(&bytes[96..100], &0u32.to_le_bytes());
+        assert!(verify_checksum(&bytes));
+    }
+}
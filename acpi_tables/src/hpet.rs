@@ -0,0 +1,110 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::sdt::{GenericAddress, OemFields, SDT};
+
+// HPET
+pub const HPET_LEN: u32 = 56;
+pub const HPET_REVISION: u8 = 1;
+// Event timer block id: hardware revision 1, three comparators, 64-bit main counter, legacy
+// replacement route capable, PCI vendor id 0x8086.
+pub const HPET_DEFAULT_EVENT_TIMER_BLOCK_ID: u32 = 0x8086_a201;
+// Minimum clock ticks between periodic interrupts without losing interrupts.
+pub const HPET_DEFAULT_MIN_TICK: u16 = 0x80;
+// HPET fields offset
+const HPET_FIELD_EVENT_TIMER_BLOCK_ID: usize = 36;
+const HPET_FIELD_BASE_ADDRESS: usize = 40;
+const HPET_FIELD_HPET_NUMBER: usize = 52;
+const HPET_FIELD_MIN_TICK: usize = 53;
+const HPET_FIELD_PAGE_PROTECTION: usize = 55;
+
+/// Builder for the High Precision Event Timer description table (signature "HPET").
+#[derive(Clone)]
+pub struct Hpet {
+    pub oem: OemFields,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub min_tick: u16,
+    pub page_protection: u8,
+}
+
+impl Hpet {
+    /// Describes HPET block `hpet_number` with its registers mapped at `base_address`.
+    pub fn new(base_address: u64, hpet_number: u8) -> Self {
+        Hpet {
+            oem: OemFields::default(),
+            event_timer_block_id: HPET_DEFAULT_EVENT_TIMER_BLOCK_ID,
+            base_address: GenericAddress::mmio(base_address),
+            hpet_number,
+            min_tick: HPET_DEFAULT_MIN_TICK,
+            page_protection: 0,
+        }
+    }
+
+    /// Sets the OEM and creator fields of the table header.
+    pub fn set_oem(&mut self, oem: OemFields) -> &mut Self {
+        self.oem = oem;
+        self
+    }
+
+    /// Lays out the table, including the SDT header and checksum.
+    pub fn to_sdt(&self) -> SDT {
+        let mut hpet = SDT::with_oem(*b"HPET", HPET_LEN, HPET_REVISION, &self.oem);
+
+        hpet.write(HPET_FIELD_EVENT_TIMER_BLOCK_ID, self.event_timer_block_id);
+        hpet.write(HPET_FIELD_BASE_ADDRESS, self.base_address);
+        hpet.write(HPET_FIELD_HPET_NUMBER, self.hpet_number);
+        hpet.write(HPET_FIELD_MIN_TICK, self.min_tick);
+        hpet.write(HPET_FIELD_PAGE_PROTECTION, self.page_protection);
+
+        hpet
+    }
+
+    /// Returns the serialized table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_sdt().as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdt::ADDRESS_SPACE_SYSTEM_MEMORY;
+    use crate::verify_checksum;
+
+    #[test]
+    fn test_hpet() {
+        let bytes = Hpet::new(0xfed0_0000, 0).to_bytes();
+        assert_eq!(std::mem::size_of::<GenericAddress>(), 12);
+        assert_eq!(bytes.len(), 56);
+        assert_eq!(&bytes[0..4], b"HPET");
+        assert_eq!(&bytes[4..8], &56u32.to_le_bytes());
+        assert_eq!(
+            &bytes[36..40],
+            &HPET_DEFAULT_EVENT_TIMER_BLOCK_ID.to_le_bytes()
+        );
+
+        let gas = &bytes[40..52];
+        assert_eq!(gas[0], ADDRESS_SPACE_SYSTEM_MEMORY);
+        assert_eq!(gas[1], 64);
+        assert_eq!(gas[2], 0);
+        assert_eq!(&gas[4..12], &0xfed0_0000u64.to_le_bytes());
+
+        assert_eq!(bytes[52], 0);
+        assert_eq!(&bytes[53..55], &HPET_DEFAULT_MIN_TICK.to_le_bytes());
+        assert_eq!(bytes[55], 0);
+        assert!(verify_checksum(&bytes));
+    }
+
+    #[test]
+    fn test_hpet_oem() {
+        let mut hpet = Hpet::new(0xfed0_0000, 0);
+        hpet.set_oem(OemFields::new("ACME", "TBL").unwrap());
+        let bytes = hpet.to_bytes();
+        assert_eq!(&bytes[10..16], b"ACME  ");
+        assert_eq!(&bytes[16..24], b"TBL     ");
+        assert!(verify_checksum(&bytes));
+    }
+}
@@ -4,11 +4,58 @@
 
 pub mod aml;
 pub mod facs;
+pub mod fadt;
+pub mod gtdt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
 pub mod rsdp;
 pub mod sdt;
+pub mod spcr;
+pub mod waet;
+pub mod xsdt;
 
 pub use self::sdt::HEADER_LEN;
 
-fn generate_checksum(data: &[u8]) -> u8 {
+/// Returns the byte that, added to `data`, makes the sum of all bytes zero mod 256.
+pub fn generate_checksum(data: &[u8]) -> u8 {
     (255 - data.iter().fold(0u8, |acc, x| acc.wrapping_add(*x))).wrapping_add(1)
 }
+
+/// Returns true if the bytes of `data`, including its checksum byte, sum to zero mod 256.
+pub fn verify_checksum(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data() -> Vec<u8> {
+        // Simple LCG so the data is arbitrary but reproducible.
+        let mut state = 0x1234_5678u32;
+        (0..64)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checksum_verifies() {
+        let mut data = test_data();
+        data[9] = 0;
+        data[9] = generate_checksum(&data);
+        assert!(verify_checksum(&data));
+    }
+
+    #[test]
+    fn checksum_detects_corruption() {
+        let mut data = test_data();
+        data[9] = 0;
+        data[9] = generate_checksum(&data);
+        data[20] ^= 0x01;
+        assert!(!verify_checksum(&data));
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use data_model::DataInit;
+
+use crate::sdt::{OemFields, SDT};
+
+// MADT
+pub const MADT_LEN: u32 = 44;
+pub const MADT_REVISION: u8 = 5;
+// MADT fields offset
+const MADT_FIELD_LAPIC_ADDR: usize = 36;
+const MADT_FIELD_FLAGS: usize = 40;
+// MADT types
+const MADT_TYPE_LOCAL_APIC: u8 = 0;
+const MADT_TYPE_IO_APIC: u8 = 1;
+const MADT_TYPE_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_TYPE_NMI_SOURCE: u8 = 3;
+// MADT flags
+pub const MADT_PCAT_COMPAT: u32 = 1;
+// Local APIC flags
+pub const MADT_ENABLED: u32 = 1;
+// MPS INTI flags for interrupt source overrides and NMI sources
+pub const MPS_INTI_POLARITY_ACTIVE_HIGH: u16 = 0b01;
+pub const MPS_INTI_POLARITY_ACTIVE_LOW: u16 = 0b11;
+pub const MPS_INTI_TRIGGER_EDGE: u16 = 0b01 << 2;
+pub const MPS_INTI_TRIGGER_LEVEL: u16 = 0b11 << 2;
+// Bus value for ISA interrupt source overrides
+pub const MADT_BUS_ISA: u8 = 0;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LocalAPIC {
+    _type: u8,
+    _length: u8,
+    _processor_id: u8,
+    _apic_id: u8,
+    _flags: u32,
+}
+
+// Safe as LocalAPIC structure only contains raw data
+unsafe impl DataInit for LocalAPIC {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct IOAPIC {
+    _type: u8,
+    _length: u8,
+    _ioapic_id: u8,
+    _reserved: u8,
+    _apic_address: u32,
+    _gsi_base: u32,
+}
+
+// Safe as IOAPIC structure only contains raw data
+unsafe impl DataInit for IOAPIC {}
+
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct InterruptSourceOverride {
+    _type: u8,
+    _length: u8,
+    _bus: u8,
+    _source: u8,
+    _gsi: u32,
+    _flags: u16,
+}
+
+// Safe as InterruptSourceOverride structure only contains raw data
+unsafe impl DataInit for InterruptSourceOverride {}
+
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct NmiSource {
+    _type: u8,
+    _length: u8,
+    _flags: u16,
+    _gsi: u32,
+}
+
+// Safe as NmiSource structure only contains raw data
+unsafe impl DataInit for NmiSource {}
+
+/// Builder for the Multiple APIC Description Table (signature "APIC").
+pub struct Madt {
+    sdt: SDT,
+}
+
+impl Madt {
+    /// Creates a MADT with no interrupt controller structures, describing the local APICs
+    /// at `local_apic_address`.
+    pub fn new(local_apic_address: u32) -> Self {
+        let mut sdt = SDT::with_oem(*b"APIC", MADT_LEN, MADT_REVISION, &OemFields::default());
+        sdt.write(MADT_FIELD_LAPIC_ADDR, local_apic_address);
+        Madt { sdt }
+    }
+
+    /// Sets the OEM and creator fields of the table header.
+    pub fn set_oem(&mut self, oem: OemFields) -> &mut Self {
+        self.sdt.set_oem(&oem);
+        self
+    }
+
+    /// Sets the MADT flags, e.g. `MADT_PCAT_COMPAT` when legacy 8259 PICs are present.
+    pub fn set_flags(&mut self, flags: u32) -> &mut Self {
+        self.sdt.write(MADT_FIELD_FLAGS, flags);
+        self
+    }
+
+    /// Adds an enabled Local APIC entry for processor `cpu_id`.
+    pub fn add_local_apic(&mut self, cpu_id: u8, apic_id: u8) -> &mut Self {
+        self.sdt.append(LocalAPIC {
+            _type: MADT_TYPE_LOCAL_APIC,
+            _length: std::mem::size_of::<LocalAPIC>() as u8,
+            _processor_id: cpu_id,
+            _apic_id: apic_id,
+            _flags: MADT_ENABLED,
+        });
+        self
+    }
+
+    /// Adds an IO APIC entry at `address` whose first input is `gsi_base`.
+    pub fn add_io_apic(&mut self, ioapic_id: u8, address: u32, gsi_base: u32) -> &mut Self {
+        self.sdt.append(IOAPIC {
+            _type: MADT_TYPE_IO_APIC,
+            _length: std::mem::size_of::<IOAPIC>() as u8,
+            _ioapic_id: ioapic_id,
+            _apic_address: address,
+            _gsi_base: gsi_base,
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Adds an ISA interrupt source override mapping IRQ `source` to `gsi`.
+    pub fn add_interrupt_source_override(&mut self, source: u8, gsi: u32, flags: u16) -> &mut Self {
+        self.add_interrupt_override(MADT_BUS_ISA, source, gsi, flags)
+    }
+
+    /// Adds an interrupt source override mapping IRQ `source` on `bus` to `gsi`. `flags` are the
+    /// `MPS_INTI_*` polarity and trigger mode, or 0 to conform to the bus.
+    pub fn add_interrupt_override(
+        &mut self,
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    ) -> &mut Self {
+        self.sdt.append(InterruptSourceOverride {
+            _type: MADT_TYPE_INTERRUPT_SOURCE_OVERRIDE,
+            _length: std::mem::size_of::<InterruptSourceOverride>() as u8,
+            _bus: bus,
+            _source: source,
+            _gsi: gsi,
+            _flags: flags,
+        });
+        self
+    }
+
+    /// Adds an NMI source entry marking `gsi` as non-maskable, with the `MPS_INTI_*` `flags`.
+    pub fn add_nmi_source(&mut self, gsi: u32, flags: u16) -> &mut Self {
+        self.sdt.append(NmiSource {
+            _type: MADT_TYPE_NMI_SOURCE,
+            _length: std::mem::size_of::<NmiSource>() as u8,
+            _flags: flags,
+            _gsi: gsi,
+        });
+        self
+    }
+
+    pub fn to_sdt(&self) -> SDT {
+        self.sdt.clone()
+    }
+
+    /// Returns the serialized table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.sdt.as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_madt_local_apics() {
+        let mut madt = Madt::new(0xfee0_0000);
+        for cpu in 0..4 {
+            madt.add_local_apic(cpu, cpu + 1);
+        }
+        let bytes = madt.to_bytes();
+        assert_eq!(bytes.len(), MADT_LEN as usize + 4 * 8);
+        assert_eq!(&bytes[36..40], &0xfee0_0000u32.to_le_bytes());
+        for cpu in 0..4u8 {
+            let entry = &bytes[MADT_LEN as usize + cpu as usize * 8..][..8];
+            assert_eq!(entry[0], MADT_TYPE_LOCAL_APIC);
+            assert_eq!(entry[1], 8);
+            assert_eq!(entry[2], cpu);
+            assert_eq!(entry[3], cpu + 1);
+            assert_eq!(&entry[4..8], &MADT_ENABLED.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_madt_checksum() {
+        let mut madt = Madt::new(0xfee0_0000);
+        madt.set_flags(MADT_PCAT_COMPAT)
+            .add_local_apic(0, 0)
+            .add_io_apic(0, 0xfec0_0000, 0)
+            .add_interrupt_source_override(0, 2, 0);
+        let bytes = madt.to_bytes();
+        assert_eq!(bytes.len(), MADT_LEN as usize + 8 + 12 + 10);
+        assert_eq!(&bytes[4..8], &(bytes.len() as u32).to_le_bytes());
+        let sum = bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+        assert_eq!(sum, 0);
+    }
+
+    #[test]
+    fn test_madt_overrides() {
+        let mut madt = Madt::new(0xfee0_0000);
+        madt.add_interrupt_override(
+            MADT_BUS_ISA,
+            0,
+            2,
+            MPS_INTI_POLARITY_ACTIVE_HIGH | MPS_INTI_TRIGGER_EDGE,
+        )
+        .add_interrupt_override(
+            MADT_BUS_ISA,
+            9,
+            9,
+            MPS_INTI_POLARITY_ACTIVE_HIGH | MPS_INTI_TRIGGER_LEVEL,
+        )
+        .add_nmi_source(23, MPS_INTI_POLARITY_ACTIVE_LOW | MPS_INTI_TRIGGER_LEVEL);
+        let bytes = madt.to_bytes();
+        assert_eq!(bytes.len(), MADT_LEN as usize + 10 + 10 + 8);
+
+        let entries = &bytes[MADT_LEN as usize..];
+        // IRQ0 -> GSI2, active high, edge triggered (the PIT).
+        assert_eq!(&entries[..10], &[2, 10, 0, 0, 2, 0, 0, 0, 0x05, 0x00]);
+        // IRQ9 -> GSI9, active high, level triggered (the ACPI SCI).
+        assert_eq!(&entries[10..20], &[2, 10, 0, 9, 9, 0, 0, 0, 0x0d, 0x00]);
+        // NMI on GSI23, active low, level triggered.
+        assert_eq!(&entries[20..28], &[3, 8, 0x0f, 0x00, 23, 0, 0, 0]);
+        let sum = bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+        assert_eq!(sum, 0);
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use data_model::DataInit;
+
+use crate::sdt::{OemFields, SDT};
+
+// MCFG
+pub const MCFG_LEN: u32 = 44;
+pub const MCFG_REVISION: u8 = 1;
+
+/// Configuration space base address allocation structure.
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct McfgSegment {
+    _base_address: u64,
+    _segment_group: u16,
+    _start_bus: u8,
+    _end_bus: u8,
+    _reserved: u32,
+}
+
+// Safe as McfgSegment structure only contains raw data
+unsafe impl DataInit for McfgSegment {}
+
+/// Builder for the PCI Express memory mapped configuration space table (signature "MCFG").
+pub struct Mcfg {
+    sdt: SDT,
+}
+
+impl Mcfg {
+    pub fn new() -> Self {
+        Mcfg {
+            // The header is followed by 8 reserved bytes before the first allocation.
+            sdt: SDT::with_oem(*b"MCFG", MCFG_LEN, MCFG_REVISION, &OemFields::default()),
+        }
+    }
+
+    /// Sets the OEM and creator fields of the table header.
+    pub fn set_oem(&mut self, oem: OemFields) -> &mut Self {
+        self.sdt.set_oem(&oem);
+        self
+    }
+
+    /// Adds an ECAM region at `base` covering buses `start_bus..=end_bus` of PCI segment
+    /// group `segment`.
+    pub fn add_segment(
+        &mut self,
+        base: u64,
+        segment: u16,
+        start_bus: u8,
+        end_bus: u8,
+    ) -> &mut Self {
+        self.sdt.append(McfgSegment {
+            _base_address: base,
+            _segment_group: segment,
+            _start_bus: start_bus,
+            _end_bus: end_bus,
+            _reserved: 0,
+        });
+        self
+    }
+
+    pub fn to_sdt(&self) -> SDT {
+        self.sdt.clone()
+    }
+
+    /// Returns the serialized table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.sdt.as_slice().to_vec()
+    }
+}
+
+impl Default for Mcfg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify_checksum;
+
+    #[test]
+    fn test_mcfg() {
+        let mut mcfg = Mcfg::new();
+        mcfg.add_segment(0xe000_0000, 0, 0, 255)
+            .add_segment(0x1_0000_0000, 1, 0, 15);
+        let bytes = mcfg.to_bytes();
+        assert_eq!(std::mem::size_of::<McfgSegment>(), 16);
+        assert_eq!(bytes.len(), MCFG_LEN as usize + 2 * 16);
+        assert_eq!(&bytes[4..8], &(bytes.len() as u32).to_le_bytes());
+
+        let second = &bytes[MCFG_LEN as usize + 16..];
+        assert_eq!(&second[0..8], &0x1_0000_0000u64.to_le_bytes());
+        assert_eq!(&second[8..10], &1u16.to_le_bytes());
+        assert_eq!(second[10], 0);
+        assert_eq!(second[11], 15);
+        assert!(verify_checksum(&bytes));
+    }
+}
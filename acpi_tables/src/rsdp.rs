@@ -21,6 +21,9 @@ pub struct RSDP {
 // Safe as RSDP structure only contains raw data
 unsafe impl DataInit for RSDP {}
 
+// Length of the ACPI 1.0 portion covered by `checksum`.
+const RSDP_V1_LEN: usize = 20;
+
 impl RSDP {
     pub fn new(oem_id: [u8; 6], xsdt_addr: u64) -> Self {
         let mut rsdp = RSDP {
@@ -35,11 +38,27 @@ impl RSDP {
             _reserved: [0; 3],
         };
 
-        rsdp.checksum = super::generate_checksum(&rsdp.as_slice()[0..19]);
+        rsdp.checksum = super::generate_checksum(&rsdp.as_slice()[0..RSDP_V1_LEN]);
         rsdp.extended_checksum = super::generate_checksum(&rsdp.as_slice());
         rsdp
     }
 
+    /// Creates an ACPI 1.0 RSDP pointing at a 32-bit RSDT. Only the first 20 bytes are
+    /// meaningful to guests and covered by the checksum.
+    pub fn new_v1(oem_id: [u8; 6], rsdt_addr: u32) -> Self {
+        let mut rsdp = RSDP {
+            signature: *b"RSD PTR ",
+            checksum: 0,
+            oem_id,
+            revision: 0,
+            _rsdt_addr: rsdt_addr,
+            ..Default::default()
+        };
+
+        rsdp.checksum = super::generate_checksum(&rsdp.as_slice()[0..RSDP_V1_LEN]);
+        rsdp
+    }
+
     pub fn len() -> usize {
         std::mem::size_of::<RSDP>()
     }
@@ -47,7 +66,8 @@ impl RSDP {
 
 #[cfg(test)]
 mod tests {
-    use super::RSDP;
+    use super::{RSDP, RSDP_V1_LEN};
+    use crate::verify_checksum;
     use data_model::DataInit;
 
     #[test]
@@ -64,4 +84,24 @@ mod tests {
             .fold(0u8, |acc, x| acc.wrapping_add(*x));
         assert_eq!(sum, 0);
     }
+
+    #[test]
+    fn test_rsdp_v2_checksums() {
+        let rsdp = RSDP::new(*b"CROSVM", 0x1_2345_6789);
+        let bytes = rsdp.as_slice();
+        assert_eq!(bytes.len(), 36);
+        assert_eq!(bytes[15], 2);
+        assert_eq!(&bytes[24..32], &0x1_2345_6789u64.to_le_bytes());
+        assert!(verify_checksum(&bytes[0..RSDP_V1_LEN]));
+        assert!(verify_checksum(bytes));
+    }
+
+    #[test]
+    fn test_rsdp_v1() {
+        let rsdp = RSDP::new_v1(*b"CROSVM", 0xdead_beef);
+        let bytes = rsdp.as_slice();
+        assert_eq!(bytes[15], 0);
+        assert_eq!(&bytes[16..20], &0xdead_beefu32.to_le_bytes());
+        assert!(verify_checksum(&bytes[0..RSDP_V1_LEN]));
+    }
 }
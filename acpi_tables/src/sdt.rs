@@ -2,6 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::convert::TryInto;
+use std::fmt::{self, Display};
 use std::fs::File;
 use std::io::{ErrorKind, Read, Result};
 use std::path::PathBuf;
@@ -17,7 +19,90 @@ pub struct SDT {
 
 pub const HEADER_LEN: u32 = 36;
 const LENGTH_OFFSET: usize = 4;
+const REVISION_OFFSET: usize = 8;
 const CHECKSUM_OFFSET: usize = 9;
+const OEM_ID_OFFSET: usize = 10;
+const OEM_TABLE_ID_OFFSET: usize = 16;
+const OEM_REVISION_OFFSET: usize = 24;
+const CREATOR_ID_OFFSET: usize = 28;
+const CREATOR_REVISION_OFFSET: usize = 32;
+// Generic address structure address space ids
+pub const ADDRESS_SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_SYSTEM_IO: u8 = 1;
+// Generic address structure access sizes
+pub const ACCESS_SIZE_BYTE: u8 = 1;
+
+/// Reasons `OemFields::new` rejects an identifier.
+#[derive(Debug, PartialEq)]
+pub enum OemError {
+    /// The OEM ID is longer than 6 bytes.
+    OemIdTooLong(usize),
+    /// The OEM table ID is longer than 8 bytes.
+    OemTableIdTooLong(usize),
+}
+
+impl Display for OemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::OemError::*;
+
+        match self {
+            OemIdTooLong(len) => write!(f, "OEM ID is {} bytes, at most 6 fit", len),
+            OemTableIdTooLong(len) => write!(f, "OEM table ID is {} bytes, at most 8 fit", len),
+        }
+    }
+}
+
+impl std::error::Error for OemError {}
+
+/// The OEM and creator identification in the header of every table.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OemFields {
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl OemFields {
+    /// Identifies tables as coming from `oem_id` with the table ID `oem_table_id`. Shorter
+    /// identifiers are padded with spaces. The revision and creator fields are the defaults.
+    pub fn new(oem_id: &str, oem_table_id: &str) -> std::result::Result<Self, OemError> {
+        let mut oem = OemFields::default();
+        if !pad_id(&mut oem.oem_id, oem_id) {
+            return Err(OemError::OemIdTooLong(oem_id.len()));
+        }
+        if !pad_id(&mut oem.oem_table_id, oem_table_id) {
+            return Err(OemError::OemTableIdTooLong(oem_table_id.len()));
+        }
+        Ok(oem)
+    }
+}
+
+impl Default for OemFields {
+    fn default() -> Self {
+        OemFields {
+            oem_id: *b"CROSVM",
+            oem_table_id: *b"CROSVMDT",
+            oem_revision: 1,
+            creator_id: u32::from_le_bytes(*b"CROS"),
+            creator_revision: 0,
+        }
+    }
+}
+
+// Copies `id` into `field`, padding it with spaces. Returns false if `id` doesn't fit.
+fn pad_id(field: &mut [u8], id: &str) -> bool {
+    if id.len() > field.len() {
+        return false;
+    }
+    let (head, tail) = field.split_at_mut(id.len());
+    head.copy_from_slice(id.as_bytes());
+    for b in tail {
+        *b = b' ';
+    }
+    true
+}
 
 #[allow(clippy::len_without_is_empty)]
 impl SDT {
@@ -59,13 +144,35 @@ impl SDT {
         sdt
     }
 
+    /// Like `new`, but with all of the OEM and creator fields of the header taken from `oem`.
+    pub fn with_oem(signature: [u8; 4], length: u32, revision: u8, oem: &OemFields) -> Self {
+        let mut sdt = SDT::new(
+            signature,
+            length,
+            revision,
+            oem.oem_id,
+            oem.oem_table_id,
+            oem.oem_revision,
+        );
+        sdt.set_oem(oem);
+        sdt
+    }
+
+    /// Overwrites the OEM and creator fields of the header.
+    pub fn set_oem(&mut self, oem: &OemFields) {
+        self.write(OEM_ID_OFFSET, oem.oem_id);
+        self.write(OEM_TABLE_ID_OFFSET, oem.oem_table_id);
+        self.write(OEM_REVISION_OFFSET, oem.oem_revision);
+        self.write(CREATOR_ID_OFFSET, oem.creator_id);
+        self.write(CREATOR_REVISION_OFFSET, oem.creator_revision);
+    }
+
     /// Set up the ACPI table from file content. Verify file checksum.
     pub fn from_file(path: &PathBuf) -> Result<Self> {
         let mut file = File::open(path)?;
         let mut data = Vec::new();
         file.read_to_end(&mut data)?;
-        let checksum = super::generate_checksum(data.as_slice());
-        if checksum == 0 {
+        if super::verify_checksum(data.as_slice()) {
             Ok(SDT { data })
         } else {
             Err(ErrorKind::InvalidData.into())
@@ -112,9 +219,151 @@ impl SDT {
     }
 }
 
+/// Reasons a table is rejected by `parse`.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The buffer is shorter than the table header.
+    TooShort(usize),
+    /// The length in the header is shorter than the header or longer than the buffer.
+    LengthMismatch { length: u32, available: usize },
+    /// The bytes of the table don't sum to zero.
+    BadChecksum,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ParseError::*;
+
+        match self {
+            TooShort(len) => write!(f, "{} bytes is too short for a table header", len),
+            LengthMismatch { length, available } => write!(
+                f,
+                "table length {} doesn't fit the {} bytes available",
+                length, available
+            ),
+            BadChecksum => write!(f, "table checksum doesn't match"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A validated, read-only view of a table in a byte buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct SdtView<'a> {
+    data: &'a [u8],
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a> SdtView<'a> {
+    // The unwraps below are safe as `parse` checked that the whole header is present.
+    pub fn signature(&self) -> [u8; 4] {
+        self.data[0..4].try_into().unwrap()
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.data[REVISION_OFFSET]
+    }
+
+    pub fn oem_id(&self) -> [u8; 6] {
+        self.data[OEM_ID_OFFSET..OEM_ID_OFFSET + 6]
+            .try_into()
+            .unwrap()
+    }
+
+    pub fn oem_table_id(&self) -> [u8; 8] {
+        self.data[OEM_TABLE_ID_OFFSET..OEM_TABLE_ID_OFFSET + 8]
+            .try_into()
+            .unwrap()
+    }
+
+    pub fn oem_revision(&self) -> u32 {
+        u32::from_le_bytes(
+            self.data[OEM_REVISION_OFFSET..OEM_REVISION_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    /// The whole table, including the header.
+    pub fn as_slice(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The table contents following the header.
+    pub fn body(&self) -> &'a [u8] {
+        &self.data[HEADER_LEN as usize..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Validates the table at the start of `data`: the length in its header must cover at least the
+/// header and fit within `data`, and its bytes must sum to zero. Anything in `data` past the
+/// length given in the header is ignored.
+pub fn parse(data: &[u8]) -> std::result::Result<SdtView, ParseError> {
+    if data.len() < HEADER_LEN as usize {
+        return Err(ParseError::TooShort(data.len()));
+    }
+    // The unwrap is safe as the header is present.
+    let length = u32::from_le_bytes(data[LENGTH_OFFSET..LENGTH_OFFSET + 4].try_into().unwrap());
+    if length < HEADER_LEN || length as usize > data.len() {
+        return Err(ParseError::LengthMismatch {
+            length,
+            available: data.len(),
+        });
+    }
+    let data = &data[..length as usize];
+    if !super::verify_checksum(data) {
+        return Err(ParseError::BadChecksum);
+    }
+    Ok(SdtView { data })
+}
+
+/// ACPI generic address structure.
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+pub struct GenericAddress {
+    pub address_space_id: u8,
+    pub register_bit_width: u8,
+    pub register_bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+// Safe as GenericAddress structure only contains raw data
+unsafe impl DataInit for GenericAddress {}
+
+impl GenericAddress {
+    /// Describes a 64-bit wide register block in memory space at `address`.
+    pub fn mmio(address: u64) -> Self {
+        GenericAddress {
+            address_space_id: ADDRESS_SPACE_SYSTEM_MEMORY,
+            register_bit_width: 64,
+            register_bit_offset: 0,
+            access_size: 0,
+            address,
+        }
+    }
+
+    /// Describes a register block of `bit_width` bits, accessed a byte at a time, in I/O space at
+    /// `port`.
+    pub fn io(port: u16, bit_width: u8) -> Self {
+        GenericAddress {
+            address_space_id: ADDRESS_SPACE_SYSTEM_IO,
+            register_bit_width: bit_width,
+            register_bit_offset: 0,
+            access_size: ACCESS_SIZE_BYTE,
+            address: port.into(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::SDT;
+    use super::{parse, OemError, OemFields, ParseError, SDT};
     use std::io::Write;
     use tempfile::NamedTempFile;
 
@@ -134,6 +383,19 @@ mod tests {
         assert_eq!(sum, 0);
     }
 
+    #[test]
+    fn test_sdt_append() {
+        let mut sdt = SDT::new(*b"TEST", 36, 1, *b"CROSVM", *b"TESTTEST", 1);
+        sdt.append(0x1234_5678u32);
+        sdt.append_slice(&[1, 2, 3]);
+        let bytes = sdt.as_slice();
+        assert_eq!(sdt.len(), 43);
+        assert_eq!(&bytes[4..8], &43u32.to_le_bytes());
+        assert_eq!(&bytes[36..40], &0x1234_5678u32.to_le_bytes());
+        assert_eq!(&bytes[40..43], &[1, 2, 3]);
+        assert!(crate::verify_checksum(bytes));
+    }
+
     #[test]
     fn test_sdt_read_write() -> Result<(), std::io::Error> {
         let temp_file = NamedTempFile::new()?;
@@ -151,4 +413,72 @@ mod tests {
         assert_eq!(actual_sdt.as_slice(), expected_sdt.as_slice());
         Ok(())
     }
+
+    #[test]
+    fn test_oem_fields() {
+        let oem = OemFields::new("ACME", "ACMETBL").unwrap();
+        assert_eq!(&oem.oem_id, b"ACME  ");
+        assert_eq!(&oem.oem_table_id, b"ACMETBL ");
+        assert_eq!(
+            OemFields::new("TOOLONG", "TBL").unwrap_err(),
+            OemError::OemIdTooLong(7)
+        );
+        assert_eq!(
+            OemFields::new("ACME", "ACMETABLE").unwrap_err(),
+            OemError::OemTableIdTooLong(9)
+        );
+
+        let mut oem = oem;
+        oem.oem_revision = 3;
+        oem.creator_id = u32::from_le_bytes(*b"ACME");
+        oem.creator_revision = 5;
+        let sdt = SDT::with_oem(*b"TEST", 40, 1, &oem);
+        let bytes = sdt.as_slice();
+        assert_eq!(&bytes[10..16], b"ACME  ");
+        assert_eq!(&bytes[16..24], b"ACMETBL ");
+        assert_eq!(&bytes[24..28], &3u32.to_le_bytes());
+        assert_eq!(&bytes[28..32], b"ACME");
+        assert_eq!(&bytes[32..36], &5u32.to_le_bytes());
+        assert!(crate::verify_checksum(bytes));
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut sdt = SDT::new(*b"TEST", 36, 2, *b"CROSVM", *b"TESTTEST", 7);
+        sdt.append_slice(&[1, 2, 3, 4]);
+        let mut bytes = sdt.as_slice().to_vec();
+        // Trailing bytes past the table's length are ignored.
+        bytes.push(0xff);
+
+        let view = parse(&bytes).unwrap();
+        assert_eq!(&view.signature(), b"TEST");
+        assert_eq!(view.revision(), 2);
+        assert_eq!(&view.oem_id(), b"CROSVM");
+        assert_eq!(&view.oem_table_id(), b"TESTTEST");
+        assert_eq!(view.oem_revision(), 7);
+        assert_eq!(view.len(), 40);
+        assert_eq!(view.body(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_parse_length_mismatch() {
+        let sdt = SDT::new(*b"TEST", 40, 1, *b"CROSVM", *b"TESTTEST", 1);
+        let bytes = sdt.as_slice();
+        assert_eq!(
+            parse(&bytes[..39]).unwrap_err(),
+            ParseError::LengthMismatch {
+                length: 40,
+                available: 39
+            }
+        );
+        assert_eq!(parse(&bytes[..20]).unwrap_err(), ParseError::TooShort(20));
+    }
+
+    #[test]
+    fn test_parse_bad_checksum() {
+        let sdt = SDT::new(*b"TEST", 40, 1, *b"CROSVM", *b"TESTTEST", 1);
+        let mut bytes = sdt.as_slice().to_vec();
+        bytes[38] ^= 0x10;
+        assert_eq!(parse(&bytes).unwrap_err(), ParseError::BadChecksum);
+    }
 }
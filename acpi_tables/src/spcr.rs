@@ -0,0 +1,138 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::sdt::{GenericAddress, OemFields, SDT};
+
+// SPCR
+pub const SPCR_LEN: u32 = 80;
+pub const SPCR_REVISION: u8 = 2;
+// SPCR interface types
+pub const SPCR_INTERFACE_16550: u8 = 0;
+// SPCR interrupt types
+pub const SPCR_INTERRUPT_PCAT: u8 = 1 << 0;
+pub const SPCR_INTERRUPT_IO_APIC: u8 = 1 << 1;
+// SPCR baud rates
+pub const SPCR_BAUD_RATE_AS_IS: u8 = 0;
+pub const SPCR_BAUD_RATE_9600: u8 = 3;
+pub const SPCR_BAUD_RATE_19200: u8 = 4;
+pub const SPCR_BAUD_RATE_57600: u8 = 6;
+pub const SPCR_BAUD_RATE_115200: u8 = 7;
+// SPCR parity
+pub const SPCR_PARITY_NONE: u8 = 0;
+// SPCR fields offset
+const SPCR_FIELD_INTERFACE_TYPE: usize = 36;
+const SPCR_FIELD_BASE_ADDRESS: usize = 40;
+const SPCR_FIELD_INTERRUPT_TYPE: usize = 52;
+const SPCR_FIELD_IRQ: usize = 53;
+const SPCR_FIELD_GSIV: usize = 54;
+const SPCR_FIELD_BAUD_RATE: usize = 58;
+const SPCR_FIELD_PARITY: usize = 59;
+const SPCR_FIELD_STOP_BITS: usize = 60;
+const SPCR_FIELD_FLOW_CONTROL: usize = 61;
+const SPCR_FIELD_PCI_DEVICE_ID: usize = 64;
+const SPCR_FIELD_PCI_VENDOR_ID: usize = 66;
+
+/// Builder for the Serial Port Console Redirection table (signature "SPCR").
+///
+/// Describes a 16550 compatible UART in I/O space. The console is not a PCI
+/// device, so the PCI fields are always reported as absent.
+#[derive(Clone)]
+pub struct Spcr {
+    pub oem: OemFields,
+    pub base_address: GenericAddress,
+    pub irq: u8,
+    pub baud_rate: u8,
+    pub parity: u8,
+    pub stop_bits: u8,
+    pub flow_control: u8,
+}
+
+impl Spcr {
+    /// Describes a UART at I/O port `base_address` raising interrupt `irq`, running at 115200
+    /// baud with no parity, one stop bit and no flow control.
+    pub fn new(base_address: u16, irq: u8) -> Self {
+        Spcr {
+            oem: OemFields::default(),
+            base_address: GenericAddress::io(base_address, 8),
+            irq,
+            baud_rate: SPCR_BAUD_RATE_115200,
+            parity: SPCR_PARITY_NONE,
+            stop_bits: 1,
+            flow_control: 0,
+        }
+    }
+
+    /// Sets the OEM and creator fields of the table header.
+    pub fn set_oem(&mut self, oem: OemFields) -> &mut Self {
+        self.oem = oem;
+        self
+    }
+
+    pub fn set_baud_rate(&mut self, baud_rate: u8) -> &mut Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    pub fn set_parity(&mut self, parity: u8) -> &mut Self {
+        self.parity = parity;
+        self
+    }
+
+    /// Lays out the table, including the SDT header and checksum.
+    pub fn to_sdt(&self) -> SDT {
+        let mut spcr = SDT::with_oem(*b"SPCR", SPCR_LEN, SPCR_REVISION, &self.oem);
+
+        spcr.write(SPCR_FIELD_INTERFACE_TYPE, SPCR_INTERFACE_16550);
+        spcr.write(SPCR_FIELD_BASE_ADDRESS, self.base_address);
+        spcr.write(
+            SPCR_FIELD_INTERRUPT_TYPE,
+            SPCR_INTERRUPT_PCAT | SPCR_INTERRUPT_IO_APIC,
+        );
+        spcr.write(SPCR_FIELD_IRQ, self.irq);
+        spcr.write(SPCR_FIELD_GSIV, u32::from(self.irq));
+        spcr.write(SPCR_FIELD_BAUD_RATE, self.baud_rate);
+        spcr.write(SPCR_FIELD_PARITY, self.parity);
+        spcr.write(SPCR_FIELD_STOP_BITS, self.stop_bits);
+        spcr.write(SPCR_FIELD_FLOW_CONTROL, self.flow_control);
+        spcr.write(SPCR_FIELD_PCI_DEVICE_ID, 0xffffu16);
+        spcr.write(SPCR_FIELD_PCI_VENDOR_ID, 0xffffu16);
+
+        spcr
+    }
+
+    /// Returns the serialized table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_sdt().as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdt::{ACCESS_SIZE_BYTE, ADDRESS_SPACE_SYSTEM_IO};
+    use crate::verify_checksum;
+
+    #[test]
+    fn test_spcr() {
+        let mut spcr = Spcr::new(0x3f8, 4);
+        spcr.set_baud_rate(SPCR_BAUD_RATE_9600);
+        let bytes = spcr.to_bytes();
+        assert_eq!(bytes.len(), SPCR_LEN as usize);
+        assert_eq!(&bytes[0..4], b"SPCR");
+
+        let gas = &bytes[40..52];
+        assert_eq!(gas[0], ADDRESS_SPACE_SYSTEM_IO);
+        assert_eq!(gas[1], 8);
+        assert_eq!(gas[2], 0);
+        assert_eq!(gas[3], ACCESS_SIZE_BYTE);
+        assert_eq!(&gas[4..12], &0x3f8u64.to_le_bytes());
+
+        assert_eq!(bytes[53], 4);
+        assert_eq!(&bytes[54..58], &4u32.to_le_bytes());
+        assert_eq!(bytes[58], SPCR_BAUD_RATE_9600);
+        assert_eq!(bytes[59], SPCR_PARITY_NONE);
+        assert_eq!(bytes[60], 1);
+        assert!(verify_checksum(&bytes));
+    }
+}
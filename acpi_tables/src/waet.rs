@@ -0,0 +1,86 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::sdt::{OemFields, SDT};
+
+// WAET
+pub const WAET_LEN: u32 = 40;
+pub const WAET_REVISION: u8 = 1;
+// WAET emulated device flags
+pub const WAET_RTC_GOOD: u32 = 1 << 0;
+pub const WAET_PM_TIMER_GOOD: u32 = 1 << 1;
+// WAET fields offset
+const WAET_FIELD_EMULATED_DEVICE_FLAGS: usize = 36;
+
+/// Builder for the Windows ACPI Emulated Devices Table (signature "WAET").
+///
+/// The flags tell the guest which emulated devices don't need the workarounds
+/// it applies to real hardware.
+#[derive(Clone, Default)]
+pub struct Waet {
+    oem: OemFields,
+    flags: u32,
+}
+
+impl Waet {
+    pub fn new() -> Self {
+        Waet::default()
+    }
+
+    /// Sets the OEM and creator fields of the table header.
+    pub fn set_oem(&mut self, oem: OemFields) -> &mut Self {
+        self.oem = oem;
+        self
+    }
+
+    /// Declares that the RTC status register doesn't need to be read after each update.
+    pub fn set_rtc_good(&mut self) -> &mut Self {
+        self.flags |= WAET_RTC_GOOD;
+        self
+    }
+
+    /// Declares that the ACPI PM timer can be read once per timestamp.
+    pub fn set_pm_timer_good(&mut self) -> &mut Self {
+        self.flags |= WAET_PM_TIMER_GOOD;
+        self
+    }
+
+    /// Lays out the table, including the SDT header and checksum.
+    pub fn to_sdt(&self) -> SDT {
+        let mut waet = SDT::with_oem(*b"WAET", WAET_LEN, WAET_REVISION, &self.oem);
+        waet.write(WAET_FIELD_EMULATED_DEVICE_FLAGS, self.flags);
+        waet
+    }
+
+    /// Returns the serialized table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_sdt().as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify_checksum;
+
+    #[test]
+    fn test_waet() {
+        let mut waet = Waet::new();
+        let bytes = waet.to_bytes();
+        assert_eq!(bytes.len(), WAET_LEN as usize);
+        assert_eq!(&bytes[0..4], b"WAET");
+        assert_eq!(&bytes[36..40], &0u32.to_le_bytes());
+        assert!(verify_checksum(&bytes));
+
+        waet.set_rtc_good();
+        let bytes = waet.to_bytes();
+        assert_eq!(&bytes[36..40], &WAET_RTC_GOOD.to_le_bytes());
+        assert!(verify_checksum(&bytes));
+
+        waet.set_pm_timer_good();
+        let bytes = waet.to_bytes();
+        assert_eq!(&bytes[36..40], &3u32.to_le_bytes());
+        assert!(verify_checksum(&bytes));
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::sdt::{OemFields, SDT};
+
+pub const XSDT_REVISION: u8 = 1;
+
+/// Builder for the Extended System Description Table, which holds 64-bit pointers to the
+/// other system description tables.
+pub struct Xsdt {
+    sdt: SDT,
+}
+
+impl Xsdt {
+    pub fn new(oem_id: [u8; 6], oem_table: [u8; 8], oem_revision: u32) -> Self {
+        Xsdt {
+            sdt: SDT::new(
+                *b"XSDT",
+                crate::HEADER_LEN,
+                XSDT_REVISION,
+                oem_id,
+                oem_table,
+                oem_revision,
+            ),
+        }
+    }
+
+    /// Sets the OEM and creator fields of the table header.
+    pub fn set_oem(&mut self, oem: OemFields) -> &mut Self {
+        self.sdt.set_oem(&oem);
+        self
+    }
+
+    /// Appends the guest physical address of another table.
+    pub fn add_table(&mut self, addr: u64) -> &mut Self {
+        self.sdt.append(addr);
+        self
+    }
+
+    pub fn to_sdt(&self) -> SDT {
+        self.sdt.clone()
+    }
+
+    /// Returns the serialized table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.sdt.as_slice().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify_checksum;
+
+    #[test]
+    fn test_xsdt() {
+        let mut xsdt = Xsdt::new(*b"CROSVM", *b"CROSVMDT", 1);
+        xsdt.add_table(0x1_0000_0000).add_table(0x2000);
+        let bytes = xsdt.to_bytes();
+        assert_eq!(bytes.len(), crate::HEADER_LEN as usize + 16);
+        assert_eq!(&bytes[36..44], &0x1_0000_0000u64.to_le_bytes());
+        assert_eq!(&bytes[44..52], &0x2000u64.to_le_bytes());
+        assert!(verify_checksum(&bytes));
+    }
+}
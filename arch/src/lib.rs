@@ -72,6 +72,21 @@ pub enum VcpuAffinity {
     PerVcpu(BTreeMap<usize, Vec<usize>>),
 }
 
+/// Number of sockets, cores per socket, and threads per core presented to the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuTopology {
+    pub sockets: usize,
+    pub cores: usize,
+    pub threads: usize,
+}
+
+impl CpuTopology {
+    /// Returns the total number of vcpus in the topology.
+    pub fn vcpu_count(&self) -> usize {
+        self.sockets * self.cores * self.threads
+    }
+}
+
 /// Holds the pieces needed to build a VM. Passed to `build_vm` in the `LinuxArch` trait below to
 /// create a `RunnableLinuxVm`.
 pub struct VmComponents {
@@ -79,6 +94,7 @@ pub struct VmComponents {
     pub vcpu_count: usize,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
+    pub cpu_topology: Option<CpuTopology>,
     pub hugepages: bool,
     pub vm_image: VmImage,
     pub android_fstab: Option<File>,
@@ -105,6 +121,7 @@ pub struct RunnableLinuxVm<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch> {
     pub vcpus: Option<Vec<Vcpu>>,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
+    pub cpu_topology: Option<CpuTopology>,
     pub irq_chip: I,
     pub has_bios: bool,
     pub io_bus: Bus,
@@ -181,6 +198,8 @@ pub trait LinuxArch {
     /// * `vcpu_id` - The id of the given `vcpu`.
     /// * `num_cpus` - Number of virtual CPUs the guest will have.
     /// * `has_bios` - Whether the `VmImage` is a `Bios` image
+    /// * `no_smt` - Whether to present all vcpus as cores without hyperthreads.
+    /// * `cpu_topology` - The sockets, cores, and threads to present the vcpus as, if given.
     fn configure_vcpu(
         guest_mem: &GuestMemory,
         hypervisor: &dyn HypervisorArch,
@@ -190,8 +209,12 @@ pub trait LinuxArch {
         num_cpus: usize,
         has_bios: bool,
         no_smt: bool,
+        cpu_topology: Option<CpuTopology>,
     ) -> Result<(), Self::Error>;
 
+    /// Formats the registers of `vcpu` as text, for post-mortem debugging.
+    fn dump_vcpu_registers<T: VcpuArch>(vcpu: &T) -> Result<String, Self::Error>;
+
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     /// Reads vCPU's registers.
     fn debug_read_registers<T: VcpuArch>(vcpu: &T) -> Result<GdbStubRegs, Self::Error>;
@@ -432,6 +455,7 @@ pub fn generate_pci_root(
 /// * `irq_chip` - the IrqChip object for registering irq events
 /// * `irq_num` - assigned interrupt to use
 /// * `resources` - the SystemAllocator to allocate IO and MMIO for acpi
+/// * `acpi_interface` - present the battery as an ACPI battery and AC adapter
 pub fn add_goldfish_battery(
     amls: &mut Vec<u8>,
     battery_jail: Option<Minijail>,
@@ -439,6 +463,7 @@ pub fn add_goldfish_battery(
     irq_chip: &mut impl IrqChip,
     irq_num: u32,
     resources: &mut SystemAllocator,
+    acpi_interface: bool,
 ) -> Result<Tube, DeviceRegistrationError> {
     let alloc = resources.get_anon_alloc();
     let mmio_base = resources
@@ -468,13 +493,14 @@ pub fn add_goldfish_battery(
     #[cfg(not(feature = "power-monitor-powerd"))]
     let create_monitor = None;
 
-    let goldfish_bat = devices::GoldfishBattery::new(
+    let mut goldfish_bat = devices::GoldfishBattery::new(
         mmio_base,
         irq_num,
         irq_evt,
         irq_resample_evt,
         response_tube,
         create_monitor,
+        acpi_interface,
     )
     .map_err(DeviceRegistrationError::RegisterBattery)?;
     Aml::to_aml_bytes(&goldfish_bat, amls);
@@ -495,6 +521,7 @@ pub fn add_goldfish_battery(
                 .map_err(DeviceRegistrationError::MmioInsert)?;
         }
         None => {
+            goldfish_bat.on_sandboxed();
             mmio_bus
                 .insert(
                     Arc::new(Mutex::new(goldfish_bat)),
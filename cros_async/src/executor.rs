@@ -7,7 +7,7 @@ use std::future::Future;
 use async_task::Task;
 
 use crate::poll_source::Error as PollError;
-use crate::uring_executor::use_uring;
+use crate::uring_executor::is_uring_available;
 use crate::{
     AsyncResult, FdExecutor, IntoAsync, IoSourceExt, PollSource, URingExecutor, UringSource,
 };
@@ -125,7 +125,7 @@ pub enum Executor {
 impl Executor {
     /// Create a new `Executor`.
     pub fn new() -> AsyncResult<Self> {
-        if use_uring() {
+        if is_uring_available() {
             Ok(URingExecutor::new().map(Executor::Uring)?)
         } else {
             Ok(FdExecutor::new()
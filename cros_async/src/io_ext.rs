@@ -17,9 +17,12 @@
 
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::slice;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use futures::future::join_all;
 use sys_util::net::UnixSeqpacket;
 use thiserror::Error as ThisError;
 
@@ -30,6 +33,12 @@ pub enum Error {
     /// An error with a polled(FD) source.
     #[error("An error with a poll source: {0}")]
     Poll(crate::poll_source::Error),
+    /// The operation did not complete before its timeout expired.
+    #[error("The operation timed out")]
+    TimedOut,
+    /// Failed to create or arm the timer for a timeout.
+    #[error("Failed to set up the timeout timer: {0}")]
+    Timer(sys_util::Error),
     /// An error with a uring source.
     #[error("An error with a uring source: {0}")]
     Uring(crate::uring_executor::Error),
@@ -87,7 +96,9 @@ pub trait WriteAsync {
         mem_offsets: &'a [MemRegion],
     ) -> Result<usize>;
 
-    /// See `fallocate(2)`. Note this op is synchronous when using the Polled backend.
+    /// See `fallocate(2)`. `mode` is passed through as the `libc::FALLOC_FL_*` flags, for example
+    /// `FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE` to deallocate a range of a sparse file.
+    /// Note this op is synchronous when using the Polled backend.
     async fn fallocate(&self, file_offset: u64, len: u64, mode: u32) -> Result<()>;
 
     /// Sync all completed write operations to the backing storage.
@@ -105,6 +116,28 @@ pub trait IoSourceExt<F>: ReadAsync + WriteAsync {
 
     /// Provides a ref to the underlying IO source.
     fn as_source(&self) -> &F;
+
+    /// Reads each `(file_offset, region)` pair in `ranges` from the file into `mem`, returning the
+    /// total number of bytes read. All of the reads are started before any is awaited, so backends
+    /// that batch submissions, such as io_uring, issue them with a single syscall.
+    async fn readv_to_mem<'a>(
+        &'a self,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        ranges: &'a [(u64, MemRegion)],
+    ) -> Result<usize> {
+        let reads = ranges.iter().map(|(file_offset, region)| {
+            self.read_to_mem(*file_offset, Arc::clone(&mem), slice::from_ref(region))
+        });
+        join_all(reads)
+            .await
+            .into_iter()
+            .try_fold(0, |total, res| res.map(|len| total + len))
+    }
+
+    /// Requests cancellation of the source's operations that are still in progress in the kernel.
+    /// Awaiting a canceled operation returns an error. Sources that do their I/O synchronously
+    /// once the fd is ready have nothing to cancel.
+    fn cancel_all(&self) -> Result<()>;
 }
 
 /// Marker trait signifying that the implementor is suitable for use with
@@ -118,11 +151,16 @@ pub trait IntoAsync: AsRawFd {}
 impl IntoAsync for File {}
 impl IntoAsync for UnixSeqpacket {}
 impl IntoAsync for &UnixSeqpacket {}
+// Control sockets only support `wait_readable`, after which they can be read or accepted from
+// without blocking.
+impl IntoAsync for UnixDatagram {}
+impl IntoAsync for UnixListener {}
 
 #[cfg(test)]
 mod tests {
     use std::fs::{File, OpenOptions};
     use std::future::Future;
+    use std::io::Write;
     use std::os::unix::io::AsRawFd;
     use std::pin::Pin;
     use std::sync::Arc;
@@ -133,7 +171,7 @@ mod tests {
 
     use super::*;
     use crate::executor::{async_poll_from, async_uring_from};
-    use crate::mem::VecIoWrapper;
+    use crate::mem::{DataIoWrapper, VecIoWrapper};
     use crate::{Executor, FdExecutor, MemRegion, PollSource, URingExecutor, UringSource};
 
     struct State {
@@ -317,6 +355,110 @@ mod tests {
         poll_ex.run_until(go(poll_source)).unwrap();
     }
 
+    #[test]
+    fn readmem_array() {
+        async fn go<F: AsRawFd>(async_source: Box<dyn IoSourceExt<F>>) {
+            let mem = Arc::new(DataIoWrapper::from([0x55u8; 32]));
+            let ret = async_source
+                .read_to_mem(
+                    0,
+                    Arc::<DataIoWrapper<[u8; 32]>>::clone(&mem),
+                    &[MemRegion { offset: 8, len: 16 }],
+                )
+                .await
+                .unwrap();
+            assert_eq!(ret, 16);
+            let buf = match Arc::try_unwrap(mem) {
+                Ok(w) => w.into_inner(),
+                Err(_) => panic!("Too many buffer refs"),
+            };
+            assert!(buf[..8].iter().all(|&b| b == 0x55));
+            assert!(buf[8..24].iter().all(|&b| b == 0));
+            assert!(buf[24..].iter().all(|&b| b == 0x55));
+        }
+
+        let f = File::open("/dev/zero").unwrap();
+        let ex = URingExecutor::new().unwrap();
+        let uring_source = async_uring_from(f, &ex).unwrap();
+        ex.run_until(go(uring_source)).unwrap();
+
+        let f = File::open("/dev/zero").unwrap();
+        let poll_ex = FdExecutor::new().unwrap();
+        let poll_source = async_poll_from(f, &poll_ex).unwrap();
+        poll_ex.run_until(go(poll_source)).unwrap();
+    }
+
+    #[test]
+    fn readmem_file_subrange() {
+        async fn go<F: AsRawFd>(async_source: Box<dyn IoSourceExt<F>>) {
+            let mem = Arc::new(VecIoWrapper::from(vec![0x55u8; 64]));
+            let ret = async_source
+                .read_to_mem(
+                    16,
+                    Arc::<VecIoWrapper>::clone(&mem),
+                    &[MemRegion { offset: 8, len: 16 }],
+                )
+                .await
+                .unwrap();
+            assert_eq!(ret, 16);
+            let vec: Vec<u8> = match Arc::try_unwrap(mem) {
+                Ok(v) => v.into(),
+                Err(_) => panic!("Too many vec refs"),
+            };
+            assert!(vec.iter().take(8).all(|&b| b == 0x55));
+            assert_eq!(vec[8..24], (16u8..32).collect::<Vec<u8>>()[..]);
+            assert!(vec.iter().skip(24).all(|&b| b == 0x55));
+        }
+
+        let mut f = tempfile::tempfile().unwrap();
+        f.write_all(&(0u8..64).collect::<Vec<u8>>()).unwrap();
+
+        let ex = URingExecutor::new().unwrap();
+        let uring_source = async_uring_from(f.try_clone().unwrap(), &ex).unwrap();
+        ex.run_until(go(uring_source)).unwrap();
+
+        let poll_ex = FdExecutor::new().unwrap();
+        let poll_source = async_poll_from(f, &poll_ex).unwrap();
+        poll_ex.run_until(go(poll_source)).unwrap();
+    }
+
+    #[test]
+    fn read_ranges() {
+        async fn go<F: AsRawFd>(async_source: Box<dyn IoSourceExt<F>>) {
+            let mem = Arc::new(VecIoWrapper::from(vec![0x55u8; 64]));
+            let ret = async_source
+                .readv_to_mem(
+                    Arc::<VecIoWrapper>::clone(&mem),
+                    &[
+                        (40, MemRegion { offset: 0, len: 8 }),
+                        (4, MemRegion { offset: 32, len: 4 }),
+                    ],
+                )
+                .await
+                .unwrap();
+            assert_eq!(ret, 8 + 4);
+            let vec: Vec<u8> = match Arc::try_unwrap(mem) {
+                Ok(v) => v.into(),
+                Err(_) => panic!("Too many vec refs"),
+            };
+            assert_eq!(vec[0..8], (40u8..48).collect::<Vec<u8>>()[..]);
+            assert!(vec.iter().skip(8).take(24).all(|&b| b == 0x55));
+            assert_eq!(vec[32..36], [4u8, 5, 6, 7]);
+            assert!(vec.iter().skip(36).all(|&b| b == 0x55));
+        }
+
+        let mut f = tempfile::tempfile().unwrap();
+        f.write_all(&(0u8..64).collect::<Vec<u8>>()).unwrap();
+
+        let ex = URingExecutor::new().unwrap();
+        let uring_source = async_uring_from(f.try_clone().unwrap(), &ex).unwrap();
+        ex.run_until(go(uring_source)).unwrap();
+
+        let poll_ex = FdExecutor::new().unwrap();
+        let poll_source = async_poll_from(f, &poll_ex).unwrap();
+        poll_ex.run_until(go(poll_source)).unwrap();
+    }
+
     #[test]
     fn writemem() {
         async fn go<F: AsRawFd>(async_source: Box<dyn IoSourceExt<F>>) {
@@ -343,6 +485,108 @@ mod tests {
         poll_ex.run_until(go(poll_source)).unwrap();
     }
 
+    #[test]
+    fn writemem_gather() {
+        async fn go<F: AsRawFd>(async_source: Box<dyn IoSourceExt<F>>) {
+            let mem = Arc::new(VecIoWrapper::from((0u8..64).collect::<Vec<u8>>()));
+            let ret = async_source
+                .write_from_mem(
+                    4,
+                    Arc::<VecIoWrapper>::clone(&mem),
+                    &[
+                        MemRegion { offset: 32, len: 4 },
+                        MemRegion { offset: 8, len: 8 },
+                    ],
+                )
+                .await
+                .unwrap();
+            assert_eq!(ret, 4 + 8);
+
+            // A region outside the memory fails the whole write rather than being skipped.
+            assert!(async_source
+                .write_from_mem(
+                    0,
+                    Arc::<VecIoWrapper>::clone(&mem),
+                    &[
+                        MemRegion { offset: 0, len: 4 },
+                        MemRegion { offset: 60, len: 8 },
+                    ],
+                )
+                .await
+                .is_err());
+
+            let (ret, vec) = async_source.read_to_vec(0, vec![0x55u8; 20]).await.unwrap();
+            assert_eq!(ret, 16);
+            assert_eq!(vec[0..4], [0u8; 4]);
+            assert_eq!(vec[4..8], [32u8, 33, 34, 35]);
+            assert_eq!(vec[8..16], (8u8..16).collect::<Vec<u8>>()[..]);
+        }
+
+        let f = tempfile::tempfile().unwrap();
+        let ex = URingExecutor::new().unwrap();
+        let uring_source = async_uring_from(f, &ex).unwrap();
+        ex.run_until(go(uring_source)).unwrap();
+
+        let f = tempfile::tempfile().unwrap();
+        let poll_ex = FdExecutor::new().unwrap();
+        let poll_source = async_poll_from(f, &poll_ex).unwrap();
+        poll_ex.run_until(go(poll_source)).unwrap();
+    }
+
+    #[test]
+    fn punch_hole() {
+        async fn go<F: AsRawFd>(source: Box<dyn IoSourceExt<F>>) {
+            let (len, _) = source.write_from_vec(0, vec![0x55u8; 4096]).await.unwrap();
+            assert_eq!(len, 4096);
+
+            let mode = (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32;
+            if let Err(e) = source.fallocate(0, 4096, mode).await {
+                match e {
+                    Error::Uring(crate::uring_executor::Error::Io(io_err))
+                        if io_err.kind() == std::io::ErrorKind::InvalidInput =>
+                    {
+                        // Skip the test on kernels before fallocate support.
+                        return;
+                    }
+                    _ => panic!("Unexpected error on fallocate: {}", e),
+                }
+            }
+
+            let (len, vec) = source.read_to_vec(0, vec![0xaau8; 4096]).await.unwrap();
+            assert_eq!(len, 4096);
+            assert!(vec.iter().all(|&b| b == 0));
+        }
+
+        let f = tempfile::tempfile().unwrap();
+        let ex = URingExecutor::new().unwrap();
+        let uring_source = async_uring_from(f, &ex).unwrap();
+        ex.run_until(go(uring_source)).unwrap();
+
+        let f = tempfile::tempfile().unwrap();
+        let poll_ex = FdExecutor::new().unwrap();
+        let poll_source = async_poll_from(f, &poll_ex).unwrap();
+        poll_ex.run_until(go(poll_source)).unwrap();
+    }
+
+    #[test]
+    fn cancel_read() {
+        async fn go(source: Box<dyn IoSourceExt<File>>) {
+            let read = source.read_to_vec(0, vec![0u8; 8]);
+            let cancel = async { source.cancel_all().unwrap() };
+            match futures::future::join(read, cancel).await {
+                (Err(Error::Uring(crate::uring_executor::Error::Cancelled)), ()) => {}
+                (Err(e), ()) => panic!("Unexpected error from read: {}", e),
+                (Ok(_), ()) => panic!("Read completed successfully"),
+            }
+        }
+
+        // Nothing is ever written to the pipe so the read can only finish by being canceled.
+        let (r, _w) = sys_util::pipe(true).unwrap();
+        let ex = URingExecutor::new().unwrap();
+        let uring_source = async_uring_from(r, &ex).unwrap();
+        ex.run_until(go(uring_source)).unwrap();
+    }
+
     #[test]
     fn read_u64s() {
         async fn go(async_source: File, ex: URingExecutor) -> u64 {
@@ -379,6 +623,57 @@ mod tests {
         assert_eq!(val, 0xaa);
     }
 
+    #[test]
+    fn wait_readable_pipe() {
+        async fn go<F: AsRawFd>(source: Box<dyn IoSourceExt<F>>) {
+            source.wait_readable().await.unwrap();
+        }
+
+        let (r, mut w) = sys_util::pipe(true).unwrap();
+        w.write_all(&[0x55]).unwrap();
+        let ex = URingExecutor::new().unwrap();
+        let uring_source = async_uring_from(r, &ex).unwrap();
+        ex.run_until(go(uring_source)).unwrap();
+
+        let (r, mut w) = sys_util::pipe(true).unwrap();
+        w.write_all(&[0x55]).unwrap();
+        let poll_ex = FdExecutor::new().unwrap();
+        let poll_source = async_poll_from(r, &poll_ex).unwrap();
+        poll_ex.run_until(go(poll_source)).unwrap();
+    }
+
+    #[test]
+    fn wait_readable_control_sockets() {
+        use std::os::unix::net::UnixStream;
+
+        async fn recv(source: Box<dyn IoSourceExt<UnixDatagram>>) {
+            source.wait_readable().await.unwrap();
+            let mut buf = [0u8; 4];
+            let len = source.as_source().recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"ping");
+        }
+
+        async fn accept(source: Box<dyn IoSourceExt<UnixListener>>) {
+            source.wait_readable().await.unwrap();
+            source.as_source().accept().unwrap();
+        }
+
+        let ex = Executor::new().unwrap();
+
+        let (a, b) = UnixDatagram::pair().unwrap();
+        b.set_nonblocking(true).unwrap();
+        a.send(b"ping").unwrap();
+        ex.run_until(recv(ex.async_from(b).unwrap())).unwrap();
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("control.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let _client = UnixStream::connect(&path).unwrap();
+        ex.run_until(accept(ex.async_from(listener).unwrap()))
+            .unwrap();
+    }
+
     #[test]
     fn fsync() {
         async fn go<F: AsRawFd>(source: Box<dyn IoSourceExt<F>>) {
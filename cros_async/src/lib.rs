@@ -82,8 +82,8 @@ pub use io_ext::{
 pub use mem::{BackingMemory, MemRegion};
 pub use poll_source::PollSource;
 pub use select::SelectResult;
-pub use timer::TimerAsync;
-pub use uring_executor::URingExecutor;
+pub use timer::{with_timeout, TimerAsync};
+pub use uring_executor::{is_uring_available, URingExecutor};
 pub use uring_source::UringSource;
 
 use std::future::Future;
@@ -134,7 +134,7 @@ pub fn empty<T>() -> Empty<T> {
 ///    assert_eq!(55, run_one(fut).unwrap());
 ///    ```
 pub fn run_one<F: Future>(fut: F) -> Result<F::Output> {
-    if uring_executor::use_uring() {
+    if is_uring_available() {
         run_one_uring(fut)
     } else {
         run_one_poll(fut)
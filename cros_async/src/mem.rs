@@ -4,7 +4,7 @@
 
 use std::fmt::{self, Display};
 
-use data_model::VolatileSlice;
+use data_model::{DataInit, VolatileSlice};
 
 #[derive(Debug)]
 pub enum Error {
@@ -38,6 +38,12 @@ pub struct MemRegion {
     pub len: usize,
 }
 
+/// Returns the total number of bytes covered by `mems`, or `None` if the sum overflows a `usize`.
+pub fn total_len(mems: &[MemRegion]) -> Option<usize> {
+    mems.iter()
+        .try_fold(0usize, |total, mem| total.checked_add(mem.len))
+}
+
 /// Trait for memory that can yeild both iovecs in to the backing memory.
 /// Must be OK to modify the backing memory without owning a mut able reference. For example,
 /// this is safe for GuestMemory and VolatileSlices in crosvm as those types guarantee they are
@@ -46,9 +52,27 @@ pub unsafe trait BackingMemory {
     /// Returns VolatileSlice pointing to the backing memory. This is most commonly unsafe.
     /// To implement this safely the implementor must guarantee that the backing memory can be
     /// modified out of band without affecting safety guarantees.
+    /// A region with a length of zero yields a valid empty slice as long as its offset is not past
+    /// the end of the memory.
     fn get_volatile_slice(&self, mem_range: MemRegion) -> Result<VolatileSlice>;
 }
 
+// Check that the offsets are all valid in a backing buffer of `buf_len` bytes. Lengths past
+// `isize::MAX` are always rejected as no slice can be that long.
+fn check_addrs(buf_len: usize, mem_range: &MemRegion) -> Result<()> {
+    if mem_range.len > isize::MAX as usize {
+        return Err(Error::InvalidOffset(mem_range.offset, mem_range.len));
+    }
+    let end = mem_range
+        .offset
+        .checked_add(mem_range.len as u64)
+        .ok_or(Error::InvalidOffset(mem_range.offset, mem_range.len))?;
+    if end > buf_len as u64 {
+        return Err(Error::InvalidOffset(mem_range.offset, mem_range.len));
+    }
+    Ok(())
+}
+
 /// Wrapper to be used for passing a Vec in as backing memory for asynchronous operations.  The
 /// wrapper owns a Vec according to the borrow checker. It is loaning this vec out to the kernel(or
 /// other modifiers) through the `BackingMemory` trait. This allows multiple modifiers of the array
@@ -79,18 +103,6 @@ impl VecIoWrapper {
     pub fn len(&self) -> usize {
         self.inner.len()
     }
-
-    // Check that the offsets are all valid in the backing vec.
-    fn check_addrs(&self, mem_range: &MemRegion) -> Result<()> {
-        let end = mem_range
-            .offset
-            .checked_add(mem_range.len as u64)
-            .ok_or(Error::InvalidOffset(mem_range.offset, mem_range.len))?;
-        if end > self.inner.len() as u64 {
-            return Err(Error::InvalidOffset(mem_range.offset, mem_range.len));
-        }
-        Ok(())
-    }
 }
 
 // Safe to implement BackingMemory as the vec is only accessible inside the wrapper and these iovecs
@@ -99,7 +111,7 @@ impl VecIoWrapper {
 // is consumed by `into`, which can't happen if there are outstanding mut borrows.
 unsafe impl BackingMemory for VecIoWrapper {
     fn get_volatile_slice(&self, mem_range: MemRegion) -> Result<VolatileSlice<'_>> {
-        self.check_addrs(&mem_range)?;
+        check_addrs(self.inner.len(), &mem_range)?;
         // Safe because the mem_range range is valid in the backing memory as checked above.
         unsafe {
             Ok(VolatileSlice::from_raw_parts(
@@ -109,3 +121,160 @@ unsafe impl BackingMemory for VecIoWrapper {
         }
     }
 }
+
+/// Wrapper to be used for passing a small plain data buffer, such as an array of bytes, in as
+/// backing memory for asynchronous operations. The wrapper owns the buffer and stores it inline, so
+/// it can be shared with the kernel through an `Arc` without the separate heap allocation a `Vec`
+/// needs. As with `VecIoWrapper`, the buffer can only be modified through the `VolatileSlice`s
+/// handed out until the wrapper is turned back in to the buffer with `into_inner`.
+///
+/// There is no wrapper for a borrowed `&mut [u8]`. Operations take their memory as an
+/// `Arc<dyn BackingMemory + Send + Sync>`, which a borrow can't satisfy, and the kernel may keep
+/// writing to the memory after the future for an operation is dropped.
+pub struct DataIoWrapper<T: DataInit> {
+    inner: T,
+}
+
+impl<T: DataInit> From<T> for DataIoWrapper<T> {
+    fn from(buf: T) -> Self {
+        DataIoWrapper { inner: buf }
+    }
+}
+
+impl<T: DataInit> DataIoWrapper<T> {
+    /// Get the length in bytes of the buffer that is wrapped.
+    pub fn len(&self) -> usize {
+        self.inner.as_slice().len()
+    }
+
+    /// Returns true if the wrapped buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+// Safe to implement BackingMemory as the buffer is only accessible inside the wrapper and these
+// iovecs are the only thing allowed to modify it. `DataInit` guarantees that the buffer is plain
+// data held in the wrapper itself, and that any bytes written to it leave it valid.
+unsafe impl<T: DataInit> BackingMemory for DataIoWrapper<T> {
+    fn get_volatile_slice(&self, mem_range: MemRegion) -> Result<VolatileSlice<'_>> {
+        let bytes = self.inner.as_slice();
+        check_addrs(bytes.len(), &mem_range)?;
+        // Safe because the mem_range range is valid in the backing memory as checked above.
+        unsafe {
+            Ok(VolatileSlice::from_raw_parts(
+                bytes.as_ptr().add(mem_range.offset as usize) as *mut _,
+                mem_range.len,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use sys_util::FileReadWriteVolatile;
+
+    use super::*;
+
+    #[test]
+    fn total_len_empty() {
+        assert_eq!(total_len(&[]), Some(0));
+    }
+
+    #[test]
+    fn total_len_sums_regions() {
+        let mems = [
+            MemRegion { offset: 0, len: 32 },
+            MemRegion {
+                offset: 200,
+                len: 56,
+            },
+        ];
+        assert_eq!(total_len(&mems), Some(88));
+    }
+
+    #[test]
+    fn total_len_overflow() {
+        let mems = [
+            MemRegion {
+                offset: 0,
+                len: usize::MAX,
+            },
+            MemRegion { offset: 0, len: 1 },
+        ];
+        assert_eq!(total_len(&mems), None);
+    }
+
+    #[test]
+    fn data_wrapper_read_into_array() {
+        let wrapper = DataIoWrapper::from([0x55u8; 16]);
+        assert_eq!(wrapper.len(), 16);
+        let slice = wrapper
+            .get_volatile_slice(MemRegion { offset: 4, len: 8 })
+            .unwrap();
+        let mut f = File::open("/dev/zero").unwrap();
+        f.read_exact_volatile(slice).unwrap();
+        let buf = wrapper.into_inner();
+        assert!(buf[..4].iter().all(|&b| b == 0x55));
+        assert!(buf[4..12].iter().all(|&b| b == 0));
+        assert!(buf[12..].iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn vec_wrapper_zero_length() {
+        let wrapper = VecIoWrapper::from(vec![0u8; 16]);
+        for &offset in &[0, 8, 16] {
+            let slice = wrapper
+                .get_volatile_slice(MemRegion { offset, len: 0 })
+                .unwrap();
+            assert_eq!(slice.size(), 0);
+        }
+        assert!(wrapper
+            .get_volatile_slice(MemRegion { offset: 17, len: 0 })
+            .is_err());
+
+        let empty = VecIoWrapper::from(Vec::new());
+        let slice = empty
+            .get_volatile_slice(MemRegion { offset: 0, len: 0 })
+            .unwrap();
+        assert_eq!(slice.size(), 0);
+    }
+
+    #[test]
+    fn vec_wrapper_oversized() {
+        let wrapper = VecIoWrapper::from(vec![0u8; 16]);
+        assert!(wrapper
+            .get_volatile_slice(MemRegion {
+                offset: 0,
+                len: isize::MAX as usize + 1
+            })
+            .is_err());
+        assert!(wrapper
+            .get_volatile_slice(MemRegion {
+                offset: 0,
+                len: usize::MAX
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn data_wrapper_out_of_bounds() {
+        let wrapper = DataIoWrapper::from([0u8; 16]);
+        assert!(wrapper
+            .get_volatile_slice(MemRegion { offset: 8, len: 9 })
+            .is_err());
+        assert!(wrapper
+            .get_volatile_slice(MemRegion {
+                offset: u64::MAX,
+                len: 1
+            })
+            .is_err());
+    }
+}
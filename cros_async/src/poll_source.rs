@@ -13,7 +13,7 @@ use std::sync::Arc;
 use thiserror::Error as ThisError;
 
 use crate::fd_executor::{self, FdExecutor, RegisteredSource};
-use crate::mem::{BackingMemory, MemRegion};
+use crate::mem::{self, BackingMemory, MemRegion};
 use crate::{AsyncError, AsyncResult};
 use crate::{IoSourceExt, ReadAsync, WriteAsync};
 use data_model::VolatileSlice;
@@ -32,6 +32,9 @@ pub enum Error {
     /// An error occurred when executing fsync synchronously.
     #[error("An error occurred when executing fsync synchronously: {0}")]
     Fsync(sys_util::Error),
+    /// A memory region didn't fit in the backing memory.
+    #[error("Invalid memory region: {0}")]
+    InvalidMemRegion(mem::Error),
     /// An error occurred when reading the FD.
     #[error("An error occurred when reading the FD: {0}.")]
     Read(sys_util::Error),
@@ -116,10 +119,12 @@ impl<F: AsRawFd> ReadAsync for PollSource<F> {
         mem: Arc<dyn BackingMemory + Send + Sync>,
         mem_offsets: &'a [MemRegion],
     ) -> AsyncResult<usize> {
+        // Dropping an invalid region would shift the data of every region after it.
         let mut iovecs = mem_offsets
             .iter()
-            .filter_map(|&mem_vec| mem.get_volatile_slice(mem_vec).ok())
-            .collect::<Vec<VolatileSlice>>();
+            .map(|&mem_vec| mem.get_volatile_slice(mem_vec))
+            .collect::<mem::Result<Vec<VolatileSlice>>>()
+            .map_err(Error::InvalidMemRegion)?;
 
         loop {
             // Safe because we trust the kernel not to write path the length given and the length is
@@ -221,11 +226,12 @@ impl<F: AsRawFd> WriteAsync for PollSource<F> {
         mem: Arc<dyn BackingMemory + Send + Sync>,
         mem_offsets: &'a [MemRegion],
     ) -> AsyncResult<usize> {
+        // Dropping an invalid region would shift the data of every region after it.
         let iovecs = mem_offsets
             .iter()
             .map(|&mem_vec| mem.get_volatile_slice(mem_vec))
-            .filter_map(|r| r.ok())
-            .collect::<Vec<VolatileSlice>>();
+            .collect::<mem::Result<Vec<VolatileSlice>>>()
+            .map_err(Error::InvalidMemRegion)?;
 
         loop {
             // Safe because we trust the kernel not to write path the length given and the length is
@@ -297,6 +303,12 @@ impl<F: AsRawFd> IoSourceExt<F> for PollSource<F> {
     fn as_source(&self) -> &F {
         self
     }
+
+    /// Reads and writes are done synchronously once the fd is ready, so there is nothing to
+    /// cancel.
+    fn cancel_all(&self) -> AsyncResult<()> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -2,11 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::future::Future;
 use std::time::Duration;
 
+use futures::future::{select, Either};
+use futures::pin_mut;
 use sys_util::{Result as SysResult, TimerFd};
 
-use crate::{AsyncResult, Executor, IntoAsync, IoSourceExt};
+use crate::{AsyncError, AsyncResult, Executor, IntoAsync, IoSourceExt};
 #[cfg(test)]
 use crate::{FdExecutor, URingExecutor};
 
@@ -46,9 +49,33 @@ impl TimerAsync {
 
 impl IntoAsync for TimerFd {}
 
+/// Runs `f` until it completes or `timeout` expires, whichever happens first. If the timeout
+/// expires first, `f` is dropped, which cancels any I/O it has outstanding, and
+/// `AsyncError::TimedOut` is returned.
+pub async fn with_timeout<F: Future>(
+    f: F,
+    timeout: Duration,
+    ex: &Executor,
+) -> AsyncResult<F::Output> {
+    let tfd = TimerFd::new().map_err(AsyncError::Timer)?;
+    tfd.reset(timeout, None).map_err(AsyncError::Timer)?;
+    let timer = TimerAsync::new(tfd, ex)?;
+    let expired = timer.next_val();
+
+    pin_mut!(f, expired);
+    match select(f, expired).await {
+        Either::Left((val, _)) => Ok(val),
+        Either::Right((res, _)) => {
+            res?;
+            Err(AsyncError::TimedOut)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ReadAsync;
     use std::time::{Duration, Instant};
 
     #[test]
@@ -96,4 +123,41 @@ mod tests {
         let ex = FdExecutor::new().unwrap();
         ex.run_until(this_test(&ex)).unwrap();
     }
+
+    #[test]
+    fn timeout_empty_pipe() {
+        async fn this_test(ex: &Executor) {
+            // Nothing is ever written to the pipe so the read can't complete.
+            let (rx, _tx) = sys_util::pipe(true).expect("failed to create pipe");
+            let source = ex.async_from(rx).expect("failed to create async source");
+
+            let dur = Duration::from_millis(10);
+            let now = Instant::now();
+            match with_timeout(source.read_to_vec(0, vec![0u8; 8]), dur, ex).await {
+                Err(AsyncError::TimedOut) => {}
+                Err(e) => panic!("unexpected error waiting for read: {}", e),
+                Ok(_) => panic!("read of an empty pipe completed"),
+            }
+            assert!(now.elapsed() >= dur);
+        }
+
+        let ex = Executor::Uring(URingExecutor::new().unwrap());
+        ex.run_until(this_test(&ex)).unwrap();
+
+        let ex = Executor::Fd(FdExecutor::new().unwrap());
+        ex.run_until(this_test(&ex)).unwrap();
+    }
+
+    #[test]
+    fn timeout_not_reached() {
+        async fn this_test(ex: &Executor) {
+            let val = with_timeout(async { 5 }, Duration::from_secs(60), ex)
+                .await
+                .expect("future timed out");
+            assert_eq!(val, 5);
+        }
+
+        let ex = Executor::new().unwrap();
+        ex.run_until(this_test(&ex)).unwrap();
+    }
 }
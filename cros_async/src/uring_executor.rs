@@ -79,6 +79,9 @@ use crate::waker::{new_waker, WakerToken, WeakWake};
 
 #[derive(Debug, ThisError)]
 pub enum Error {
+    /// The operation was canceled before it completed.
+    #[error("The operation was canceled")]
+    Cancelled,
     /// Failed to copy the FD for the polling context.
     #[error("Failed to copy the FD for the polling context: {0}")]
     DuplicatingFd(sys_util::Error),
@@ -94,6 +97,9 @@ pub enum Error {
     /// Error doing the IO.
     #[error("Error during IO: {0}")]
     Io(io::Error),
+    /// The number of in-flight operations has reached the queue depth.
+    #[error("The uring queue is full")]
+    QueueFull,
     /// Creating a context to wait on FDs failed.
     #[error("Error creating the fd waiting context: {0}")]
     CreatingContext(io_uring::Error),
@@ -109,13 +115,48 @@ pub enum Error {
     /// Failed to submit or wait for io_uring events.
     #[error("URing::enter: {0}")]
     URingEnter(io_uring::Error),
+    /// The kernel doesn't support io_uring or doesn't allow this process to use it.
+    #[error("io_uring is unavailable: {0}")]
+    UringUnavailable(io_uring::Error),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
-// Checks if the uring executor is available.
-// Caches the result so that the check is only run once.
-// Useful for falling back to the FD executor on pre-uring kernels.
-pub(crate) fn use_uring() -> bool {
+// Converts an io_uring error into the closest matching `io::Error`.
+fn uring_to_io_error(e: io_uring::Error) -> io::Error {
+    match e {
+        io_uring::Error::RingEnter(errno) | io_uring::Error::Setup(errno) => {
+            io::Error::from_raw_os_error(errno)
+        }
+        io_uring::Error::NoSpace => io::Error::new(io::ErrorKind::WouldBlock, e.to_string()),
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        use Error::*;
+        match e {
+            Cancelled => io::Error::from_raw_os_error(libc::ECANCELED),
+            DuplicatingFd(e) => e.into(),
+            ExecutorGone => io::Error::new(io::ErrorKind::Other, e),
+            InvalidOffset | InvalidSource => io::Error::new(io::ErrorKind::InvalidInput, e),
+            Io(e) => e,
+            QueueFull => io::Error::new(io::ErrorKind::WouldBlock, e),
+            CreatingContext(e) => uring_to_io_error(e),
+            RemovingWaker(e) => uring_to_io_error(e),
+            SubmittingOp(e) => uring_to_io_error(e),
+            URingContextError(e) => uring_to_io_error(e),
+            URingEnter(e) => uring_to_io_error(e),
+            UringUnavailable(e) => uring_to_io_error(e),
+        }
+    }
+}
+
+/// Checks if the uring executor is available.
+///
+/// The result is cached so that the check is only run once. Useful for falling back to the FD
+/// executor or a blocking path on pre-uring kernels.
+pub fn is_uring_available() -> bool {
     const UNKNOWN: u32 = 0;
     const URING: u32 = 1;
     const FD: u32 = 2;
@@ -197,6 +238,13 @@ impl RegisteredSource {
         })
     }
 
+    /// Requests that the kernel cancel all of this source's operations that haven't completed yet.
+    /// Awaiting an operation that was canceled before it finished returns `Error::Cancelled`.
+    pub fn cancel_all(&self) -> Result<()> {
+        let ex = self.ex.upgrade().ok_or(Error::ExecutorGone)?;
+        ex.cancel_source_io(self)
+    }
+
     pub fn poll_fd_readable(&self) -> Result<PendingOperation> {
         let events = WatchingEvents::empty().set_read();
 
@@ -230,7 +278,7 @@ const PROCESSING: i32 = 0xdb31_83a3u32 as i32;
 // Indicates that one or more futures may be ready to make progress.
 const WOKEN: i32 = 0x0fc7_8f7eu32 as i32;
 
-// Number of entries in the ring.
+// Default number of entries in the ring.
 const NUM_ENTRIES: usize = 256;
 
 // An operation that has been submitted to the uring and is potentially being waited on.
@@ -251,6 +299,23 @@ enum OpStatus {
 struct Ring {
     ops: Slab<OpStatus>,
     registered_sources: Slab<Arc<File>>,
+    // The number of entries in `ops` that aren't `Nop`s.
+    in_flight: usize,
+}
+
+impl Ring {
+    // Returns the number of operations that have been submitted but whose results haven't been
+    // collected yet. Nops used for waking the executor aren't counted so that a full queue can't
+    // prevent wake ups.
+    fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    // Removes the operation for `token`, which must not be a `Nop`.
+    fn remove_op(&mut self, token: usize) {
+        self.ops.remove(token);
+        self.in_flight -= 1;
+    }
 }
 
 struct RawExecutor {
@@ -261,22 +326,39 @@ struct RawExecutor {
     ring: Mutex<Ring>,
     thread_id: Mutex<Option<ThreadId>>,
     state: AtomicI32,
+    queue_depth: usize,
 }
 
 impl RawExecutor {
-    fn new() -> Result<RawExecutor> {
+    fn new(queue_depth: usize) -> Result<RawExecutor> {
         Ok(RawExecutor {
-            ctx: URingContext::new(NUM_ENTRIES).map_err(Error::CreatingContext)?,
+            ctx: URingContext::new(queue_depth).map_err(|e| match e {
+                io_uring::Error::Setup(libc::ENOSYS) | io_uring::Error::Setup(libc::EPERM) => {
+                    Error::UringUnavailable(e)
+                }
+                e => Error::CreatingContext(e),
+            })?,
             queue: RunnableQueue::new(),
             ring: Mutex::new(Ring {
-                ops: Slab::with_capacity(NUM_ENTRIES),
-                registered_sources: Slab::with_capacity(NUM_ENTRIES),
+                ops: Slab::with_capacity(queue_depth),
+                registered_sources: Slab::with_capacity(queue_depth),
+                in_flight: 0,
             }),
             thread_id: Mutex::new(None),
             state: AtomicI32::new(PROCESSING),
+            queue_depth,
         })
     }
 
+    // Fails with `QueueFull` if adding another operation would exceed the queue depth.
+    fn check_queue_depth(&self, ring: &Ring) -> Result<()> {
+        if ring.in_flight() >= self.queue_depth {
+            Err(Error::QueueFull)
+        } else {
+            Ok(())
+        }
+    }
+
     fn wake(&self) {
         let oldstate = self.state.swap(WOKEN, Ordering::Release);
         if oldstate == WAITING {
@@ -399,7 +481,7 @@ impl RawExecutor {
                         if data.canceled {
                             // No one is waiting for this operation and the uring is done with
                             // it so it's safe to remove.
-                            ring.ops.remove(token);
+                            ring.remove_op(token);
                         }
                         if let Some(waker) = data.waker {
                             waker.wake();
@@ -429,7 +511,7 @@ impl RawExecutor {
             }
             OpStatus::Completed(res) => {
                 let out = res.take();
-                ring.ops.remove(token.0);
+                ring.remove_op(token.0);
                 Some(out.expect("Missing result in completed operation"))
             }
         }
@@ -452,15 +534,56 @@ impl RawExecutor {
 
                     // Keep the rest of the op data as the uring might still be accessing either
                     // the source of the backing memory so it needs to live until the kernel
-                    // completes the operation.  TODO: cancel the operation in the uring.
+                    // completes the operation. Ask the kernel to cancel it so that it doesn't
+                    // stay around forever.
+                    if let Err(e) = self.add_cancel(&mut ring, token.0) {
+                        warn!("Failed to cancel uring operation: {}", e);
+                    }
                 }
                 OpStatus::Completed(_) => {
-                    ring.ops.remove(token.0);
+                    ring.remove_op(token.0);
                 }
             }
         }
     }
 
+    // Ask the kernel to cancel every operation it still has pending on `source`. Each operation
+    // completes with `ECANCELED` if it was canceled before it finished.
+    fn cancel_source_io(&self, source: &RegisteredSource) -> Result<()> {
+        let mut ring = self.ring.lock();
+        let src = ring
+            .registered_sources
+            .get(source.tag)
+            .map(Arc::clone)
+            .ok_or(Error::InvalidSource)?;
+        let pending: Vec<usize> = ring
+            .ops
+            .iter()
+            .filter_map(|(token, op)| match op {
+                OpStatus::Pending(data) if !data.canceled && Arc::ptr_eq(&data._file, &src) => {
+                    Some(token)
+                }
+                _ => None,
+            })
+            .collect();
+        for token in pending {
+            self.add_cancel(&mut ring, token)?;
+        }
+        Ok(())
+    }
+
+    fn add_cancel(&self, ring: &mut Ring, target: usize) -> Result<()> {
+        let entry = ring.ops.vacant_entry();
+        let next_op_token = entry.key();
+        self.ctx
+            .add_cancel(usize_to_u64(target), usize_to_u64(next_op_token))
+            .map_err(Error::SubmittingOp)?;
+
+        // Like a Nop, no one waits for the result of the cancel request itself.
+        entry.insert(OpStatus::Nop);
+        Ok(())
+    }
+
     fn register_source(&self, f: Arc<File>) -> usize {
         self.ring.lock().registered_sources.insert(f)
     }
@@ -483,6 +606,7 @@ impl RawExecutor {
             .get(source.tag)
             .map(Arc::clone)
             .ok_or(Error::InvalidSource)?;
+        self.check_queue_depth(&ring)?;
         let entry = ring.ops.vacant_entry();
         let next_op_token = entry.key();
         self.ctx
@@ -495,6 +619,7 @@ impl RawExecutor {
             waker: None,
             canceled: false,
         }));
+        ring.in_flight += 1;
 
         Ok(WakerToken(next_op_token))
     }
@@ -512,6 +637,7 @@ impl RawExecutor {
             .get(source.tag)
             .map(Arc::clone)
             .ok_or(Error::InvalidSource)?;
+        self.check_queue_depth(&ring)?;
         let entry = ring.ops.vacant_entry();
         let next_op_token = entry.key();
         self.ctx
@@ -530,6 +656,7 @@ impl RawExecutor {
             waker: None,
             canceled: false,
         }));
+        ring.in_flight += 1;
 
         Ok(WakerToken(next_op_token))
     }
@@ -541,6 +668,7 @@ impl RawExecutor {
             .get(source.tag)
             .map(Arc::clone)
             .ok_or(Error::InvalidSource)?;
+        self.check_queue_depth(&ring)?;
         let entry = ring.ops.vacant_entry();
         let next_op_token = entry.key();
         self.ctx
@@ -553,6 +681,7 @@ impl RawExecutor {
             waker: None,
             canceled: false,
         }));
+        ring.in_flight += 1;
 
         Ok(WakerToken(next_op_token))
     }
@@ -577,6 +706,7 @@ impl RawExecutor {
             .get(source.tag)
             .map(Arc::clone)
             .ok_or(Error::InvalidSource)?;
+        self.check_queue_depth(&ring)?;
 
         // We can't insert the OpData into the slab yet because `iovecs` borrows `mem` below.
         let entry = ring.ops.vacant_entry();
@@ -603,6 +733,7 @@ impl RawExecutor {
             waker: None,
             canceled: false,
         }));
+        ring.in_flight += 1;
 
         Ok(WakerToken(next_op_token))
     }
@@ -627,6 +758,7 @@ impl RawExecutor {
             .get(source.tag)
             .map(Arc::clone)
             .ok_or(Error::InvalidSource)?;
+        self.check_queue_depth(&ring)?;
 
         // We can't insert the OpData into the slab yet because `iovecs` borrows `mem` below.
         let entry = ring.ops.vacant_entry();
@@ -653,6 +785,7 @@ impl RawExecutor {
             waker: None,
             canceled: false,
         }));
+        ring.in_flight += 1;
 
         Ok(WakerToken(next_op_token))
     }
@@ -714,11 +847,30 @@ pub struct URingExecutor {
 
 impl URingExecutor {
     pub fn new() -> Result<URingExecutor> {
-        let raw = RawExecutor::new().map(Arc::new)?;
+        URingExecutor::with_queue_depth(NUM_ENTRIES)
+    }
+
+    /// Create an executor whose ring has room for `queue_depth` entries. Once `queue_depth`
+    /// operations are in flight, starting another one fails with `Error::QueueFull` until some of
+    /// the pending operations complete.
+    pub fn with_queue_depth(queue_depth: usize) -> Result<URingExecutor> {
+        let raw = RawExecutor::new(queue_depth).map(Arc::new)?;
 
         Ok(URingExecutor { raw })
     }
 
+    /// Returns the maximum number of operations that can be in flight at once.
+    pub fn queue_depth(&self) -> usize {
+        self.raw.queue_depth
+    }
+
+    /// Returns the number of operations that have been started but whose results haven't been
+    /// collected yet. Callers can use this to throttle submissions before hitting
+    /// `Error::QueueFull`.
+    pub fn in_flight(&self) -> usize {
+        self.raw.ring.lock().in_flight()
+    }
+
     pub fn spawn<F>(&self, f: F) -> Task<F::Output>
     where
         F: Future + Send + 'static,
@@ -797,7 +949,13 @@ impl Future for PendingOperation {
         if let Some(ex) = self.ex.upgrade() {
             if let Some(result) = ex.get_result(token, cx) {
                 self.waker_token = None;
-                Poll::Ready(result.map_err(Error::Io))
+                Poll::Ready(result.map_err(|e| {
+                    if e.raw_os_error() == Some(libc::ECANCELED) {
+                        Error::Cancelled
+                    } else {
+                        Error::Io(e)
+                    }
+                }))
             } else {
                 // If we haven't submitted the operation yet, and the executor runs on a different
                 // thread then submit it now. Otherwise the executor will submit it automatically
@@ -1024,6 +1182,130 @@ mod tests {
         assert_eq!(buf, new_val);
     }
 
+    #[test]
+    fn error_to_io_error() {
+        let kind = |e: Error| io::Error::from(e).kind();
+        assert_eq!(kind(Error::InvalidOffset), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(Error::InvalidSource), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(Error::QueueFull), io::ErrorKind::WouldBlock);
+        assert_eq!(kind(Error::ExecutorGone), io::ErrorKind::Other);
+        assert_eq!(
+            kind(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof))),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            kind(Error::SubmittingOp(io_uring::Error::NoSpace)),
+            io::ErrorKind::WouldBlock
+        );
+
+        let errno = |e: Error| io::Error::from(e).raw_os_error();
+        assert_eq!(errno(Error::Cancelled), Some(libc::ECANCELED));
+        assert_eq!(
+            errno(Error::DuplicatingFd(sys_util::Error::new(libc::EMFILE))),
+            Some(libc::EMFILE)
+        );
+        assert_eq!(
+            errno(Error::CreatingContext(io_uring::Error::Setup(libc::ENOMEM))),
+            Some(libc::ENOMEM)
+        );
+        assert_eq!(
+            errno(Error::URingEnter(io_uring::Error::RingEnter(libc::EINTR))),
+            Some(libc::EINTR)
+        );
+        assert_eq!(
+            errno(Error::UringUnavailable(io_uring::Error::Setup(
+                libc::ENOSYS
+            ))),
+            Some(libc::ENOSYS)
+        );
+    }
+
+    #[test]
+    fn uring_availability() {
+        // Either answer is fine, but it must be stable and match whether an executor can be made.
+        let available = is_uring_available();
+        assert_eq!(available, is_uring_available());
+        if !available {
+            assert!(URingExecutor::new().is_err());
+        }
+    }
+
+    #[test]
+    fn cancel_pending_read() {
+        async fn cancel_read(source: &RegisteredSource, op: PendingOperation) {
+            source.cancel_all().expect("failed to cancel read");
+            match op.await {
+                Err(Error::Cancelled) => {}
+                Err(e) => panic!("Unexpected error from read: {}", e),
+                Ok(_) => panic!("Read completed successfully"),
+            }
+        }
+
+        let bm =
+            Arc::new(VecIoWrapper::from(vec![0u8; 16])) as Arc<dyn BackingMemory + Send + Sync>;
+
+        // Nothing is ever written to the pipe so the read can only finish by being canceled.
+        let (rx, _tx) = sys_util::pipe(true).expect("Pipe failed");
+
+        let ex = URingExecutor::new().unwrap();
+        let rx_source = ex.register_source(&rx).expect("register source failed");
+
+        let read_task = rx_source
+            .start_read_to_mem(0, Arc::clone(&bm), &[MemRegion { offset: 0, len: 8 }])
+            .expect("failed to start read to mem");
+
+        ex.run_until(cancel_read(&rx_source, read_task))
+            .expect("Failed to run executor");
+        ex.run_until(UringQueueEmpty { ex: &ex })
+            .expect("Failed to wait for cancel to complete");
+    }
+
+    #[test]
+    fn queue_full() {
+        const QUEUE_DEPTH: usize = 8;
+
+        let bm =
+            Arc::new(VecIoWrapper::from(vec![0u8; 4096])) as Arc<dyn BackingMemory + Send + Sync>;
+
+        // Use pipes to create reads that will block until data is written.
+        let (rx, mut tx) = sys_util::pipe(true).expect("Pipe failed");
+
+        let ex = URingExecutor::with_queue_depth(QUEUE_DEPTH).unwrap();
+        assert_eq!(ex.queue_depth(), QUEUE_DEPTH);
+        assert_eq!(ex.in_flight(), 0);
+
+        let rx_source = ex.register_source(&rx).expect("register source failed");
+
+        let mut ops = Vec::new();
+        for i in 0..QUEUE_DEPTH {
+            let offset = i * 8;
+            ops.push(
+                rx_source
+                    .start_read_to_mem(0, Arc::clone(&bm), &[MemRegion { offset, len: 8 }])
+                    .expect("failed to start read to mem"),
+            );
+        }
+        assert_eq!(ex.in_flight(), QUEUE_DEPTH);
+
+        match rx_source.start_read_to_mem(0, Arc::clone(&bm), &[MemRegion { offset: 0, len: 8 }]) {
+            Err(Error::QueueFull) => {}
+            Err(e) => panic!("Unexpected error starting read: {}", e),
+            Ok(_) => panic!("Started more operations than the queue depth"),
+        }
+
+        // Completing the reads frees up room in the queue.
+        tx.write_all(&[0u8; 8 * QUEUE_DEPTH]).expect("write failed");
+        let results = ex
+            .run_until(futures::future::join_all(ops))
+            .expect("Failed to run executor");
+        assert!(results.into_iter().all(|r| r.is_ok()));
+        assert_eq!(ex.in_flight(), 0);
+
+        rx_source
+            .start_read_to_mem(0, Arc::clone(&bm), &[MemRegion { offset: 0, len: 8 }])
+            .expect("failed to start read after queue drained");
+    }
+
     #[test]
     fn drop_on_different_thread() {
         let ex = URingExecutor::new().unwrap();
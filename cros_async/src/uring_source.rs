@@ -3,9 +3,10 @@
 // found in the LICENSE file.
 
 use std::convert::TryInto;
+use std::fs::File;
 use std::io;
 use std::ops::{Deref, DerefMut};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::Arc;
 
 use async_trait::async_trait;
@@ -59,6 +60,19 @@ impl<F: AsRawFd> UringSource<F> {
     }
 }
 
+impl UringSource<File> {
+    /// Creates a new `UringSource` from a raw fd, such as a tap or vhost fd that was never wrapped
+    /// in a `File`.
+    ///
+    /// # Safety
+    /// The returned `UringSource` takes ownership of `fd` and closes it when dropped, even if
+    /// registering it with `ex` fails. `fd` must be a valid, open file descriptor that isn't owned
+    /// by anything else.
+    pub unsafe fn from_raw_fd(fd: RawFd, ex: &URingExecutor) -> Result<UringSource<File>> {
+        UringSource::new(File::from_raw_fd(fd), ex)
+    }
+}
+
 #[async_trait(?Send)]
 impl<F: AsRawFd> crate::ReadAsync for UringSource<F> {
     /// Reads from the iosource at `file_offset` and fill the given `vec`.
@@ -213,6 +227,12 @@ impl<F: AsRawFd> crate::IoSourceExt<F> for UringSource<F> {
     fn as_source_mut(&mut self) -> &mut F {
         &mut self.source
     }
+
+    /// Asks the kernel to cancel the source's pending uring operations.
+    fn cancel_all(&self) -> AsyncResult<()> {
+        self.registered_source.cancel_all()?;
+        Ok(())
+    }
 }
 
 impl<F: AsRawFd> Deref for UringSource<F> {
@@ -587,4 +607,23 @@ mod tests {
         let ex = URingExecutor::new().unwrap();
         ex.run_until(go(&ex)).unwrap();
     }
+
+    #[test]
+    fn from_raw_fd() {
+        use std::io::Write;
+        use std::os::unix::io::IntoRawFd;
+
+        async fn go(ex: &URingExecutor) {
+            let (rx, mut tx) = sys_util::pipe(true).unwrap();
+            // Safe because `rx` is a valid fd whose ownership is handed to the source.
+            let source = unsafe { UringSource::from_raw_fd(rx.into_raw_fd(), ex) }.unwrap();
+            tx.write_all(&[1, 2, 3, 4]).unwrap();
+            let (ret, v) = source.read_to_vec(0, vec![0u8; 4]).await.unwrap();
+            assert_eq!(ret, 4);
+            assert_eq!(v, vec![1, 2, 3, 4]);
+        }
+
+        let ex = URingExecutor::new().unwrap();
+        ex.run_until(go(&ex)).unwrap();
+    }
 }
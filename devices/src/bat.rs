@@ -107,6 +107,7 @@ pub struct GoldfishBattery {
     kill_evt: Option<Event>,
     tube: Option<Tube>,
     create_power_monitor: Option<Box<dyn CreatePowerMonitorFn>>,
+    acpi_interface: bool,
 }
 
 /// Goldfish Battery MMIO offset
@@ -141,6 +142,12 @@ const BATTERY_STATUS_VAL_NOT_CHARGING: u32 = 3;
 /// Goldfish Battery health
 const BATTERY_HEALTH_VAL_UNKNOWN: u32 = 0;
 
+/// ACPI battery state bits reported by _BST
+const ACPI_BATTERY_STATE_DISCHARGING: u8 = 1 << 0;
+const ACPI_BATTERY_STATE_CHARGING: u8 = 1 << 1;
+/// ACPI value for an unknown rate or voltage
+const ACPI_BATTERY_VALUE_UNKNOWN: u32 = 0xffff_ffff;
+
 fn command_monitor(
     tube: Tube,
     irq_evt: Event,
@@ -303,6 +310,10 @@ impl GoldfishBattery {
     ///               the battery properties changing.
     /// * `irq_resample_evt` - Resample interrupt event notified at EOI.
     /// * `socket` - Battery control socket
+    /// * `create_power_monitor` - Creates a monitor that mirrors the host's power state.
+    /// * `acpi_interface` - Present the battery to the guest as an ACPI battery and AC adapter
+    ///                      that read the registers through a memory operation region, instead of
+    ///                      as a goldfish device.
     pub fn new(
         mmio_base: u64,
         irq_num: u32,
@@ -310,6 +321,7 @@ impl GoldfishBattery {
         irq_resample_evt: Event,
         tube: Tube,
         create_power_monitor: Option<Box<dyn CreatePowerMonitorFn>>,
+        acpi_interface: bool,
     ) -> Result<Self> {
         if mmio_base + GOLDFISHBAT_MMIO_LEN - 1 > u32::MAX as u64 {
             return Err(BatteryError::Non32BitMmioAddress);
@@ -339,9 +351,124 @@ impl GoldfishBattery {
             kill_evt: None,
             tube: Some(tube),
             create_power_monitor,
+            acpi_interface,
         })
     }
 
+    // Appends an ACPI battery and AC adapter that read the battery registers through a memory
+    // operation region. Capacities are reported in percent.
+    fn acpi_to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        aml::OpRegion::new(
+            "GFBR".into(),
+            aml::OpRegionSpace::SystemMemory,
+            self.mmio_base as usize,
+            GOLDFISHBAT_MMIO_LEN as usize,
+        )
+        .to_aml_bytes(bytes);
+        aml::Field::new(
+            "GFBR".into(),
+            aml::FieldAccessType::DWord,
+            aml::FieldUpdateRule::Preserve,
+            vec![
+                aml::FieldEntry::Reserved(BATTERY_AC_ONLINE as usize * 8),
+                aml::FieldEntry::Named(*b"GACO", 32),
+                aml::FieldEntry::Named(*b"GBST", 32),
+                aml::FieldEntry::Reserved(32),
+                aml::FieldEntry::Named(*b"GBPR", 32),
+                aml::FieldEntry::Named(*b"GBCP", 32),
+            ],
+        )
+        .to_aml_bytes(bytes);
+
+        let state = aml::Index::new(&aml::ZERO, &aml::Path::new("BSTP"), &aml::ZERO);
+        let remaining = aml::Index::new(&aml::ZERO, &aml::Path::new("BSTP"), &2u8);
+        aml::Device::new(
+            "BAT0".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C0A")),
+                &aml::Name::new("_UID".into(), &aml::ONE),
+                &aml::Method::new(
+                    "_STA".into(),
+                    0,
+                    false,
+                    vec![
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Path::new("GBPR"), &aml::ZERO),
+                            vec![&aml::Return::new(&0x0fu8)],
+                        ),
+                        &aml::Return::new(&0x1fu8),
+                    ],
+                ),
+                &aml::Name::new(
+                    "_BIF".into(),
+                    &aml::Package::new(vec![
+                        &aml::ZERO,                  // power unit: mW/mWh
+                        &100u8,                      // design capacity
+                        &100u8,                      // last full charge capacity
+                        &aml::ONE,                   // rechargeable
+                        &ACPI_BATTERY_VALUE_UNKNOWN, // design voltage
+                        &10u8,                       // design capacity of warning
+                        &5u8,                        // design capacity of low
+                        &aml::ONE,                   // capacity granularity 1
+                        &aml::ONE,                   // capacity granularity 2
+                        &"crosvm",                   // model number
+                        &"",                         // serial number
+                        &"LION",                     // battery type
+                        &"crosvm",                   // OEM information
+                    ]),
+                ),
+                &aml::Name::new(
+                    "BSTP".into(),
+                    &aml::Package::new(vec![
+                        &aml::ZERO,
+                        &ACPI_BATTERY_VALUE_UNKNOWN,
+                        &aml::ZERO,
+                        &ACPI_BATTERY_VALUE_UNKNOWN,
+                    ]),
+                ),
+                &aml::Method::new(
+                    "_BST".into(),
+                    0,
+                    true,
+                    vec![
+                        &aml::Store::new(&state, &aml::ZERO),
+                        &aml::If::new(
+                            &aml::Equal::new(
+                                &aml::Path::new("GBST"),
+                                &(BATTERY_STATUS_VAL_CHARGING as u8),
+                            ),
+                            vec![&aml::Store::new(&state, &ACPI_BATTERY_STATE_CHARGING)],
+                        ),
+                        &aml::If::new(
+                            &aml::Equal::new(
+                                &aml::Path::new("GBST"),
+                                &(BATTERY_STATUS_VAL_DISCHARGING as u8),
+                            ),
+                            vec![&aml::Store::new(&state, &ACPI_BATTERY_STATE_DISCHARGING)],
+                        ),
+                        &aml::Store::new(&remaining, &aml::Path::new("GBCP")),
+                        &aml::Return::new(&aml::Path::new("BSTP")),
+                    ],
+                ),
+            ],
+        )
+        .to_aml_bytes(bytes);
+
+        aml::Device::new(
+            "ADP0".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"ACPI0003"),
+                &aml::Method::new(
+                    "_PSR".into(),
+                    0,
+                    false,
+                    vec![&aml::Return::new(&aml::Path::new("GACO"))],
+                ),
+            ],
+        )
+        .to_aml_bytes(bytes);
+    }
+
     /// return the fds used by this device
     pub fn keep_rds(&self) -> Vec<RawDescriptor> {
         let mut rds = vec![
@@ -427,6 +554,15 @@ impl BusDevice for GoldfishBattery {
         "GoldfishBattery".to_owned()
     }
 
+    fn on_sandboxed(&mut self) {
+        // The ACPI methods only poll the registers and never enable interrupts, so handle control
+        // commands from the start. This runs in the device process, so the monitor thread updates
+        // the state the registers are read from.
+        if self.acpi_interface {
+            self.start_monitor();
+        }
+    }
+
     fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
         if data.len() != std::mem::size_of::<u32>() {
             warn!(
@@ -497,6 +633,11 @@ impl BusDevice for GoldfishBattery {
 
 impl Aml for GoldfishBattery {
     fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        if self.acpi_interface {
+            self.acpi_to_aml_bytes(bytes);
+            return;
+        }
+
         aml::Device::new(
             "GFBY".into(),
             vec![
@@ -513,3 +654,109 @@ impl Aml for GoldfishBattery {
         .to_aml_bytes(bytes);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use minijail::Minijail;
+
+    use crate::ProxyDevice;
+
+    #[test]
+    fn acpi_battery_aml() {
+        let (control_tube, response_tube) = Tube::pair().unwrap();
+        let mut battery = GoldfishBattery::new(
+            0xfe00_0000,
+            9,
+            Event::new().unwrap(),
+            Event::new().unwrap(),
+            response_tube,
+            None,
+            true,
+        )
+        .unwrap();
+        assert!(battery.monitor_thread.is_none());
+        battery.on_sandboxed();
+
+        // Commands are handled without the guest enabling interrupts.
+        control_tube
+            .send(&BatControlCommand::SetCapacity(50))
+            .unwrap();
+        assert!(matches!(
+            control_tube.recv::<BatControlResult>().unwrap(),
+            BatControlResult::Ok
+        ));
+        let mut data = [0u8; 4];
+        battery.read(
+            BusAccessInfo {
+                offset: BATTERY_CAPACITY as u64,
+                address: 0xfe00_0000 + BATTERY_CAPACITY as u64,
+                id: 0,
+            },
+            &mut data,
+        );
+        assert_eq!(u32::from_ne_bytes(data), 50);
+
+        let mut aml = Vec::new();
+        battery.to_aml_bytes(&mut aml);
+        let contains = |needle: &[u8]| aml.windows(needle.len()).any(|w| w == needle);
+
+        // Name (_BIF, Package (0x0D) { 0, 100, 100, 1, 0xFFFFFFFF, 10, 5, 1, 1, "crosvm", "",
+        //     "LION", "crosvm" })
+        assert!(contains(&[
+            0x08, 0x5F, 0x42, 0x49, 0x46, 0x12, 0x2B, 0x0D, 0x00, 0x0A, 0x64, 0x0A, 0x64, 0x01,
+            0x0C, 0xFF, 0xFF, 0xFF, 0xFF, 0x0A, 0x0A, 0x0A, 0x05, 0x01, 0x01, 0x0D, 0x63, 0x72,
+            0x6F, 0x73, 0x76, 0x6D, 0x00, 0x0D, 0x00, 0x0D, 0x4C, 0x49, 0x4F, 0x4E, 0x00, 0x0D,
+            0x63, 0x72, 0x6F, 0x73, 0x76, 0x6D, 0x00,
+        ]));
+        // Store (GBCP, Index (BSTP, 0x02)), where GBCP is the capacity register read above.
+        assert!(contains(&[
+            0x70, 0x47, 0x42, 0x43, 0x50, 0x88, 0x42, 0x53, 0x54, 0x50, 0x0A, 0x02, 0x00,
+        ]));
+        // OperationRegion (GFBR, SystemMemory, 0xFE000000, 0x1000)
+        assert!(contains(&[
+            0x5B, 0x80, 0x47, 0x46, 0x42, 0x52, 0x00, 0x0C, 0x00, 0x00, 0x00, 0xFE, 0x0B, 0x00,
+            0x10,
+        ]));
+        // The goldfish device isn't exposed.
+        assert!(!contains(b"GFSH0001"));
+    }
+    // TODO(b/173833661): Find a way to ensure these tests are run single-threaded.
+    #[test]
+    #[ignore]
+    fn acpi_battery_proxied() {
+        let (control_tube, response_tube) = Tube::pair().unwrap();
+        let battery = GoldfishBattery::new(
+            0xfe00_0000,
+            9,
+            Event::new().unwrap(),
+            Event::new().unwrap(),
+            response_tube,
+            None,
+            true,
+        )
+        .unwrap();
+        let keep_rds = battery.keep_rds();
+        let mut proxy = ProxyDevice::new(battery, &Minijail::new().unwrap(), keep_rds).unwrap();
+
+        // The command is handled in the device process, where the registers are read.
+        control_tube
+            .send(&BatControlCommand::SetCapacity(30))
+            .unwrap();
+        assert!(matches!(
+            control_tube.recv::<BatControlResult>().unwrap(),
+            BatControlResult::Ok
+        ));
+        let mut data = [0u8; 4];
+        proxy.read(
+            BusAccessInfo {
+                offset: BATTERY_CAPACITY as u64,
+                address: 0xfe00_0000 + BATTERY_CAPACITY as u64,
+                id: 0,
+            },
+            &mut data,
+        );
+        assert_eq!(u32::from_ne_bytes(data), 30);
+    }
+}
@@ -393,12 +393,15 @@ pub struct Balloon {
 }
 
 impl Balloon {
-    /// Creates a new virtio balloon device.
-    pub fn new(base_features: u64, command_tube: Tube) -> Result<Balloon> {
+    /// Creates a new virtio balloon device. The guest is asked to inflate the balloon to
+    /// `init_balloon_size` bytes as soon as its driver starts.
+    pub fn new(base_features: u64, command_tube: Tube, init_balloon_size: u64) -> Result<Balloon> {
         Ok(Balloon {
             command_tube: Some(command_tube),
             config: Arc::new(BalloonConfig {
-                num_pages: AtomicUsize::new(0),
+                num_pages: AtomicUsize::new(
+                    (init_balloon_size >> VIRTIO_BALLOON_PFN_SHIFT) as usize,
+                ),
                 actual_pages: AtomicUsize::new(0),
             }),
             kill_evt: None,
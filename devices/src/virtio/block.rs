@@ -22,7 +22,7 @@ use data_model::{DataInit, Le16, Le32, Le64};
 use disk::DiskFile;
 
 use sync::Mutex;
-use vm_control::{DiskControlCommand, DiskControlResult};
+use vm_control::{DiskControlCommand, DiskControlResult, VmRequest, VmResponse};
 use vm_memory::GuestMemory;
 
 use super::{
@@ -45,6 +45,10 @@ const DISCARD_SECTOR_ALIGNMENT: u32 = 128;
 
 const ID_LEN: usize = 20;
 
+// How long to wait before retrying a request that suspended the VM with a host I/O error. The
+// request is retried until it succeeds, so it completes soon after the VM is resumed.
+const STALLED_REQUEST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 /// Virtio block device identifier.
 /// This is an ASCII string terminated by a \0, unless all 20 bytes are used,
 /// in which case the \0 terminator is omitted.
@@ -246,6 +250,33 @@ impl ExecuteError {
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
+
+    // Whether the error came from the host file backing the disk, rather than from the request or
+    // the disk image format.
+    fn is_host_io_error(&self) -> bool {
+        match self {
+            ExecuteError::Flush(e) => disk::is_host_io_error(e),
+            ExecuteError::ReadIo { desc_error, .. } => disk::is_host_io_error(desc_error),
+            ExecuteError::WriteIo { desc_error, .. } => disk::is_host_io_error(desc_error),
+            ExecuteError::DiscardWriteZeroes {
+                ioerr: Some(ioerr), ..
+            } => disk::is_host_io_error(ioerr),
+            _ => false,
+        }
+    }
+}
+
+// Asks the main process to suspend the VM through `vm_control_tube`.
+fn suspend_vm(vm_control_tube: &Tube) {
+    if let Err(e) = vm_control_tube.send(&VmRequest::Suspend) {
+        error!("block: failed to request VM suspend: {}", e);
+        return;
+    }
+    match vm_control_tube.recv() {
+        Ok(VmResponse::Ok) => {}
+        Ok(resp) => error!("block: unexpected response to VM suspend: {}", resp),
+        Err(e) => error!("block: failed to receive VM suspend response: {}", e),
+    }
 }
 
 struct Worker {
@@ -258,6 +289,10 @@ struct Worker {
     sparse: bool,
     id: Option<BlockId>,
     control_tube: Option<Tube>,
+    vm_control_tube: Option<Tube>,
+    // A request that failed with a host I/O error while the VM was suspended, to be retried before
+    // any other.
+    stalled_request: Option<DescriptorChain>,
 }
 
 impl Worker {
@@ -271,6 +306,7 @@ impl Worker {
         flush_timer: &mut Timer,
         flush_timer_armed: &mut bool,
         mem: &GuestMemory,
+        report_host_errors: bool,
     ) -> result::Result<usize, ExecuteError> {
         let mut reader =
             Reader::new(mem.clone(), avail_desc.clone()).map_err(ExecuteError::Descriptor)?;
@@ -297,6 +333,8 @@ impl Worker {
             flush_timer_armed,
         ) {
             Ok(()) => VIRTIO_BLK_S_OK,
+            // Leave the request incomplete so that the caller can retry it.
+            Err(e) if !report_host_errors && e.is_host_io_error() => return Err(e),
             Err(e) => {
                 error!("failed executing disk request: {}", e);
                 e.status()
@@ -314,17 +352,25 @@ impl Worker {
         queue_index: usize,
         flush_timer: &mut Timer,
         flush_timer_armed: &mut bool,
+        retry_timer: &mut Timer,
     ) {
         let queue = &mut self.queues[queue_index];
 
         let disk_size = self.disk_size.lock();
 
-        while let Some(avail_desc) = queue.pop(&self.mem) {
+        loop {
+            let avail_desc = match self.stalled_request.take() {
+                Some(avail_desc) => avail_desc,
+                None => match queue.pop(&self.mem) {
+                    Some(avail_desc) => avail_desc,
+                    None => break,
+                },
+            };
             queue.set_notify(&self.mem, false);
             let desc_index = avail_desc.index;
 
             let len = match Worker::process_one_request(
-                avail_desc,
+                avail_desc.clone(),
                 self.read_only,
                 self.sparse,
                 &mut *self.disk_image,
@@ -333,8 +379,22 @@ impl Worker {
                 flush_timer,
                 flush_timer_armed,
                 &self.mem,
+                self.vm_control_tube.is_none(),
             ) {
                 Ok(len) => len,
+                Err(e) if e.is_host_io_error() && self.vm_control_tube.is_some() => {
+                    error!("block: suspending VM after failed request: {}", e);
+                    // unwrap is safe as the tube was just checked above.
+                    suspend_vm(self.vm_control_tube.as_ref().unwrap());
+                    // The guest can't notify the queue again until the request completes, so
+                    // retry it from the timer instead.
+                    self.stalled_request = Some(avail_desc);
+                    if let Err(e) = retry_timer.reset(STALLED_REQUEST_RETRY_DELAY, None) {
+                        error!("block: failed to arm the retry timer: {}", e);
+                    }
+                    queue.set_notify(&self.mem, true);
+                    break;
+                }
                 Err(e) => {
                     error!("block: failed to handle request: {}", e);
                     0
@@ -379,6 +439,7 @@ impl Worker {
         #[derive(PollToken)]
         enum Token {
             FlushTimer,
+            RetryTimer,
             QueueAvailable,
             ControlRequest,
             InterruptResample,
@@ -394,8 +455,17 @@ impl Worker {
         };
         let mut flush_timer_armed = false;
 
+        let mut retry_timer = match Timer::new() {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Failed to create the retry timer: {}", e);
+                return;
+            }
+        };
+
         let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
             (&flush_timer, Token::FlushTimer),
+            (&retry_timer, Token::RetryTimer),
             (&queue_evt, Token::QueueAvailable),
             (&kill_evt, Token::Kill),
         ])
@@ -440,12 +510,29 @@ impl Worker {
                             break 'wait;
                         }
                     }
+                    Token::RetryTimer => {
+                        if let Err(e) = retry_timer.wait() {
+                            error!("Failed to clear retry timer: {}", e);
+                            break 'wait;
+                        }
+                        self.process_queue(
+                            0,
+                            &mut flush_timer,
+                            &mut flush_timer_armed,
+                            &mut retry_timer,
+                        );
+                    }
                     Token::QueueAvailable => {
                         if let Err(e) = queue_evt.read() {
                             error!("failed reading queue Event: {}", e);
                             break 'wait;
                         }
-                        self.process_queue(0, &mut flush_timer, &mut flush_timer_armed);
+                        self.process_queue(
+                            0,
+                            &mut flush_timer,
+                            &mut flush_timer_armed,
+                            &mut retry_timer,
+                        );
                     }
                     Token::ControlRequest => {
                         let control_tube = match self.control_tube.as_ref() {
@@ -505,6 +592,7 @@ pub struct Block {
     block_size: u32,
     id: Option<BlockId>,
     control_tube: Option<Tube>,
+    vm_control_tube: Option<Tube>,
 }
 
 fn build_config_space(disk_size: u64, seg_max: u32, block_size: u32) -> virtio_blk_config {
@@ -524,7 +612,8 @@ fn build_config_space(disk_size: u64, seg_max: u32, block_size: u32) -> virtio_b
 }
 
 impl Block {
-    /// Create a new virtio block device that operates on the given DiskFile.
+    /// Create a new virtio block device that operates on the given DiskFile. If `vm_control_tube`
+    /// is given, host I/O errors suspend the VM through it instead of failing the request.
     pub fn new(
         base_features: u64,
         disk_image: Box<dyn DiskFile>,
@@ -533,6 +622,7 @@ impl Block {
         block_size: u32,
         id: Option<BlockId>,
         control_tube: Option<Tube>,
+        vm_control_tube: Option<Tube>,
     ) -> SysResult<Block> {
         if block_size % SECTOR_SIZE as u32 != 0 {
             error!(
@@ -582,6 +672,7 @@ impl Block {
             block_size,
             id,
             control_tube,
+            vm_control_tube,
         })
     }
 
@@ -760,6 +851,10 @@ impl VirtioDevice for Block {
             keep_rds.push(control_tube.as_raw_descriptor());
         }
 
+        if let Some(vm_control_tube) = &self.vm_control_tube {
+            keep_rds.push(vm_control_tube.as_raw_descriptor());
+        }
+
         keep_rds
     }
 
@@ -809,6 +904,7 @@ impl VirtioDevice for Block {
         let id = self.id.take();
         if let Some(disk_image) = self.disk_image.take() {
             let control_tube = self.control_tube.take();
+            let vm_control_tube = self.vm_control_tube.take();
             let worker_result =
                 thread::Builder::new()
                     .name("virtio_blk".to_string())
@@ -823,6 +919,8 @@ impl VirtioDevice for Block {
                             sparse,
                             id,
                             control_tube,
+                            vm_control_tube,
+                            stalled_request: None,
                         };
                         worker.run(queue_evts.remove(0), kill_evt);
                         worker
@@ -857,6 +955,7 @@ impl VirtioDevice for Block {
                 Ok(worker) => {
                     self.disk_image = Some(worker.disk_image);
                     self.control_tube = worker.control_tube;
+                    self.vm_control_tube = worker.vm_control_tube;
                     return true;
                 }
             }
@@ -867,8 +966,10 @@ impl VirtioDevice for Block {
 
 #[cfg(test)]
 mod tests {
+    use disk::QcowFile;
+    use std::fs::OpenOptions;
     use std::mem::size_of_val;
-    use tempfile::tempfile;
+    use tempfile::{tempfile, NamedTempFile};
     use vm_memory::GuestAddress;
 
     use crate::virtio::base_features;
@@ -883,7 +984,7 @@ mod tests {
         f.set_len(0x1000).unwrap();
 
         let features = base_features(ProtectionType::Unprotected);
-        let b = Block::new(features, Box::new(f), true, false, 512, None, None).unwrap();
+        let b = Block::new(features, Box::new(f), true, false, 512, None, None, None).unwrap();
         let mut num_sectors = [0u8; 4];
         b.read_config(0, &mut num_sectors);
         // size is 0x1000, so num_sectors is 8 (4096/512).
@@ -900,7 +1001,7 @@ mod tests {
         f.set_len(0x1000).unwrap();
 
         let features = base_features(ProtectionType::Unprotected);
-        let b = Block::new(features, Box::new(f), true, false, 4096, None, None).unwrap();
+        let b = Block::new(features, Box::new(f), true, false, 4096, None, None, None).unwrap();
         let mut blk_size = [0u8; 4];
         b.read_config(20, &mut blk_size);
         // blk_size should be 4096 (0x1000).
@@ -913,7 +1014,7 @@ mod tests {
         {
             let f = tempfile().unwrap();
             let features = base_features(ProtectionType::Unprotected);
-            let b = Block::new(features, Box::new(f), false, true, 512, None, None).unwrap();
+            let b = Block::new(features, Box::new(f), false, true, 512, None, None, None).unwrap();
             // writable device should set VIRTIO_BLK_F_FLUSH + VIRTIO_BLK_F_DISCARD
             // + VIRTIO_BLK_F_WRITE_ZEROES + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE
             // + VIRTIO_BLK_F_SEG_MAX
@@ -924,7 +1025,7 @@ mod tests {
         {
             let f = tempfile().unwrap();
             let features = base_features(ProtectionType::Unprotected);
-            let b = Block::new(features, Box::new(f), false, false, 512, None, None).unwrap();
+            let b = Block::new(features, Box::new(f), false, false, 512, None, None, None).unwrap();
             // writable device should set VIRTIO_BLK_F_FLUSH
             // + VIRTIO_BLK_F_WRITE_ZEROES + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE
             // + VIRTIO_BLK_F_SEG_MAX
@@ -935,7 +1036,7 @@ mod tests {
         {
             let f = tempfile().unwrap();
             let features = base_features(ProtectionType::Unprotected);
-            let b = Block::new(features, Box::new(f), true, true, 512, None, None).unwrap();
+            let b = Block::new(features, Box::new(f), true, true, 512, None, None, None).unwrap();
             // read-only device should set VIRTIO_BLK_F_FLUSH and VIRTIO_BLK_F_RO
             // + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE + VIRTIO_BLK_F_SEG_MAX
             assert_eq!(0x100000264, b.features());
@@ -988,6 +1089,7 @@ mod tests {
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
+            true,
         )
         .expect("execute failed");
 
@@ -1042,6 +1144,7 @@ mod tests {
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
+            true,
         )
         .expect("execute failed");
 
@@ -1050,6 +1153,137 @@ mod tests {
         assert_eq!(status, VIRTIO_BLK_S_IOERR);
     }
 
+    #[test]
+    fn write_read_only_qcow() {
+        // Writes to a qcow image opened read-only are rejected by the image format rather than
+        // the host file, so they fail the request instead of leaving it to be retried.
+        let temp = NamedTempFile::new().unwrap();
+        let disk_size = 0x10_0000;
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp.path())
+            .unwrap();
+        QcowFile::new(f, disk_size).unwrap();
+        let f = OpenOptions::new().read(true).open(temp.path()).unwrap();
+        let mut qcow = QcowFile::from(f).unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+
+        let req_hdr = virtio_blk_req_header {
+            req_type: Le32::from(VIRTIO_BLK_T_OUT),
+            reserved: Le32::from(0),
+            sector: Le64::from(0),
+        };
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),  // Place descriptor chain at 0x100.
+            GuestAddress(0x1000), // Describe buffer at 0x1000.
+            vec![
+                // Request header
+                (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+                // I/O buffer (1 sector of data)
+                (DescriptorType::Readable, 512),
+                // Request status
+                (DescriptorType::Writable, 1),
+            ],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+
+        let mut flush_timer = Timer::new().expect("failed to create flush_timer");
+        let mut flush_timer_armed = false;
+
+        Worker::process_one_request(
+            avail_desc,
+            false,
+            true,
+            &mut qcow,
+            disk_size,
+            None,
+            &mut flush_timer,
+            &mut flush_timer_armed,
+            &mem,
+            false,
+        )
+        .expect("execute failed");
+
+        let status_offset = GuestAddress((0x1000 + size_of_val(&req_hdr) + 512) as u64);
+        let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn read_host_error() {
+        // Reads from a write-only file fail in the host.
+        let temp = NamedTempFile::new().unwrap();
+        let disk_size = 0x1000;
+        temp.as_file().set_len(disk_size).unwrap();
+        let mut f = OpenOptions::new().write(true).open(temp.path()).unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+
+        let req_hdr = virtio_blk_req_header {
+            req_type: Le32::from(VIRTIO_BLK_T_IN),
+            reserved: Le32::from(0),
+            sector: Le64::from(0),
+        };
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+        let status_offset = GuestAddress((0x1000 + size_of_val(&req_hdr) + 512) as u64);
+
+        let mut flush_timer = Timer::new().expect("failed to create flush_timer");
+        let mut flush_timer_armed = false;
+
+        for &report_host_errors in &[false, true] {
+            mem.write_obj_at_addr(0xffu8, status_offset)
+                .expect("writing status failed");
+            let avail_desc = create_descriptor_chain(
+                &mem,
+                GuestAddress(0x100),  // Place descriptor chain at 0x100.
+                GuestAddress(0x1000), // Describe buffer at 0x1000.
+                vec![
+                    // Request header
+                    (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+                    // I/O buffer (1 sector of data)
+                    (DescriptorType::Writable, 512),
+                    // Request status
+                    (DescriptorType::Writable, 1),
+                ],
+                0,
+            )
+            .expect("create_descriptor_chain failed");
+
+            let result = Worker::process_one_request(
+                avail_desc,
+                false,
+                true,
+                &mut f,
+                disk_size,
+                None,
+                &mut flush_timer,
+                &mut flush_timer_armed,
+                &mem,
+                report_host_errors,
+            );
+
+            let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
+            if report_host_errors {
+                result.expect("execute failed");
+                assert_eq!(status, VIRTIO_BLK_S_IOERR);
+            } else {
+                // The request is left for the caller to retry.
+                assert!(result.expect_err("execute succeeded").is_host_io_error());
+                assert_eq!(status, 0xff);
+            }
+        }
+    }
+
     #[test]
     fn get_id() {
         let mut f = tempfile().unwrap();
@@ -1098,6 +1332,7 @@ mod tests {
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
+            true,
         )
         .expect("execute failed");
 
@@ -0,0 +1,256 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::alloc::Layout;
+use std::cmp::{max, min};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+
+use base::{
+    fallocate, AsRawDescriptor, FallocateMode, FileAllocate, FileReadWriteAtVolatile, FileSetLen,
+    FileSync, LayoutAllocation, PunchHole, RawDescriptor, WriteZeroesAt,
+};
+use data_model::VolatileSlice;
+
+use crate::DiskGetLen;
+
+/// Alignment of the file offset, length, and memory of every `O_DIRECT` transfer. This is the
+/// largest logical block size in common use, so it satisfies any host device.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+// Largest bounce buffer; longer unaligned requests are completed over several calls.
+const MAX_BOUNCE_SIZE: usize = 1 << 20;
+
+/// A flat disk image accessed with `O_DIRECT`, bypassing the host page cache.
+///
+/// `O_DIRECT` requires aligned transfers. Requests that aren't aligned to `DIRECT_IO_ALIGNMENT`
+/// are bounced through an aligned buffer covering the blocks they touch, and unaligned writes read
+/// those blocks first so the bytes around the request are preserved.
+#[derive(Debug)]
+pub struct DirectFile {
+    file: File,
+}
+
+impl DirectFile {
+    /// Wraps `file`, which should already have `O_DIRECT` set.
+    pub fn new(file: File) -> DirectFile {
+        DirectFile { file }
+    }
+
+    fn is_aligned(slice: &VolatileSlice, offset: u64) -> bool {
+        let align = DIRECT_IO_ALIGNMENT as u64;
+        offset % align == 0
+            && slice.size() as u64 % align == 0
+            && slice.as_ptr() as u64 % align == 0
+    }
+}
+
+// An aligned buffer covering the blocks touched by (a prefix of) an unaligned request.
+struct BounceBuffer {
+    alloc: LayoutAllocation,
+    len: usize,
+    // File offset of the start of the buffer.
+    start: u64,
+    // Offset of the request within the buffer.
+    head: usize,
+    // Number of bytes of the request covered by the buffer.
+    count: usize,
+}
+
+impl BounceBuffer {
+    fn new(offset: u64, request_len: usize) -> BounceBuffer {
+        let align = DIRECT_IO_ALIGNMENT as u64;
+        let start = offset - offset % align;
+        let head = (offset - start) as usize;
+        let count = min(request_len, MAX_BOUNCE_SIZE - head);
+        let len = (head + count + DIRECT_IO_ALIGNMENT - 1) & !(DIRECT_IO_ALIGNMENT - 1);
+        // unwrap is safe as the alignment is a power of two and `len` is bounded.
+        let layout = Layout::from_size_align(len, DIRECT_IO_ALIGNMENT).unwrap();
+        BounceBuffer {
+            alloc: LayoutAllocation::zeroed(layout),
+            len,
+            start,
+            head,
+            count,
+        }
+    }
+
+    fn as_slice(&self) -> VolatileSlice {
+        // Safe because the allocation is `len` bytes long and lives as long as `self`.
+        unsafe { VolatileSlice::from_raw_parts(self.alloc.as_ptr(), self.len) }
+    }
+
+    // The part of the buffer holding the request's data, limited to the first `available` bytes.
+    fn request_slice(&self, available: usize) -> VolatileSlice {
+        // unwrap is safe as `head + count` is within the buffer.
+        self.as_slice()
+            .sub_slice(self.head, min(available, self.count))
+            .unwrap()
+    }
+}
+
+impl FileReadWriteAtVolatile for DirectFile {
+    fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        if slice.size() == 0 || Self::is_aligned(&slice, offset) {
+            return self.file.read_at_volatile(slice, offset);
+        }
+
+        let bounce = BounceBuffer::new(offset, slice.size());
+        let read = self
+            .file
+            .read_at_volatile(bounce.as_slice(), bounce.start)?;
+        // A short read stops at the end of the file.
+        let data = bounce.request_slice(read.saturating_sub(bounce.head));
+        data.copy_to_volatile_slice(slice);
+        Ok(data.size())
+    }
+
+    fn write_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        if slice.size() == 0 || Self::is_aligned(&slice, offset) {
+            return self.file.write_at_volatile(slice, offset);
+        }
+
+        let bounce = BounceBuffer::new(offset, slice.size());
+        let file_len = self.file.get_len()?;
+        if bounce.start < file_len {
+            // Anything past the end of the file stays zeroed.
+            self.file
+                .read_at_volatile(bounce.as_slice(), bounce.start)?;
+        }
+        slice.copy_to_volatile_slice(bounce.request_slice(bounce.count));
+        self.file
+            .write_all_at_volatile(bounce.as_slice(), bounce.start)?;
+
+        // Writing whole blocks may have extended the file past the end of the request.
+        let end = offset + bounce.count as u64;
+        if bounce.start + bounce.len as u64 > max(file_len, end) {
+            self.file.set_len(max(file_len, end))?;
+        }
+        Ok(bounce.count)
+    }
+}
+
+impl DiskGetLen for DirectFile {
+    fn get_len(&self) -> io::Result<u64> {
+        self.file.get_len()
+    }
+}
+
+impl FileSetLen for DirectFile {
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+}
+
+impl FileSync for DirectFile {
+    fn fsync(&mut self) -> io::Result<()> {
+        self.file.fsync()
+    }
+}
+
+impl PunchHole for DirectFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.file.punch_hole(offset, length)
+    }
+}
+
+impl WriteZeroesAt for DirectFile {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        if fallocate(
+            &self.file,
+            FallocateMode::ZeroRange,
+            true,
+            offset,
+            length as u64,
+        )
+        .is_ok()
+        {
+            return Ok(length);
+        }
+
+        // The fallback has to go through the aligned write path.
+        let mut zeroes = vec![0u8; min(length, MAX_BOUNCE_SIZE)];
+        let mut written = 0;
+        while written < length {
+            let count = min(length - written, zeroes.len());
+            let slice = VolatileSlice::new(&mut zeroes[..count]);
+            match self.write_at_volatile(slice, offset + written as u64) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(n) => written += n,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(length)
+    }
+}
+
+impl FileAllocate for DirectFile {
+    fn allocate(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.file.allocate(offset, length)
+    }
+}
+
+impl AsRawDescriptor for DirectFile {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.file.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::add_fd_flags;
+    use tempfile::tempfile;
+
+    // Applies the same unaligned requests through `DirectFile` and a plain file and checks that the
+    // results match.
+    #[test]
+    fn matches_buffered() {
+        let direct = tempfile().unwrap();
+        // Not every filesystem supports O_DIRECT. The bounce path is exercised either way.
+        let _ = add_fd_flags(direct.as_raw_descriptor(), libc::O_DIRECT);
+        let mut direct = DirectFile::new(direct);
+        let mut buffered = tempfile().unwrap();
+
+        let len = 3 * DIRECT_IO_ALIGNMENT as u64 + 100;
+        direct.set_len(len).unwrap();
+        buffered.set_len(len).unwrap();
+
+        let requests: &[(u64, usize, u8)] = &[
+            (0, DIRECT_IO_ALIGNMENT, 0x11),
+            (10, 20, 0x22),
+            (DIRECT_IO_ALIGNMENT as u64 - 5, 10, 0x33),
+            (
+                2 * DIRECT_IO_ALIGNMENT as u64 + 7,
+                DIRECT_IO_ALIGNMENT + 50,
+                0x44,
+            ),
+            (len - 10, 20, 0x55),
+        ];
+        for &(offset, count, value) in requests {
+            let mut data = vec![value; count];
+            direct
+                .write_all_at_volatile(VolatileSlice::new(&mut data), offset)
+                .unwrap();
+            buffered
+                .write_all_at_volatile(VolatileSlice::new(&mut data), offset)
+                .unwrap();
+        }
+        direct.write_zeroes_at(15, 5).unwrap();
+        buffered.write_zeroes_at(15, 5).unwrap();
+
+        assert_eq!(direct.get_len().unwrap(), buffered.get_len().unwrap());
+        let len = buffered.get_len().unwrap();
+        for &(offset, count) in &[(0, len as usize), (3, 17), (4090, 12), (len - 3, 3)] {
+            let mut from_direct = vec![0u8; count];
+            let mut from_buffered = vec![0xffu8; count];
+            direct
+                .read_exact_at_volatile(VolatileSlice::new(&mut from_direct), offset)
+                .unwrap();
+            buffered
+                .read_exact_at_volatile(VolatileSlice::new(&mut from_buffered), offset)
+                .unwrap();
+            assert_eq!(from_direct, from_buffered);
+        }
+    }
+}
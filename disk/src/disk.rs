@@ -10,8 +10,8 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use base::{
-    AsRawDescriptors, FileAllocate, FileReadWriteAtVolatile, FileSetLen, FileSync, PunchHole,
-    SeekHole, WriteZeroesAt,
+    add_fd_flags, AsRawDescriptor, AsRawDescriptors, FileAllocate, FileReadWriteAtVolatile,
+    FileSetLen, FileSync, PunchHole, SeekHole, WriteZeroesAt,
 };
 use cros_async::Executor;
 use libc::EINVAL;
@@ -19,7 +19,9 @@ use remain::sorted;
 use vm_memory::GuestMemory;
 
 mod qcow;
-pub use qcow::{QcowFile, QCOW_MAGIC};
+pub use qcow::{
+    AsyncQcowFile, PreallocMode, QcowFile, QcowHeader, QcowHeaderBuilder, QcowSnapshot, QCOW_MAGIC,
+};
 
 #[cfg(feature = "composite-disk")]
 mod composite;
@@ -29,22 +31,29 @@ use composite::{CompositeDiskFile, CDISK_MAGIC, CDISK_MAGIC_LEN};
 mod android_sparse;
 use android_sparse::{AndroidSparse, SPARSE_HEADER_MAGIC};
 
+mod direct;
+pub use direct::{DirectFile, DIRECT_IO_ALIGNMENT};
+
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
     BlockDeviceNew(base::Error),
     ConversionNotSupported,
     CreateAndroidSparseDisk(android_sparse::Error),
+    CreateAsyncQcowFile(cros_async::AsyncError),
     #[cfg(feature = "composite-disk")]
     CreateCompositeDisk(composite::Error),
     CreateSingleFileDisk(cros_async::AsyncError),
+    DirectIoNotSupported(ImageType),
     Fallocate(cros_async::AsyncError),
     Fsync(cros_async::AsyncError),
+    InvalidMemRegion(cros_async::mem::Error),
     QcowError(qcow::Error),
     ReadingData(io::Error),
     ReadingHeader(io::Error),
     ReadToMem(cros_async::AsyncError),
     SeekingFile(io::Error),
+    SettingDirectIo(base::Error),
     SettingFileSize(io::Error),
     UnknownType,
     WriteFromMem(cros_async::AsyncError),
@@ -126,16 +135,20 @@ impl Display for Error {
             BlockDeviceNew(e) => write!(f, "failed to create block device: {}", e),
             ConversionNotSupported => write!(f, "requested file conversion not supported"),
             CreateAndroidSparseDisk(e) => write!(f, "failure in android sparse disk: {}", e),
+            CreateAsyncQcowFile(e) => write!(f, "failure creating async qcow file: {}", e),
             #[cfg(feature = "composite-disk")]
             CreateCompositeDisk(e) => write!(f, "failure in composite disk: {}", e),
             CreateSingleFileDisk(e) => write!(f, "failure creating single file disk: {}", e),
+            DirectIoNotSupported(t) => write!(f, "direct I/O not supported for {:?} images", t),
             Fallocate(e) => write!(f, "failure with fallocate: {}", e),
             Fsync(e) => write!(f, "failure with fsync: {}", e),
+            InvalidMemRegion(e) => write!(f, "invalid memory region: {}", e),
             QcowError(e) => write!(f, "failure in qcow: {}", e),
             ReadingData(e) => write!(f, "failed to read data: {}", e),
             ReadingHeader(e) => write!(f, "failed to read header: {}", e),
             ReadToMem(e) => write!(f, "failed to read to memory: {}", e),
             SeekingFile(e) => write!(f, "failed to seek file: {}", e),
+            SettingDirectIo(e) => write!(f, "failed to set O_DIRECT: {}", e),
             SettingFileSize(e) => write!(f, "failed to set file size: {}", e),
             UnknownType => write!(f, "unknown disk type"),
             WriteFromMem(e) => write!(f, "failed to write from memory: {}", e),
@@ -305,6 +318,12 @@ pub fn detect_image_type(file: &File) -> Result<ImageType> {
     Ok(ImageType::Raw)
 }
 
+/// Returns true if `e`, from an operation on a disk file, was raised by the host file holding the
+/// disk rather than by the disk image format, such as a qcow image with corrupt metadata.
+pub fn is_host_io_error(e: &io::Error) -> bool {
+    !e.get_ref().map_or(false, |inner| inner.is::<qcow::Error>())
+}
+
 /// Check if the image file type can be used for async disk access.
 pub fn async_ok(raw_image: &File) -> Result<bool> {
     let image_type = detect_image_type(raw_image)?;
@@ -325,6 +344,18 @@ pub fn create_async_disk_file(raw_image: File) -> Result<Box<dyn ToAsyncDisk>> {
     })
 }
 
+/// Create a disk file for a raw image that bypasses the host page cache. Other image types aren't
+/// supported.
+pub fn create_direct_disk_file(raw_image: File) -> Result<Box<dyn DiskFile>> {
+    // Detect the type first, as the header read isn't aligned for O_DIRECT.
+    let image_type = detect_image_type(&raw_image)?;
+    if image_type != ImageType::Raw {
+        return Err(Error::DirectIoNotSupported(image_type));
+    }
+    add_fd_flags(raw_image.as_raw_descriptor(), libc::O_DIRECT).map_err(Error::SettingDirectIo)?;
+    Ok(Box::new(DirectFile::new(raw_image)))
+}
+
 /// Inspect the image file type and create an appropriate disk file to match it.
 pub fn create_disk_file(raw_image: File) -> Result<Box<dyn DiskFile>> {
     let image_type = detect_image_type(&raw_image)?;
@@ -498,6 +529,7 @@ mod tests {
     use std::fs::{File, OpenOptions};
 
     use cros_async::{Executor, MemRegion};
+    use data_model::VolatileSlice;
     use vm_memory::{GuestAddress, GuestMemory};
 
     #[test]
@@ -540,6 +572,53 @@ mod tests {
         ex.run_until(write_zeros_async(&ex)).unwrap();
     }
 
+    #[test]
+    fn disk_file_trait_object() {
+        fn exercise(mut disk: Box<dyn DiskFile>) {
+            assert_eq!(disk.get_len().unwrap(), 0x10_0000);
+
+            let mut buf = [0x55u8; 512];
+            disk.write_all_at_volatile(VolatileSlice::new(&mut buf), 0x1000)
+                .unwrap();
+            let mut read_buf = [0u8; 512];
+            disk.read_exact_at_volatile(VolatileSlice::new(&mut read_buf), 0x1000)
+                .unwrap();
+            assert_eq!(read_buf, [0x55u8; 512]);
+
+            disk.write_zeroes_at(0x1000, 512).unwrap();
+            disk.read_exact_at_volatile(VolatileSlice::new(&mut read_buf), 0x1000)
+                .unwrap();
+            assert_eq!(read_buf, [0u8; 512]);
+
+            disk.write_all_at_volatile(VolatileSlice::new(&mut buf), 0x2_0000)
+                .unwrap();
+            disk.punch_hole(0x2_0000, 0x1_0000).unwrap();
+            disk.read_exact_at_volatile(VolatileSlice::new(&mut read_buf), 0x2_0000)
+                .unwrap();
+            assert_eq!(read_buf, [0u8; 512]);
+
+            disk.fsync().unwrap();
+        }
+
+        let raw = tempfile::tempfile().unwrap();
+        raw.set_len(0x10_0000).unwrap();
+        exercise(create_disk_file(raw).unwrap());
+
+        let qcow = QcowFile::new(tempfile::tempfile().unwrap(), 0x10_0000).unwrap();
+        exercise(Box::new(qcow));
+    }
+
+    #[test]
+    fn host_io_error() {
+        // Reading a write-only file fails in the host, not in the image format.
+        let f = OpenOptions::new().write(true).open("/dev/null").unwrap();
+        let mut buf = [0u8; 512];
+        let err = f
+            .read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .expect_err("read of write-only file succeeded");
+        assert!(is_host_io_error(&err));
+    }
+
     #[test]
     fn detect_image_type_raw() {
         let mut t = tempfile::tempfile().unwrap();
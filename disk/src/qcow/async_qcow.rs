@@ -0,0 +1,233 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+
+use base::FileReadWriteAtVolatile;
+use cros_async::mem::total_len;
+use cros_async::{BackingMemory, Executor, IoSourceExt, MemRegion};
+use libc::EINVAL;
+
+use crate::qcow::{Error as QcowError, QcowFile, ReadLocation};
+use crate::{Error, Result};
+
+/// A `QcowFile` that reads allocated clusters straight into guest memory with asynchronous reads
+/// of the image, instead of going through an intermediate buffer. The cluster lookups themselves
+/// are done synchronously, as are reads from a backing file.
+pub struct AsyncQcowFile {
+    qcow: RefCell<QcowFile>,
+    // The file holding the data clusters.
+    source: Box<dyn IoSourceExt<File>>,
+}
+
+impl AsyncQcowFile {
+    /// Wraps `qcow` for asynchronous reads on `ex`.
+    pub fn new(qcow: QcowFile, ex: &Executor) -> Result<AsyncQcowFile> {
+        let data_file = match qcow.data_file.as_ref() {
+            Some(data_file) => data_file,
+            None => qcow.raw_file.file(),
+        }
+        .try_clone()
+        .map_err(|e| Error::QcowError(QcowError::OpeningFile(e)))?;
+        let source = ex
+            .async_from(data_file)
+            .map_err(Error::CreateAsyncQcowFile)?;
+        Ok(AsyncQcowFile {
+            qcow: RefCell::new(qcow),
+            source,
+        })
+    }
+
+    /// Returns the wrapped `QcowFile`.
+    pub fn into_inner(self) -> QcowFile {
+        self.qcow.into_inner()
+    }
+
+    /// Reads from the disk at `file_offset` in to `mem` at `mem_offsets`. The range is split where
+    /// the clusters backing it aren't contiguous in the image, and each contiguous run is read in
+    /// a single operation. As with `Read`, the read is cut short at the virtual size and the
+    /// number of bytes read is returned.
+    pub async fn read_to_mem(
+        &self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: &[MemRegion],
+    ) -> Result<usize> {
+        let count = total_len(mem_offsets)
+            .ok_or_else(|| Error::ReadingData(io::Error::from_raw_os_error(EINVAL)))?;
+        let (read_count, runs) = self.map_range(file_offset, count)?;
+
+        for (location, start, len) in runs {
+            let regions = sub_regions(mem_offsets, start, len);
+            match location {
+                ReadLocation::Offset(offset) => {
+                    let nread = self
+                        .source
+                        .read_to_mem(offset, Arc::clone(&mem), &regions)
+                        .await
+                        .map_err(Error::ReadToMem)?;
+                    if nread != len {
+                        return Err(Error::ReadingData(io::Error::from(
+                            io::ErrorKind::UnexpectedEof,
+                        )));
+                    }
+                }
+                location => {
+                    let mut qcow = self.qcow.borrow_mut();
+                    let mut address = file_offset + start as u64;
+                    for region in regions {
+                        let slice = mem
+                            .get_volatile_slice(region)
+                            .map_err(Error::InvalidMemRegion)?;
+                        match qcow.backing_file.as_mut() {
+                            Some(backing) if location == ReadLocation::Unallocated => backing
+                                .read_exact_at_volatile(slice, address)
+                                .map_err(Error::ReadingData)?,
+                            _ => slice.write_bytes(0),
+                        }
+                        address += region.len as u64;
+                    }
+                }
+            }
+        }
+        Ok(read_count)
+    }
+
+    // Maps `count` bytes starting at `address` to runs of `(location, bytes before the run,
+    // length)`, merging clusters that are contiguous in the image and neighbouring clusters that
+    // aren't stored in it. Also returns the number of bytes within the virtual size.
+    fn map_range(
+        &self,
+        address: u64,
+        count: usize,
+    ) -> Result<(usize, Vec<(ReadLocation, usize, usize)>)> {
+        let mut qcow = self.qcow.borrow_mut();
+        let read_count = qcow.limit_range_file(address, count);
+
+        let mut runs: Vec<(ReadLocation, usize, usize)> = Vec::new();
+        let mut nread = 0;
+        while nread < read_count {
+            let curr_addr = address + nread as u64;
+            let location = qcow
+                .file_offset_read(curr_addr)
+                .map_err(Error::ReadingData)?;
+            let count = qcow.limit_range_cluster(curr_addr, read_count - nread);
+
+            let contiguous = match runs.last() {
+                Some(&(ReadLocation::Offset(run_offset), _, run_len)) => {
+                    location == ReadLocation::Offset(run_offset + run_len as u64)
+                }
+                Some(&(run_location, _, _)) => location == run_location,
+                None => false,
+            };
+            if contiguous {
+                // unwrap is safe as the run was just checked above.
+                runs.last_mut().unwrap().2 += count;
+            } else {
+                runs.push((location, nread, count));
+            }
+            nread += count;
+        }
+        Ok((read_count, runs))
+    }
+}
+
+// Returns the parts of `mem_offsets` that cover `len` bytes, starting `start` bytes into them.
+fn sub_regions(mem_offsets: &[MemRegion], mut start: usize, mut len: usize) -> Vec<MemRegion> {
+    let mut regions = Vec::new();
+    for region in mem_offsets {
+        if len == 0 {
+            break;
+        }
+        if start >= region.len {
+            start -= region.len;
+            continue;
+        }
+        let count = min(region.len - start, len);
+        regions.push(MemRegion {
+            offset: region.offset + start as u64,
+            len: count,
+        });
+        start = 0;
+        len -= count;
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempfile;
+    use vm_memory::{GuestAddress, GuestMemory};
+
+    #[test]
+    fn sub_regions_split() {
+        let mems = [
+            MemRegion {
+                offset: 0x100,
+                len: 0x10,
+            },
+            MemRegion {
+                offset: 0x400,
+                len: 0x20,
+            },
+        ];
+        let regions = sub_regions(&mems, 0x8, 0x10);
+        assert_eq!(regions.len(), 2);
+        assert_eq!((regions[0].offset, regions[0].len), (0x108, 0x8));
+        assert_eq!((regions[1].offset, regions[1].len), (0x400, 0x8));
+    }
+
+    #[test]
+    fn read_scattered_clusters() {
+        async fn read_clusters(ex: &Executor) {
+            let mut qcow = QcowFile::new(tempfile().unwrap(), 0x10_0000).unwrap();
+            // Allocate the fourth cluster before the third so they are out of order in the file,
+            // and leave the second cluster unallocated.
+            qcow.write_all_at(&[0x11u8; 0x1_0000], 0).unwrap();
+            qcow.write_all_at(&[0x44u8; 0x1_0000], 0x3_0000).unwrap();
+            qcow.write_all_at(&[0x33u8; 0x1_0000], 0x2_0000).unwrap();
+            let qcow = AsyncQcowFile::new(qcow, ex).unwrap();
+
+            // Read from the end of the first cluster to the start of the fourth, split across
+            // guest memory regions that don't line up with the clusters.
+            let guest_mem = Arc::new(GuestMemory::new(&[(GuestAddress(0), 0x10_0000)]).unwrap());
+            let mems = [
+                MemRegion {
+                    offset: 0x8_0000,
+                    len: 0x1_8000,
+                },
+                MemRegion {
+                    offset: 0x1000,
+                    len: 0x1_2000,
+                },
+            ];
+            let nread = qcow
+                .read_to_mem(0xf000, guest_mem.clone(), &mems)
+                .await
+                .unwrap();
+            assert_eq!(nread, 0x2_a000);
+
+            let mut data = vec![0u8; 0x2_a000];
+            guest_mem
+                .read_exact_at_addr(&mut data[..0x1_8000], GuestAddress(0x8_0000))
+                .unwrap();
+            guest_mem
+                .read_exact_at_addr(&mut data[0x1_8000..], GuestAddress(0x1000))
+                .unwrap();
+            assert!(data[..0x1000].iter().all(|&b| b == 0x11));
+            assert!(data[0x1000..0x1_1000].iter().all(|&b| b == 0));
+            assert!(data[0x1_1000..0x2_1000].iter().all(|&b| b == 0x33));
+            assert!(data[0x2_1000..].iter().all(|&b| b == 0x44));
+        }
+
+        let ex = Executor::new().unwrap();
+        ex.run_until(read_clusters(&ex)).unwrap();
+    }
+}
@@ -2,16 +2,19 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod async_qcow;
 mod qcow_raw_file;
 mod refcount;
+mod snapshot;
 mod vec_cache;
 
+use assertions::const_assert;
 use base::{
     error, AsRawDescriptor, AsRawDescriptors, FileAllocate, FileReadWriteAtVolatile,
     FileReadWriteVolatile, FileSetLen, FileSync, PunchHole, RawDescriptor, SeekHole, WriteZeroesAt,
 };
 use data_model::{VolatileMemory, VolatileSlice};
-use libc::{EINVAL, ENOSPC, ENOTSUP};
+use libc::EINVAL;
 use remain::sorted;
 
 use std::cmp::{max, min};
@@ -19,10 +22,14 @@ use std::fmt::{self, Display};
 use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::path::Path;
 use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+pub use crate::qcow::async_qcow::AsyncQcowFile;
 use crate::qcow::qcow_raw_file::QcowRawFile;
-use crate::qcow::refcount::RefCount;
+use crate::qcow::refcount::{RefCount, RefcountEntry};
+pub use crate::qcow::snapshot::QcowSnapshot;
 use crate::qcow::vec_cache::{CacheMap, Cacheable, VecCache};
 use crate::{create_disk_file, DiskFile, DiskGetLen};
 
@@ -32,14 +39,21 @@ pub enum Error {
     BackingFileIo(io::Error),
     BackingFileOpen(Box<crate::Error>),
     BackingFileTooLong(usize),
+    BufferTooLarge(usize),
     CompressedBlocksNotSupported,
+    DataFileOpen(io::Error),
     EvictingCache(io::Error),
+    ExternalDataFileNotWritable,
     FileTooBig(u64),
+    FileTooSmall { expected: u64, actual: u64 },
     GettingFileSize(io::Error),
     GettingRefcount(refcount::Error),
     InvalidBackingFileName(str::Utf8Error),
+    InvalidClusterAddress(u64),
     InvalidClusterIndex,
     InvalidClusterSize,
+    InvalidDataFileName(str::Utf8Error),
+    InvalidHeaderExtension(u32),
     InvalidIndex,
     InvalidL1TableOffset,
     InvalidL1TableSize(u32),
@@ -47,25 +61,44 @@ pub enum Error {
     InvalidOffset(u64),
     InvalidRefcountTableOffset,
     InvalidRefcountTableSize(u64),
+    MissingDataFileName,
     NoFreeClusters,
     NoRefcountClusters,
     NotEnoughSpaceForRefcounts,
     OpeningFile(io::Error),
+    PartialRefcountBlock,
+    Preallocating(io::Error),
+    ReadingData(io::Error),
     ReadingHeader(io::Error),
     ReadingPointers(io::Error),
     ReadingRefCountBlock(refcount::Error),
     ReadingRefCounts(io::Error),
+    ReadingSnapshots(io::Error),
+    ReadOnly,
     RebuildingRefCounts(io::Error),
+    RefcountOverflow,
     RefcountTableOffEnd,
     RefcountTableTooLarge,
+    ResizeNotSupported,
     SeekingFile(io::Error),
     SettingRefcountRefcount(io::Error),
     SizeTooSmallForNumberOfClusters,
+    SnapshotExists(String),
+    SnapshotNameTooLong(usize),
+    SnapshotNotFound(String),
+    SyncingCaches(io::Error),
     TooManyL1Entries(u64),
     TooManyRefcounts(u64),
+    TooManySnapshots(u32),
+    TruncatingFile(io::Error),
+    UnreferencedCluster(u64),
+    UnsupportedIncompatibleFeatures(u64),
     UnsupportedRefcountOrder,
     UnsupportedVersion(u32),
+    UpdatingSnapshots(io::Error),
+    WritingData(io::Error),
     WritingHeader(io::Error),
+    ZeroSizeDisk,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -82,44 +115,123 @@ impl Display for Error {
             BackingFileTooLong(len) => {
                 write!(f, "backing file name is too long: {} bytes over", len)
             }
+            BufferTooLarge(len) => write!(f, "buffer of {} bytes is larger than a cluster", len),
             CompressedBlocksNotSupported => write!(f, "compressed blocks not supported"),
+            DataFileOpen(e) => write!(f, "failed to open external data file: {}", e),
             EvictingCache(e) => write!(f, "failed to evict cache: {}", e),
+            ExternalDataFileNotWritable => {
+                write!(f, "images with an external data file can't be modified")
+            }
             FileTooBig(size) => write!(
                 f,
                 "file larger than max of {}: {}",
                 MAX_QCOW_FILE_SIZE, size
             ),
+            FileTooSmall { expected, actual } => write!(
+                f,
+                "file is {} bytes but its tables extend to {} bytes; it may be truncated",
+                actual, expected
+            ),
             GettingFileSize(e) => write!(f, "failed to get file size: {}", e),
             GettingRefcount(e) => write!(f, "failed to get refcount: {}", e),
             InvalidBackingFileName(e) => write!(f, "failed to parse filename: {}", e),
+            InvalidClusterAddress(addr) => {
+                write!(f, "cluster address {:#x} is past the end of the file", addr)
+            }
             InvalidClusterIndex => write!(f, "invalid cluster index"),
             InvalidClusterSize => write!(f, "invalid cluster size"),
+            InvalidDataFileName(e) => write!(f, "failed to parse external data file name: {}", e),
+            InvalidHeaderExtension(ext_type) => write!(
+                f,
+                "header extension {:#x} extends past the header area",
+                ext_type
+            ),
             InvalidIndex => write!(f, "invalid index"),
             InvalidL1TableOffset => write!(f, "invalid L1 table offset"),
             InvalidL1TableSize(size) => write!(f, "invalid L1 table size {}", size),
             InvalidMagic => write!(f, "invalid magic"),
-            InvalidOffset(_) => write!(f, "invalid offset"),
+            InvalidOffset(offset) => write!(f, "invalid offset {:#x}", offset),
             InvalidRefcountTableOffset => write!(f, "invalid refcount table offset"),
             InvalidRefcountTableSize(size) => write!(f, "invalid refcount table size: {}", size),
+            MissingDataFileName => write!(f, "external data file name extension is missing"),
             NoFreeClusters => write!(f, "no free clusters"),
             NoRefcountClusters => write!(f, "no refcount clusters"),
             NotEnoughSpaceForRefcounts => write!(f, "not enough space for refcounts"),
             OpeningFile(e) => write!(f, "failed to open file: {}", e),
+            PartialRefcountBlock => {
+                write!(f, "refcount block doesn't fill a whole number of bytes")
+            }
+            Preallocating(e) => write!(f, "failed to preallocate clusters: {}", e),
+            ReadingData(e) => write!(f, "failed to read data: {}", e),
             ReadingHeader(e) => write!(f, "failed to read header: {}", e),
             ReadingPointers(e) => write!(f, "failed to read pointers: {}", e),
             ReadingRefCountBlock(e) => write!(f, "failed to read ref count block: {}", e),
             ReadingRefCounts(e) => write!(f, "failed to read ref counts: {}", e),
+            ReadingSnapshots(e) => write!(f, "failed to read snapshot table: {}", e),
+            ReadOnly => write!(f, "image was opened read-only"),
             RebuildingRefCounts(e) => write!(f, "failed to rebuild ref counts: {}", e),
+            RefcountOverflow => write!(f, "refcount too large for the refcount order"),
             RefcountTableOffEnd => write!(f, "refcount table offset past file end"),
             RefcountTableTooLarge => write!(f, "too many clusters specified for refcount table"),
+            ResizeNotSupported => write!(f, "resizing a qcow image is not supported"),
             SeekingFile(e) => write!(f, "failed to seek file: {}", e),
             SettingRefcountRefcount(e) => write!(f, "failed to set refcount refcount: {}", e),
             SizeTooSmallForNumberOfClusters => write!(f, "size too small for number of clusters"),
+            SnapshotExists(name) => write!(f, "snapshot {} already exists", name),
+            SnapshotNameTooLong(len) => write!(f, "snapshot name is too long: {} bytes", len),
+            SnapshotNotFound(name) => write!(f, "snapshot {} not found", name),
+            SyncingCaches(e) => write!(f, "failed to sync caches: {}", e),
             TooManyL1Entries(count) => write!(f, "l1 entry table too large: {}", count),
             TooManyRefcounts(count) => write!(f, "ref count table too large: {}", count),
+            TooManySnapshots(count) => write!(f, "too many snapshots: {}", count),
+            TruncatingFile(e) => write!(f, "failed to truncate file: {}", e),
+            UnreferencedCluster(addr) => {
+                write!(
+                    f,
+                    "cluster {:#x} is in use but has a refcount of zero",
+                    addr
+                )
+            }
+            UnsupportedIncompatibleFeatures(features) => {
+                write!(f, "unsupported incompatible features: {:#x}", features)
+            }
             UnsupportedRefcountOrder => write!(f, "unsupported refcount order"),
             UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
+            UpdatingSnapshots(e) => write!(f, "failed to update snapshots: {}", e),
+            WritingData(e) => write!(f, "failed to write data: {}", e),
             WritingHeader(e) => write!(f, "failed to write header: {}", e),
+            ZeroSizeDisk => write!(f, "disk size must not be zero"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::Error::*;
+
+        match self {
+            BackingFileIo(e)
+            | DataFileOpen(e)
+            | EvictingCache(e)
+            | GettingFileSize(e)
+            | OpeningFile(e)
+            | Preallocating(e)
+            | ReadingData(e)
+            | ReadingHeader(e)
+            | ReadingPointers(e)
+            | ReadingRefCounts(e)
+            | ReadingSnapshots(e)
+            | RebuildingRefCounts(e)
+            | SeekingFile(e)
+            | SettingRefcountRefcount(e)
+            | SyncingCaches(e)
+            | TruncatingFile(e)
+            | UpdatingSnapshots(e)
+            | WritingData(e)
+            | WritingHeader(e) => Some(e),
+            GettingRefcount(e) | ReadingRefCountBlock(e) => Some(e),
+            InvalidBackingFileName(e) | InvalidDataFileName(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -139,8 +251,10 @@ const MAX_CLUSTER_BITS: u32 = 21;
 // This easily covers 1 TB files. When support for bigger files is needed the assumptions made to
 // keep these tables in RAM needs to be thrown out.
 const MAX_RAM_POINTER_TABLE_SIZE: u64 = 35_000_000;
-// Only support 2 byte refcounts, 2^refcount_order bits.
+// New files use 2 byte refcounts, 2^refcount_order bits.
 const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+// Refcounts are held in u16s, so support 1, 2, 4, 8, and 16 bit refcounts.
+const MAX_REFCOUNT_ORDER: u32 = 4;
 
 const V3_BARE_HEADER_SIZE: u32 = 104;
 
@@ -148,9 +262,17 @@ const V3_BARE_HEADER_SIZE: u32 = 104;
 const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 // Flags
+const ZERO_FLAG: u64 = 1 << 0;
 const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1 << 0;
+// The dirty bit is only set by writers using lazy refcounts. It is tolerated because refcounts are
+// rebuilt when it is set.
+const INCOMPATIBLE_FEATURES_DIRTY: u64 = 1 << 0;
+// Guest data is stored in a separate file named by a header extension. Only reads are supported.
+const INCOMPATIBLE_FEATURES_EXTERNAL_DATA_FILE: u64 = 1 << 2;
+const SUPPORTED_INCOMPATIBLE_FEATURES: u64 =
+    INCOMPATIBLE_FEATURES_DIRTY | INCOMPATIBLE_FEATURES_EXTERNAL_DATA_FILE;
 
 // The format supports a "header extension area", that crosvm does not use.
 const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
@@ -158,6 +280,24 @@ const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
 // Defined by the specification
 const MAX_BACKING_FILE_SIZE: u32 = 1023;
 
+// Same limit as qemu.
+const MAX_SNAPSHOTS: u32 = 65536;
+// Largest number of bytes passed to a single write when merging contiguous clusters.
+const MAX_COALESCED_WRITE: usize = 1 << 20;
+// Header extension types.
+const HEADER_EXT_END: u32 = 0;
+pub const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+pub const HEADER_EXT_FEATURE_NAME_TABLE: u32 = 0x6803_f857;
+pub const HEADER_EXT_BITMAPS: u32 = 0x2385_2875;
+pub const HEADER_EXT_EXTERNAL_DATA_FILE: u32 = 0x4441_5441;
+// Offset of the incompatible_features header field.
+const INCOMPATIBLE_FEATURES_OFFSET: u64 = 72;
+// Offset of the refcount_table_offset header field, immediately followed by
+// refcount_table_clusters.
+const REFCOUNT_TABLE_OFFSET_OFFSET: u64 = 48;
+// Offset of the nb_snapshots header field, immediately followed by snapshots_offset.
+const NB_SNAPSHOTS_OFFSET: u64 = 60;
+
 /// Contains the information from the header of a qcow file.
 #[derive(Clone, Debug)]
 pub struct QcowHeader {
@@ -191,6 +331,14 @@ pub struct QcowHeader {
     pub backing_file_path: Option<String>,
 }
 
+/// An entry in the header extension area that follows the fixed v3 header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderExtension {
+    pub ext_type: u32,
+    /// The extension data, without the padding to the next 8 byte boundary.
+    pub data: Vec<u8>,
+}
+
 // Reads the next u16 from the file.
 fn read_u16_from_file(mut f: &File) -> Result<u16> {
     let mut value = [0u8; 2];
@@ -266,66 +414,42 @@ impl QcowHeader {
         Ok(header)
     }
 
+    /// Reads the header of the qcow file at `path` without taking ownership of the file or
+    /// loading any of its tables.
+    pub fn probe(path: &Path) -> Result<QcowHeader> {
+        let mut file = File::open(path).map_err(Error::OpeningFile)?;
+        QcowHeader::new(&mut file)
+    }
+
     pub fn create_for_size_and_path(size: u64, backing_file: Option<&str>) -> Result<QcowHeader> {
-        let cluster_bits: u32 = DEFAULT_CLUSTER_BITS;
-        let cluster_size: u32 = 0x01 << cluster_bits;
-        let max_length: usize =
-            (cluster_size - V3_BARE_HEADER_SIZE - QCOW_EMPTY_HEADER_EXTENSION_SIZE) as usize;
+        let mut builder = QcowHeaderBuilder::new();
+        builder.size(size);
         if let Some(path) = backing_file {
-            if path.len() > max_length {
-                return Err(Error::BackingFileTooLong(path.len() - max_length));
-            }
+            builder.backing_file(path);
         }
-        // L2 blocks are always one cluster long. They contain cluster_size/sizeof(u64) addresses.
-        let l2_size: u32 = cluster_size / size_of::<u64>() as u32;
-        let num_clusters: u32 = div_round_up_u64(size, u64::from(cluster_size)) as u32;
-        let num_l2_clusters: u32 = div_round_up_u32(num_clusters, l2_size);
-        let l1_clusters: u32 = div_round_up_u32(num_l2_clusters, cluster_size);
-        let header_clusters = div_round_up_u32(size_of::<QcowHeader>() as u32, cluster_size);
-        Ok(QcowHeader {
-            magic: QCOW_MAGIC,
-            version: 3,
-            backing_file_offset: (if backing_file.is_none() {
-                0
-            } else {
-                V3_BARE_HEADER_SIZE + QCOW_EMPTY_HEADER_EXTENSION_SIZE
-            }) as u64,
-            backing_file_size: backing_file.map_or(0, |x| x.len()) as u32,
-            cluster_bits: DEFAULT_CLUSTER_BITS,
-            size,
-            crypt_method: 0,
-            l1_size: num_l2_clusters,
-            l1_table_offset: u64::from(cluster_size),
-            // The refcount table is after l1 + header.
-            refcount_table_offset: u64::from(cluster_size * (l1_clusters + 1)),
-            refcount_table_clusters: {
-                // Pre-allocate enough clusters for the entire refcount table as it must be
-                // continuous in the file. Allocate enough space to refcount all clusters, including
-                // the refcount clusters.
-                let max_refcount_clusters = max_refcount_clusters(
-                    DEFAULT_REFCOUNT_ORDER,
-                    cluster_size,
-                    num_clusters + l1_clusters + num_l2_clusters + header_clusters,
-                ) as u32;
-                // The refcount table needs to store the offset of each refcount cluster.
-                div_round_up_u32(
-                    max_refcount_clusters * size_of::<u64>() as u32,
-                    cluster_size,
-                )
-            },
-            nb_snapshots: 0,
-            snapshots_offset: 0,
-            incompatible_features: 0,
-            compatible_features: 0,
-            autoclear_features: 0,
-            refcount_order: DEFAULT_REFCOUNT_ORDER,
-            header_size: V3_BARE_HEADER_SIZE,
-            backing_file_path: backing_file.map(String::from),
-        })
+        builder.build()
     }
 
-    /// Write the header to `file`.
+    /// Write the header to `file` and extend `file` to `metadata_len()`, zeroing the L1 and
+    /// refcount tables.
     pub fn write_to<F: Write + Seek>(&self, file: &mut F) -> Result<()> {
+        self.write_header_to(file)?;
+
+        // Set the file length by seeking and writing a zero to the last byte. This avoids needing
+        // a `File` instead of anything that implements seek as the `file` argument.
+        // Zeros out the l1 and refcount table clusters.
+        file.seek(SeekFrom::Start(self.metadata_len() - 1))
+            .map_err(Error::WritingHeader)?;
+        file.write(&[0u8]).map_err(Error::WritingHeader)?;
+
+        Ok(())
+    }
+
+    /// Write the header to `file` without setting its length. Writing the zeroed L1 and refcount
+    /// tables can preallocate or sync the metadata region on some filesystems, so this leaves it
+    /// to the caller, which must extend `file` to at least `metadata_len()` bytes before the image
+    /// is opened.
+    pub fn write_header_to<F: Write + Seek>(&self, file: &mut F) -> Result<()> {
         // Writes the next u32 to the file.
         fn write_u32_to_file<F: Write>(f: &mut F, value: u32) -> Result<()> {
             f.write_all(&value.to_be_bytes())
@@ -362,29 +486,190 @@ impl QcowHeader {
             write!(file, "{}", backing_file_path).map_err(Error::WritingHeader)?;
         }
 
-        // Set the file length by seeking and writing a zero to the last byte. This avoids needing
-        // a `File` instead of anything that implements seek as the `file` argument.
-        // Zeros out the l1 and refcount table clusters.
+        Ok(())
+    }
+
+    /// Returns the end of the L1 and refcount tables, which is the length `write_to` gives the
+    /// file and the smallest length an image with this header can be opened with.
+    pub fn metadata_len(&self) -> u64 {
         let cluster_size = 0x01u64 << self.cluster_bits;
         let refcount_blocks_size = u64::from(self.refcount_table_clusters) * cluster_size;
-        file.seek(SeekFrom::Start(
-            self.refcount_table_offset + refcount_blocks_size - 2,
-        ))
-        .map_err(Error::WritingHeader)?;
-        file.write(&[0u8]).map_err(Error::WritingHeader)?;
+        self.refcount_table_offset + refcount_blocks_size
+    }
+}
 
-        Ok(())
+/// Builds the header of a new qcow image. The table offsets and sizes are derived from the chosen
+/// parameters by `build`, which also checks that the parameters can be used together.
+#[derive(Clone, Debug)]
+pub struct QcowHeaderBuilder {
+    version: u32,
+    cluster_bits: u32,
+    refcount_order: u32,
+    backing_file: Option<String>,
+    size: u64,
+}
+
+impl QcowHeaderBuilder {
+    /// Creates a builder for a version 3 image with the default cluster size and refcount width.
+    /// The size must be set before building.
+    pub fn new() -> QcowHeaderBuilder {
+        QcowHeaderBuilder {
+            version: 3,
+            cluster_bits: DEFAULT_CLUSTER_BITS,
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            backing_file: None,
+            size: 0,
+        }
+    }
+
+    /// Sets the qcow version. Only version 3 is supported.
+    pub fn version(&mut self, version: u32) -> &mut Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets the cluster size to 2^`cluster_bits` bytes.
+    pub fn cluster_bits(&mut self, cluster_bits: u32) -> &mut Self {
+        self.cluster_bits = cluster_bits;
+        self
+    }
+
+    /// Sets the refcount width to 2^`refcount_order` bits.
+    pub fn refcount_order(&mut self, refcount_order: u32) -> &mut Self {
+        self.refcount_order = refcount_order;
+        self
+    }
+
+    /// Sets the path of the backing file.
+    pub fn backing_file(&mut self, path: &str) -> &mut Self {
+        self.backing_file = Some(path.to_owned());
+        self
+    }
+
+    /// Sets the virtual size of the disk in bytes.
+    pub fn size(&mut self, size: u64) -> &mut Self {
+        self.size = size;
+        self
+    }
+
+    /// Validates the parameters and lays out the header, L1 table, and refcount table, in that
+    /// order, at the start of the file.
+    pub fn build(&self) -> Result<QcowHeader> {
+        if self.version != 3 {
+            return Err(Error::UnsupportedVersion(self.version));
+        }
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&self.cluster_bits) {
+            return Err(Error::InvalidClusterSize);
+        }
+        if self.refcount_order > MAX_REFCOUNT_ORDER {
+            return Err(Error::UnsupportedRefcountOrder);
+        }
+        if self.size == 0 {
+            return Err(Error::ZeroSizeDisk);
+        }
+        if self.size > MAX_QCOW_FILE_SIZE {
+            return Err(Error::FileTooBig(self.size));
+        }
+
+        let cluster_size = 0x01u64 << self.cluster_bits;
+        // The backing file name follows the header in the first cluster.
+        let max_length = min(
+            cluster_size - u64::from(V3_BARE_HEADER_SIZE + QCOW_EMPTY_HEADER_EXTENSION_SIZE),
+            u64::from(MAX_BACKING_FILE_SIZE),
+        ) as usize;
+        if let Some(path) = self.backing_file.as_ref() {
+            if path.len() > max_length {
+                return Err(Error::BackingFileTooLong(path.len() - max_length));
+            }
+        }
+
+        // L2 blocks are always one cluster long. They contain cluster_size/sizeof(u64) addresses.
+        let l2_size = cluster_size / size_of::<u64>() as u64;
+        let num_clusters = div_round_up_u64(self.size, cluster_size);
+        let num_l2_clusters = div_round_up_u64(num_clusters, l2_size);
+        if num_l2_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::TooManyL1Entries(num_l2_clusters));
+        }
+        let l1_clusters = div_round_up_u64(num_l2_clusters * size_of::<u64>() as u64, cluster_size);
+        let header_clusters = div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size);
+        // Pre-allocate enough clusters for the entire refcount table as it must be continuous in
+        // the file. Allocate enough space to refcount all clusters, including the refcount
+        // clusters.
+        let refcount_clusters = max_refcount_clusters(
+            self.refcount_order,
+            cluster_size as u32,
+            (num_clusters + l1_clusters + num_l2_clusters + header_clusters) as u32,
+        );
+        // The refcount table needs to store the offset of each refcount cluster.
+        let refcount_table_clusters =
+            div_round_up_u64(refcount_clusters * size_of::<u64>() as u64, cluster_size);
+
+        Ok(QcowHeader {
+            magic: QCOW_MAGIC,
+            version: self.version,
+            backing_file_offset: if self.backing_file.is_none() {
+                0
+            } else {
+                u64::from(V3_BARE_HEADER_SIZE + QCOW_EMPTY_HEADER_EXTENSION_SIZE)
+            },
+            backing_file_size: self.backing_file.as_ref().map_or(0, |x| x.len()) as u32,
+            cluster_bits: self.cluster_bits,
+            size: self.size,
+            crypt_method: 0,
+            l1_size: num_l2_clusters as u32,
+            l1_table_offset: cluster_size,
+            // The refcount table is after l1 + header.
+            refcount_table_offset: cluster_size * (l1_clusters + 1),
+            refcount_table_clusters: refcount_table_clusters as u32,
+            nb_snapshots: 0,
+            snapshots_offset: 0,
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: self.refcount_order,
+            header_size: V3_BARE_HEADER_SIZE,
+            backing_file_path: self.backing_file.clone(),
+        })
+    }
+}
+
+impl Default for QcowHeaderBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u32) -> u64 {
     // Use u64 as the product of the u32 inputs can overflow.
-    let refcount_bytes = (0x01 << refcount_order as u64) / 8;
-    let for_data = div_round_up_u64(num_clusters as u64 * refcount_bytes, cluster_size as u64);
-    let for_refcounts = div_round_up_u64(for_data * refcount_bytes, cluster_size as u64);
+    let refcount_bits = 0x01u64 << refcount_order;
+    let cluster_bits = cluster_size as u64 * 8;
+    let for_data = div_round_up_u64(num_clusters as u64 * refcount_bits, cluster_bits);
+    let for_refcounts = div_round_up_u64(for_data * refcount_bits, cluster_bits);
     for_data + for_refcounts
 }
 
+/// How much of a new qcow file to allocate up front.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreallocMode {
+    /// Allocate L2 tables and data clusters as they are first written.
+    Off,
+    /// Allocate every L2 table, leaving data clusters to be allocated on write.
+    Metadata,
+    /// Allocate every L2 table and data cluster. Data clusters are still sparse in the host file.
+    Full,
+}
+
+// Where the data of a guest cluster is read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReadLocation {
+    // At this offset in the image, or in the external data file if there is one.
+    Offset(u64),
+    // The cluster is flagged as reading all zeroes, whatever the backing file holds.
+    Zero,
+    // The cluster isn't in the image, read it from the backing file or as zeroes without one.
+    Unallocated,
+}
+
 /// Represents a qcow2 file. This is a sparse file format maintained by the qemu project.
 /// Full documentation of the format can be found in the qemu repository.
 ///
@@ -408,25 +693,41 @@ pub struct QcowFile {
     l1_table: VecCache<u64>,
     l2_entries: u64,
     l2_cache: CacheMap<VecCache<u64>>,
-    refcounts: RefCount,
+    refcounts: RefCount<u16>,
     current_offset: u64,
     unref_clusters: Vec<u64>, // List of freshly unreferenced clusters.
     // List of unreferenced clusters available to be used. unref clusters become available once the
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
     backing_file: Option<Box<dyn DiskFile>>,
+    snapshots: Vec<QcowSnapshot>,
+    // Update refcount blocks in place, marking the image dirty until the next sync.
+    lazy_refcounts: bool,
+    // The file was opened without write access, so only reads are allowed.
+    read_only: bool,
+    // Holds the guest data when the image uses an external data file. Data cluster offsets from
+    // the L2 tables refer to this file instead of the qcow file.
+    data_file: Option<File>,
 }
 
 impl QcowFile {
-    /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
+    /// Creates a QcowFile from `file`. File must be a valid qcow2 image. If `file` was opened
+    /// read-only, the image can be read but any attempt to modify it fails with `ReadOnly`.
     pub fn from(mut file: File) -> Result<QcowFile> {
-        let header = QcowHeader::new(&mut file)?;
+        let read_only = file_is_read_only(&file);
+        let mut header = QcowHeader::new(&mut file)?;
 
         // Only v3 files are supported.
         if header.version != 3 {
             return Err(Error::UnsupportedVersion(header.version));
         }
 
+        // Refuse images that use features that would be misread if ignored.
+        let unsupported_features = header.incompatible_features & !SUPPORTED_INCOMPATIBLE_FEATURES;
+        if unsupported_features != 0 {
+            return Err(Error::UnsupportedIncompatibleFeatures(unsupported_features));
+        }
+
         // Make sure that the L1 table fits in RAM.
         if u64::from(header.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
             return Err(Error::InvalidL1TableSize(header.l1_size));
@@ -456,14 +757,12 @@ impl QcowFile {
             None
         };
 
-        // Only support two byte refcounts.
-        let refcount_bits: u64 = 0x01u64
-            .checked_shl(header.refcount_order)
-            .ok_or(Error::UnsupportedRefcountOrder)?;
-        if refcount_bits != 16 {
+        // The widest supported refcount must exactly fill the in-memory refcount type.
+        const_assert!(1 << MAX_REFCOUNT_ORDER == <u16 as RefcountEntry>::BITS);
+        if header.refcount_order > MAX_REFCOUNT_ORDER {
             return Err(Error::UnsupportedRefcountOrder);
         }
-        let refcount_bytes = (refcount_bits + 7) / 8;
+        let refcount_bits = 0x01u16 << header.refcount_order;
 
         // Need at least one refcount cluster
         if header.refcount_table_clusters == 0 {
@@ -477,6 +776,33 @@ impl QcowFile {
         if header.refcount_table_offset > max(file_size, header.size) {
             return Err(Error::RefcountTableOffEnd);
         }
+        // A truncated file would otherwise only fail once a read reaches past its end.
+        let l2_size = cluster_size / size_of::<u64>() as u64;
+        let l1_table_end = header
+            .l1_table_offset
+            .checked_add(
+                div_round_up_u64(div_round_up_u64(header.size, cluster_size), l2_size)
+                    * size_of::<u64>() as u64,
+            )
+            .ok_or(Error::InvalidL1TableOffset)?;
+        let refcount_table_end = header
+            .refcount_table_offset
+            .checked_add(u64::from(header.refcount_table_clusters) * cluster_size)
+            .ok_or(Error::InvalidRefcountTableOffset)?;
+        // The L1 table can span several clusters, all of which are written back in place, so it
+        // must not run into the refcount table.
+        if header.l1_table_offset < refcount_table_end
+            && header.refcount_table_offset < l1_table_end
+        {
+            return Err(Error::InvalidL1TableOffset);
+        }
+        let tables_end = max(l1_table_end, refcount_table_end);
+        if file_size < tables_end {
+            return Err(Error::FileTooSmall {
+                expected: tables_end,
+                actual: file_size,
+            });
+        }
 
         // The first cluster should always have a non-zero refcount, so if it is 0,
         // this is an old file with broken refcounts, which requires a rebuild.
@@ -487,26 +813,39 @@ impl QcowFile {
         if first_refblock_addr != 0 {
             file.seek(SeekFrom::Start(first_refblock_addr))
                 .map_err(Error::SeekingFile)?;
-            let first_cluster_refcount = read_u16_from_file(&file)?;
+            // The first entry is in the first one or two bytes of the block. Sub-byte entries
+            // are packed from the least significant bit.
+            let first_refblock_bytes = read_u16_from_file(&file)?;
+            let first_cluster_refcount = if refcount_bits == 16 {
+                first_refblock_bytes
+            } else {
+                (first_refblock_bytes >> 8) & ((1 << refcount_bits) - 1)
+            };
             if first_cluster_refcount != 0 {
                 refcount_rebuild_required = false;
             }
         }
 
-        if (header.compatible_features & COMPATIBLE_FEATURES_LAZY_REFCOUNTS) != 0 {
+        // Lazily updated refcounts are only inconsistent while the image is marked dirty.
+        if (header.incompatible_features & INCOMPATIBLE_FEATURES_DIRTY) != 0 {
             refcount_rebuild_required = true;
         }
 
         let mut raw_file =
             QcowRawFile::from(file, cluster_size).ok_or(Error::InvalidClusterSize)?;
-        if refcount_rebuild_required {
+        raw_file.set_refcount_order(header.refcount_order);
+        // Reads don't depend on the refcounts, so a read-only image is used as it is. The same
+        // goes for images with an external data file, which can't be written.
+        let has_data_file =
+            (header.incompatible_features & INCOMPATIBLE_FEATURES_EXTERNAL_DATA_FILE) != 0;
+        if refcount_rebuild_required && !read_only && !has_data_file {
             QcowFile::rebuild_refcounts(&mut raw_file, header.clone())?;
+            header.incompatible_features &= !INCOMPATIBLE_FEATURES_DIRTY;
         }
 
-        let l2_size = cluster_size / size_of::<u64>() as u64;
         let num_clusters = div_round_up_u64(header.size, cluster_size);
         let num_l2_clusters = div_round_up_u64(num_clusters, l2_size);
-        let l1_clusters = div_round_up_u64(num_l2_clusters, cluster_size);
+        let l1_clusters = div_round_up_u64(num_l2_clusters * size_of::<u64>() as u64, cluster_size);
         let header_clusters = div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size);
         if num_l2_clusters > MAX_RAM_POINTER_TABLE_SIZE {
             return Err(Error::TooManyL1Entries(num_l2_clusters));
@@ -531,21 +870,54 @@ impl QcowFile {
         if u64::from(header.refcount_table_clusters) > 2 * refcount_clusters {
             return Err(Error::RefcountTableTooLarge);
         }
-        if l1_clusters + refcount_clusters > MAX_RAM_POINTER_TABLE_SIZE {
-            return Err(Error::TooManyRefcounts(refcount_clusters));
+        // A refcount table that has been grown past the size needed for the disk covers the
+        // whole file. Never read past the end of the table given in the header, even if the disk
+        // size implies more refcount blocks; addresses it doesn't cover are rejected on access.
+        let refcount_block_entries = raw_file.refcount_block_entries();
+        let refcount_table_capacity =
+            u64::from(header.refcount_table_clusters) * cluster_size / size_of::<u64>() as u64;
+        let refcount_entries = min(
+            refcount_table_capacity,
+            max(
+                refcount_clusters,
+                div_round_up_u64(
+                    div_round_up_u64(raw_file.file_len(), cluster_size),
+                    refcount_block_entries,
+                ),
+            ),
+        );
+        if l1_clusters + refcount_entries > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::TooManyRefcounts(refcount_entries));
         }
-        let refcount_block_entries = cluster_size / refcount_bytes;
-        let refcounts = RefCount::new(
+        let mut refcounts = RefCount::new(
             &mut raw_file,
             header.refcount_table_offset,
-            refcount_clusters,
+            refcount_entries,
             refcount_block_entries,
             cluster_size,
         )
         .map_err(Error::ReadingRefCounts)?;
+        let lazy_refcounts = (header.compatible_features & COMPATIBLE_FEATURES_LAZY_REFCOUNTS) != 0;
+        refcounts.set_lazy(lazy_refcounts);
 
         let l2_entries = cluster_size / size_of::<u64>() as u64;
 
+        if header.nb_snapshots > MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots(header.nb_snapshots));
+        }
+        let snapshots = QcowSnapshot::read_table(
+            raw_file.file_mut(),
+            header.snapshots_offset,
+            header.nb_snapshots,
+        )
+        .map_err(Error::ReadingSnapshots)?;
+        for snapshot in &snapshots {
+            if u64::from(snapshot.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+                return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+            }
+            offset_is_cluster_boundary(snapshot.l1_table_offset, header.cluster_bits)?;
+        }
+
         let mut qcow = QcowFile {
             raw_file,
             header,
@@ -557,8 +929,16 @@ impl QcowFile {
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
+            snapshots,
+            lazy_refcounts,
+            read_only,
+            data_file: None,
         };
 
+        if has_data_file {
+            qcow.data_file = Some(qcow.open_data_file()?);
+        }
+
         // Check that the L1 and refcount tables fit in a 64bit address space.
         qcow.header
             .l1_table_offset
@@ -580,6 +960,39 @@ impl QcowFile {
         QcowFile::new_from_header(file, header)
     }
 
+    /// Creates a new QcowFile at the given path that updates refcounts in place. This avoids
+    /// copying refcount blocks during bulk writes, at the cost of a refcount rebuild on the next
+    /// open if the file isn't synced before it is closed. The image is marked dirty in the header
+    /// while refcounts are being modified and clean again after each sync. The lazy refcounts
+    /// feature is recorded in the header, so the file keeps updating refcounts in place when it is
+    /// reopened.
+    pub fn new_with_lazy_refcounts(file: File, virtual_size: u64) -> Result<QcowFile> {
+        let mut header = QcowHeader::create_for_size_and_path(virtual_size, None)?;
+        header.compatible_features |= COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
+        let mut qcow = QcowFile::new_from_header(file, header)?;
+        // Setting the refcounts of the new metadata marked the image dirty.
+        qcow.sync_caches().map_err(Error::SyncingCaches)?;
+        Ok(qcow)
+    }
+
+    /// Creates a new QcowFile at the given path, allocating clusters up front according to
+    /// `prealloc`.
+    pub fn new_with_prealloc(
+        file: File,
+        virtual_size: u64,
+        prealloc: PreallocMode,
+    ) -> Result<QcowFile> {
+        let mut qcow = QcowFile::new(file, virtual_size)?;
+        match prealloc {
+            PreallocMode::Off => return Ok(qcow),
+            PreallocMode::Metadata => qcow.preallocate_l2_tables(),
+            PreallocMode::Full => qcow.preallocate_data_clusters(),
+        }
+        .and_then(|_| qcow.sync_caches())
+        .map_err(Error::Preallocating)?;
+        Ok(qcow)
+    }
+
     /// Creates a new QcowFile at the given path.
     pub fn new_from_backing(file: File, backing_file_name: &str) -> Result<QcowFile> {
         let backing_raw_file = OpenOptions::new()
@@ -607,14 +1020,10 @@ impl QcowFile {
         let end_cluster_addr =
             refcount_table_base + u64::from(qcow.header.refcount_table_clusters) * cluster_size;
 
-        let mut cluster_addr = 0;
-        while cluster_addr < end_cluster_addr {
-            let mut unref_clusters = qcow
-                .set_cluster_refcount(cluster_addr, 1)
-                .map_err(Error::SettingRefcountRefcount)?;
-            qcow.unref_clusters.append(&mut unref_clusters);
-            cluster_addr += cluster_size;
-        }
+        let mut unref_clusters = qcow
+            .set_cluster_refcount_range(0, div_round_up_u64(end_cluster_addr, cluster_size), 1)
+            .map_err(Error::SettingRefcountRefcount)?;
+        qcow.unref_clusters.append(&mut unref_clusters);
 
         Ok(qcow)
     }
@@ -623,6 +1032,13 @@ impl QcowFile {
         self.backing_file = backing;
     }
 
+    /// Writes all cached metadata to the file and waits for it and the data to reach the disk.
+    /// Unlike `flush`, clusters freed since the last flush aren't made available for reuse, so
+    /// this can be used to make the image durable without changing where later writes land.
+    pub fn sync(&mut self) -> Result<()> {
+        self.sync_caches().map_err(Error::SyncingCaches)
+    }
+
     /// Returns the `QcowHeader` for this file.
     pub fn header(&self) -> &QcowHeader {
         &self.header
@@ -633,7 +1049,8 @@ impl QcowFile {
         &self.l1_table.get_values()
     }
 
-    /// Returns an L2_table of cluster addresses, only used for debugging.
+    /// Returns an L2_table of cluster addresses, only used for debugging. Entries for clusters that
+    /// read as all zeroes have `ZERO_FLAG` set in bit 0.
     pub fn l2_table(&mut self, l1_index: usize) -> Result<Option<&[u64]>> {
         let l2_addr_disk = *self.l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
 
@@ -648,15 +1065,12 @@ impl QcowFile {
                 Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)
                     .map_err(Error::ReadingPointers)?,
             );
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache
                 .insert(l1_index, table, |index, evicted| {
-                    raw_file.write_pointer_table(
-                        l1_table[index],
-                        evicted.get_values(),
-                        CLUSTER_USED_FLAG,
-                    )
+                    raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
                 })
                 .map_err(Error::EvictingCache)?;
         }
@@ -665,51 +1079,501 @@ impl QcowFile {
         Ok(Some(self.l2_cache.get(&l1_index).unwrap().get_values()))
     }
 
-    /// Returns the refcount table for this file. This is only useful for debugging.
-    pub fn ref_table(&self) -> &[u64] {
-        &self.refcounts.ref_table()
+    /// Returns the refcount table for this file. This is only useful for debugging.
+    pub fn ref_table(&self) -> &[u64] {
+        &self.refcounts.ref_table()
+    }
+
+    /// Returns the `index`th refcount block from the file.
+    pub fn refcount_block(&mut self, index: usize) -> Result<Option<&[u16]>> {
+        self.refcounts
+            .refcount_block(&mut self.raw_file, index)
+            .map_err(Error::ReadingRefCountBlock)
+    }
+
+    /// Returns the first cluster in the file with a 0 refcount. Used for testing.
+    pub fn first_zero_refcount(&mut self) -> Result<Option<u64>> {
+        let file_size = self.raw_file.file_len();
+        let cluster_size = 0x01u64 << self.header.cluster_bits;
+
+        let mut cluster_addr = 0;
+        while cluster_addr < file_size {
+            let cluster_refcount = self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, cluster_addr)
+                .map_err(Error::GettingRefcount)?;
+            if cluster_refcount == 0 {
+                return Ok(Some(cluster_addr));
+            }
+            cluster_addr += cluster_size;
+        }
+        Ok(None)
+    }
+
+    /// Reads into `buf` starting at `offset` without using or changing the current offset. Like
+    /// `Read`, the read is cut short at the virtual size and the number of bytes read is returned.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let slice = VolatileSlice::new(buf);
+        self.read_cb(offset, slice.size(), |file, already_read, offset, count| {
+            let sub_slice = slice.get_slice(already_read, count).unwrap();
+            match file {
+                Some(f) => f.read_exact_at_volatile(sub_slice, offset),
+                None => {
+                    sub_slice.write_bytes(0);
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    /// Writes `buf` starting at `offset` without using or changing the current offset. Like
+    /// `Write`, the write is cut short at the virtual size and the number of bytes written is
+    /// returned.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        self.write_cb(offset, buf.len(), |file, already_written, count| {
+            file.write_all(&buf[already_written..(already_written + count)])
+        })
+    }
+
+    /// Reads exactly `buf.len()` bytes starting at `offset`. Unlike `Read`, this doesn't use or
+    /// change the current offset and fails instead of returning a short read when the range
+    /// extends past the virtual size.
+    pub fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.check_range(offset, buf.len())?;
+        self.read_at(buf, offset)?;
+        Ok(())
+    }
+
+    /// Writes all of `buf` starting at `offset`. Unlike `Write`, this doesn't use or change the
+    /// current offset and fails instead of writing a prefix when the range extends past the
+    /// virtual size.
+    pub fn write_all_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        self.check_range(offset, buf.len())?;
+        self.write_at(buf, offset)?;
+        Ok(())
+    }
+
+    // Fails with `UnexpectedEof` if `count` bytes starting at `offset` aren't all within the
+    // virtual size.
+    fn check_range(&self, offset: u64, count: usize) -> std::io::Result<()> {
+        match offset.checked_add(count as u64) {
+            Some(end) if end <= self.virtual_size() => Ok(()),
+            _ => Err(std::io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                Error::InvalidOffset(offset),
+            )),
+        }
+    }
+
+    /// Writes the full virtual disk contents to `out` as a raw image. Clusters that aren't
+    /// allocated in this file or a backing file are written as zeros. Only one cluster is held in
+    /// memory at a time.
+    pub fn export_raw<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let virtual_size = self.virtual_size();
+        let mut buf = vec![0u8; cluster_size as usize];
+
+        let mut address = 0;
+        while address < virtual_size {
+            let count = min(cluster_size, virtual_size - address) as usize;
+            let data = &mut buf[..count];
+            let location = self.file_offset_read(address).map_err(Error::ReadingData)?;
+            match (location, self.backing_file.as_mut()) {
+                (ReadLocation::Offset(offset), _) => match self.data_file.as_mut() {
+                    Some(data_file) => data_file
+                        .read_exact_at_volatile(VolatileSlice::new(data), offset)
+                        .map_err(Error::ReadingData)?,
+                    None => self
+                        .raw_file
+                        .read_cluster(offset, data)
+                        .map_err(Error::ReadingData)?,
+                },
+                (ReadLocation::Unallocated, Some(backing)) => backing
+                    .read_exact_at_volatile(VolatileSlice::new(data), address)
+                    .map_err(Error::ReadingData)?,
+                _ => {
+                    for b in data.iter_mut() {
+                        *b = 0;
+                    }
+                }
+            }
+            out.write_all(&buf[..count]).map_err(Error::WritingData)?;
+            address += count as u64;
+        }
+        Ok(())
+    }
+
+    /// Flushes all metadata and then shrinks the file to end just past the last cluster that is
+    /// still referenced, returning the space held by trailing free clusters to the host. The
+    /// header, L1 table, and refcount table are never truncated.
+    pub fn truncate_to_minimal(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.flush().map_err(Error::SyncingCaches)?;
+
+        let cluster_size = self.raw_file.cluster_size();
+        let l1_end =
+            self.header.l1_table_offset + self.l1_table.len() as u64 * size_of::<u64>() as u64;
+        let refcount_table_end = self.header.refcount_table_offset
+            + u64::from(self.header.refcount_table_clusters) * cluster_size;
+        let round_up = |address: u64| (address + cluster_size - 1) & !(cluster_size - 1);
+        let metadata_end = round_up(max(l1_end, refcount_table_end));
+
+        let mut new_len = round_up(self.raw_file.file_len());
+        while new_len > metadata_end {
+            let last_cluster = new_len - cluster_size;
+            let refcount = self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, last_cluster)
+                .map_err(Error::GettingRefcount)?;
+            if refcount != 0 || !self.avail_clusters.contains(&last_cluster) {
+                break;
+            }
+            new_len = last_cluster;
+        }
+
+        if new_len < self.raw_file.file_len() {
+            self.avail_clusters.retain(|&addr| addr < new_len);
+            self.raw_file
+                .truncate(new_len)
+                .map_err(Error::TruncatingFile)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the cluster containing the guest address `address` is backed by a cluster
+    /// in this file. Only the L1 and L2 tables are consulted, so this can be used to skip holes
+    /// without reading data. Clusters flagged as reading all zeroes are reported as unallocated.
+    pub fn is_cluster_allocated(&mut self, address: u64) -> Result<bool> {
+        if address >= self.virtual_size() {
+            return Err(Error::InvalidOffset(address));
+        }
+        self.cluster_allocated(address)
+            .map_err(Error::ReadingPointers)
+    }
+
+    /// Returns the `(offset, length)` ranges of the virtual disk that are backed by clusters in
+    /// this file, in order, with adjacent allocated clusters merged into a single range. Holes,
+    /// clusters flagged as reading all zeroes, and data only present in a backing file are
+    /// skipped, so copying these ranges is enough to back up the image's own data.
+    pub fn allocated_ranges(&mut self) -> Result<Vec<(u64, u64)>> {
+        let size = self.virtual_size();
+        let mut ranges = Vec::new();
+        let mut address = 0;
+        while let Some(start) = self
+            .find_allocated_cluster(address, true)
+            .map_err(Error::ReadingPointers)?
+        {
+            let end = self
+                .find_allocated_cluster(start, false)
+                .map_err(Error::ReadingPointers)?
+                .unwrap_or(size);
+            ranges.push((start, end - start));
+            address = end;
+        }
+        Ok(ranges)
+    }
+
+    /// Reads the header extensions that follow the header. The area ends at the first end of
+    /// extensions marker, the backing file name, or the end of the first cluster, whichever comes
+    /// first.
+    pub fn header_extensions(&mut self) -> Result<Vec<HeaderExtension>> {
+        let mut area_end = min(self.raw_file.cluster_size(), self.raw_file.file_len());
+        if self.header.backing_file_offset != 0 {
+            area_end = min(area_end, self.header.backing_file_offset);
+        }
+        let area_start = u64::from(self.header.header_size);
+        if area_start >= area_end {
+            return Ok(Vec::new());
+        }
+
+        let mut area = vec![0u8; (area_end - area_start) as usize];
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(area_start))
+            .map_err(Error::ReadingHeader)?;
+        file.read_exact(&mut area).map_err(Error::ReadingHeader)?;
+
+        let mut extensions = Vec::new();
+        let mut pos = 0;
+        // Each entry is a type and a length, both big endian u32s, followed by the data padded to
+        // a multiple of 8 bytes.
+        while pos + 8 <= area.len() {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(&area[pos..pos + 4]);
+            let ext_type = u32::from_be_bytes(value);
+            value.copy_from_slice(&area[pos + 4..pos + 8]);
+            let len = u32::from_be_bytes(value) as usize;
+            if ext_type == HEADER_EXT_END {
+                break;
+            }
+
+            let data_start = pos + 8;
+            let data_end = data_start + len;
+            if data_end > area.len() {
+                return Err(Error::InvalidHeaderExtension(ext_type));
+            }
+            extensions.push(HeaderExtension {
+                ext_type,
+                data: area[data_start..data_end].to_vec(),
+            });
+            pos = data_end + (8 - len % 8) % 8;
+        }
+        Ok(extensions)
+    }
+
+    // Opens the external data file named by the header extension for reading. Relative names are
+    // resolved against the current directory, as for backing files.
+    fn open_data_file(&mut self) -> Result<File> {
+        let extension = self
+            .header_extensions()?
+            .into_iter()
+            .find(|ext| ext.ext_type == HEADER_EXT_EXTERNAL_DATA_FILE)
+            .ok_or(Error::MissingDataFileName)?;
+        let path = str::from_utf8(&extension.data).map_err(Error::InvalidDataFileName)?;
+        OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(Error::DataFileOpen)
+    }
+
+    /// Returns the snapshots stored in the file.
+    pub fn snapshots(&self) -> &[QcowSnapshot] {
+        &self.snapshots
+    }
+
+    /// Saves the current contents of the disk as a snapshot called `name`. Clusters are shared
+    /// with the snapshot and copied the next time they are written.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        if name.len() > u16::max_value() as usize {
+            return Err(Error::SnapshotNameTooLong(name.len()));
+        }
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS as usize {
+            return Err(Error::TooManySnapshots(self.snapshots.len() as u32));
+        }
+        self.add_snapshot(name).map_err(Error::UpdatingSnapshots)
+    }
+
+    /// Removes the snapshot called `name`, freeing any clusters only it referenced.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let index = self.snapshot_index(name)?;
+        self.remove_snapshot(index)
+            .map_err(Error::UpdatingSnapshots)
+    }
+
+    /// Reverts the disk to the contents saved in the snapshot called `name`. The snapshot is kept.
+    pub fn apply_snapshot(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let index = self.snapshot_index(name)?;
+        self.revert_to_snapshot(index)
+            .map_err(Error::UpdatingSnapshots)
+    }
+
+    // Fails with `ReadOnly` or `ExternalDataFileNotWritable` if the image can't be modified.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(Error::ReadOnly)
+        } else if self.data_file.is_some() {
+            Err(Error::ExternalDataFileNotWritable)
+        } else {
+            Ok(())
+        }
+    }
+
+    // `check_writable` for the `std::io` based interfaces.
+    fn check_writable_io(&self) -> std::io::Result<()> {
+        self.check_writable()
+            .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))
+    }
+
+    fn snapshot_index(&self, name: &str) -> Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| Error::SnapshotNotFound(name.to_string()))
+    }
+
+    fn add_snapshot(&mut self, name: &str) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        // Every cluster reachable from the active L1 table gains a reference from the snapshot.
+        let l1_table = self.l1_table.get_values().to_vec();
+        let referenced = self.referenced_clusters(&l1_table)?;
+        self.check_refcount_headroom(&referenced)?;
+        for &addr in &referenced {
+            self.increment_refcount(addr)?;
+        }
+
+        let cluster_size = self.raw_file.cluster_size();
+        let l1_clusters = div_round_up_u64(l1_table.len() as u64 * 8, cluster_size);
+        let l1_table_offset = self.allocate_clusters(l1_clusters)?;
+        self.raw_file
+            .write_pointer_table(l1_table_offset, &l1_table, 0)?;
+
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(QcowSnapshot::new(
+            id.to_string(),
+            name.to_string(),
+            l1_table_offset,
+            l1_table.len() as u32,
+            date.as_secs() as u32,
+            date.subsec_nanos(),
+            self.virtual_size(),
+        ));
+        self.write_snapshot_table(snapshots)?;
+
+        // The active L2 tables now point at shared clusters, so they must no longer be marked as
+        // safe to write in place.
+        for &l2_addr in l1_table.iter().filter(|&&addr| addr != 0) {
+            let l2_table = Self::read_l2_cluster(&mut self.raw_file, l2_addr)?;
+            self.raw_file.write_pointer_table(l2_addr, &l2_table, 0)?;
+        }
+        self.sync_caches()
+    }
+
+    fn remove_snapshot(&mut self, index: usize) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        let snapshot = self.snapshots[index].clone();
+        let l1_table = self.read_snapshot_l1_table(&snapshot)?;
+        let referenced = self.referenced_clusters(&l1_table)?;
+
+        // Drop the table entry first so a failure part way through only leaks clusters.
+        let mut snapshots = self.snapshots.clone();
+        snapshots.remove(index);
+        self.write_snapshot_table(snapshots)?;
+
+        for addr in referenced {
+            self.decrement_refcount(addr)?;
+        }
+        let cluster_size = self.raw_file.cluster_size();
+        let l1_clusters = div_round_up_u64(u64::from(snapshot.l1_size) * 8, cluster_size);
+        for i in 0..l1_clusters {
+            self.decrement_refcount(snapshot.l1_table_offset + i * cluster_size)?;
+        }
+        self.sync_caches()
+    }
+
+    fn revert_to_snapshot(&mut self, index: usize) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        let snapshot = self.snapshots[index].clone();
+        if snapshot.l1_size as usize > self.l1_table.len() {
+            return Err(std::io::Error::new(
+                io::ErrorKind::InvalidData,
+                Error::InvalidL1TableSize(snapshot.l1_size),
+            ));
+        }
+        let snapshot_l1_table = self.read_snapshot_l1_table(&snapshot)?;
+
+        // Reference the snapshot's clusters before releasing the active ones, they may overlap.
+        let referenced = self.referenced_clusters(&snapshot_l1_table)?;
+        self.check_refcount_headroom(&referenced)?;
+        for &addr in &referenced {
+            self.increment_refcount(addr)?;
+        }
+        let active_l1_table = self.l1_table.get_values().to_vec();
+        let unreferenced = self.referenced_clusters(&active_l1_table)?;
+
+        for l1_index in 0..self.l1_table.len() {
+            self.l1_table[l1_index] = snapshot_l1_table.get(l1_index).copied().unwrap_or(0);
+        }
+        // All cached L2 tables are clean and belong to the old L1 table.
+        self.l2_cache = CacheMap::new(100);
+        self.sync_caches()?;
+
+        for addr in unreferenced {
+            self.decrement_refcount(addr)?;
+        }
+        self.sync_caches()
+    }
+
+    fn read_snapshot_l1_table(&mut self, snapshot: &QcowSnapshot) -> std::io::Result<Vec<u64>> {
+        self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )
     }
 
-    /// Returns the `index`th refcount block from the file.
-    pub fn refcount_block(&mut self, index: usize) -> Result<Option<&[u16]>> {
-        self.refcounts
-            .refcount_block(&mut self.raw_file, index)
-            .map_err(Error::ReadingRefCountBlock)
+    // Returns the L2 tables and data clusters reachable from `l1_table`.
+    fn referenced_clusters(&mut self, l1_table: &[u64]) -> std::io::Result<Vec<u64>> {
+        let mut clusters = Vec::new();
+        for &l2_addr in l1_table.iter().filter(|&&addr| addr != 0) {
+            self.check_cluster_addr(l2_addr)?;
+            clusters.push(l2_addr);
+            for entry in Self::read_l2_cluster(&mut self.raw_file, l2_addr)? {
+                // Clusters flagged as reading zeroes may still hold a preallocated host cluster.
+                let data_addr = entry & L2_TABLE_OFFSET_MASK;
+                if data_addr != 0 {
+                    self.check_cluster_addr(data_addr)?;
+                    clusters.push(data_addr);
+                }
+            }
+        }
+        Ok(clusters)
     }
 
-    /// Returns the first cluster in the file with a 0 refcount. Used for testing.
-    pub fn first_zero_refcount(&mut self) -> Result<Option<u64>> {
-        let file_size = self
-            .raw_file
-            .file_mut()
-            .metadata()
-            .map_err(Error::GettingFileSize)?
-            .len();
-        let cluster_size = 0x01u64 << self.header.cluster_bits;
+    // Replaces the on-disk snapshot table with `snapshots` and frees the clusters of the old one.
+    fn write_snapshot_table(&mut self, snapshots: Vec<QcowSnapshot>) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let old_offset = self.header.snapshots_offset;
+        let old_clusters = div_round_up_u64(
+            QcowSnapshot::table_bytes(&self.snapshots).len() as u64,
+            cluster_size,
+        );
 
-        let mut cluster_addr = 0;
-        while cluster_addr < file_size {
-            let cluster_refcount = self
-                .refcounts
-                .get_cluster_refcount(&mut self.raw_file, cluster_addr)
-                .map_err(Error::GettingRefcount)?;
-            if cluster_refcount == 0 {
-                return Ok(Some(cluster_addr));
+        let table = QcowSnapshot::table_bytes(&snapshots);
+        let new_offset = if snapshots.is_empty() {
+            0
+        } else {
+            let offset =
+                self.allocate_clusters(div_round_up_u64(table.len() as u64, cluster_size))?;
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&table)?;
+            offset
+        };
+        // The new table and its refcounts must be on disk before the header points at them.
+        self.sync_caches()?;
+
+        let nb_snapshots = snapshots.len() as u32;
+        let mut fields = Vec::with_capacity(12);
+        fields.extend_from_slice(&nb_snapshots.to_be_bytes());
+        fields.extend_from_slice(&new_offset.to_be_bytes());
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(NB_SNAPSHOTS_OFFSET))?;
+        file.write_all(&fields)?;
+        file.sync_data()?;
+        self.header.nb_snapshots = nb_snapshots;
+        self.header.snapshots_offset = new_offset;
+        self.snapshots = snapshots;
+
+        if old_offset != 0 {
+            for i in 0..old_clusters {
+                self.decrement_refcount(old_offset + i * cluster_size)?;
             }
-            cluster_addr += cluster_size;
         }
-        Ok(None)
+        Ok(())
     }
 
     fn find_avail_clusters(&mut self) -> Result<()> {
         let cluster_size = self.raw_file.cluster_size();
 
-        let file_size = self
-            .raw_file
-            .file_mut()
-            .metadata()
-            .map_err(Error::GettingFileSize)?
-            .len();
+        let file_size = self.raw_file.file_len();
 
         for i in (0..file_size).step_by(cluster_size as usize) {
             let refcount = self
@@ -746,7 +1610,10 @@ impl QcowFile {
             header: QcowHeader,
             cluster_size: u64,
         ) -> Result<()> {
-            let l1_clusters = div_round_up_u64(header.l1_size as u64, cluster_size);
+            let l1_clusters = div_round_up_u64(
+                u64::from(header.l1_size) * size_of::<u64>() as u64,
+                cluster_size,
+            );
             let l1_table_offset = header.l1_table_offset;
             for i in 0..l1_clusters {
                 add_ref(refcounts, cluster_size, l1_table_offset + i * cluster_size)?;
@@ -757,18 +1624,15 @@ impl QcowFile {
         // Traverse the L1 and L2 tables to find all reachable data clusters.
         fn set_data_refcounts(
             refcounts: &mut [u16],
-            header: QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
             cluster_size: u64,
             raw_file: &mut QcowRawFile,
         ) -> Result<()> {
             let l1_table = raw_file
-                .read_pointer_table(
-                    header.l1_table_offset,
-                    header.l1_size as u64,
-                    Some(L1_TABLE_OFFSET_MASK),
-                )
+                .read_pointer_table(l1_table_offset, l1_size as u64, Some(L1_TABLE_OFFSET_MASK))
                 .map_err(Error::ReadingPointers)?;
-            for l1_index in 0..header.l1_size as usize {
+            for l1_index in 0..l1_size as usize {
                 let l2_addr_disk = *l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
                 if l2_addr_disk != 0 {
                     // Add a reference to the L2 table cluster itself.
@@ -793,6 +1657,50 @@ impl QcowFile {
             Ok(())
         }
 
+        // Add references to the snapshot table and everything reachable from each snapshot.
+        fn set_snapshot_refcounts(
+            refcounts: &mut [u16],
+            header: QcowHeader,
+            cluster_size: u64,
+            raw_file: &mut QcowRawFile,
+        ) -> Result<()> {
+            if header.nb_snapshots > MAX_SNAPSHOTS {
+                return Err(Error::TooManySnapshots(header.nb_snapshots));
+            }
+            let snapshots = QcowSnapshot::read_table(
+                raw_file.file_mut(),
+                header.snapshots_offset,
+                header.nb_snapshots,
+            )
+            .map_err(Error::ReadingSnapshots)?;
+            let table_size = QcowSnapshot::table_bytes(&snapshots).len() as u64;
+            for i in 0..div_round_up_u64(table_size, cluster_size) {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    header.snapshots_offset + i * cluster_size,
+                )?;
+            }
+            for snapshot in snapshots {
+                let l1_clusters = div_round_up_u64(u64::from(snapshot.l1_size) * 8, cluster_size);
+                for i in 0..l1_clusters {
+                    add_ref(
+                        refcounts,
+                        cluster_size,
+                        snapshot.l1_table_offset + i * cluster_size,
+                    )?;
+                }
+                set_data_refcounts(
+                    refcounts,
+                    snapshot.l1_table_offset,
+                    snapshot.l1_size,
+                    cluster_size,
+                    raw_file,
+                )?;
+            }
+            Ok(())
+        }
+
         // Add references to the top-level refcount table clusters.
         fn set_refcount_table_refcounts(
             refcounts: &mut [u16],
@@ -850,8 +1758,9 @@ impl QcowFile {
             raw_file: &mut QcowRawFile,
             refcount_block_entries: u64,
         ) -> Result<()> {
-            // Rewrite the header with lazy refcounts enabled while we are rebuilding the tables.
-            header.compatible_features |= COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
+            // Rewrite the header marked dirty while we are rebuilding the tables, so that they are
+            // rebuilt again if this is interrupted.
+            header.incompatible_features |= INCOMPATIBLE_FEATURES_DIRTY;
             raw_file
                 .file_mut()
                 .seek(SeekFrom::Start(0))
@@ -865,22 +1774,12 @@ impl QcowFile {
                     refcounts.len(),
                     refblock_start + refcount_block_entries as usize,
                 );
-                let refblock = &refcounts[refblock_start..refblock_end];
+                let mut refblock = refcounts[refblock_start..refblock_end].to_vec();
+                // If this is the last (partial) cluster, pad it out to a full refblock cluster.
+                refblock.resize(refcount_block_entries as usize, 0);
                 raw_file
-                    .write_refcount_block(*refblock_addr, refblock)
+                    .write_refcount_block(*refblock_addr, &refblock)
                     .map_err(Error::WritingHeader)?;
-
-                // If this is the last (partial) cluster, pad it out to a full refblock cluster.
-                if refblock.len() < refcount_block_entries as usize {
-                    let refblock_padding =
-                        vec![0u16; refcount_block_entries as usize - refblock.len()];
-                    raw_file
-                        .write_refcount_block(
-                            *refblock_addr + refblock.len() as u64 * 2,
-                            &refblock_padding,
-                        )
-                        .map_err(Error::WritingHeader)?;
-                }
             }
 
             // Rewrite the top-level refcount table.
@@ -888,8 +1787,8 @@ impl QcowFile {
                 .write_pointer_table(header.refcount_table_offset, &ref_table, 0)
                 .map_err(Error::WritingHeader)?;
 
-            // Rewrite the header again, now with lazy refcounts disabled.
-            header.compatible_features &= !COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
+            // Rewrite the header again, now clean with the refcounts known to be consistent.
+            header.incompatible_features &= !INCOMPATIBLE_FEATURES_DIRTY;
             raw_file
                 .file_mut()
                 .seek(SeekFrom::Start(0))
@@ -901,19 +1800,13 @@ impl QcowFile {
 
         let cluster_size = raw_file.cluster_size();
 
-        let file_size = raw_file
-            .file_mut()
-            .metadata()
-            .map_err(Error::GettingFileSize)?
-            .len();
+        let file_size = raw_file.file_len();
 
-        let refcount_bits = 1u64 << header.refcount_order;
-        let refcount_bytes = div_round_up_u64(refcount_bits, 8);
-        let refcount_block_entries = cluster_size / refcount_bytes;
+        let refcount_block_entries = raw_file.refcount_block_entries();
         let pointers_per_cluster = cluster_size / size_of::<u64>() as u64;
         let data_clusters = div_round_up_u64(header.size, cluster_size);
         let l2_clusters = div_round_up_u64(data_clusters, pointers_per_cluster);
-        let l1_clusters = div_round_up_u64(l2_clusters, cluster_size);
+        let l1_clusters = div_round_up_u64(l2_clusters * size_of::<u64>() as u64, cluster_size);
         let header_clusters = div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size);
         let max_clusters = data_clusters + l2_clusters + l1_clusters + header_clusters;
         let mut max_valid_cluster_index = max_clusters;
@@ -943,7 +1836,14 @@ impl QcowFile {
         // Find all references clusters and rebuild refcounts.
         set_header_refcount(&mut refcounts, cluster_size)?;
         set_l1_refcounts(&mut refcounts, header.clone(), cluster_size)?;
-        set_data_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
+        set_data_refcounts(
+            &mut refcounts,
+            header.l1_table_offset,
+            header.l1_size,
+            cluster_size,
+            raw_file,
+        )?;
+        set_snapshot_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
         set_refcount_table_refcounts(&mut refcounts, header.clone(), cluster_size)?;
 
         // Allocate clusters to store the new reference count blocks.
@@ -1000,61 +1900,88 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Gets where the data at the given guest address is read from, which is the offset in the host
+    // file unless the L1, L2, or data clusters have yet to be allocated or the cluster is flagged
+    // as reading all zeroes.
+    fn file_offset_read(&mut self, address: u64) -> std::io::Result<ReadLocation> {
         if address >= self.virtual_size() as u64 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
+            return Err(std::io::Error::new(
+                io::ErrorKind::InvalidInput,
+                Error::InvalidOffset(address),
+            ));
         }
 
         let l1_index = self.l1_table_index(address) as usize;
         let l2_addr_disk = *self
             .l1_table
             .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+            .ok_or_else(|| std::io::Error::new(io::ErrorKind::InvalidInput, Error::InvalidIndex))?;
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(ReadLocation::Unallocated);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
 
         if !self.l2_cache.contains_key(&l1_index) {
             // Not in the cache.
+            self.check_cluster_addr(l2_addr_disk)?;
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
 
+            let l2_flags = self.l2_entry_flags();
+
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         };
 
         let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
+        if cluster_addr & ZERO_FLAG != 0 {
+            return Ok(ReadLocation::Zero);
+        }
         if cluster_addr == 0 {
-            return Ok(None);
+            return Ok(ReadLocation::Unallocated);
         }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        // Clusters in an external data file aren't bounded by the qcow file's length.
+        if self.data_file.is_none() {
+            self.check_cluster_addr(cluster_addr)?;
+        }
+        Ok(ReadLocation::Offset(
+            cluster_addr + self.raw_file.cluster_offset(address),
+        ))
+    }
+
+    // Fails with `InvalidClusterAddress` if `cluster_addr`, read from an L1 or L2 table, doesn't
+    // point inside the file.
+    fn check_cluster_addr(&self, cluster_addr: u64) -> std::io::Result<()> {
+        if cluster_addr >= self.raw_file.file_len() {
+            return Err(std::io::Error::new(
+                io::ErrorKind::InvalidData,
+                Error::InvalidClusterAddress(cluster_addr),
+            ));
+        }
+        Ok(())
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
     // to be allocated, they will be.
     fn file_offset_write(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() as u64 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
+            return Err(std::io::Error::new(
+                io::ErrorKind::InvalidInput,
+                Error::InvalidOffset(address),
+            ));
         }
 
         let l1_index = self.l1_table_index(address) as usize;
         let l2_addr_disk = *self
             .l1_table
             .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+            .ok_or_else(|| std::io::Error::new(io::ErrorKind::InvalidInput, Error::InvalidIndex))?;
         let l2_index = self.l2_table_index(address) as usize;
 
         let mut set_refcounts = Vec::new();
@@ -1070,16 +1997,14 @@ impl QcowFile {
                 self.l1_table[l1_index] = new_addr;
                 VecCache::new(self.l2_entries as usize)
             } else {
+                self.check_cluster_addr(l2_addr_disk)?;
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
             };
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         }
 
@@ -1100,7 +2025,47 @@ impl QcowFile {
                 self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
                 cluster_addr
             }
-            a => a,
+            entry if entry & ZERO_FLAG != 0 => {
+                // The cluster reads as zeroes whatever the backing file holds, so it needs zeroed
+                // storage. A preallocated host cluster is reused unless a snapshot shares it.
+                let old_addr = entry & L2_TABLE_OFFSET_MASK;
+                let refcount = if old_addr == 0 {
+                    0
+                } else {
+                    self.check_cluster_addr(old_addr)?;
+                    self.cluster_refcount(old_addr)?
+                };
+                let cluster_addr = if refcount == 1 {
+                    self.raw_file.zero_cluster(old_addr)?;
+                    old_addr
+                } else {
+                    if refcount > 1 {
+                        set_refcounts.push((old_addr, refcount - 1));
+                    }
+                    self.append_data_cluster(None)?
+                };
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                cluster_addr
+            }
+            a => {
+                self.check_cluster_addr(a)?;
+                let refcount = if self.snapshots.is_empty() {
+                    1
+                } else {
+                    self.cluster_refcount(a)?
+                };
+                if refcount > 1 {
+                    // The cluster is shared with a snapshot, copy it before it is modified.
+                    let mut cluster_data = vec![0u8; self.raw_file.cluster_size() as usize];
+                    self.raw_file.read_cluster(a, &mut cluster_data)?;
+                    let cluster_addr = self.append_data_cluster(Some(cluster_data))?;
+                    self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                    set_refcounts.push((a, refcount - 1));
+                    cluster_addr
+                } else {
+                    a
+                }
+            }
         };
 
         for (addr, count) in set_refcounts {
@@ -1126,8 +2091,18 @@ impl QcowFile {
             // The index must be valid from when it was insterted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                let refcount = if self.snapshots.is_empty() {
+                    1
+                } else {
+                    self.cluster_refcount(addr)?
+                };
+                if refcount > 1 {
+                    // The table is still used by a snapshot.
+                    set_refcounts.push((addr, refcount - 1));
+                } else {
+                    self.unref_clusters.push(addr);
+                    set_refcounts.push((addr, 0));
+                }
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1144,27 +2119,57 @@ impl QcowFile {
         Ok(())
     }
 
+    // Allocates an empty L2 table for every L1 entry that doesn't have one.
+    fn preallocate_l2_tables(&mut self) -> std::io::Result<()> {
+        for l1_index in 0..self.l1_table.len() {
+            if self.l1_table[l1_index] != 0 {
+                continue;
+            }
+            // New clusters are zeroed, which is an L2 table with no data clusters allocated.
+            let new_addr = self.get_new_cluster(None)?;
+            let mut newly_unref = self.set_cluster_refcount(new_addr, 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+            self.l1_table[l1_index] = new_addr;
+        }
+        Ok(())
+    }
+
+    // Allocates every data cluster, along with the L2 tables that point to them.
+    fn preallocate_data_clusters(&mut self) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let mut address = 0;
+        while address < self.virtual_size() {
+            self.file_offset_write(address)?;
+            address += cluster_size;
+        }
+        Ok(())
+    }
+
     // Allocate a new cluster and return its offset within the raw file.
     fn get_new_cluster(&mut self, initial_data: Option<Vec<u8>>) -> std::io::Result<u64> {
         // First use a pre allocated cluster if one is available.
         if let Some(free_cluster) = self.avail_clusters.pop() {
             if let Some(initial_data) = initial_data {
-                self.raw_file.write_cluster(free_cluster, initial_data)?;
+                self.raw_file.write_cluster(free_cluster, &initial_data)?;
             } else {
                 self.raw_file.zero_cluster(free_cluster)?;
             }
             return Ok(free_cluster);
         }
 
+        self.reserve_refcount_range(1)?;
         let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
         if let Some(new_cluster) = self.raw_file.add_cluster_end(max_valid_cluster_offset)? {
             if let Some(initial_data) = initial_data {
-                self.raw_file.write_cluster(new_cluster, initial_data)?;
+                self.raw_file.write_cluster(new_cluster, &initial_data)?;
             }
             Ok(new_cluster)
         } else {
             error!("No free clusters in get_new_cluster()");
-            Err(std::io::Error::from_raw_os_error(ENOSPC))
+            Err(std::io::Error::new(
+                io::ErrorKind::Other,
+                Error::NoFreeClusters,
+            ))
         }
     }
 
@@ -1181,14 +2186,17 @@ impl QcowFile {
     // Returns true if the cluster containing `address` is already allocated.
     fn cluster_allocated(&mut self, address: u64) -> std::io::Result<bool> {
         if address >= self.virtual_size() as u64 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
+            return Err(std::io::Error::new(
+                io::ErrorKind::InvalidInput,
+                Error::InvalidOffset(address),
+            ));
         }
 
         let l1_index = self.l1_table_index(address) as usize;
         let l2_addr_disk = *self
             .l1_table
             .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+            .ok_or_else(|| std::io::Error::new(io::ErrorKind::InvalidInput, Error::InvalidIndex))?;
         let l2_index = self.l2_table_index(address) as usize;
 
         if l2_addr_disk == 0 {
@@ -1201,20 +2209,18 @@ impl QcowFile {
             // Not in the cache.
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         }
 
         let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
-        // If cluster_addr != 0, the cluster is allocated.
-        Ok(cluster_addr != 0)
+        // If cluster_addr != 0, the cluster is allocated. Clusters flagged as reading zeroes are
+        // reported as holes even if they keep a host cluster.
+        Ok(cluster_addr != 0 && cluster_addr & ZERO_FLAG == 0)
     }
 
     // Find the first guest address greater than or equal to `address` whose allocation state
@@ -1253,14 +2259,17 @@ impl QcowFile {
     // Any future reads of this cluster will return all zeroes (or the backing file, if in use).
     fn deallocate_cluster(&mut self, address: u64) -> std::io::Result<()> {
         if address >= self.virtual_size() as u64 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
+            return Err(std::io::Error::new(
+                io::ErrorKind::InvalidInput,
+                Error::InvalidOffset(address),
+            ));
         }
 
         let l1_index = self.l1_table_index(address) as usize;
         let l2_addr_disk = *self
             .l1_table
             .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+            .ok_or_else(|| std::io::Error::new(io::ErrorKind::InvalidInput, Error::InvalidIndex))?;
         let l2_index = self.l2_table_index(address) as usize;
 
         if l2_addr_disk == 0 {
@@ -1273,30 +2282,29 @@ impl QcowFile {
             // Not in the cache.
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         }
 
-        let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
+        let entry = self.l2_cache.get(&l1_index).unwrap()[l2_index];
+        let cluster_addr = entry & L2_TABLE_OFFSET_MASK;
         if cluster_addr == 0 {
-            // This cluster is already unallocated; nothing to do.
+            // This cluster is already unallocated or reads as zeroes without storage; nothing to
+            // do.
             return Ok(());
         }
 
         // Decrement the refcount.
-        let refcount = self
-            .refcounts
-            .get_cluster_refcount(&mut self.raw_file, cluster_addr)
-            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let refcount = self.cluster_refcount(cluster_addr)?;
         if refcount == 0 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
+            return Err(std::io::Error::new(
+                io::ErrorKind::InvalidData,
+                Error::UnreferencedCluster(cluster_addr),
+            ));
         }
 
         let new_refcount = refcount - 1;
@@ -1304,8 +2312,18 @@ impl QcowFile {
         self.unref_clusters.append(&mut newly_unref);
 
         // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
+        if self.snapshots.is_empty() {
+            // unwrap is safe as we just checked/inserted this entry.
+            self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
+        } else {
+            // The L2 table may be shared with a snapshot, so it can't be modified in place.
+            let mut set_refcounts = Vec::new();
+            self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+            for (addr, count) in set_refcounts {
+                let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+                self.unref_clusters.append(&mut newly_unref);
+            }
+        }
 
         if new_refcount == 0 {
             let cluster_size = self.raw_file.cluster_size();
@@ -1345,7 +2363,14 @@ impl QcowFile {
                 } else {
                     // Any space in unallocated clusters can be left alone, since
                     // unallocated clusters already read back as zeroes.
-                    self.file_offset_read(curr_addr)?
+                    match self.file_offset_read(curr_addr)? {
+                        // Allocated clusters may be shared with a snapshot and need to be copied.
+                        ReadLocation::Offset(_) if !self.snapshots.is_empty() => {
+                            Some(self.file_offset_write(curr_addr)?)
+                        }
+                        ReadLocation::Offset(offset) => Some(offset),
+                        ReadLocation::Zero | ReadLocation::Unallocated => None,
+                    }
                 };
                 if let Some(offset) = offset {
                     // Partial cluster - zero it out.
@@ -1361,46 +2386,257 @@ impl QcowFile {
     }
 
     // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // cluster is compressed. Entries keep `ZERO_FLAG` along with the cluster address so that
+    // clusters reading all zeroes aren't confused with unallocated ones, and aren't lost when the
+    // table is written back.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
         if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
+            return Err(std::io::Error::new(
+                io::ErrorKind::Other,
+                Error::CompressedBlocksNotSupported,
+            ));
         }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| *entry & (L2_TABLE_OFFSET_MASK | ZERO_FLAG))
             .collect())
     }
 
+    // Allocates `count` contiguous clusters at the end of the file, each with a refcount of one.
+    // Returns the address of the first.
+    fn allocate_clusters(&mut self, count: u64) -> std::io::Result<u64> {
+        self.reserve_refcount_range(count)?;
+        let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+        let mut clusters = Vec::new();
+        for _ in 0..count {
+            match self.raw_file.add_cluster_end(max_valid_cluster_offset)? {
+                Some(addr) => clusters.push(addr),
+                None => {
+                    return Err(std::io::Error::new(
+                        io::ErrorKind::Other,
+                        Error::NoFreeClusters,
+                    ))
+                }
+            }
+        }
+        // Refcounts are set after all clusters are added, setting them can allocate refblocks.
+        for &addr in &clusters {
+            let mut newly_unref = self.set_cluster_refcount(addr, 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        clusters
+            .first()
+            .copied()
+            .ok_or_else(|| std::io::Error::new(io::ErrorKind::InvalidInput, Error::InvalidIndex))
+    }
+
+    // Grows the refcount table until it covers `count` more clusters at the end of the file.
+    fn reserve_refcount_range(&mut self, count: u64) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let last_cluster = div_round_up_u64(self.raw_file.file_len(), cluster_size)
+            .checked_add(count.saturating_sub(1))
+            .and_then(|index| index.checked_mul(cluster_size))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    io::ErrorKind::Other,
+                    Error::FileTooBig(self.raw_file.file_len()),
+                )
+            })?;
+        while last_cluster > self.refcounts.max_valid_cluster_offset() {
+            self.grow_refcount_table()?;
+        }
+        Ok(())
+    }
+
+    // Doubles the number of refcount blocks the refcount table can point to. The table is
+    // extended in place if its clusters have room, otherwise it is moved to the end of the file.
+    fn grow_refcount_table(&mut self) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let entries_per_cluster = cluster_size / size_of::<u64>() as u64;
+        let old_offset = self.header.refcount_table_offset;
+        let old_clusters = u64::from(self.header.refcount_table_clusters);
+
+        let new_entries = self.refcounts.ref_table().len() as u64 * 2;
+        if new_entries > MAX_RAM_POINTER_TABLE_SIZE {
+            error!("Refcount table can't grow to {} entries", new_entries);
+            return Err(std::io::Error::new(
+                io::ErrorKind::Other,
+                Error::RefcountTableTooLarge,
+            ));
+        }
+        if new_entries <= old_clusters * entries_per_cluster {
+            self.refcounts.grow_table(old_offset, new_entries);
+            return Ok(());
+        }
+
+        // The new table lies past the range covered by the old one, so its clusters are added
+        // directly and their refcounts set once the table has grown.
+        let new_clusters = div_round_up_u64(new_entries, entries_per_cluster);
+        let mut clusters = Vec::new();
+        for _ in 0..new_clusters {
+            match self.raw_file.add_cluster_end(u64::max_value())? {
+                Some(addr) => clusters.push(addr),
+                None => {
+                    return Err(std::io::Error::new(
+                        io::ErrorKind::Other,
+                        Error::NoFreeClusters,
+                    ))
+                }
+            }
+        }
+        let new_offset = clusters[0];
+        self.refcounts
+            .grow_table(new_offset, new_clusters * entries_per_cluster);
+        for addr in clusters {
+            let mut newly_unref = self.set_cluster_refcount(addr, 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        // The new table and the blocks it points to must be on disk before the header points at
+        // them.
+        self.sync_caches()?;
+
+        let mut fields = Vec::with_capacity(12);
+        fields.extend_from_slice(&new_offset.to_be_bytes());
+        fields.extend_from_slice(&(new_clusters as u32).to_be_bytes());
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(REFCOUNT_TABLE_OFFSET_OFFSET))?;
+        file.write_all(&fields)?;
+        file.sync_data()?;
+        self.header.refcount_table_offset = new_offset;
+        self.header.refcount_table_clusters = new_clusters as u32;
+
+        for i in 0..old_clusters {
+            self.decrement_refcount(old_offset + i * cluster_size)?;
+        }
+        Ok(())
+    }
+
+    fn cluster_refcount(&mut self, address: u64) -> std::io::Result<u16> {
+        self.refcounts
+            .get_cluster_refcount(&mut self.raw_file, address)
+            .map_err(|e| match e {
+                // Failures to read or write the file are passed on as they are.
+                refcount::Error::EvictingRefCounts(e) | refcount::Error::ReadingRefCounts(e) => e,
+                e => std::io::Error::new(io::ErrorKind::InvalidData, Error::GettingRefcount(e)),
+            })
+    }
+
+    // Fails if adding a reference to each of `clusters` would overflow a refcount.
+    fn check_refcount_headroom(&mut self, clusters: &[u64]) -> std::io::Result<()> {
+        let refcount_bits = 1u32 << self.header.refcount_order;
+        let max_refcount = ((1u32 << refcount_bits) - 1) as u16;
+        for &addr in clusters {
+            if self.cluster_refcount(addr)? >= max_refcount {
+                return Err(std::io::Error::new(
+                    io::ErrorKind::Other,
+                    Error::RefcountOverflow,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn increment_refcount(&mut self, address: u64) -> std::io::Result<()> {
+        let refcount = self.cluster_refcount(address)?;
+        let mut newly_unref = self.set_cluster_refcount(address, refcount + 1)?;
+        self.unref_clusters.append(&mut newly_unref);
+        Ok(())
+    }
+
+    // Drops a reference to the cluster at `address`, queueing it for reuse once unreferenced.
+    fn decrement_refcount(&mut self, address: u64) -> std::io::Result<()> {
+        let refcount = self.cluster_refcount(address)?;
+        if refcount == 0 {
+            return Err(std::io::Error::new(
+                io::ErrorKind::InvalidData,
+                Error::UnreferencedCluster(address),
+            ));
+        }
+        let mut newly_unref = self.set_cluster_refcount(address, refcount - 1)?;
+        self.unref_clusters.append(&mut newly_unref);
+        if refcount == 1 {
+            self.unref_clusters.push(address);
+        }
+        Ok(())
+    }
+
+    // Flags for non-zero L2 entries. Clusters that may be shared with a snapshot aren't marked as
+    // used by a single table.
+    fn l2_entry_flags(&self) -> u64 {
+        if self.snapshots.is_empty() {
+            CLUSTER_USED_FLAG
+        } else {
+            0
+        }
+    }
+
+    // Sets or clears the dirty bit in the on-disk header. The bit is set before lazily updated
+    // refcounts can reach the disk, so they are rebuilt if the image isn't closed cleanly.
+    fn set_header_dirty(&mut self, dirty: bool) -> std::io::Result<()> {
+        let features = if dirty {
+            self.header.incompatible_features | INCOMPATIBLE_FEATURES_DIRTY
+        } else {
+            self.header.incompatible_features & !INCOMPATIBLE_FEATURES_DIRTY
+        };
+        if features == self.header.incompatible_features {
+            return Ok(());
+        }
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(INCOMPATIBLE_FEATURES_OFFSET))?;
+        file.write_all(&features.to_be_bytes())?;
+        file.sync_data()?;
+        self.header.incompatible_features = features;
+        Ok(())
+    }
+
     // Set the refcount for a cluster with the given address.
     // Returns a list of any refblocks that can be reused, this happens when a refblock is moved,
     // the old location can be reused.
     fn set_cluster_refcount(&mut self, address: u64, refcount: u16) -> std::io::Result<Vec<u64>> {
+        self.set_cluster_refcount_range(address, 1, refcount)
+    }
+
+    // Sets the refcount of the `count` clusters starting at `address`. Each refcount block in the
+    // range is loaded, and copied if needed, once rather than once per cluster.
+    fn set_cluster_refcount_range(
+        &mut self,
+        address: u64,
+        count: u64,
+        refcount: u16,
+    ) -> std::io::Result<Vec<u64>> {
+        self.check_writable_io()?;
+        if self.lazy_refcounts {
+            self.set_header_dirty(true)?;
+        }
+        let cluster_size = self.raw_file.cluster_size();
         let mut added_clusters = Vec::new();
         let mut unref_clusters = Vec::new();
-        let mut refcount_set = false;
         let mut new_cluster = None;
+        let mut address = address;
+        let mut remaining = count;
 
-        while !refcount_set {
-            match self.refcounts.set_cluster_refcount(
+        while remaining > 0 {
+            match self.refcounts.set_cluster_refcount_range(
                 &mut self.raw_file,
                 address,
+                remaining,
                 refcount,
                 new_cluster.take(),
             ) {
-                Ok(None) => {
-                    refcount_set = true;
-                }
-                Ok(Some(freed_cluster)) => {
-                    unref_clusters.push(freed_cluster);
-                    refcount_set = true;
+                Ok((set_count, freed_cluster)) => {
+                    unref_clusters.extend(freed_cluster);
+                    address += set_count * cluster_size;
+                    remaining -= set_count;
                 }
                 Err(refcount::Error::EvictingRefCounts(e)) => {
                     return Err(e);
                 }
                 Err(refcount::Error::InvalidIndex) => {
-                    return Err(std::io::Error::from_raw_os_error(EINVAL));
+                    return Err(std::io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        Error::InvalidClusterIndex,
+                    ));
                 }
                 Err(refcount::Error::NeedCluster(addr)) => {
                     // Read the address and call set_cluster_refcount again.
@@ -1431,18 +2667,20 @@ impl QcowFile {
     }
 
     fn sync_caches(&mut self) -> std::io::Result<()> {
+        self.check_writable_io()?;
         // Write out all dirty L2 tables.
+        let l2_flags = self.l2_entry_flags();
         for (l1_index, l2_table) in self.l2_cache.iter_mut().filter(|(_k, v)| v.dirty()) {
             // The index must be valid from when we insterted it.
             let addr = self.l1_table[*l1_index];
             if addr != 0 {
-                self.raw_file.write_pointer_table(
-                    addr,
-                    l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
-                )?;
+                self.raw_file
+                    .write_pointer_table(addr, l2_table.get_values(), l2_flags)?;
             } else {
-                return Err(std::io::Error::from_raw_os_error(EINVAL));
+                return Err(std::io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    Error::InvalidIndex,
+                ));
             }
             l2_table.mark_clean();
         }
@@ -1467,6 +2705,10 @@ impl QcowFile {
         if sync_required {
             self.raw_file.file_mut().sync_data()?;
         }
+        // All refcounts are on disk, so the image is consistent again.
+        if self.lazy_refcounts {
+            self.set_header_dirty(false)?;
+        }
         Ok(())
     }
 
@@ -1483,15 +2725,21 @@ impl QcowFile {
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let location = self.file_offset_read(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
-            if let Some(offset) = file_offset {
-                cb(Some(self.raw_file.file_mut()), nread, offset, count)?;
-            } else if let Some(backing) = self.backing_file.as_mut() {
-                cb(Some(backing.as_mut()), nread, curr_addr, count)?;
-            } else {
-                cb(None, nread, 0, count)?;
+            match (location, self.backing_file.as_mut()) {
+                (ReadLocation::Offset(offset), _) => {
+                    let file = match self.data_file.as_mut() {
+                        Some(data_file) => data_file,
+                        None => self.raw_file.file_mut(),
+                    };
+                    cb(Some(file), nread, offset, count)?;
+                }
+                (ReadLocation::Unallocated, Some(backing)) => {
+                    cb(Some(backing.as_mut()), nread, curr_addr, count)?;
+                }
+                _ => cb(None, nread, 0, count)?,
             }
 
             nread += count;
@@ -1501,40 +2749,78 @@ impl QcowFile {
 
     // Writes `count` bytes starting at `address`, calling `cb` repeatedly with the backing file,
     // number of bytes written so far, and number of bytes to write to the file in that invocation.
+    // Clusters that are contiguous in the file are passed to `cb` as a single run of up to
+    // `MAX_COALESCED_WRITE` bytes, so a sequential write to freshly allocated clusters doesn't
+    // need a write per cluster.
     fn write_cb<F>(&mut self, address: u64, count: usize, mut cb: F) -> std::io::Result<usize>
     where
         F: FnMut(&mut File, usize, usize) -> std::io::Result<()>,
     {
+        self.check_writable_io()?;
         let write_count: usize = self.limit_range_file(address, count);
 
+        // The pending run as (file offset, bytes written before it, length).
+        let mut run: Option<(u64, usize, usize)> = None;
         let mut nwritten: usize = 0;
         while nwritten < write_count {
             let curr_addr = address + nwritten as u64;
             let offset = self.file_offset_write(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
-            if let Err(e) = self.raw_file.file_mut().seek(SeekFrom::Start(offset)) {
-                return Err(e);
-            }
-            if let Err(e) = cb(self.raw_file.file_mut(), nwritten, count) {
-                return Err(e);
-            }
+            run = match run {
+                Some((run_offset, run_start, run_len))
+                    if run_offset + run_len as u64 == offset
+                        && run_len + count <= MAX_COALESCED_WRITE =>
+                {
+                    Some((run_offset, run_start, run_len + count))
+                }
+                pending => {
+                    if let Some((run_offset, run_start, run_len)) = pending {
+                        self.write_run(run_offset, run_start, run_len, &mut cb)?;
+                    }
+                    Some((offset, nwritten, count))
+                }
+            };
 
             nwritten += count;
         }
+        if let Some((run_offset, run_start, run_len)) = run {
+            self.write_run(run_offset, run_start, run_len, &mut cb)?;
+        }
         Ok(write_count)
     }
+
+    // Seeks to `offset` and calls `cb` to write `count` bytes, starting `nwritten` bytes into the
+    // caller's data.
+    fn write_run<F>(
+        &mut self,
+        offset: u64,
+        nwritten: usize,
+        count: usize,
+        cb: &mut F,
+    ) -> std::io::Result<()>
+    where
+        F: FnMut(&mut File, usize, usize) -> std::io::Result<()>,
+    {
+        self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
+        cb(self.raw_file.file_mut(), nwritten, count)
+    }
 }
 
 impl Drop for QcowFile {
     fn drop(&mut self) {
-        let _ = self.sync_caches();
+        if self.check_writable().is_ok() {
+            let _ = self.sync_caches();
+        }
     }
 }
 
 impl AsRawDescriptors for QcowFile {
     fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
         let mut descriptors = vec![self.raw_file.file().as_raw_descriptor()];
+        if let Some(data_file) = &self.data_file {
+            descriptors.push(data_file.as_raw_descriptor());
+        }
         if let Some(backing) = &self.backing_file {
             descriptors.append(&mut backing.as_raw_descriptors());
         }
@@ -1593,7 +2879,10 @@ impl Seek for QcowFile {
                 return Ok(o);
             }
         }
-        Err(std::io::Error::from_raw_os_error(EINVAL))
+        Err(std::io::Error::new(
+            io::ErrorKind::InvalidInput,
+            Error::SeekingFile(std::io::Error::from_raw_os_error(EINVAL)),
+        ))
     }
 }
 
@@ -1608,7 +2897,10 @@ impl Write for QcowFile {
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.sync_caches()?;
+        self.sync().map_err(|e| match e {
+            Error::SyncingCaches(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, e),
+        })?;
         self.avail_clusters.append(&mut self.unref_clusters);
         Ok(())
     }
@@ -1677,7 +2969,7 @@ impl FileSetLen for QcowFile {
     fn set_len(&self, _len: u64) -> std::io::Result<()> {
         Err(std::io::Error::new(
             std::io::ErrorKind::Other,
-            "set_len() not supported for QcowFile",
+            Error::ResizeNotSupported,
         ))
     }
 }
@@ -1699,6 +2991,7 @@ impl FileAllocate for QcowFile {
 
 impl PunchHole for QcowFile {
     fn punch_hole(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        self.check_writable_io()?;
         let mut remaining = length;
         let mut offset = offset;
         while remaining > 0 {
@@ -1756,13 +3049,15 @@ fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     Ok(())
 }
 
-// Ceiling of the division of `dividend`/`divisor`.
-fn div_round_up_u64(dividend: u64, divisor: u64) -> u64 {
-    dividend / divisor + if dividend % divisor != 0 { 1 } else { 0 }
+// Returns true if `file` was opened without write access.
+fn file_is_read_only(file: &File) -> bool {
+    // Safe because this only reads the status flags of a valid descriptor.
+    let flags = unsafe { libc::fcntl(file.as_raw_descriptor(), libc::F_GETFL) };
+    flags >= 0 && flags & libc::O_ACCMODE == libc::O_RDONLY
 }
 
 // Ceiling of the division of `dividend`/`divisor`.
-fn div_round_up_u32(dividend: u32, divisor: u32) -> u32 {
+fn div_round_up_u64(dividend: u64, divisor: u64) -> u64 {
     dividend / divisor + if dividend % divisor != 0 { 1 } else { 0 }
 }
 
@@ -1845,6 +3140,61 @@ mod tests {
         testfn(qcow_file); // File closed when the function exits.
     }
 
+    #[test]
+    fn probe_header() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        QcowFile::new(file, 0x10_0000).unwrap();
+
+        let header = QcowHeader::probe(&path).unwrap();
+        assert_eq!(header.magic, QCOW_MAGIC);
+        assert_eq!(header.size, 0x10_0000);
+        assert_eq!(header.cluster_bits, DEFAULT_CLUSTER_BITS);
+
+        match QcowHeader::probe(&dir.path().join("missing.qcow2")) {
+            Err(Error::OpeningFile(_)) => (),
+            _ => panic!("probed a missing file"),
+        }
+    }
+
+    #[test]
+    fn error_display() {
+        use std::error::Error as _;
+
+        let err = Error::InvalidOffset(0x1200);
+        assert_eq!(err.to_string(), "invalid offset 0x1200");
+        assert!(err.source().is_none());
+
+        let err = Error::FileTooSmall {
+            expected: 4096,
+            actual: 512,
+        };
+        assert_eq!(
+            err.to_string(),
+            "file is 512 bytes but its tables extend to 4096 bytes; it may be truncated"
+        );
+
+        let io_err = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "short read");
+        let err = Error::ReadingHeader(io_err());
+        assert_eq!(err.to_string(), "failed to read header: short read");
+        assert_eq!(err.source().unwrap().to_string(), "short read");
+
+        // Refcount errors chain through to the io error that caused them.
+        let err = Error::GettingRefcount(refcount::Error::ReadingRefCounts(io_err()));
+        assert_eq!(
+            err.to_string(),
+            "failed to get refcount: failed to read the file into the refcount cache: short read"
+        );
+        let source = err.source().unwrap();
+        assert_eq!(source.source().unwrap().to_string(), "short read");
+    }
+
     #[test]
     fn default_header() {
         let header = QcowHeader::create_for_size_and_path(0x10_0000, None);
@@ -1857,6 +3207,32 @@ mod tests {
         QcowFile::from(disk_file).expect("Failed to create Qcow from default Header");
     }
 
+    #[test]
+    fn header_without_len() {
+        let header = QcowHeader::create_for_size_and_path(0x10_0000, None).unwrap();
+
+        let mut sized_file = tempfile().expect("failed to create tempfile");
+        header.write_to(&mut sized_file).unwrap();
+        assert_eq!(sized_file.metadata().unwrap().len(), header.metadata_len());
+
+        let mut lazy_file = tempfile().expect("failed to create tempfile");
+        header.write_header_to(&mut lazy_file).unwrap();
+        let header_len = lazy_file.metadata().unwrap().len();
+        assert!(header_len < header.metadata_len());
+        assert_eq!(header_len, lazy_file.seek(SeekFrom::Current(0)).unwrap());
+
+        lazy_file.set_len(header.metadata_len()).unwrap();
+        for mut file in vec![sized_file, lazy_file] {
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let mut q = QcowFile::from(file).expect("Failed to open image");
+            q.write_all(&[0x55; 512]).unwrap();
+            q.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = [0u8; 512];
+            q.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == 0x55));
+        }
+    }
+
     #[test]
     fn header_read() {
         with_basic_file(&valid_header(), |mut disk_file: File| {
@@ -1871,14 +3247,122 @@ mod tests {
         let mut disk_file = tempfile().expect("failed to create tempfile");
         header
             .write_to(&mut disk_file)
-            .expect("Failed to write header to shm.");
+            .expect("Failed to write header to shm.");
+        disk_file.seek(SeekFrom::Start(0)).unwrap();
+        let read_header = QcowHeader::new(&mut disk_file).expect("Failed to create header.");
+        assert_eq!(
+            header.backing_file_path,
+            Some(String::from("/my/path/to/a/file"))
+        );
+        assert_eq!(read_header.backing_file_path, header.backing_file_path);
+    }
+
+    #[test]
+    fn header_builder() {
+        let header = QcowHeaderBuilder::new()
+            .cluster_bits(12)
+            .refcount_order(3)
+            .backing_file("/my/path/to/a/file")
+            .size(0x100_0000)
+            .build()
+            .expect("Failed to build header.");
+        assert_eq!(header.cluster_bits, 12);
+        assert_eq!(header.refcount_order, 3);
+        // 4096 clusters need 8 L2 tables of 512 entries each.
+        assert_eq!(header.l1_size, 8);
+        assert_eq!(header.l1_table_offset, 0x1000);
+        assert_eq!(header.refcount_table_offset, 0x2000);
+
+        let mut disk_file = tempfile().expect("failed to create tempfile");
+        header
+            .write_to(&mut disk_file)
+            .expect("Failed to write header to file.");
         disk_file.seek(SeekFrom::Start(0)).unwrap();
-        let read_header = QcowHeader::new(&mut disk_file).expect("Failed to create header.");
+        let read_header = QcowHeader::new(&mut disk_file).expect("Failed to read header.");
+        assert_eq!(read_header.cluster_bits, 12);
+        assert_eq!(read_header.refcount_order, 3);
+        assert_eq!(read_header.backing_file_path, header.backing_file_path);
+
+        // The defaults match create_for_size_and_path.
+        let header = QcowHeaderBuilder::new().size(0x10_0000).build().unwrap();
+        let expected = QcowHeader::create_for_size_and_path(0x10_0000, None).unwrap();
+        assert_eq!(header.l1_size, expected.l1_size);
+        assert_eq!(header.refcount_table_offset, expected.refcount_table_offset);
         assert_eq!(
-            header.backing_file_path,
-            Some(String::from("/my/path/to/a/file"))
+            header.refcount_table_clusters,
+            expected.refcount_table_clusters
         );
-        assert_eq!(read_header.backing_file_path, header.backing_file_path);
+    }
+
+    #[test]
+    fn header_builder_small_clusters() {
+        let header = QcowHeaderBuilder::new()
+            .cluster_bits(MIN_CLUSTER_BITS)
+            .size(0x10_0000)
+            .build()
+            .unwrap();
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new_from_header(file, header).unwrap();
+        let data = [0x5au8; 0x1000];
+        q.write_all_at(&data, 0x8_0000).unwrap();
+        let mut buf = [0u8; 0x1000];
+        q.read_exact_at(&mut buf, 0x8_0000).unwrap();
+        assert_eq!(buf[..], data[..]);
+    }
+
+    #[test]
+    fn header_builder_invalid() {
+        fn build(f: impl FnOnce(&mut QcowHeaderBuilder)) -> Result<QcowHeader> {
+            let mut builder = QcowHeaderBuilder::new();
+            builder.size(0x10_0000);
+            f(&mut builder);
+            builder.build()
+        }
+
+        match build(|b| {
+            b.version(2);
+        }) {
+            Err(Error::UnsupportedVersion(2)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match build(|b| {
+            b.cluster_bits(MIN_CLUSTER_BITS - 1);
+        }) {
+            Err(Error::InvalidClusterSize) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match build(|b| {
+            b.cluster_bits(MAX_CLUSTER_BITS + 1);
+        }) {
+            Err(Error::InvalidClusterSize) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match build(|b| {
+            b.refcount_order(MAX_REFCOUNT_ORDER + 1);
+        }) {
+            Err(Error::UnsupportedRefcountOrder) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match build(|b| {
+            b.size(0);
+        }) {
+            Err(Error::ZeroSizeDisk) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match build(|b| {
+            b.size(MAX_QCOW_FILE_SIZE + 1);
+        }) {
+            Err(Error::FileTooBig(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // The backing file name must fit in the first cluster after the header.
+        let long_path = "a".repeat(512);
+        match build(|b| {
+            b.cluster_bits(MIN_CLUSTER_BITS).backing_file(&long_path);
+        }) {
+            Err(Error::BackingFileTooLong(_)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
     }
 
     #[test]
@@ -1892,12 +3376,70 @@ mod tests {
     #[test]
     fn invalid_refcount_order() {
         let mut header = valid_header();
-        header[99] = 2;
+        header[99] = 5;
         with_basic_file(&header, |disk_file: File| {
             QcowFile::from(disk_file).expect_err("Invalid refcount order worked.");
         });
     }
 
+    #[test]
+    fn one_bit_refcounts() {
+        let file = tempfile().expect("failed to create tempfile");
+        let reopen = file.try_clone().unwrap();
+        let mut header = QcowHeader::create_for_size_and_path(0x10_0000, None).unwrap();
+        header.refcount_order = 0;
+        let mut q = QcowFile::new_from_header(file, header).unwrap();
+        assert_eq!(q.refcounts.refcounts_per_block(), 65536 * 8);
+
+        // Writing allocates an L2 table and a data cluster, bumping their refcounts to one.
+        let used_before = q
+            .refcount_block(0)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .filter(|&&c| c != 0)
+            .count();
+        q.seek(SeekFrom::Start(0x8_0000)).unwrap();
+        q.write_all(&[0x5a; 512]).unwrap();
+        q.flush().unwrap();
+        let used_after = q
+            .refcount_block(0)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .filter(|&&c| c != 0)
+            .count();
+        assert_eq!(used_after, used_before + 2);
+        drop(q);
+
+        // Each cluster's refcount is a single bit, packed from the least significant bit.
+        let mut q = QcowFile::from(reopen).unwrap();
+        assert_eq!(q.header.refcount_order, 0);
+        let refblock_addr = q.ref_table()[0];
+        let mut first_byte = [0u8];
+        q.raw_file
+            .read_cluster(refblock_addr, &mut first_byte)
+            .unwrap();
+        assert_eq!(first_byte[0], (1u8 << used_after) - 1);
+        assert_eq!(q.first_zero_refcount().unwrap(), None);
+
+        let mut buf = [0u8; 512];
+        q.seek(SeekFrom::Start(0x8_0000)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x5a));
+    }
+
+    #[test]
+    fn unsupported_incompatible_features() {
+        let mut header = valid_header();
+        header[79] = 0x02;
+        with_basic_file(&header, |disk_file: File| match QcowFile::from(disk_file) {
+            Err(Error::UnsupportedIncompatibleFeatures(0x02)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("Unsupported incompatible feature worked."),
+        });
+    }
+
     #[test]
     fn invalid_cluster_bits() {
         let mut header = valid_header();
@@ -1964,6 +3506,40 @@ mod tests {
         });
     }
 
+    #[test]
+    fn multi_cluster_l1_table() {
+        // 8 TB needs 16384 L1 entries, which take two 64k clusters.
+        const SIZE: u64 = 0x800_0000_0000;
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file.try_clone().unwrap(), SIZE).unwrap();
+        assert_eq!(q.l1_table().len(), 0x4000);
+        let header = q.header().clone();
+        assert!(header.l1_table_offset + 0x4000 * 8 <= header.refcount_table_offset);
+
+        let data = [0x5au8; 0x1000];
+        q.write_all_at(&data, SIZE - 0x1000).unwrap();
+        q.write_all_at(&data, 0).unwrap();
+        q.flush().unwrap();
+        drop(q);
+
+        let mut q = QcowFile::from(file).unwrap();
+        let mut buf = [0u8; 0x1000];
+        q.read_exact_at(&mut buf, SIZE - 0x1000).unwrap();
+        assert_eq!(buf[..], data[..]);
+        q.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..], data[..]);
+    }
+
+    #[test]
+    fn l1_table_overlapping_refcount_table_rejected() {
+        let mut header = valid_header();
+        // Move the L1 table to the second refcount table cluster.
+        header[40..48].copy_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00]);
+        with_basic_file(&header, |disk_file: File| {
+            QcowFile::from(disk_file).expect_err("Created disk with overlapping tables");
+        });
+    }
+
     #[test]
     fn test_header_huge_num_refcounts() {
         let mut header = valid_header();
@@ -2045,6 +3621,47 @@ mod tests {
         });
     }
 
+    #[test]
+    fn volatile_write_read() {
+        with_basic_file(&valid_header(), |disk_file: File| {
+            let mut q = QcowFile::from(disk_file).unwrap();
+            // Straddle a cluster boundary so the transfer is split by limit_range_cluster.
+            let mut b: Vec<u8> = (0..0x1000).map(|i| i as u8).collect();
+            q.seek(SeekFrom::Start(0xff800)).expect("Failed to seek.");
+            let written = q
+                .write_volatile(VolatileSlice::new(&mut b))
+                .expect("Failed to write volatile.");
+            assert_eq!(written, b.len());
+
+            // Reading through the byte-buffer path sees the volatile write.
+            let mut buf = vec![0u8; 0x1000];
+            q.seek(SeekFrom::Start(0xff800)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, b);
+
+            // Reading through the volatile path matches the byte-buffer path.
+            let mut volatile_buf = vec![0u8; 0x1000];
+            q.seek(SeekFrom::Start(0xff800)).expect("Failed to seek.");
+            let read = q
+                .read_volatile(VolatileSlice::new(&mut volatile_buf))
+                .expect("Failed to read volatile.");
+            assert_eq!(read, volatile_buf.len());
+            assert_eq!(volatile_buf, buf);
+            assert_eq!(seek_cur(&mut q), 0x100800);
+        });
+    }
+
+    #[test]
+    fn volatile_read_unallocated() {
+        with_basic_file(&valid_header(), |disk_file: File| {
+            let mut q = QcowFile::from(disk_file).unwrap();
+            let mut buf = [0x55u8; 0x100];
+            q.read_at_volatile(VolatileSlice::new(&mut buf), 0x2000)
+                .expect("Failed to read volatile.");
+            assert!(buf.iter().all(|&b| b == 0));
+        });
+    }
+
     #[test]
     fn write_zeroes_read() {
         with_basic_file(&valid_header(), |disk_file: File| {
@@ -2520,6 +4137,99 @@ mod tests {
         });
     }
 
+    #[test]
+    fn write_coalesced_clusters() {
+        with_default_file(0x400_0000, |mut q| {
+            // Allocate a cluster out of order so the long write below covers both contiguous and
+            // non-contiguous runs.
+            q.write_all_at(&[0x11u8; 0x1000], 0x8_0000).unwrap();
+
+            let data: Vec<u8> = (0..0x18_0000 + 0x123).map(|i| (i % 251) as u8).collect();
+            q.write_all_at(&data, 0x1_0100).unwrap();
+
+            let mut readback = vec![0u8; data.len()];
+            q.read_exact_at(&mut readback, 0x1_0100).unwrap();
+            assert!(readback == data);
+
+            // Bytes on either side of the write are untouched.
+            let mut buf = [0xffu8; 0x100];
+            q.read_exact_at(&mut buf, 0x1_0000).unwrap();
+            assert_eq!(buf, [0u8; 0x100]);
+            let end = 0x1_0100 + data.len() as u64;
+            q.read_exact_at(&mut buf, end).unwrap();
+            assert_eq!(buf, [0u8; 0x100]);
+        });
+    }
+
+    // Returns the offset in the image that the guest cluster at `address` is read from, which must
+    // be allocated.
+    fn allocated_offset(q: &mut QcowFile, address: u64) -> u64 {
+        match q.file_offset_read(address).unwrap() {
+            ReadLocation::Offset(offset) => offset,
+            location => panic!("cluster at {:#x} is not allocated: {:?}", address, location),
+        }
+    }
+
+    #[test]
+    fn sync_keeps_unref_clusters() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+        q.write_all_at(&[0x11u8; 0x1_0000], 0).unwrap();
+        q.write_all_at(&[0x22u8; 0x1000], 0x2_0000).unwrap();
+        q.flush().unwrap();
+        let freed = allocated_offset(&mut q, 0);
+        q.punch_hole(0, 0x1_0000).unwrap();
+
+        q.sync().unwrap();
+        assert!(q.unref_clusters.contains(&freed));
+        assert!(!q.avail_clusters.contains(&freed));
+
+        // The synced state is visible to another handle on the file.
+        let mut reopened = QcowFile::from(file).unwrap();
+        assert!(!reopened.is_cluster_allocated(0).unwrap());
+        let mut buf = [0u8; 0x1000];
+        reopened.read_exact_at(&mut buf, 0x2_0000).unwrap();
+        assert_eq!(buf, [0x22u8; 0x1000]);
+        drop(reopened);
+
+        q.flush().unwrap();
+        assert!(q.unref_clusters.is_empty());
+        assert!(q.avail_clusters.contains(&freed));
+    }
+
+    #[test]
+    fn set_cluster_refcount_range_matches_single() {
+        // Start a few clusters before the end of the first refcount block so the range spans two
+        // blocks.
+        const START: u64 = (0x8000 - 4) * 0x1_0000;
+        const COUNT: u64 = 8;
+        let range_file = tempfile().expect("failed to create tempfile");
+        let single_file = tempfile().expect("failed to create tempfile");
+        let mut range_q = QcowFile::new(range_file.try_clone().unwrap(), 1 << 40).unwrap();
+        let mut single_q = QcowFile::new(single_file.try_clone().unwrap(), 1 << 40).unwrap();
+
+        range_q.set_cluster_refcount_range(START, COUNT, 1).unwrap();
+        for i in 0..COUNT {
+            single_q
+                .set_cluster_refcount(START + i * 0x1_0000, 1)
+                .unwrap();
+        }
+        range_q.flush().unwrap();
+        single_q.flush().unwrap();
+        drop(range_q);
+        drop(single_q);
+
+        let mut range_q = QcowFile::from(range_file).unwrap();
+        let mut single_q = QcowFile::from(single_file).unwrap();
+        assert_eq!(range_q.ref_table(), single_q.ref_table());
+        for index in 0..2 {
+            let range_block = range_q.refcount_block(index).unwrap().unwrap().to_vec();
+            let single_block = single_q.refcount_block(index).unwrap().unwrap().to_vec();
+            assert_eq!(range_block, single_block);
+        }
+        assert_eq!(range_q.refcount_block(1).unwrap().unwrap()[..4], [1u16; 4]);
+    }
+
     #[test]
     fn combo_write_read() {
         with_default_file(1024 * 1024 * 1024 * 256, |mut qcow_file| {
@@ -2680,6 +4390,752 @@ mod tests {
         });
     }
 
+    #[test]
+    fn prealloc_modes() {
+        let virtual_size = 4 * 1024 * 1024;
+        let cluster_size = 65536;
+        let num_clusters = (virtual_size / cluster_size) as usize;
+
+        // No mode leaves unreferenced clusters, so the file length counts the allocated clusters.
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new_with_prealloc(file, virtual_size, PreallocMode::Off).unwrap();
+        assert_eq!(q.l1_table()[0], 0);
+        assert_eq!(q.first_zero_refcount().unwrap(), None);
+        let off_len = q.raw_file.file().metadata().unwrap().len();
+
+        // Metadata allocates the only L2 table.
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q =
+            QcowFile::new_with_prealloc(file, virtual_size, PreallocMode::Metadata).unwrap();
+        assert_ne!(q.l1_table()[0], 0);
+        assert_eq!(q.first_zero_refcount().unwrap(), None);
+        let l2_table = q.l2_table(0).unwrap().unwrap();
+        assert!(l2_table.iter().all(|&addr| addr == 0));
+        assert_eq!(
+            q.raw_file.file().metadata().unwrap().len(),
+            off_len + cluster_size
+        );
+
+        // Full allocates the L2 table and every data cluster.
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new_with_prealloc(file, virtual_size, PreallocMode::Full).unwrap();
+        assert_ne!(q.l1_table()[0], 0);
+        assert_eq!(q.first_zero_refcount().unwrap(), None);
+        let l2_table = q.l2_table(0).unwrap().unwrap();
+        assert!(l2_table[..num_clusters].iter().all(|&addr| addr != 0));
+        assert!(l2_table[num_clusters..].iter().all(|&addr| addr == 0));
+        let file_len = q.raw_file.file().metadata().unwrap().len();
+        assert_eq!(file_len, off_len + (1 + num_clusters as u64) * cluster_size);
+
+        // Writing to a fully preallocated file doesn't need to grow it.
+        q.seek(SeekFrom::Start(virtual_size - 1)).unwrap();
+        q.write_all(&[0xaa]).unwrap();
+        assert_eq!(q.raw_file.file().metadata().unwrap().len(), file_len);
+    }
+
+    #[test]
+    fn export_raw_scattered_clusters() {
+        let cluster_size = 65536;
+        // Three full clusters followed by a partial one.
+        let virtual_size = 3 * cluster_size + 0x800;
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file, virtual_size).unwrap();
+
+        let mut expected = vec![0u8; virtual_size as usize];
+        for &(offset, len, value) in &[
+            (100u64, 200usize, 0x11u8),
+            (2 * cluster_size, cluster_size as usize, 0x22),
+            (3 * cluster_size + 0x700, 0x100, 0x33),
+        ] {
+            q.seek(SeekFrom::Start(offset)).unwrap();
+            q.write_all(&vec![value; len]).unwrap();
+            for b in &mut expected[offset as usize..offset as usize + len] {
+                *b = value;
+            }
+        }
+
+        let mut raw = Vec::new();
+        q.export_raw(&mut raw).unwrap();
+        assert_eq!(raw.len(), virtual_size as usize);
+        assert!(raw == expected);
+    }
+
+    #[test]
+    fn export_raw_unallocated() {
+        let virtual_size = 0x100_0200;
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file, virtual_size).unwrap();
+
+        struct ZeroCounter(u64);
+        impl Write for ZeroCounter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                assert!(buf.iter().all(|&b| b == 0));
+                self.0 += buf.len() as u64;
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut out = ZeroCounter(0);
+        q.export_raw(&mut out).unwrap();
+        assert_eq!(out.0, virtual_size);
+    }
+
+    #[test]
+    fn l2_entry_past_eof() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut disk_file = file.try_clone().unwrap();
+        let cluster_size = 65536;
+        let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+        q.write_all(&[0x55; 512]).unwrap();
+        q.flush().unwrap();
+        let l2_addr = q.l1_table()[0];
+        drop(q);
+
+        // Point the L2 entry for the second cluster well past the end of the file.
+        let bogus_addr = 0x100_0000_0000u64;
+        disk_file.seek(SeekFrom::Start(l2_addr + 8)).unwrap();
+        disk_file
+            .write_all(&(bogus_addr | CLUSTER_USED_FLAG).to_be_bytes())
+            .unwrap();
+
+        let mut q = QcowFile::from(disk_file).unwrap();
+        let mut buf = [0u8; 512];
+        q.seek(SeekFrom::Start(cluster_size)).unwrap();
+        let err = q.read_exact(&mut buf).expect_err("read past EOF succeeded");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!crate::is_host_io_error(&err));
+        q.seek(SeekFrom::Start(cluster_size)).unwrap();
+        q.write_all(&buf).expect_err("write past EOF succeeded");
+
+        // The valid cluster is still readable.
+        q.seek(SeekFrom::Start(0)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn unaligned_l2_address_not_written() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut disk_file = file.try_clone().unwrap();
+        let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+        q.write_all(&[0x55; 512]).unwrap();
+        q.flush().unwrap();
+        let l1_table_offset = q.header.l1_table_offset;
+        let l2_addr = q.l1_table()[0];
+        drop(q);
+
+        let mut l2_before = [0u8; 64];
+        disk_file.seek(SeekFrom::Start(l2_addr)).unwrap();
+        disk_file.read_exact(&mut l2_before).unwrap();
+
+        // Point the first L1 entry into the middle of its L2 cluster. The offset survives
+        // `L1_TABLE_OFFSET_MASK`, which only clears the low 9 bits.
+        disk_file.seek(SeekFrom::Start(l1_table_offset)).unwrap();
+        disk_file
+            .write_all(&((l2_addr + 512) | CLUSTER_USED_FLAG).to_be_bytes())
+            .unwrap();
+
+        let mut q = QcowFile::from(disk_file.try_clone().unwrap()).unwrap();
+        q.write_all(&[0xaa; 512]).unwrap();
+        let err = q.flush().expect_err("unaligned L2 table written");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!crate::is_host_io_error(&err));
+        drop(q);
+
+        let mut l2_after = [0u8; 64];
+        disk_file.seek(SeekFrom::Start(l2_addr)).unwrap();
+        disk_file.read_exact(&mut l2_after).unwrap();
+        assert_eq!(l2_before, l2_after);
+    }
+
+    #[test]
+    fn read_write_at_cross_cluster() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        // Straddles the boundary between the first and second clusters.
+        let offset = 65536 - 512;
+        q.write_all_at(&data, offset).unwrap();
+
+        let mut buf = vec![0u8; 1024];
+        q.read_exact_at(&mut buf, offset).unwrap();
+        assert_eq!(buf, data);
+        // The current offset used by `Read` and `Write` is untouched.
+        assert_eq!(seek_cur(&mut q), 0);
+    }
+
+    #[test]
+    fn read_write_at_keeps_offset() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+        q.seek(SeekFrom::Start(0x1234)).unwrap();
+
+        assert_eq!(q.write_at(&[0x66u8; 0x3000], 0x1_f000).unwrap(), 0x3000);
+        assert_eq!(seek_cur(&mut q), 0x1234);
+
+        let mut buf = [0u8; 0x3000];
+        assert_eq!(q.read_at(&mut buf, 0x1_f000).unwrap(), 0x3000);
+        assert!(buf.iter().all(|&b| b == 0x66));
+        assert_eq!(seek_cur(&mut q), 0x1234);
+
+        // Transfers are cut short at the virtual size instead of failing.
+        assert_eq!(q.read_at(&mut buf, 0x10_0000 - 0x100).unwrap(), 0x100);
+        assert_eq!(q.write_at(&buf, 0x10_0000 - 0x200).unwrap(), 0x200);
+        assert_eq!(q.read_at(&mut buf, 0x10_0000).unwrap(), 0);
+        assert_eq!(seek_cur(&mut q), 0x1234);
+    }
+
+    #[test]
+    fn read_write_at_out_of_range() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+        let mut buf = [0u8; 512];
+
+        let err = q.read_exact_at(&mut buf, 0x10_0000 - 256).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        q.read_exact_at(&mut buf, u64::MAX).unwrap_err();
+        q.write_all_at(&buf, 0x10_0000).unwrap_err();
+
+        // Nothing was written by the failed calls.
+        assert_eq!(q.first_zero_refcount().unwrap(), None);
+        assert_eq!(q.l1_table()[0], 0);
+    }
+
+    #[test]
+    fn snapshot_keeps_old_data() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+        q.write_all_at(&[0x11u8; 0x20000], 0).unwrap();
+
+        q.create_snapshot("snap").unwrap();
+        assert_eq!(q.snapshots().len(), 1);
+        assert_eq!(q.snapshots()[0].name, "snap");
+        assert_eq!(q.snapshots()[0].disk_size(), Some(0x10_0000));
+
+        // Overwriting a shared cluster leaves the snapshot's copy intact.
+        q.write_all_at(&[0x22u8; 512], 0x100).unwrap();
+        let mut buf = [0u8; 512];
+        q.read_exact_at(&mut buf, 0x100).unwrap();
+        assert_eq!(buf, [0x22u8; 512]);
+
+        q.apply_snapshot("snap").unwrap();
+        q.read_exact_at(&mut buf, 0x100).unwrap();
+        assert_eq!(buf, [0x11u8; 512]);
+        q.read_exact_at(&mut buf, 0x10000).unwrap();
+        assert_eq!(buf, [0x11u8; 512]);
+    }
+
+    #[test]
+    fn refcount_table_grows() {
+        // With 512 byte clusters each refcount block covers 128KB, so copying the disk for a
+        // snapshot runs past the space covered by the initial one cluster refcount table.
+        const DISK_SIZE: u64 = 0x40_0000;
+        let mut header = QcowHeader::create_for_size_and_path(DISK_SIZE, None).unwrap();
+        header.cluster_bits = 9;
+        header.l1_size = (DISK_SIZE / 512 / 64) as u32;
+        header.l1_table_offset = 512;
+        header.refcount_table_offset = 512 * 3;
+        header.refcount_table_clusters = 1;
+
+        let file = tempfile().expect("failed to create tempfile");
+        {
+            let mut q = QcowFile::new_from_header(file.try_clone().unwrap(), header).unwrap();
+            q.write_all_at(&vec![0x11u8; DISK_SIZE as usize], 0)
+                .unwrap();
+            q.create_snapshot("snap").unwrap();
+            q.write_all_at(&vec![0x22u8; DISK_SIZE as usize], 0)
+                .unwrap();
+            assert_eq!(q.header().refcount_table_clusters, 2);
+            assert_ne!(q.header().refcount_table_offset, 512 * 3);
+        }
+
+        let mut q = QcowFile::from(file).unwrap();
+        assert_eq!(q.header().refcount_table_clusters, 2);
+        let mut buf = vec![0u8; DISK_SIZE as usize];
+        q.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x22));
+
+        q.apply_snapshot("snap").unwrap();
+        q.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x11));
+    }
+
+    #[test]
+    fn refcount_table_smaller_than_file() {
+        // With 512 byte clusters one refcount table cluster covers 8MB, less than the disk size.
+        const DISK_SIZE: u64 = 0x100_0000;
+        let mut header = QcowHeader::create_for_size_and_path(DISK_SIZE, None).unwrap();
+        header.cluster_bits = 9;
+        header.l1_size = (DISK_SIZE / 512 / 64) as u32;
+        header.l1_table_offset = 0x200;
+        header.refcount_table_offset = 0x1200;
+        header.refcount_table_clusters = 1;
+
+        let mut file = tempfile().expect("failed to create tempfile");
+        header.write_to(&mut file).unwrap();
+        // Point the table at a refblock that marks the header cluster as used.
+        file.seek(SeekFrom::Start(0x1200)).unwrap();
+        file.write_all(&0x1400u64.to_be_bytes()).unwrap();
+        file.seek(SeekFrom::Start(0x1400)).unwrap();
+        file.write_all(&1u16.to_be_bytes()).unwrap();
+        // Clusters past 8MB aren't covered by the table.
+        file.set_len(0x90_0000).unwrap();
+
+        match QcowFile::from(file) {
+            Err(Error::GettingRefcount(refcount::Error::InvalidIndex)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("opened a file not covered by its refcount table"),
+        }
+    }
+
+    #[test]
+    fn lazy_refcounts_dirty_bit() {
+        let mut file = tempfile().expect("failed to create tempfile");
+        {
+            let mut q =
+                QcowFile::new_with_lazy_refcounts(file.try_clone().unwrap(), 0x10_0000).unwrap();
+            assert_eq!(
+                q.header().incompatible_features & INCOMPATIBLE_FEATURES_DIRTY,
+                0
+            );
+            let header = QcowHeader::new(&mut file).unwrap();
+            assert_ne!(
+                header.compatible_features & COMPATIBLE_FEATURES_LAZY_REFCOUNTS,
+                0
+            );
+
+            q.write_all_at(&[0x11u8; 0x2_0000], 0).unwrap();
+            assert_ne!(
+                q.header().incompatible_features & INCOMPATIBLE_FEATURES_DIRTY,
+                0
+            );
+            let header = QcowHeader::new(&mut file).unwrap();
+            assert_ne!(
+                header.incompatible_features & INCOMPATIBLE_FEATURES_DIRTY,
+                0
+            );
+
+            q.sync_caches().unwrap();
+            assert_eq!(
+                q.header().incompatible_features & INCOMPATIBLE_FEATURES_DIRTY,
+                0
+            );
+            let header = QcowHeader::new(&mut file).unwrap();
+            assert_eq!(
+                header.incompatible_features & INCOMPATIBLE_FEATURES_DIRTY,
+                0
+            );
+        }
+
+        // The image keeps updating refcounts lazily when it is reopened.
+        let mut q = QcowFile::from(file).unwrap();
+        assert!(q.lazy_refcounts);
+        let mut buf = [0u8; 512];
+        q.read_exact_at(&mut buf, 0x1_0000).unwrap();
+        assert_eq!(buf, [0x11u8; 512]);
+    }
+
+    #[test]
+    fn truncated_file() {
+        let file = tempfile().expect("failed to create tempfile");
+        let refcount_table_end = {
+            let q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+            let header = q.header();
+            header.refcount_table_offset
+                + u64::from(header.refcount_table_clusters) * q.raw_file.cluster_size()
+        };
+        file.set_len(refcount_table_end - 0x100).unwrap();
+        match QcowFile::from(file) {
+            Err(Error::FileTooSmall { expected, actual }) => {
+                assert_eq!(expected, refcount_table_end);
+                assert_eq!(actual, refcount_table_end - 0x100);
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("opened a truncated file"),
+        }
+    }
+
+    #[test]
+    fn read_only_file() {
+        let file = tempfile().expect("failed to create tempfile");
+        let path = format!("/proc/self/fd/{}", file.as_raw_descriptor());
+        let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+        q.write_all_at(&[0x55u8; 0x1000], 0x2000).unwrap();
+        q.flush().unwrap();
+        let file_len = q.raw_file.file().metadata().unwrap().len();
+
+        let read_only = OpenOptions::new().read(true).open(&path).unwrap();
+        let mut ro = QcowFile::from(read_only).unwrap();
+        let mut buf = [0u8; 0x1000];
+        ro.read_exact_at(&mut buf, 0x2000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+
+        let kind = |r: std::io::Result<()>| r.unwrap_err().kind();
+        assert_eq!(
+            kind(ro.write_all_at(&[0x66u8; 0x1000], 0x2000)),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            kind(ro.write_all_at(&[0x66u8; 0x1000], 0x2_0000)),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            kind(ro.punch_hole(0x2000, 0x1000)),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(kind(ro.flush()), std::io::ErrorKind::PermissionDenied);
+        match ro.create_snapshot("snap") {
+            Err(Error::ReadOnly) => {}
+            r => panic!("expected ReadOnly, got {:?}", r),
+        }
+        drop(ro);
+
+        // The failed writes left the image untouched.
+        assert_eq!(q.raw_file.file().metadata().unwrap().len(), file_len);
+        q.read_exact_at(&mut buf, 0x2000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+        assert!(!q.is_cluster_allocated(0x2_0000).unwrap());
+    }
+
+    #[test]
+    fn external_data_file() {
+        let mut file = tempfile().expect("failed to create tempfile");
+        let mut data_file = tempfile().expect("failed to create tempfile");
+        let data_path = format!("/proc/self/fd/{}", data_file.as_raw_descriptor());
+
+        // Allocate clusters in a regular image so its L2 tables can be reused as the mapping into
+        // the data file.
+        let (header_size, offsets) = {
+            let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+            q.write_all_at(&[0x11u8; 0x1000], 0x1000).unwrap();
+            q.write_all_at(&[0x22u8; 0x1000], 0x3_0000).unwrap();
+            q.flush().unwrap();
+            let offsets = [
+                allocated_offset(&mut q, 0x1000),
+                allocated_offset(&mut q, 0x3_0000),
+            ];
+            (q.header().header_size, offsets)
+        };
+        data_file.seek(SeekFrom::Start(offsets[0])).unwrap();
+        data_file.write_all(&[0xaau8; 0x1000]).unwrap();
+        data_file.seek(SeekFrom::Start(offsets[1])).unwrap();
+        data_file.write_all(&[0xbbu8; 0x1000]).unwrap();
+
+        // Point the image at the data file.
+        let mut extension = Vec::new();
+        extension.extend_from_slice(&HEADER_EXT_EXTERNAL_DATA_FILE.to_be_bytes());
+        extension.extend_from_slice(&(data_path.len() as u32).to_be_bytes());
+        extension.extend_from_slice(data_path.as_bytes());
+        extension.resize((extension.len() + 7) / 8 * 8 + 8, 0);
+        file.seek(SeekFrom::Start(u64::from(header_size))).unwrap();
+        file.write_all(&extension).unwrap();
+        file.seek(SeekFrom::Start(INCOMPATIBLE_FEATURES_OFFSET))
+            .unwrap();
+        file.write_all(&INCOMPATIBLE_FEATURES_EXTERNAL_DATA_FILE.to_be_bytes())
+            .unwrap();
+        let file_len = file.metadata().unwrap().len();
+
+        let mut q = QcowFile::from(file.try_clone().unwrap()).unwrap();
+        let mut buf = [0u8; 0x1000];
+        q.read_exact_at(&mut buf, 0x1000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xaa));
+        q.read_exact_at(&mut buf, 0x3_0000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xbb));
+        q.read_exact_at(&mut buf, 0x8_0000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+
+        assert_eq!(
+            q.write_all_at(&[0x33u8; 0x1000], 0x1000)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        match q.create_snapshot("snap") {
+            Err(Error::ExternalDataFileNotWritable) => {}
+            r => panic!("expected ExternalDataFileNotWritable, got {:?}", r),
+        }
+        drop(q);
+        assert_eq!(file.metadata().unwrap().len(), file_len);
+    }
+
+    #[test]
+    fn header_extensions() {
+        let mut header = valid_header();
+        header.extend_from_slice(&[
+            0xe2, 0x79, 0x2a, 0xca, // backing format extension
+            0x00, 0x00, 0x00, 0x05, // length
+            b'q', b'c', b'o', b'w', b'2', 0x00, 0x00, 0x00, // "qcow2" and padding
+            0x12, 0x34, 0x56, 0x78, // unknown extension
+            0x00, 0x00, 0x00, 0x08, // length
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // data
+            0x00, 0x00, 0x00, 0x00, // end of extensions
+            0x00, 0x00, 0x00, 0x00, // length
+        ]);
+        with_basic_file(&header, |disk_file: File| {
+            let mut q = QcowFile::from(disk_file).unwrap();
+            let extensions = q.header_extensions().unwrap();
+            assert_eq!(
+                extensions,
+                vec![
+                    HeaderExtension {
+                        ext_type: HEADER_EXT_BACKING_FORMAT,
+                        data: b"qcow2".to_vec(),
+                    },
+                    HeaderExtension {
+                        ext_type: 0x1234_5678,
+                        data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                    },
+                ]
+            );
+        });
+
+        // A default file only has the end marker.
+        with_default_file(0x10_0000, |mut q| {
+            assert!(q.header_extensions().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn header_extension_too_long() {
+        let mut header = valid_header();
+        header.extend_from_slice(&[
+            0xe2, 0x79, 0x2a, 0xca, // backing format extension
+            0x00, 0x01, 0x00, 0x00, // length past the first cluster
+        ]);
+        with_basic_file(&header, |disk_file: File| {
+            let mut q = QcowFile::from(disk_file).unwrap();
+            match q.header_extensions() {
+                Err(Error::InvalidHeaderExtension(HEADER_EXT_BACKING_FORMAT)) => (),
+                _ => panic!("oversized header extension accepted"),
+            }
+        });
+    }
+
+    #[test]
+    fn is_cluster_allocated() {
+        let mut file = tempfile().expect("failed to create tempfile");
+        {
+            let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+            q.write_all_at(&[0x11u8; 512], 0).unwrap();
+            q.write_all_at(&[0x22u8; 512], 0x2_0000).unwrap();
+            assert!(q.is_cluster_allocated(0).unwrap());
+            assert!(q.is_cluster_allocated(0xffff).unwrap());
+            assert!(!q.is_cluster_allocated(0x1_0000).unwrap());
+            assert!(q.is_cluster_allocated(0x2_0000).unwrap());
+            match q.is_cluster_allocated(0x10_0000) {
+                Err(Error::InvalidOffset(0x10_0000)) => (),
+                _ => panic!("address past the end of the disk accepted"),
+            }
+        }
+
+        // Flag the third cluster as reading all zeroes, keeping its host cluster.
+        let l2_addr = {
+            let q = QcowFile::from(file.try_clone().unwrap()).unwrap();
+            q.l1_table()[0]
+        };
+        let entry_offset = l2_addr + 2 * size_of::<u64>() as u64;
+        file.seek(SeekFrom::Start(entry_offset)).unwrap();
+        let entry = read_u64_from_file(&file).unwrap();
+        assert_ne!(entry & L2_TABLE_OFFSET_MASK, 0);
+        file.seek(SeekFrom::Start(entry_offset)).unwrap();
+        file.write_all(&(entry | ZERO_FLAG).to_be_bytes()).unwrap();
+
+        let mut q = QcowFile::from(file).unwrap();
+        assert!(q.is_cluster_allocated(0).unwrap());
+        assert!(!q.is_cluster_allocated(0x2_0000).unwrap());
+        let mut buf = [0xffu8; 512];
+        q.read_exact_at(&mut buf, 0x2_0000).unwrap();
+        assert_eq!(buf, [0u8; 512]);
+    }
+
+    #[test]
+    fn zero_flag_with_backing() {
+        // Returns the raw L2 entry for `cluster` of the image in `file`.
+        fn l2_entry(file: &File, cluster: u64) -> u64 {
+            let l2_addr = {
+                let q = QcowFile::from(file.try_clone().unwrap()).unwrap();
+                q.l1_table()[0]
+            };
+            let mut f = file.try_clone().unwrap();
+            f.seek(SeekFrom::Start(l2_addr + cluster * size_of::<u64>() as u64))
+                .unwrap();
+            read_u64_from_file(&f).unwrap()
+        }
+
+        fn set_l2_entry(file: &File, cluster: u64, entry: u64) {
+            let l2_addr = {
+                let q = QcowFile::from(file.try_clone().unwrap()).unwrap();
+                q.l1_table()[0]
+            };
+            let mut f = file.try_clone().unwrap();
+            f.seek(SeekFrom::Start(l2_addr + cluster * size_of::<u64>() as u64))
+                .unwrap();
+            f.write_all(&entry.to_be_bytes()).unwrap();
+        }
+
+        fn with_backing(file: &File) -> QcowFile {
+            let mut backing = QcowFile::new(tempfile().unwrap(), 0x10_0000).unwrap();
+            backing.write_all_at(&[0x77u8; 0x4_0000], 0).unwrap();
+            let mut q = QcowFile::from(file.try_clone().unwrap()).unwrap();
+            q.set_backing_file(Some(Box::new(backing)));
+            q
+        }
+
+        let file = tempfile().expect("failed to create tempfile");
+        {
+            let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+            q.write_all_at(&[0x22u8; 0x2_0000], 0x1_0000).unwrap();
+        }
+        // Flag the second cluster as reading zeroes while keeping its host cluster, and the fourth
+        // without one.
+        let host_addr = l2_entry(&file, 1) & L2_TABLE_OFFSET_MASK;
+        assert_ne!(host_addr, 0);
+        set_l2_entry(&file, 1, host_addr | ZERO_FLAG);
+        set_l2_entry(&file, 3, ZERO_FLAG);
+
+        {
+            let mut q = with_backing(&file);
+            let mut buf = vec![0xffu8; 0x4_0000];
+            q.read_exact_at(&mut buf, 0).unwrap();
+            assert!(buf[..0x1_0000].iter().all(|&b| b == 0x77));
+            assert!(buf[0x1_0000..0x2_0000].iter().all(|&b| b == 0));
+            assert!(buf[0x2_0000..0x3_0000].iter().all(|&b| b == 0x22));
+            assert!(buf[0x3_0000..].iter().all(|&b| b == 0));
+            assert!(!q.is_cluster_allocated(0x1_0000).unwrap());
+            assert!(!q.is_cluster_allocated(0x3_0000).unwrap());
+            assert_eq!(q.allocated_ranges().unwrap(), vec![(0x2_0000, 0x1_0000)]);
+
+            // Dirty the L2 table so it is written back when the file is closed.
+            q.write_all_at(&[0x33u8; 512], 0).unwrap();
+        }
+        assert_eq!(
+            l2_entry(&file, 1) & !CLUSTER_USED_FLAG,
+            host_addr | ZERO_FLAG
+        );
+        assert_eq!(l2_entry(&file, 3), ZERO_FLAG);
+
+        {
+            // Writes to the flagged clusters fill the rest of the cluster with zeroes, not with
+            // the backing file, and reuse the host cluster when there is one.
+            let mut q = with_backing(&file);
+            q.write_all_at(&[0x44u8; 512], 0x1_0200).unwrap();
+            q.write_all_at(&[0x55u8; 512], 0x3_0200).unwrap();
+            let mut buf = vec![0xffu8; 0x1_0000];
+            q.read_exact_at(&mut buf, 0x1_0000).unwrap();
+            assert!(buf[..0x200].iter().all(|&b| b == 0));
+            assert!(buf[0x200..0x400].iter().all(|&b| b == 0x44));
+            assert!(buf[0x400..].iter().all(|&b| b == 0));
+            q.read_exact_at(&mut buf, 0x3_0000).unwrap();
+            assert!(buf[..0x200].iter().all(|&b| b == 0));
+            assert!(buf[0x200..0x400].iter().all(|&b| b == 0x55));
+            assert!(buf[0x400..].iter().all(|&b| b == 0));
+        }
+        assert_eq!(l2_entry(&file, 1) & !CLUSTER_USED_FLAG, host_addr);
+        assert_eq!(l2_entry(&file, 3) & ZERO_FLAG, 0);
+        assert_ne!(l2_entry(&file, 3) & L2_TABLE_OFFSET_MASK, 0);
+    }
+
+    #[test]
+    fn allocated_ranges() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+        assert_eq!(q.allocated_ranges().unwrap(), vec![]);
+
+        // Clusters 1 and 2 are adjacent, clusters 5 and 15 stand alone.
+        q.write_all_at(&[0x11u8; 0x100], 0x1_ff00).unwrap();
+        q.write_all_at(&[0x22u8; 0x100], 0x2_0000).unwrap();
+        q.write_all_at(&[0x33u8; 0x10], 0x5_8000).unwrap();
+        q.write_all_at(&[0x44u8; 0x10], 0xf_fff0).unwrap();
+        assert_eq!(
+            q.allocated_ranges().unwrap(),
+            vec![
+                (0x1_0000, 0x2_0000),
+                (0x5_0000, 0x1_0000),
+                (0xf_0000, 0x1_0000)
+            ]
+        );
+
+        // Zeroing whole clusters frees them.
+        q.write_zeroes_all_at(0x2_0000, 0x1_0000).unwrap();
+        q.write_zeroes_all_at(0x5_0000, 0x1_0000).unwrap();
+        assert_eq!(
+            q.allocated_ranges().unwrap(),
+            vec![(0x1_0000, 0x1_0000), (0xf_0000, 0x1_0000)]
+        );
+    }
+
+    #[test]
+    fn truncate_to_minimal() {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut q = QcowFile::new(file, 0x100_0000).unwrap();
+        q.write_all_at(&[0x11u8; 512], 0).unwrap();
+        q.flush().unwrap();
+        let low_len = q.raw_file.file_len();
+
+        q.write_all_at(&[0x22u8; 512], 0xf0_0000).unwrap();
+        q.flush().unwrap();
+        let high_len = q.raw_file.file_len();
+        assert!(high_len > low_len);
+
+        q.punch_hole(0xf0_0000, 0x1_0000).unwrap();
+        q.truncate_to_minimal().unwrap();
+        assert_eq!(q.raw_file.file_len(), low_len);
+        assert_eq!(q.raw_file.file().metadata().unwrap().len(), low_len);
+
+        let mut buf = [0u8; 512];
+        q.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [0x11u8; 512]);
+        q.read_exact_at(&mut buf, 0xf0_0000).unwrap();
+        assert_eq!(buf, [0u8; 512]);
+
+        // Nothing past the metadata is free, so a second call doesn't shrink the file.
+        q.truncate_to_minimal().unwrap();
+        assert_eq!(q.raw_file.file_len(), low_len);
+
+        q.write_all_at(&[0x33u8; 512], 0xf0_0000).unwrap();
+        q.read_exact_at(&mut buf, 0xf0_0000).unwrap();
+        assert_eq!(buf, [0x33u8; 512]);
+    }
+
+    #[test]
+    fn snapshot_delete() {
+        let file = tempfile().expect("failed to create tempfile");
+        {
+            let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+            q.write_all_at(&[0x11u8; 512], 0).unwrap();
+            q.create_snapshot("snap").unwrap();
+            match q.create_snapshot("snap") {
+                Err(Error::SnapshotExists(_)) => (),
+                _ => panic!("duplicate snapshot name accepted"),
+            }
+            q.write_all_at(&[0x22u8; 512], 0).unwrap();
+        }
+
+        // The snapshot table is persisted.
+        let mut q = QcowFile::from(file).unwrap();
+        assert_eq!(q.header().nb_snapshots, 1);
+        assert_eq!(q.snapshots()[0].name, "snap");
+
+        q.delete_snapshot("snap").unwrap();
+        assert!(q.snapshots().is_empty());
+        assert_eq!(q.header().nb_snapshots, 0);
+        assert_eq!(q.header().snapshots_offset, 0);
+        match q.delete_snapshot("snap") {
+            Err(Error::SnapshotNotFound(_)) => (),
+            _ => panic!("deleted a missing snapshot"),
+        }
+
+        // The active data is untouched and nothing is left shared.
+        let mut buf = [0u8; 512];
+        q.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [0x22u8; 512]);
+        let refcounts = q.refcount_block(0).unwrap().unwrap().to_vec();
+        assert!(refcounts.iter().all(|&count| count <= 1));
+    }
+
     #[test]
     fn rebuild_refcounts() {
         with_basic_file(&valid_header(), |mut disk_file: File| {
@@ -2,12 +2,15 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cmp::max;
 use std::fs::File;
 use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
 
-use base::{FileReadWriteAtVolatile, WriteZeroes};
-use data_model::VolatileSlice;
+use base::WriteZeroes;
+
+use super::refcount::RefcountEntry;
+use super::Error;
 
 /// A qcow file. Allows reading/writing clusters and appending clusters.
 #[derive(Debug)]
@@ -15,22 +18,39 @@ pub struct QcowRawFile {
     file: File,
     cluster_size: u64,
     cluster_mask: u64,
+    refcount_bits: u64,
+    // Length of `file`, kept up to date as clusters are added.
+    file_len: u64,
 }
 
 impl QcowRawFile {
     /// Creates a `QcowRawFile` from the given `File`, `None` is returned if `cluster_size` is not
-    /// a power of two.
+    /// a power of two or the length of `file` can't be read.
     pub fn from(file: File, cluster_size: u64) -> Option<Self> {
         if cluster_size.count_ones() != 1 {
             return None;
         }
+        let file_len = file.metadata().ok()?.len();
         Some(QcowRawFile {
             file,
             cluster_size,
             cluster_mask: cluster_size - 1,
+            refcount_bits: 16,
+            file_len,
         })
     }
 
+    /// Sets the width of refcount entries to 2^`refcount_order` bits. Defaults to 16 bit
+    /// refcounts. Orders up to 4 are supported.
+    pub fn set_refcount_order(&mut self, refcount_order: u32) {
+        self.refcount_bits = 1 << refcount_order;
+    }
+
+    /// Returns the number of refcount entries in a refcount block.
+    pub fn refcount_block_entries(&self) -> u64 {
+        self.cluster_size * 8 / self.refcount_bits
+    }
+
     /// Reads `count` 64 bit offsets and returns them as a vector.
     /// `mask` optionally ands out some of the bits on the file.
     pub fn read_pointer_table(
@@ -58,48 +78,52 @@ impl QcowRawFile {
     }
 
     /// Writes `table` of u64 pointers to `offset` in the file.
-    /// `non_zero_flags` will be ORed with all non-zero values in `table`.
-    /// writing.
+    /// `non_zero_flags` will be ORed with all values in `table` that hold an address. Values that
+    /// only carry flags in the bits below the cluster size are written unchanged.
+    /// `offset` must be cluster aligned.
     pub fn write_pointer_table(
         &mut self,
         offset: u64,
         table: &[u64],
         non_zero_flags: u64,
     ) -> io::Result<()> {
+        self.check_cluster_aligned(offset)?;
         self.file.seek(SeekFrom::Start(offset))?;
         let mut buffer = BufWriter::with_capacity(table.len() * size_of::<u64>(), &self.file);
         for addr in table {
-            let val = if *addr == 0 {
-                0
+            let val = if *addr & !(self.cluster_size - 1) == 0 {
+                *addr
             } else {
                 *addr | non_zero_flags
             };
             buffer.write_all(&val.to_be_bytes())?;
         }
+        drop(buffer);
+        self.grow_file_len(offset + (table.len() * size_of::<u64>()) as u64);
         Ok(())
     }
 
     /// Read a refcount block from the file and returns a Vec containing the block.
     /// Always returns a cluster's worth of data.
-    pub fn read_refcount_block(&mut self, offset: u64) -> io::Result<Vec<u16>> {
-        let count = self.cluster_size / size_of::<u16>() as u64;
-        let mut table = vec![0; count as usize];
+    pub fn read_refcount_block<T: RefcountEntry>(&mut self, offset: u64) -> io::Result<Vec<T>> {
+        let mut bytes = vec![0u8; self.cluster_size as usize];
         self.file.seek(SeekFrom::Start(offset))?;
-        for refcount in &mut table {
-            let mut value = [0u8; 2];
-            self.file.read_exact(&mut value)?;
-            *refcount = u16::from_be_bytes(value);
-        }
-        Ok(table)
+        self.file.read_exact(&mut bytes)?;
+        Ok(T::read(&bytes, self.refcount_bits as u32))
     }
 
-    /// Writes a refcount block to the file.
-    pub fn write_refcount_block(&mut self, offset: u64, table: &[u16]) -> io::Result<()> {
+    /// Writes a refcount block to the cluster at `offset`. Each value in `table` must fit in a
+    /// refcount entry and, for sub-byte refcounts, `table` must fill a whole number of bytes.
+    pub fn write_refcount_block<T: RefcountEntry>(
+        &mut self,
+        offset: u64,
+        table: &[T],
+    ) -> io::Result<()> {
+        self.check_cluster_aligned(offset)?;
+        let bytes = T::write(table, self.refcount_bits as u32)?;
         self.file.seek(SeekFrom::Start(offset))?;
-        let mut buffer = BufWriter::with_capacity(table.len() * size_of::<u16>(), &self.file);
-        for count in table {
-            buffer.write_all(&count.to_be_bytes())?;
-        }
+        self.file.write_all(&bytes)?;
+        self.grow_file_len(offset + bytes.len() as u64);
         Ok(())
     }
 
@@ -108,17 +132,39 @@ impl QcowRawFile {
         // Determine where the new end of the file should be and set_len, which
         // translates to truncate(2).
         let file_end: u64 = self.file.seek(SeekFrom::End(0))?;
-        let new_cluster_address: u64 = (file_end + self.cluster_size - 1) & !self.cluster_mask;
+        let (new_cluster_address, new_len) = self
+            .next_cluster(file_end)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, Error::FileTooBig(file_end)))?;
 
         if new_cluster_address > max_valid_cluster_offset {
             return Ok(None);
         }
 
-        self.file.set_len(new_cluster_address + self.cluster_size)?;
+        self.file.set_len(new_len)?;
+        self.file_len = new_len;
 
         Ok(Some(new_cluster_address))
     }
 
+    /// Shrinks the file to `len` bytes. The clusters past `len` must no longer be in use.
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.file_len = len;
+        Ok(())
+    }
+
+    // Returns the address of the first cluster boundary at or after `file_end` and the file
+    // length needed to hold a cluster there. Returns None if the length can't be represented as a
+    // file offset.
+    fn next_cluster(&self, file_end: u64) -> Option<(u64, u64)> {
+        let address = file_end.checked_add(self.cluster_mask)? & !self.cluster_mask;
+        let len = address.checked_add(self.cluster_size)?;
+        if len > i64::max_value() as u64 {
+            return None;
+        }
+        Some((address, len))
+    }
+
     /// Returns a reference to the underlying file.
     pub fn file(&self) -> &File {
         &self.file
@@ -129,6 +175,17 @@ impl QcowRawFile {
         &mut self.file
     }
 
+    /// Returns the length of the file without querying its metadata. Only writes made through
+    /// `QcowRawFile` are tracked, so the file must not be extended through `file_mut`.
+    pub fn file_len(&self) -> u64 {
+        self.file_len
+    }
+
+    // Records that the file now extends to at least `end`.
+    fn grow_file_len(&mut self, end: u64) {
+        self.file_len = max(self.file_len, end);
+    }
+
     /// Returns the size of the file's clusters.
     pub fn cluster_size(&self) -> u64 {
         self.cluster_size
@@ -144,18 +201,135 @@ impl QcowRawFile {
         let cluster_size = self.cluster_size as usize;
         self.file.seek(SeekFrom::Start(address))?;
         self.file.write_zeroes_all(cluster_size)?;
+        self.grow_file_len(address + cluster_size as u64);
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes from the start of the cluster at `address`. `buf` must not be
+    /// larger than a cluster.
+    pub fn read_cluster(&mut self, address: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_cluster_buf(buf.len())?;
+        self.file.seek(SeekFrom::Start(address))?;
+        self.file.read_exact(buf)
+    }
+
+    /// Writes `buf` to the start of the cluster at `address`. `buf` must not be larger than a
+    /// cluster.
+    pub fn write_cluster(&mut self, address: u64, buf: &[u8]) -> io::Result<()> {
+        self.check_cluster_buf(buf.len())?;
+        self.file.seek(SeekFrom::Start(address))?;
+        self.file.write_all(buf)?;
+        self.grow_file_len(address + buf.len() as u64);
+        Ok(())
+    }
+
+    // Tables are always stored at the start of a cluster. An unaligned offset comes from a corrupt
+    // L1 or refcount table and writing there would overlap whatever follows it.
+    fn check_cluster_aligned(&self, offset: u64) -> io::Result<()> {
+        if self.cluster_offset(offset) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                Error::InvalidOffset(offset),
+            ));
+        }
         Ok(())
     }
 
-    /// Writes
-    pub fn write_cluster(&mut self, address: u64, mut initial_data: Vec<u8>) -> io::Result<()> {
-        if (initial_data.len() as u64) < self.cluster_size {
+    fn check_cluster_buf(&self, len: usize) -> io::Result<()> {
+        if len as u64 > self.cluster_size {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "`initial_data` is too small",
+                Error::BufferTooLarge(len),
             ));
         }
-        let volatile_slice = VolatileSlice::new(&mut initial_data[..self.cluster_size as usize]);
-        self.file.write_all_at_volatile(volatile_slice, address)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn raw_file_with_clusters(cluster_size: u64, count: u64) -> QcowRawFile {
+        let file = tempfile().expect("failed to create tempfile");
+        file.set_len(cluster_size * count).unwrap();
+        QcowRawFile::from(file, cluster_size).unwrap()
+    }
+
+    #[test]
+    fn file_len_tracks_added_clusters() {
+        let mut raw_file = raw_file_with_clusters(512, 2);
+        assert_eq!(raw_file.file_len(), 1024);
+        for _ in 0..4 {
+            raw_file.add_cluster_end(u64::max_value()).unwrap().unwrap();
+            assert_eq!(
+                raw_file.file_len(),
+                raw_file.file().metadata().unwrap().len()
+            );
+        }
+        assert_eq!(raw_file.file_len(), 512 * 6);
+    }
+
+    #[test]
+    fn next_cluster_overflow() {
+        let raw_file = raw_file_with_clusters(512, 0);
+        assert_eq!(raw_file.next_cluster(0), Some((0, 512)));
+        assert_eq!(raw_file.next_cluster(1), Some((512, 1024)));
+        assert_eq!(raw_file.next_cluster(u64::max_value() - 10), None);
+        assert_eq!(raw_file.next_cluster(i64::max_value() as u64 - 100), None);
+    }
+
+    #[test]
+    fn cluster_full_buffer() {
+        let mut raw_file = raw_file_with_clusters(512, 3);
+        let data: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        raw_file.write_cluster(512, &data).unwrap();
+
+        let mut buf = vec![0u8; 512];
+        raw_file.read_cluster(512, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        // The neighboring clusters are untouched.
+        raw_file.read_cluster(1024, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn cluster_short_buffer() {
+        let mut raw_file = raw_file_with_clusters(512, 2);
+        raw_file.write_cluster(512, &[0x55u8; 16]).unwrap();
+
+        let mut buf = [0u8; 32];
+        raw_file.read_cluster(512, &mut buf).unwrap();
+        assert_eq!(buf[..16], [0x55u8; 16]);
+        assert_eq!(buf[16..], [0u8; 16]);
+    }
+
+    #[test]
+    fn unaligned_table_write_rejected() {
+        let mut raw_file = raw_file_with_clusters(512, 2);
+        let err = raw_file
+            .write_pointer_table(520, &[0x200; 4], 0)
+            .expect_err("unaligned pointer table written");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = raw_file
+            .write_refcount_block(8, &[1; 256])
+            .expect_err("unaligned refcount block written");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut buf = vec![0u8; 512];
+        raw_file.read_cluster(0, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        raw_file.read_cluster(512, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_eq!(raw_file.file_len(), 1024);
+    }
+
+    #[test]
+    fn cluster_buffer_too_large() {
+        let mut raw_file = raw_file_with_clusters(512, 2);
+        let mut buf = vec![0u8; 513];
+        assert!(raw_file.read_cluster(0, &mut buf).is_err());
+        assert!(raw_file.write_cluster(0, &buf).is_err());
     }
 }
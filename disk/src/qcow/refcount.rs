@@ -2,13 +2,13 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::HashSet;
 use std::fmt::{self, Display};
 use std::io;
 
-use libc::EINVAL;
-
 use crate::qcow::qcow_raw_file::QcowRawFile;
 use crate::qcow::vec_cache::{CacheMap, Cacheable, VecCache};
+use crate::qcow::Error as QcowError;
 
 #[derive(Debug)]
 pub enum Error {
@@ -46,18 +46,109 @@ impl Display for Error {
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::EvictingRefCounts(e) | Error::ReadingRefCounts(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The in-memory type of a refcount. Refcount blocks are cached as vectors of `RefcountEntry`s and
+/// converted from and to their on-disk form of `refcount_bits` wide entries, which are big endian
+/// and, below 8 bits, packed starting from the least significant bits of each byte.
+pub trait RefcountEntry: 'static + Copy + Default {
+    /// The width of the widest on-disk refcount that fits in this type.
+    const BITS: u32;
+
+    /// Unpacks a refcount block of `refcount_bits` wide entries.
+    fn read(bytes: &[u8], refcount_bits: u32) -> Vec<Self>;
+
+    /// Packs `table` into `refcount_bits` wide entries. Fails if a value in `table` doesn't fit
+    /// or, for sub-byte refcounts, `table` doesn't fill a whole number of bytes.
+    fn write(table: &[Self], refcount_bits: u32) -> io::Result<Vec<u8>>;
+}
+
+impl RefcountEntry for u16 {
+    const BITS: u32 = 16;
+
+    fn read(bytes: &[u8], refcount_bits: u32) -> Vec<u16> {
+        match refcount_bits {
+            16 => bytes
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect(),
+            8 => bytes.iter().map(|&b| u16::from(b)).collect(),
+            bits => {
+                let per_byte = 8 / bits as usize;
+                let mask = (1u8 << bits) - 1;
+                let mut table = Vec::with_capacity(bytes.len() * per_byte);
+                for byte in bytes {
+                    for i in 0..per_byte {
+                        table.push(u16::from((byte >> (i * bits as usize)) & mask));
+                    }
+                }
+                table
+            }
+        }
+    }
+
+    fn write(table: &[u16], refcount_bits: u32) -> io::Result<Vec<u8>> {
+        let bits = refcount_bits as usize;
+        let max_refcount = ((1u32 << bits) - 1) as u16;
+        if table.iter().any(|&count| count > max_refcount) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                QcowError::RefcountOverflow,
+            ));
+        }
+        if table.len() * bits % 8 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                QcowError::PartialRefcountBlock,
+            ));
+        }
+
+        let bytes = match bits {
+            16 => {
+                let mut bytes = Vec::with_capacity(table.len() * 2);
+                for count in table {
+                    bytes.extend_from_slice(&count.to_be_bytes());
+                }
+                bytes
+            }
+            8 => table.iter().map(|&count| count as u8).collect(),
+            _ => table
+                .chunks(8 / bits)
+                .map(|counts| {
+                    counts
+                        .iter()
+                        .enumerate()
+                        .fold(0u8, |acc, (i, &count)| acc | ((count as u8) << (i * bits)))
+                })
+                .collect(),
+        };
+        Ok(bytes)
+    }
+}
+
 /// Represents the refcount entries for an open qcow file.
 #[derive(Debug)]
-pub struct RefCount {
+pub struct RefCount<E: RefcountEntry> {
     ref_table: VecCache<u64>,
     refcount_table_offset: u64,
-    refblock_cache: CacheMap<VecCache<u16>>,
+    refblock_cache: CacheMap<VecCache<E>>,
+    // Indices of the blocks moved to a new cluster since the refcount table was last flushed.
+    // Nothing on disk points at their new clusters yet, so they can be updated in place.
+    moved_blocks: HashSet<usize>,
     refcount_block_entries: u64, // number of refcounts in a cluster.
     cluster_size: u64,
     max_valid_cluster_offset: u64,
+    lazy: bool,
 }
 
-impl RefCount {
+impl<E: RefcountEntry> RefCount<E> {
     /// Creates a `RefCount` from `file`, reading the refcount table from `refcount_table_offset`.
     /// `refcount_table_entries` specifies the number of refcount blocks used by this image.
     /// `refcount_block_entries` indicates the number of refcounts in each refcount block.
@@ -68,7 +159,7 @@ impl RefCount {
         refcount_table_entries: u64,
         refcount_block_entries: u64,
         cluster_size: u64,
-    ) -> io::Result<RefCount> {
+    ) -> io::Result<RefCount<E>> {
         let ref_table = VecCache::from_vec(raw_file.read_pointer_table(
             refcount_table_offset,
             refcount_table_entries,
@@ -80,9 +171,11 @@ impl RefCount {
             ref_table,
             refcount_table_offset,
             refblock_cache: CacheMap::new(50),
+            moved_blocks: HashSet::new(),
             refcount_block_entries,
             cluster_size,
             max_valid_cluster_offset,
+            lazy: false,
         })
     }
 
@@ -96,6 +189,27 @@ impl RefCount {
         self.max_valid_cluster_offset
     }
 
+    /// When `lazy` is set, refcount blocks are updated in place instead of being moved to a new
+    /// cluster on their first modification after a flush. The on-disk refcounts can then be
+    /// inconsistent until the next flush, so the caller must mark the image dirty.
+    pub fn set_lazy(&mut self, lazy: bool) {
+        self.lazy = lazy;
+    }
+
+    /// Extends the refcount table to `entries` entries stored at `refcount_table_offset`. The new
+    /// entries are unallocated. The whole table is written to the new location by the next
+    /// `flush_table()`.
+    pub fn grow_table(&mut self, refcount_table_offset: u64, entries: u64) {
+        let mut ref_table = VecCache::new(entries as usize);
+        for (i, addr) in self.ref_table.get_values().iter().enumerate() {
+            ref_table[i] = *addr;
+        }
+        self.ref_table = ref_table;
+        self.refcount_table_offset = refcount_table_offset;
+        self.max_valid_cluster_offset =
+            (entries * self.refcount_block_entries - 1) * self.cluster_size;
+    }
+
     /// Returns `NeedNewCluster` if a new cluster needs to be allocated for refcounts. If an
     /// existing cluster needs to be read, `NeedCluster(addr)` is returned. The Caller should
     /// allocate a cluster or read the required one and call this function again with the cluster.
@@ -105,9 +219,25 @@ impl RefCount {
         &mut self,
         raw_file: &mut QcowRawFile,
         cluster_address: u64,
-        refcount: u16,
-        mut new_cluster: Option<(u64, VecCache<u16>)>,
+        refcount: E,
+        new_cluster: Option<(u64, VecCache<E>)>,
     ) -> Result<Option<u64>> {
+        self.set_cluster_refcount_range(raw_file, cluster_address, 1, refcount, new_cluster)
+            .map(|(_, dropped_cluster)| dropped_cluster)
+    }
+
+    /// Like `set_cluster_refcount`, but sets the refcount of up to `count` consecutive clusters
+    /// starting at `cluster_address`, stopping at the end of the refcount block that covers
+    /// `cluster_address`. On success, the number of clusters set is returned along with the
+    /// optional dropped cluster.
+    pub fn set_cluster_refcount_range(
+        &mut self,
+        raw_file: &mut QcowRawFile,
+        cluster_address: u64,
+        count: u64,
+        refcount: E,
+        mut new_cluster: Option<(u64, VecCache<E>)>,
+    ) -> Result<(u64, Option<u64>)> {
         let (table_index, block_index) = self.get_refcount_index(cluster_address);
 
         let block_addr_disk = *self.ref_table.get(table_index).ok_or(Error::InvalidIndex)?;
@@ -116,6 +246,9 @@ impl RefCount {
         if !self.refblock_cache.contains_key(&table_index) {
             // Need a new cluster
             if let Some((addr, table)) = new_cluster.take() {
+                if block_addr_disk == 0 {
+                    self.moved_blocks.insert(table_index);
+                }
                 self.ref_table[table_index] = addr;
                 let ref_table = &self.ref_table;
                 self.refblock_cache
@@ -131,12 +264,14 @@ impl RefCount {
             }
         }
 
-        // Unwrap is safe here as the entry was filled directly above.
-        let dropped_cluster = if !self.refblock_cache.get(&table_index).unwrap().dirty() {
+        // A block is moved at most once per table flush. Checking whether the cached block is
+        // dirty isn't enough, as a moved block that was evicted and read back is clean.
+        let dropped_cluster = if !self.lazy && !self.moved_blocks.contains(&table_index) {
             // Free the previously used block and use a new one. Writing modified counts to new
             // blocks keeps the on-disk state consistent even if it's out of date.
             if let Some((addr, _)) = new_cluster.take() {
                 self.ref_table[table_index] = addr;
+                self.moved_blocks.insert(table_index);
                 Some(block_addr_disk)
             } else {
                 return Err(Error::NeedNewCluster);
@@ -145,8 +280,12 @@ impl RefCount {
             None
         };
 
-        self.refblock_cache.get_mut(&table_index).unwrap()[block_index] = refcount;
-        Ok(dropped_cluster)
+        let set_count = count.min(self.refcount_block_entries - block_index as u64);
+        let block = self.refblock_cache.get_mut(&table_index).unwrap();
+        for index in block_index..block_index + set_count as usize {
+            block[index] = refcount;
+        }
+        Ok((set_count, dropped_cluster))
     }
 
     /// Flush the dirty refcount blocks. This must be done before flushing the table that points to
@@ -158,7 +297,10 @@ impl RefCount {
             if addr != 0 {
                 raw_file.write_refcount_block(addr, block.get_values())?;
             } else {
-                return Err(std::io::Error::from_raw_os_error(EINVAL));
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    QcowError::InvalidIndex,
+                ));
             }
             block.mark_clean();
         }
@@ -175,6 +317,7 @@ impl RefCount {
                 0,
             )?;
             self.ref_table.mark_clean();
+            self.moved_blocks.clear();
             Ok(true)
         } else {
             Ok(false)
@@ -182,11 +325,7 @@ impl RefCount {
     }
 
     /// Gets the refcount for a cluster with the given address.
-    pub fn get_cluster_refcount(
-        &mut self,
-        raw_file: &mut QcowRawFile,
-        address: u64,
-    ) -> Result<u16> {
+    pub fn get_cluster_refcount(&mut self, raw_file: &mut QcowRawFile, address: u64) -> Result<E> {
         let (table_index, block_index) = self.get_refcount_index(address);
         let block_addr_disk = *self.ref_table.get(table_index).ok_or(Error::InvalidIndex)?;
         if block_addr_disk == 0 {
@@ -218,7 +357,7 @@ impl RefCount {
         &mut self,
         raw_file: &mut QcowRawFile,
         table_index: usize,
-    ) -> Result<Option<&[u16]>> {
+    ) -> Result<Option<&[E]>> {
         let block_addr_disk = *self.ref_table.get(table_index).ok_or(Error::InvalidIndex)?;
         if block_addr_disk == 0 {
             return Ok(None);
@@ -250,3 +389,118 @@ impl RefCount {
         (refcount_table_index as usize, block_index as usize)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    const CLUSTER_SIZE: u64 = 512;
+    const BLOCK_ENTRIES: u64 = CLUSTER_SIZE / 2;
+
+    // Returns a `RefCount` with `blocks` unallocated refcount blocks, its table in the first
+    // cluster of the file.
+    fn empty_refcounts(blocks: u64) -> (RefCount<u16>, QcowRawFile) {
+        let file = tempfile().unwrap();
+        file.set_len(CLUSTER_SIZE).unwrap();
+        let mut raw_file = QcowRawFile::from(file, CLUSTER_SIZE).unwrap();
+        let refcounts =
+            RefCount::new(&mut raw_file, 0, blocks, BLOCK_ENTRIES, CLUSTER_SIZE).unwrap();
+        (refcounts, raw_file)
+    }
+
+    // Sets the refcount of `address` to 1, reading and allocating refcount blocks as needed the
+    // way `QcowFile` does. Returns the number of clusters allocated for refcount blocks.
+    fn set_refcount(
+        refcounts: &mut RefCount<u16>,
+        raw_file: &mut QcowRawFile,
+        address: u64,
+    ) -> u64 {
+        let mut allocated = 0;
+        let mut new_cluster = None;
+        loop {
+            match refcounts.set_cluster_refcount(raw_file, address, 1, new_cluster.take()) {
+                Ok(_) => return allocated,
+                Err(Error::NeedCluster(addr)) => {
+                    let block = raw_file.read_refcount_block(addr).unwrap();
+                    new_cluster = Some((addr, VecCache::from_vec(block)));
+                }
+                Err(Error::NeedNewCluster) => {
+                    let addr = raw_file.add_cluster_end(u64::max_value()).unwrap().unwrap();
+                    allocated += 1;
+                    new_cluster = Some((addr, VecCache::new(BLOCK_ENTRIES as usize)));
+                }
+                Err(e) => panic!("failed to set refcount: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn refcount_blocks_moved_once_per_flush() {
+        // More blocks than fit in the cache, so some are evicted and read back.
+        let blocks = 60;
+        let (mut refcounts, mut raw_file) = empty_refcounts(blocks);
+
+        let mut allocated = 0;
+        for _ in 0..3 {
+            for block in 0..blocks {
+                let address = block * BLOCK_ENTRIES * CLUSTER_SIZE;
+                allocated += set_refcount(&mut refcounts, &mut raw_file, address);
+            }
+        }
+        // Each block is moved only once, including those read back after being evicted.
+        assert_eq!(allocated, blocks);
+
+        // Once the table is on disk, the next update moves the block again.
+        refcounts.flush_blocks(&mut raw_file).unwrap();
+        assert!(refcounts.flush_table(&mut raw_file).unwrap());
+        assert_eq!(set_refcount(&mut refcounts, &mut raw_file, CLUSTER_SIZE), 1);
+        assert_eq!(
+            set_refcount(&mut refcounts, &mut raw_file, 2 * CLUSTER_SIZE),
+            0
+        );
+        assert_eq!(
+            refcounts
+                .get_cluster_refcount(&mut raw_file, CLUSTER_SIZE)
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn u16_refcounts_16_bit() {
+        let bytes = [0x00, 0x01, 0x12, 0x34, 0xff, 0xff];
+        let table = u16::read(&bytes, 16);
+        assert_eq!(table, [1, 0x1234, 0xffff]);
+        assert_eq!(u16::write(&table, 16).unwrap(), bytes);
+    }
+
+    #[test]
+    fn u16_refcounts_8_bit() {
+        let bytes = [0x00, 0x01, 0xff];
+        let table = u16::read(&bytes, 8);
+        assert_eq!(table, [0, 1, 0xff]);
+        assert_eq!(u16::write(&table, 8).unwrap(), bytes);
+        assert_eq!(
+            u16::write(&[0x100], 8).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn u16_refcounts_sub_byte() {
+        // Entries are packed starting from the least significant bits.
+        let table = u16::read(&[0b1110_0100], 2);
+        assert_eq!(table, [0, 1, 2, 3]);
+        assert_eq!(u16::write(&table, 2).unwrap(), [0b1110_0100]);
+        assert_eq!(u16::read(&[0b1000_0001], 1), [1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(
+            u16::write(&[1, 0, 1], 1).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            u16::write(&[4, 0, 0, 0], 2).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
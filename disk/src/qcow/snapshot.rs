@@ -0,0 +1,197 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+// Size of the fixed part of a snapshot table entry.
+const SNAPSHOT_HEADER_SIZE: usize = 40;
+// Version 3 images require at least the large VM state size and the disk size.
+const SNAPSHOT_EXTRA_DATA_SIZE: usize = 16;
+// Same limit as qemu, bounds the memory used by a corrupt entry.
+const MAX_SNAPSHOT_EXTRA_DATA_SIZE: u32 = 1024;
+
+/// An entry in the qcow snapshot table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QcowSnapshot {
+    /// Unique ID, by convention a decimal number.
+    pub id: String,
+    pub name: String,
+    /// Offset of the snapshot's copy of the L1 table.
+    pub l1_table_offset: u64,
+    /// Number of entries in the snapshot's L1 table.
+    pub l1_size: u32,
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    pub vm_clock_nsec: u64,
+    pub vm_state_size: u32,
+    extra_data: Vec<u8>,
+}
+
+impl QcowSnapshot {
+    /// Creates a disk-only snapshot entry of a disk with `disk_size` bytes.
+    pub fn new(
+        id: String,
+        name: String,
+        l1_table_offset: u64,
+        l1_size: u32,
+        date_sec: u32,
+        date_nsec: u32,
+        disk_size: u64,
+    ) -> QcowSnapshot {
+        let mut extra_data = Vec::with_capacity(SNAPSHOT_EXTRA_DATA_SIZE);
+        extra_data.extend_from_slice(&0u64.to_be_bytes()); // vm_state_size_large
+        extra_data.extend_from_slice(&disk_size.to_be_bytes());
+        QcowSnapshot {
+            id,
+            name,
+            l1_table_offset,
+            l1_size,
+            date_sec,
+            date_nsec,
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            extra_data,
+        }
+    }
+
+    /// Returns the virtual disk size when the snapshot was taken, if recorded.
+    pub fn disk_size(&self) -> Option<u64> {
+        let mut size = [0u8; 8];
+        size.copy_from_slice(self.extra_data.get(8..16)?);
+        Some(u64::from_be_bytes(size))
+    }
+
+    /// Reads `count` snapshot table entries starting at `offset`.
+    pub fn read_table<F: Read + Seek>(
+        f: &mut F,
+        offset: u64,
+        count: u32,
+    ) -> io::Result<Vec<QcowSnapshot>> {
+        f.seek(SeekFrom::Start(offset))?;
+        (0..count).map(|_| QcowSnapshot::read_from(f)).collect()
+    }
+
+    fn read_from<F: Read + Seek>(f: &mut F) -> io::Result<QcowSnapshot> {
+        let mut header = [0u8; SNAPSHOT_HEADER_SIZE];
+        f.read_exact(&mut header)?;
+        let u16_at = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+        let u32_at = |i: usize| {
+            let mut b = [0u8; 4];
+            b.copy_from_slice(&header[i..i + 4]);
+            u32::from_be_bytes(b)
+        };
+        let u64_at = |i: usize| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&header[i..i + 8]);
+            u64::from_be_bytes(b)
+        };
+
+        let id_str_size = u16_at(12) as usize;
+        let name_size = u16_at(14) as usize;
+        let extra_data_size = u32_at(36);
+        if extra_data_size > MAX_SNAPSHOT_EXTRA_DATA_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot extra data too large",
+            ));
+        }
+
+        let mut extra_data = vec![0u8; extra_data_size as usize];
+        f.read_exact(&mut extra_data)?;
+        let id = read_string(f, id_str_size)?;
+        let name = read_string(f, name_size)?;
+
+        // Entries are padded to a multiple of 8 bytes.
+        let entry_size = SNAPSHOT_HEADER_SIZE + extra_data.len() + id_str_size + name_size;
+        f.seek(SeekFrom::Current(padding(entry_size) as i64))?;
+
+        Ok(QcowSnapshot {
+            id,
+            name,
+            l1_table_offset: u64_at(0),
+            l1_size: u32_at(8),
+            date_sec: u32_at(16),
+            date_nsec: u32_at(20),
+            vm_clock_nsec: u64_at(24),
+            vm_state_size: u32_at(32),
+            extra_data,
+        })
+    }
+
+    /// Serializes `snapshots` as a snapshot table.
+    pub fn table_bytes(snapshots: &[QcowSnapshot]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for snapshot in snapshots {
+            snapshot.write_to(&mut bytes);
+        }
+        bytes
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        let start = bytes.len();
+        bytes.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.l1_size.to_be_bytes());
+        bytes.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.date_sec.to_be_bytes());
+        bytes.extend_from_slice(&self.date_nsec.to_be_bytes());
+        bytes.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        bytes.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        bytes.extend_from_slice(&(self.extra_data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.extra_data);
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        let entry_size = bytes.len() - start;
+        bytes.resize(bytes.len() + padding(entry_size), 0);
+    }
+}
+
+fn read_string<F: Read>(f: &mut F, len: usize) -> io::Result<String> {
+    let mut bytes = vec![0u8; len];
+    f.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Returns the number of bytes needed to pad `len` to a multiple of 8.
+fn padding(len: usize) -> usize {
+    (8 - len % 8) % 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn table_round_trip() {
+        let snapshots = vec![
+            QcowSnapshot::new(
+                "1".to_string(),
+                "first".to_string(),
+                0x30000,
+                1,
+                5,
+                6,
+                0x10_0000,
+            ),
+            QcowSnapshot::new(
+                "2".to_string(),
+                "second one".to_string(),
+                0x50000,
+                2,
+                7,
+                8,
+                0x20_0000,
+            ),
+        ];
+        let bytes = QcowSnapshot::table_bytes(&snapshots);
+        // 40 byte header, 16 bytes of extra data, then the padded id and name.
+        assert_eq!(bytes.len(), 64 + 72);
+
+        let mut cursor = Cursor::new(bytes);
+        let read = QcowSnapshot::read_table(&mut cursor, 0, 2).unwrap();
+        assert_eq!(read, snapshots);
+        assert_eq!(read[1].disk_size(), Some(0x20_0000));
+    }
+}
@@ -81,8 +81,17 @@ fuzz_target!(|bytes| {
     let features = base_features(ProtectionType::Unprotected);
 
     let disk_file = tempfile::tempfile().unwrap();
-    let mut block =
-        Block::new(features, Box::new(disk_file), false, true, 512, None, None).unwrap();
+    let mut block = Block::new(
+        features,
+        Box::new(disk_file),
+        false,
+        true,
+        512,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
 
     block.activate(
         mem,
@@ -537,6 +537,25 @@ impl URingContext {
         })
     }
 
+    /// Requests cancellation of the operation that was added with `target` as its user data. If
+    /// the operation is canceled it completes with `ECANCELED`. The result of the cancel request
+    /// itself is returned separately with `user_data`.
+    pub fn add_cancel(&self, target: UserData, user_data: UserData) -> Result<()> {
+        self.submit_ring.lock().prep_next_sqe(|sqe, _iovec| {
+            sqe.opcode = IORING_OP_ASYNC_CANCEL as u8;
+            sqe.fd = -1;
+            sqe.user_data = user_data;
+            sqe.addr = target;
+
+            sqe.len = 0;
+            sqe.__bindgen_anon_1.off = 0;
+            sqe.__bindgen_anon_3.__bindgen_anon_1.buf_index = 0;
+            sqe.__bindgen_anon_2.rw_flags = 0;
+            sqe.ioprio = 0;
+            sqe.flags = 0;
+        })
+    }
+
     // Calls io_uring_enter, submitting any new sqes that have been added to the submit queue and
     // waiting for `wait_nr` operations to complete.
     fn enter(&self, wait_nr: u64) -> Result<()> {
@@ -1190,6 +1209,28 @@ mod tests {
         assert_eq!(res.unwrap(), 1_u32);
     }
 
+    #[test]
+    fn cancel_poll() {
+        let (rx, _tx) = pipe(true).unwrap();
+        let uring = URingContext::new(16).unwrap();
+        uring
+            .add_poll_fd(rx.as_raw_fd(), &WatchingEvents::empty().set_read(), 17)
+            .unwrap();
+        uring.add_cancel(17, 18).unwrap();
+
+        let mut results = BTreeMap::new();
+        while results.len() < 2 {
+            for (user_data, res) in uring.wait().unwrap() {
+                results.insert(user_data, res);
+            }
+        }
+        assert_eq!(
+            results[&17].as_ref().unwrap_err().raw_os_error(),
+            Some(libc::ECANCELED)
+        );
+        assert!(results[&18].is_ok());
+    }
+
     #[test]
     fn queue_many_ebusy_retry() {
         let num_entries = 16;
@@ -122,6 +122,17 @@ pub struct MacAddress {
 }
 
 impl MacAddress {
+    /// Makes a locally administered unicast address from `octets`, overriding the two low bits of
+    /// the first octet that mark an address as local and multicast.
+    pub fn local_unicast(mut octets: [u8; 6usize]) -> MacAddress {
+        octets[0] = (octets[0] | 0x02) & !0x01;
+        MacAddress {
+            family: net_sys::ARPHRD_ETHER,
+            addr: octets,
+            __pad: [0; 8usize],
+        }
+    }
+
     pub fn octets(&self) -> [u8; 6usize] {
         self.addr
     }
@@ -650,6 +661,14 @@ mod tests {
         assert!("not a mac address".parse::<MacAddress>().is_err());
     }
 
+    #[test]
+    fn local_unicast_mac_address() {
+        let mac = MacAddress::local_unicast([0xff, 1, 2, 3, 4, 5]);
+        assert_eq!(mac.octets(), [0xfe, 1, 2, 3, 4, 5]);
+        let mac = MacAddress::local_unicast([0; 6]);
+        assert_eq!(mac.octets(), [0x02, 0, 0, 0, 0, 0]);
+    }
+
     #[test]
     fn tap_create() {
         Tap::new(true, false).unwrap();
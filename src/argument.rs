@@ -43,6 +43,7 @@
 //! }
 //! ```
 
+use std::cmp::max;
 use std::fmt::{self, Display};
 use std::result;
 
@@ -270,7 +271,11 @@ where
                             ));
                         }
                         let name = &arg[1..2];
-                        let value = if arg.len() > 2 { Some(&arg[2..]) } else { None };
+                        // Accept both `-c5` and `-c=5`.
+                        let value = match &arg[2..] {
+                            "" => None,
+                            v => Some(v.strip_prefix('=').unwrap_or(v)),
+                        };
                         if let Err(e) = f(name, value) {
                             if let Error::ExpectedValue(_) = e {
                                 State::Value {
@@ -361,10 +366,29 @@ where
     })
 }
 
+/// Parses a size in bytes with an optional base-1024 `K`, `M`, `G`, or `T` suffix, e.g. "4096",
+/// "64K", or "2G".
+pub fn parse_size(s: &str) -> Result<u64> {
+    let invalid_value = || Error::InvalidValue {
+        value: s.to_owned(),
+        expected: String::from("a size in bytes, optionally followed by K, M, G, or T"),
+    };
+    let (digits, shift) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 30),
+        Some('T') | Some('t') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let size: u64 = digits.parse().map_err(|_| invalid_value())?;
+    size.checked_mul(1u64 << shift).ok_or_else(invalid_value)
+}
+
 /// Prints command line usage information to stdout.
 ///
 /// Usage information is printed according to the help fields in `args` with a leading usage line.
-/// The usage line is of the format "`program_name` \[ARGUMENTS\] `required_arg`".
+/// The usage line is of the format "`program_name` \[ARGUMENTS\] `required_arg`". Descriptions are
+/// aligned in a column and wrapped to the width of the terminal.
 pub fn print_help(program_name: &str, required_arg: &str, args: &[Argument]) {
     println!(
         "Usage: {} {}{}\n",
@@ -376,29 +400,102 @@ pub fn print_help(program_name: &str, required_arg: &str, args: &[Argument]) {
         return;
     }
     println!("Argument{}:", if args.len() > 1 { "s" } else { "" });
-    for arg in args {
-        match arg.short {
-            Some(s) => print!(" -{}, ", s),
-            None => print!("     "),
+    print!("{}", format_arguments(args, terminal_width()));
+}
+
+// Width used when stdout isn't a terminal.
+const DEFAULT_HELP_WIDTH: usize = 80;
+// Descriptions start at most this far in. Arguments with longer flags start their description on
+// the next line.
+const MAX_HELP_INDENT: usize = 40;
+// Descriptions are never wrapped narrower than this, even on very narrow terminals.
+const MIN_HELP_WIDTH: usize = 20;
+
+fn terminal_width() -> usize {
+    let mut ws = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // Safe because TIOCGWINSZ only writes to `ws`, which is a valid winsize, and the return value
+    // is checked.
+    let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+    if ret == 0 && ws.ws_col > 0 {
+        ws.ws_col as usize
+    } else {
+        DEFAULT_HELP_WIDTH
+    }
+}
+
+// Formats the flag portion of an argument's help line, e.g. " -c, --cpus=N".
+fn argument_flags(arg: &Argument) -> String {
+    let mut flags = match arg.short {
+        Some(s) => format!(" -{}, ", s),
+        None => "     ".to_owned(),
+    };
+    if arg.long.is_empty() {
+        // Positional arguments only have a value name.
+        flags.push_str("  ");
+    } else {
+        flags.push_str("--");
+        flags.push_str(arg.long);
+        if arg.value.is_some() {
+            flags.push('=');
         }
-        if arg.long.is_empty() {
-            print!("  ");
-        } else {
-            print!("--");
+    }
+    if let Some(v) = arg.value {
+        flags.push_str(v);
+    }
+    flags
+}
+
+// Splits `text` into lines of at most `width` characters, breaking at whitespace. Words longer
+// than `width` are put on a line of their own.
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.len() + 1 + word.len() > width {
+            lines.push(line);
+            line = String::new();
         }
-        print!("{:<12}", arg.long);
-        if let Some(v) = arg.value {
-            if arg.long.is_empty() {
-                print!(" ");
-            } else {
-                print!("=");
-            }
-            print!("{:<10}", v);
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    lines.push(line);
+    lines
+}
+
+// Formats one line per argument, or more for long descriptions, with descriptions aligned and
+// wrapped to `width` columns.
+fn format_arguments(args: &[Argument], width: usize) -> String {
+    let flags: Vec<String> = args.iter().map(argument_flags).collect();
+    let indent = flags
+        .iter()
+        .map(|f| f.len() + 2)
+        .filter(|&len| len <= MAX_HELP_INDENT)
+        .max()
+        .unwrap_or(MAX_HELP_INDENT);
+    let help_width = max(width.saturating_sub(indent), MIN_HELP_WIDTH);
+
+    let mut out = String::new();
+    for (arg, flag) in args.iter().zip(flags) {
+        let mut help_lines = wrap_text(arg.help, help_width).into_iter();
+        if flag.len() + 2 > indent {
+            out.push_str(&flag);
+            out.push('\n');
         } else {
-            print!("{:<11}", "");
+            let first = help_lines.next().unwrap_or_default();
+            out.push_str(&format!("{:<indent$}{}\n", flag, first, indent = indent));
+        }
+        for line in help_lines {
+            out.push_str(&format!("{:indent$}{}\n", "", line, indent = indent));
         }
-        println!("{}", arg.help);
     }
+    out
 }
 
 #[cfg(test)]
@@ -466,7 +563,7 @@ mod tests {
             "Number of CPUs to use. (default: 1)",
         )];
         let match_res = set_arguments(
-            ["-c", "5", "--cpus", "5", "-c5", "--cpus=5"].iter(),
+            ["-c", "5", "--cpus", "5", "-c5", "-c=5", "--cpus=5"].iter(),
             &arguments[..],
             |name, value| {
                 assert_eq!(name, "cpus");
@@ -546,4 +643,52 @@ mod tests {
             "2D"
         );
     }
+
+    #[test]
+    fn help_is_wrapped_and_aligned() {
+        let arguments = [
+            Argument::short_value('c', "cpus", "N", "Number of CPUs to use. (default: 1)"),
+            Argument::value(
+                "tap-fd",
+                "fd",
+                "File descriptor for configured tap device. A different virtual network card will \
+                 be added each time this argument is given.",
+            ),
+            Argument::flag(
+                "a-flag-with-a-name-too-long-to-fit-the-column",
+                "Short help.",
+            ),
+        ];
+        let width = 60;
+        let help = format_arguments(&arguments, width);
+        let lines: Vec<&str> = help.lines().collect();
+
+        assert!(lines.iter().all(|line| line.len() <= width));
+        // The tap-fd description wraps onto indented continuation lines.
+        let indent = "     --tap-fd=fd".len() + 2;
+        assert!(lines[0].starts_with(" -c, --cpus=N"));
+        assert_eq!(&lines[0][indent..], "Number of CPUs to use. (default: 1)");
+        assert_eq!(&lines[1][indent..indent + 4], "File");
+        assert!(lines[2].starts_with(&" ".repeat(indent)));
+        // Flags too long for the column get their description on the next line.
+        let last = lines.len() - 1;
+        assert_eq!(
+            lines[last - 1].trim(),
+            "--a-flag-with-a-name-too-long-to-fit-the-column"
+        );
+        assert_eq!(lines[last].trim(), "Short help.");
+    }
+
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10G").unwrap(), 10 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("1t").unwrap(), 1024 * 1024 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("18446744073709551615K").is_err());
+    }
 }
@@ -19,7 +19,7 @@ use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use arch::{Pstore, SerialHardware, SerialParameters, VcpuAffinity};
+use arch::{CpuTopology, Pstore, SerialHardware, SerialParameters, VcpuAffinity};
 use devices::virtio::fs::passthrough;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::GpuParameters;
@@ -41,6 +41,8 @@ pub enum Executable {
     Bios(PathBuf),
     /// A elf linux kernel, loaded and executed by crosvm.
     Kernel(PathBuf),
+    /// A file descriptor of an already open elf linux kernel, loaded and executed by crosvm.
+    KernelFd(RawFd),
     /// Path to a plugin executable that is forked by crosvm.
     Plugin(PathBuf),
 }
@@ -50,12 +52,23 @@ pub enum Executable {
 /// This is based on the virtio-block ID length limit.
 pub const DISK_ID_LEN: usize = 20;
 
+/// What a disk does when I/O to the host file backing it fails.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskErrorPolicy {
+    /// Fail the request with an error status to the guest.
+    Report,
+    /// Suspend the VM, leaving the request to be retried once it is resumed.
+    Stop,
+}
+
 pub struct DiskOption {
     pub path: PathBuf,
     pub read_only: bool,
     pub sparse: bool,
     pub block_size: u32,
     pub id: Option<[u8; DISK_ID_LEN]>,
+    pub direct: bool,
+    pub error_policy: DiskErrorPolicy,
 }
 
 pub struct VhostUserOption {
@@ -197,6 +210,7 @@ pub struct Config {
     pub vhost_vsock_device_path: PathBuf,
     pub vhost_net_device_path: PathBuf,
     pub vcpu_count: Option<usize>,
+    pub cpu_topology: Option<CpuTopology>,
     pub rt_cpus: Vec<usize>,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
@@ -207,6 +221,7 @@ pub struct Config {
     pub android_fstab: Option<PathBuf>,
     pub initrd_path: Option<PathBuf>,
     pub params: Vec<String>,
+    pub cmdline_file: Option<PathBuf>,
     pub socket_path: Option<PathBuf>,
     pub plugin_root: Option<PathBuf>,
     pub plugin_mounts: Vec<BindMount>,
@@ -253,7 +268,9 @@ pub struct Config {
     pub battery_type: Option<BatteryType>,
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     pub gdb: Option<u32>,
+    pub balloon: bool,
     pub balloon_bias: i64,
+    pub balloon_size: Option<u64>,
     pub vhost_user_blk: Vec<VhostUserOption>,
     pub vhost_user_fs: Vec<VhostUserFsOption>,
     pub vhost_user_net: Vec<VhostUserOption>,
@@ -273,6 +290,7 @@ impl Default for Config {
             vhost_vsock_device_path: PathBuf::from(VHOST_VSOCK_PATH),
             vhost_net_device_path: PathBuf::from(VHOST_NET_PATH),
             vcpu_count: None,
+            cpu_topology: None,
             rt_cpus: Vec::new(),
             vcpu_affinity: None,
             no_smt: false,
@@ -283,6 +301,7 @@ impl Default for Config {
             android_fstab: None,
             initrd_path: None,
             params: Vec::new(),
+            cmdline_file: None,
             socket_path: None,
             plugin_root: None,
             plugin_mounts: Vec::new(),
@@ -329,7 +348,9 @@ impl Default for Config {
             battery_type: None,
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             gdb: None,
+            balloon: true,
             balloon_bias: 0,
+            balloon_size: None,
             vhost_user_blk: Vec::new(),
             vhost_user_fs: Vec::new(),
             vhost_user_net: Vec::new(),
@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 use std::cmp::{max, min, Reverse};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 #[cfg(feature = "gpu")]
 use std::env;
@@ -10,7 +11,7 @@ use std::error::Error as StdError;
 use std::ffi::CStr;
 use std::fmt::{self, Display};
 use std::fs::{File, OpenOptions};
-use std::io::{self, stdin, Read};
+use std::io::{self, stdin, Read, Write};
 use std::iter;
 use std::mem;
 use std::net::Ipv4Addr;
@@ -60,11 +61,11 @@ use vm_memory::{GuestAddress, GuestMemory, MemoryPolicy};
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 use crate::gdb::{gdb_thread, GdbStub};
 use crate::{
-    Config, DiskOption, Executable, SharedDir, SharedDirKind, TouchDeviceOption, VhostUserFsOption,
-    VhostUserOption,
+    Config, DiskErrorPolicy, DiskOption, Executable, SharedDir, SharedDirKind, TouchDeviceOption,
+    VhostUserFsOption, VhostUserOption,
 };
 use arch::{
-    self, LinuxArch, RunnableLinuxVm, SerialHardware, SerialParameters, VcpuAffinity,
+    self, CpuTopology, LinuxArch, RunnableLinuxVm, SerialHardware, SerialParameters, VcpuAffinity,
     VirtioDeviceStub, VmComponents, VmImage,
 };
 
@@ -490,7 +491,12 @@ fn simple_jail(cfg: &Config, policy: &str) -> Result<Option<Minijail>> {
 
 type DeviceResult<T = VirtioDeviceStub> = std::result::Result<T, Error>;
 
-fn create_block_device(cfg: &Config, disk: &DiskOption, disk_device_tube: Tube) -> DeviceResult {
+fn create_block_device(
+    cfg: &Config,
+    disk: &DiskOption,
+    disk_device_tube: Tube,
+    vm_control_tube: Option<Tube>,
+) -> DeviceResult {
     // Special case '/proc/self/fd/*' paths. The FD is already open, just use it.
     let raw_image: File = if disk.path.parent() == Some(Path::new("/proc/self/fd")) {
         // Safe because we will validate |raw_fd|.
@@ -510,7 +516,12 @@ fn create_block_device(cfg: &Config, disk: &DiskOption, disk_device_tube: Tube)
     };
     flock(&raw_image, lock_op, true).map_err(Error::DiskImageLock)?;
 
-    let dev = if disk::async_ok(&raw_image).map_err(Error::CreateDiskError)? {
+    // Direct I/O needs the bounce buffers of the synchronous disk path, which is also the only one
+    // that can suspend the VM on errors.
+    let dev = if !disk.direct
+        && vm_control_tube.is_none()
+        && disk::async_ok(&raw_image).map_err(Error::CreateDiskError)?
+    {
         let async_file = disk::create_async_disk_file(raw_image).map_err(Error::CreateDiskError)?;
         Box::new(
             virtio::BlockAsync::new(
@@ -525,7 +536,12 @@ fn create_block_device(cfg: &Config, disk: &DiskOption, disk_device_tube: Tube)
             .map_err(Error::BlockDeviceNew)?,
         ) as Box<dyn VirtioDevice>
     } else {
-        let disk_file = disk::create_disk_file(raw_image).map_err(Error::CreateDiskError)?;
+        let disk_file = if disk.direct {
+            disk::create_direct_disk_file(raw_image)
+        } else {
+            disk::create_disk_file(raw_image)
+        }
+        .map_err(Error::CreateDiskError)?;
         Box::new(
             virtio::Block::new(
                 virtio::base_features(cfg.protected_vm),
@@ -535,6 +551,7 @@ fn create_block_device(cfg: &Config, disk: &DiskOption, disk_device_tube: Tube)
                 disk.block_size,
                 disk.id,
                 Some(disk_device_tube),
+                vm_control_tube,
             )
             .map_err(Error::BlockDeviceNew)?,
         ) as Box<dyn VirtioDevice>
@@ -760,8 +777,12 @@ fn create_vinput_device(cfg: &Config, dev_path: &Path) -> DeviceResult {
 }
 
 fn create_balloon_device(cfg: &Config, tube: Tube) -> DeviceResult {
-    let dev = virtio::Balloon::new(virtio::base_features(cfg.protected_vm), tube)
-        .map_err(Error::BalloonDeviceNew)?;
+    let dev = virtio::Balloon::new(
+        virtio::base_features(cfg.protected_vm),
+        tube,
+        cfg.balloon_size.unwrap_or(0),
+    )
+    .map_err(Error::BalloonDeviceNew)?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -1371,9 +1392,10 @@ fn create_virtio_devices(
     vm: &mut impl Vm,
     resources: &mut SystemAllocator,
     _exit_evt: &Event,
+    control_tubes: &mut Vec<TaggedControlTube>,
     wayland_device_tube: Tube,
     gpu_device_tube: Tube,
-    balloon_device_tube: Tube,
+    balloon_device_tube: Option<Tube>,
     disk_device_tubes: &mut Vec<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
@@ -1392,7 +1414,20 @@ fn create_virtio_devices(
 
     for disk in &cfg.disks {
         let disk_device_tube = disk_device_tubes.remove(0);
-        devs.push(create_block_device(cfg, disk, disk_device_tube)?);
+        let vm_control_tube = match disk.error_policy {
+            DiskErrorPolicy::Report => None,
+            DiskErrorPolicy::Stop => {
+                let (host_tube, device_tube) = Tube::pair().map_err(Error::CreateTube)?;
+                control_tubes.push(TaggedControlTube::Vm(host_tube));
+                Some(device_tube)
+            }
+        };
+        devs.push(create_block_device(
+            cfg,
+            disk,
+            disk_device_tube,
+            vm_control_tube,
+        )?);
     }
 
     for blk in &cfg.vhost_user_blk {
@@ -1448,7 +1483,9 @@ fn create_virtio_devices(
         devs.push(create_vinput_device(cfg, dev_path)?);
     }
 
-    devs.push(create_balloon_device(cfg, balloon_device_tube)?);
+    if let Some(balloon_device_tube) = balloon_device_tube {
+        devs.push(create_balloon_device(cfg, balloon_device_tube)?);
+    }
 
     // We checked above that if the IP is defined, then the netmask is, too.
     for tap_fd in &cfg.tap_fd {
@@ -1628,7 +1665,7 @@ fn create_devices(
     control_tubes: &mut Vec<TaggedControlTube>,
     wayland_device_tube: Tube,
     gpu_device_tube: Tube,
-    balloon_device_tube: Tube,
+    balloon_device_tube: Option<Tube>,
     disk_device_tubes: &mut Vec<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
@@ -1641,6 +1678,7 @@ fn create_devices(
         vm,
         resources,
         exit_evt,
+        control_tubes,
         wayland_device_tube,
         gpu_device_tube,
         balloon_device_tube,
@@ -1823,6 +1861,7 @@ fn runnable_vcpu<V>(
     run_rt: bool,
     vcpu_affinity: Vec<usize>,
     no_smt: bool,
+    cpu_topology: Option<CpuTopology>,
     has_bios: bool,
     use_hypervisor_signals: bool,
 ) -> Result<(V, VcpuRunHandle)>
@@ -1864,6 +1903,7 @@ where
         vcpu_count,
         has_bios,
         no_smt,
+        cpu_topology,
     )
     .map_err(Error::ConfigureVcpu)?;
 
@@ -1979,6 +2019,7 @@ fn run_vcpu<V>(
     run_rt: bool,
     vcpu_affinity: Vec<usize>,
     no_smt: bool,
+    cpu_topology: Option<CpuTopology>,
     start_barrier: Arc<Barrier>,
     has_bios: bool,
     io_bus: devices::Bus,
@@ -2012,6 +2053,7 @@ where
                 run_rt,
                 vcpu_affinity,
                 no_smt,
+                cpu_topology,
                 has_bios,
                 use_hypervisor_signals,
             );
@@ -2095,6 +2137,15 @@ where
                                         VmRunMode::Exiting => break 'vcpu_loop,
                                     }
                                 }
+                                VcpuControl::DumpState(reply) => {
+                                    let state = Arch::dump_vcpu_registers(&vcpu)
+                                        .unwrap_or_else(|e| {
+                                            format!("failed to read registers: {}", e)
+                                        });
+                                    if let Err(e) = reply.send((cpu_id, state)) {
+                                        error!("failed to send vcpu {} state: {}", cpu_id, e);
+                                    }
+                                }
                                 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
                                 VcpuControl::Debug(d) => {
                                     match &to_gdb_tube {
@@ -2325,6 +2376,14 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         Some(Executable::Kernel(ref kernel_path)) => VmImage::Kernel(
             File::open(kernel_path).map_err(|e| Error::OpenKernel(kernel_path.to_path_buf(), e))?,
         ),
+        Some(Executable::KernelFd(kernel_fd)) => VmImage::Kernel(
+            // Safe because we ensure that we get a unique handle to the fd.
+            unsafe {
+                File::from_raw_descriptor(
+                    validate_raw_descriptor(kernel_fd).map_err(Error::ValidateRawDescriptor)?,
+                )
+            },
+        ),
         Some(Executable::Bios(ref bios_path)) => VmImage::Bios(
             File::open(bios_path).map_err(|e| Error::OpenBios(bios_path.to_path_buf(), e))?,
         ),
@@ -2340,6 +2399,7 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         vcpu_count: cfg.vcpu_count.unwrap_or(1),
         vcpu_affinity: cfg.vcpu_affinity.clone(),
         no_smt: cfg.no_smt,
+        cpu_topology: cfg.cpu_topology,
         hugepages: cfg.hugepages,
         vm_image,
         android_fstab: cfg
@@ -2414,7 +2474,12 @@ where
     let (wayland_host_tube, wayland_device_tube) = Tube::pair().map_err(Error::CreateTube)?;
     control_tubes.push(TaggedControlTube::VmMemory(wayland_host_tube));
     // Balloon gets a special socket so balloon requests can be forwarded from the main process.
-    let (balloon_host_tube, balloon_device_tube) = Tube::pair().map_err(Error::CreateTube)?;
+    let (balloon_host_tube, balloon_device_tube) = if cfg.balloon {
+        let (host, device) = Tube::pair().map_err(Error::CreateTube)?;
+        (Some(host), Some(device))
+    } else {
+        (None, None)
+    };
 
     // Create one control socket per disk.
     let mut disk_device_tubes = Vec::new();
@@ -2594,6 +2659,59 @@ fn kick_all_vcpus(
     irq_chip.kick_halted_vcpus();
 }
 
+// Writes the registers of each vcpu and a summary of the guest memory map for
+// `VmRequest::DumpState`. Vcpus that don't answer within a second are listed without registers.
+fn dump_vm_state(
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+    irq_chip: &impl IrqChip,
+    guest_mem: &GuestMemory,
+    path: &Path,
+    file: Option<File>,
+) -> io::Result<()> {
+    let mut file = match file {
+        Some(file) => file,
+        None => File::create(path)?,
+    };
+
+    let (reply_tx, reply_rx) = mpsc::channel();
+    for (handle, tube) in vcpu_handles {
+        if let Err(e) = tube.send(VcpuControl::DumpState(reply_tx.clone())) {
+            error!("failed to send DumpState: {}", e);
+        }
+        let _ = handle.kill(SIGRTMIN() + 0);
+    }
+    irq_chip.kick_halted_vcpus();
+    drop(reply_tx);
+
+    let mut vcpu_states = BTreeMap::new();
+    while vcpu_states.len() < vcpu_handles.len() {
+        match reply_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok((cpu_id, state)) => {
+                vcpu_states.insert(cpu_id, state);
+            }
+            Err(_) => break,
+        }
+    }
+    for cpu_id in 0..vcpu_handles.len() {
+        match vcpu_states.get(&cpu_id) {
+            Some(state) => writeln!(file, "vcpu {}:\n{}", cpu_id, state)?,
+            None => writeln!(file, "vcpu {}: no response", cpu_id)?,
+        }
+    }
+
+    writeln!(file, "guest memory:")?;
+    guest_mem.with_regions::<_, io::Error>(|index, guest_addr, size, _, _, _| {
+        writeln!(
+            file,
+            "region {}: {:#x}-{:#x}",
+            index,
+            guest_addr.offset(),
+            guest_addr.offset() + size as u64
+        )
+    })?;
+    file.flush()
+}
+
 // BalloonPolicy determines the size to set the balloon.
 struct BalloonPolicy {
     // Estimate for when the guest starts aggressivly freeing memory.
@@ -2762,7 +2880,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
     mut linux: RunnableLinuxVm<V, Vcpu, I>,
     control_server_socket: Option<UnlinkUnixSeqpacketListener>,
     mut control_tubes: Vec<TaggedControlTube>,
-    balloon_host_tube: Tube,
+    balloon_host_tube: Option<Tube>,
     disk_host_tubes: &[Tube],
     usb_control_tube: Tube,
     sigchld_fd: SignalFd,
@@ -2818,7 +2936,10 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
 
     // Balance available memory between guest and host every second.
     let mut balancemem_timer = Timer::new().map_err(Error::CreateTimer)?;
-    let mut balloon_policy = if let Ok(critical_margin) = file_to_i64(LOWMEM_MARGIN, 0) {
+    let lowmem_margin = file_to_i64(LOWMEM_MARGIN, 0);
+    let mut balloon_policy = if let (Some(balloon_host_tube), Ok(critical_margin)) =
+        (balloon_host_tube.as_ref(), lowmem_margin.as_ref())
+    {
         // Create timer request balloon stats every 1s.
         wait_ctx
             .add(&balancemem_timer, Token::BalanceMemory)
@@ -2831,7 +2952,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
 
         // Listen for balloon statistics from the guest so we can balance.
         wait_ctx
-            .add(&balloon_host_tube, Token::BalloonResult)
+            .add(balloon_host_tube, Token::BalloonResult)
             .map_err(Error::WaitContextAdd)?;
         Some(BalloonPolicy::new(
             linux.vm.get_memory().memory_size() as i64,
@@ -2839,7 +2960,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             balloon_bias,
         ))
     } else {
-        warn!("Unable to open low mem margin, maybe not a chrome os kernel");
+        if lowmem_margin.is_err() {
+            warn!("Unable to open low mem margin, maybe not a chrome os kernel");
+        }
         None
     };
 
@@ -2885,6 +3008,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             linux.rt_cpus.contains(&cpu_id),
             vcpu_affinity,
             linux.no_smt,
+            linux.cpu_topology,
             vcpu_thread_barrier.clone(),
             linux.has_bios,
             linux.io_bus.clone(),
@@ -2969,46 +3093,52 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                 Token::BalanceMemory => {
                     balancemem_timer.wait().map_err(Error::Timer)?;
                     let command = BalloonControlCommand::Stats {};
-                    if let Err(e) = balloon_host_tube.send(&command) {
-                        warn!("failed to send stats request to balloon device: {}", e);
+                    if let Some(balloon_host_tube) = &balloon_host_tube {
+                        if let Err(e) = balloon_host_tube.send(&command) {
+                            warn!("failed to send stats request to balloon device: {}", e);
+                        }
                     }
                 }
                 Token::BalloonResult => {
-                    match balloon_host_tube.recv() {
-                        Ok(BalloonControlResult::Stats {
-                            stats,
-                            balloon_actual: balloon_actual_u,
-                        }) => {
-                            match balloon_policy
-                                .as_mut()
-                                .map(|p| p.delta(stats, balloon_actual_u))
-                            {
-                                None => {
-                                    error!(
-                                        "got result from balloon stats, but no policy is running"
-                                    );
-                                }
-                                Some(Err(e)) => {
-                                    warn!("failed to run balloon policy {}", e);
-                                }
-                                Some(Ok(delta)) if delta != 0 => {
-                                    let target = max((balloon_actual_u as i64) + delta, 0) as u64;
-                                    let command =
-                                        BalloonControlCommand::Adjust { num_bytes: target };
-                                    if let Err(e) = balloon_host_tube.send(&command) {
-                                        warn!(
-                                            "failed to send memory value to balloon device: {}",
-                                            e
+                    // Only registered when there is a balloon device.
+                    if let Some(balloon_host_tube) = &balloon_host_tube {
+                        match balloon_host_tube.recv() {
+                            Ok(BalloonControlResult::Stats {
+                                stats,
+                                balloon_actual: balloon_actual_u,
+                            }) => {
+                                match balloon_policy
+                                    .as_mut()
+                                    .map(|p| p.delta(stats, balloon_actual_u))
+                                {
+                                    None => {
+                                        error!(
+                                            "got result from balloon stats, but no policy is running"
                                         );
                                     }
+                                    Some(Err(e)) => {
+                                        warn!("failed to run balloon policy {}", e);
+                                    }
+                                    Some(Ok(delta)) if delta != 0 => {
+                                        let target =
+                                            max((balloon_actual_u as i64) + delta, 0) as u64;
+                                        let command =
+                                            BalloonControlCommand::Adjust { num_bytes: target };
+                                        if let Err(e) = balloon_host_tube.send(&command) {
+                                            warn!(
+                                                "failed to send memory value to balloon device: {}",
+                                                e
+                                            );
+                                        }
+                                    }
+                                    Some(Ok(_)) => {}
                                 }
-                                Some(Ok(_)) => {}
                             }
-                        }
-                        Err(e) => {
-                            error!("failed to recv BalloonControlResult: {}", e);
-                        }
-                    };
+                            Err(e) => {
+                                error!("failed to recv BalloonControlResult: {}", e);
+                            }
+                        };
+                    }
                 }
                 Token::VmControlServer => {
                     if let Some(socket_server) = &control_server_socket {
@@ -3034,13 +3164,34 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                             TaggedControlTube::Vm(tube) => match tube.recv::<VmRequest>() {
                                 Ok(request) => {
                                     let mut run_mode_opt = None;
-                                    let response = request.execute(
-                                        &mut run_mode_opt,
-                                        &balloon_host_tube,
-                                        disk_host_tubes,
-                                        &usb_control_tube,
-                                        &mut linux.bat_control,
-                                    );
+                                    let response = match request {
+                                        VmRequest::DumpState { path, file } => {
+                                            match dump_vm_state(
+                                                &vcpu_handles,
+                                                &linux.irq_chip,
+                                                linux.vm.get_memory(),
+                                                &path,
+                                                file.map(|f| f.0),
+                                            ) {
+                                                Ok(()) => VmResponse::Ok,
+                                                Err(e) => {
+                                                    error!(
+                                                        "failed to dump state to {}: {}",
+                                                        path.display(),
+                                                        e
+                                                    );
+                                                    VmResponse::Err(e.into())
+                                                }
+                                            }
+                                        }
+                                        request => request.execute(
+                                            &mut run_mode_opt,
+                                            balloon_host_tube.as_ref(),
+                                            disk_host_tubes,
+                                            &usb_control_tube,
+                                            &mut linux.bat_control,
+                                        ),
+                                    };
                                     if let Err(e) = tube.send(&response) {
                                         error!("failed to send VmResponse: {}", e);
                                     }
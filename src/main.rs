@@ -8,8 +8,11 @@ pub mod panic_hook;
 
 use std::collections::BTreeMap;
 use std::default::Default;
+use std::fmt::{self, Display};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::String;
@@ -17,29 +20,31 @@ use std::thread::sleep;
 use std::time::Duration;
 
 use arch::{
-    set_default_serial_parameters, Pstore, SerialHardware, SerialParameters, SerialType,
-    VcpuAffinity,
+    set_default_serial_parameters, CpuTopology, Pstore, SerialHardware, SerialParameters,
+    SerialType, VcpuAffinity,
 };
 use base::{debug, error, getpid, info, kill_process_group, reap_child, syslog, warn};
 #[cfg(feature = "direct")]
 use crosvm::DirectIoOption;
 use crosvm::{
-    argument::{self, print_help, set_arguments, Argument},
-    platform, BindMount, Config, DiskOption, Executable, GidMap, SharedDir, TouchDeviceOption,
-    VhostUserFsOption, VhostUserOption, DISK_ID_LEN,
+    argument::{self, parse_size, print_help, set_arguments, Argument},
+    platform, BindMount, Config, DiskErrorPolicy, DiskOption, Executable, GidMap, SharedDir,
+    TouchDeviceOption, VhostUserFsOption, VhostUserOption, DISK_ID_LEN,
 };
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::{GpuMode, GpuParameters};
 use devices::ProtectionType;
 #[cfg(feature = "audio")]
 use devices::{Ac97Backend, Ac97Parameters};
-use disk::QcowFile;
+use disk::{ImageType, QcowFile};
+use net_util::MacAddress;
 use vm_control::{
     client::{
-        do_modify_battery, do_usb_attach, do_usb_detach, do_usb_list, handle_request, vms_request,
-        ModifyUsbError, ModifyUsbResult,
+        do_modify_battery, do_ping, do_stop_with_timeout, do_usb_attach, do_usb_detach,
+        do_usb_list, handle_request, vms_request, ModifyUsbError, ModifyUsbResult,
     },
-    BalloonControlCommand, BatteryType, DiskControlCommand, UsbControlResult, VmRequest,
+    BalloonControlCommand, BatteryType, DiskControlCommand, DumpStateFile, UsbControlResult,
+    VmRequest, VmResponse,
 };
 
 fn executable_is_plugin(executable: &Option<Executable>) -> bool {
@@ -74,6 +79,35 @@ fn wait_all_children() -> bool {
     false
 }
 
+// Parses a guest memory size in MiB. A bare number is a count of MiB for backward compatibility,
+// while a number with a K, M, G, or T suffix is a size in bytes that must be a whole number of MiB.
+fn parse_mem_size(s: &str) -> argument::Result<u64> {
+    if let Ok(mib) = s.parse() {
+        return Ok(mib);
+    }
+    let bytes = parse_size(s)?;
+    if bytes % (1 << 20) != 0 {
+        return Err(argument::Error::InvalidValue {
+            value: s.to_owned(),
+            expected: String::from("this value for `mem` must be a whole number of MiB"),
+        });
+    }
+    Ok(bytes >> 20)
+}
+
+// Parses a balloon size given either as a number of 4 KiB pages or with a size suffix.
+fn parse_balloon_size(s: &str) -> argument::Result<u64> {
+    if let Ok(pages) = s.parse::<u64>() {
+        return pages
+            .checked_mul(4096)
+            .ok_or_else(|| argument::Error::InvalidValue {
+                value: s.to_owned(),
+                expected: String::from("this value for `balloon-size` is too large"),
+            });
+    }
+    parse_size(s)
+}
+
 /// Parse a comma-separated list of CPU numbers and ranges and convert it to a Vec of CPU numbers.
 fn parse_cpu_set(s: &str) -> argument::Result<Vec<usize>> {
     let mut cpuset = Vec::new();
@@ -153,6 +187,41 @@ fn parse_cpu_affinity(s: &str) -> argument::Result<VcpuAffinity> {
     }
 }
 
+fn parse_cpu_topology(s: &str) -> argument::Result<CpuTopology> {
+    let mut topology = CpuTopology {
+        sockets: 1,
+        cores: 1,
+        threads: 1,
+    };
+
+    for opt in s.split(',') {
+        let mut kv = opt.splitn(2, '=');
+        let k = kv.next().unwrap_or("");
+        let v = kv.next().unwrap_or("");
+        let count = v
+            .parse()
+            .ok()
+            .filter(|&count: &usize| count > 0)
+            .ok_or_else(|| argument::Error::InvalidValue {
+                value: v.to_owned(),
+                expected: format!("`{}` must be a positive integer", k),
+            })?;
+        match k {
+            "sockets" => topology.sockets = count,
+            "cores" => topology.cores = count,
+            "threads" => topology.threads = count,
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown cpu-topology parameter {}",
+                    k
+                )));
+            }
+        }
+    }
+
+    Ok(topology)
+}
+
 #[cfg(feature = "gpu")]
 fn parse_gpu_options(s: Option<&str>) -> argument::Result<GpuParameters> {
     let mut gpu_params: GpuParameters = Default::default();
@@ -556,6 +625,16 @@ fn parse_serial_options(s: &str) -> argument::Result<SerialParameters> {
         });
     }
 
+    match serial_setting.type_ {
+        SerialType::File | SerialType::UnixSocket if serial_setting.path.is_none() => {
+            return Err(argument::Error::InvalidValue {
+                value: serial_setting.type_.to_string(),
+                expected: String::from("serial type requires a `path`"),
+            });
+        }
+        _ => {}
+    }
+
     Ok(serial_setting)
 }
 
@@ -741,6 +820,85 @@ fn parse_direct_io_options(s: Option<&str>) -> argument::Result<DirectIoOption>
     })
 }
 
+// Reads the arguments in the config file at `path`, one `key=value` (or `key` for flags) per line.
+// Blank lines and `#` comments are ignored. Each argument is returned in its `--key=value` form so it
+// can be fed through `set_arguments` like the command line.
+fn read_config_file(path: &Path) -> argument::Result<Vec<String>> {
+    let file = File::open(path).map_err(|_| argument::Error::InvalidValue {
+        value: path.to_string_lossy().into_owned(),
+        expected: String::from("unable to open `cfg` file"),
+    })?;
+    let reader = BufReader::new(file);
+    let mut config_args = Vec::new();
+    for l in reader.lines() {
+        let line = l.map_err(|_| argument::Error::InvalidValue {
+            value: path.to_string_lossy().into_owned(),
+            expected: String::from("unable to read `cfg` file"),
+        })?;
+        let trimmed_line = line.splitn(2, '#').next().unwrap().trim();
+        if trimmed_line.is_empty() {
+            continue;
+        }
+        let mut kv = trimmed_line.splitn(2, '=');
+        let key = kv.next().unwrap().trim();
+        match kv.next() {
+            Some(value) => config_args.push(format!("--{}={}", key, value.trim())),
+            None => config_args.push(format!("--{}", key)),
+        }
+    }
+    Ok(config_args)
+}
+
+// Applies the arguments in the config file at `path` to `cfg`. Arguments named in `cli_names` were
+// given on the command line and are skipped so that the command line takes precedence.
+fn apply_config_file(
+    cfg: &mut Config,
+    path: &Path,
+    arguments: &[Argument],
+    cli_names: &[String],
+) -> argument::Result<()> {
+    let config_args = read_config_file(path)?;
+    set_arguments(config_args.iter(), arguments, |name, value| {
+        if name == "cfg" {
+            return Err(argument::Error::InvalidValue {
+                value: value.unwrap_or("").to_owned(),
+                expected: String::from("`cfg` can not be given inside a config file"),
+            });
+        }
+        if cli_names.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        set_argument(cfg, name, value)
+    })
+}
+
+// Returns the letters the guest kernel gives the virtio block device at `index`, following the same
+// scheme as sd devices: a..z, then aa..az, ba..bz, and so on.
+fn virtio_blk_letters(index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut n = index + 1;
+    while n > 0 {
+        n -= 1;
+        letters.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    letters.reverse();
+    // unwrap is safe as only ASCII letters were pushed.
+    String::from_utf8(letters).unwrap()
+}
+
+// Reads the whitespace separated kernel command line parameters in `path`. Lines starting with `#`
+// are comments.
+fn read_cmdline_file(path: &Path) -> std::io::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split_whitespace())
+        .map(String::from)
+        .collect())
+}
+
 fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::Result<()> {
     match name {
         "" => {
@@ -759,6 +917,30 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             }
             cfg.executable_path = Some(Executable::Kernel(kernel_path));
         }
+        "kernel-fd" => {
+            if cfg.executable_path.is_some() {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "A VM executable was already specified: {:?}",
+                    cfg.executable_path
+                )));
+            }
+            // A plain parse of the signed `RawFd` would accept negative values.
+            let kernel_fd = value
+                .unwrap()
+                .parse::<RawFd>()
+                .ok()
+                .filter(|&fd| fd >= 0)
+                .ok_or_else(|| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from(
+                        "this value for `kernel-fd` must be an unsigned integer",
+                    ),
+                })?;
+            cfg.executable_path = Some(Executable::KernelFd(kernel_fd));
+        }
+        "cfg" => {
+            // The config file is applied by `run_vm` before the rest of the command line.
+        }
         "kvm-device" => {
             let kvm_device_path = PathBuf::from(value.unwrap());
             if !kvm_device_path.exists() {
@@ -813,6 +995,22 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
         "params" => {
             cfg.params.push(value.unwrap().to_owned());
         }
+        "cmdline-file" => {
+            if cfg.cmdline_file.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`cmdline-file` already given".to_owned(),
+                ));
+            }
+            let cmdline_file = PathBuf::from(value.unwrap());
+            let file_params =
+                read_cmdline_file(&cmdline_file).map_err(|e| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: format!("a readable kernel command line file: {}", e),
+                })?;
+            // Parameters from the file come before any given by `params`.
+            cfg.params.splice(0..0, file_params);
+            cfg.cmdline_file = Some(cmdline_file);
+        }
         "cpus" => {
             if cfg.vcpu_count.is_some() {
                 return Err(argument::Error::TooManyArguments(
@@ -830,6 +1028,14 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                         })?,
                 )
         }
+        "cpu-topology" => {
+            if cfg.cpu_topology.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`cpu-topology` already given".to_owned(),
+                ));
+            }
+            cfg.cpu_topology = Some(parse_cpu_topology(value.unwrap())?);
+        }
         "cpu-affinity" => {
             if cfg.vcpu_affinity.is_some() {
                 return Err(argument::Error::TooManyArguments(
@@ -855,16 +1061,7 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     "`mem` already given".to_owned(),
                 ));
             }
-            cfg.memory =
-                Some(
-                    value
-                        .unwrap()
-                        .parse()
-                        .map_err(|_| argument::Error::InvalidValue {
-                            value: value.unwrap().to_owned(),
-                            expected: String::from("this value for `mem` needs to be integer"),
-                        })?,
-                )
+            cfg.memory = Some(parse_mem_size(value.unwrap())?);
         }
         "hugepages" => {
             cfg.hugepages = true;
@@ -964,14 +1161,9 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                 });
             }
             if name.ends_with("root") {
-                if cfg.disks.len() >= 26 {
-                    return Err(argument::Error::TooManyArguments(
-                        "ran out of letters for to assign to root disk".to_owned(),
-                    ));
-                }
                 cfg.params.push(format!(
                     "root=/dev/vd{} {}",
-                    char::from(b'a' + cfg.disks.len() as u8),
+                    virtio_blk_letters(cfg.disks.len()),
                     if read_only { "ro" } else { "rw" }
                 ));
             }
@@ -982,6 +1174,8 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                 sparse: true,
                 block_size: 512,
                 id: None,
+                direct: false,
+                error_policy: DiskErrorPolicy::Report,
             };
 
             for opt in components {
@@ -1004,11 +1198,17 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                         disk.sparse = sparse;
                     }
                     "block_size" => {
-                        let block_size =
+                        let block_size: u32 =
                             value.parse().map_err(|_| argument::Error::InvalidValue {
                                 value: value.to_owned(),
                                 expected: String::from("`block_size` must be an integer"),
                             })?;
+                        if !block_size.is_power_of_two() {
+                            return Err(argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`block_size` must be a power of two"),
+                            });
+                        }
                         disk.block_size = block_size;
                     }
                     "id" => {
@@ -1027,6 +1227,27 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                         id[..value.len()].copy_from_slice(value.as_bytes());
                         disk.id = Some(id);
                     }
+                    "direct" => {
+                        let direct = value.parse().map_err(|_| argument::Error::InvalidValue {
+                            value: value.to_owned(),
+                            expected: String::from("`direct` must be a boolean"),
+                        })?;
+                        disk.direct = direct;
+                    }
+                    "on_error" => {
+                        disk.error_policy = match value {
+                            "report" => DiskErrorPolicy::Report,
+                            "stop" => DiskErrorPolicy::Stop,
+                            _ => {
+                                return Err(argument::Error::InvalidValue {
+                                    value: value.to_owned(),
+                                    expected: String::from(
+                                        "`on_error` must be one of `report` or `stop`",
+                                    ),
+                                })
+                            }
+                        };
+                    }
                     _ => {
                         return Err(argument::Error::InvalidValue {
                             value: kind.to_owned(),
@@ -1053,6 +1274,8 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                 sparse: false,
                 block_size: base::pagesize() as u32,
                 id: None,
+                direct: false,
+                error_policy: DiskErrorPolicy::Report,
             });
         }
         "pstore" => {
@@ -1255,15 +1478,27 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     "`cid` alread given".to_owned(),
                 ));
             }
-            cfg.cid = Some(
-                value
-                    .unwrap()
-                    .parse()
-                    .map_err(|_| argument::Error::InvalidValue {
-                        value: value.unwrap().to_owned(),
-                        expected: String::from("this value for `cid` must be an unsigned integer"),
-                    })?,
-            );
+            let cid: u64 = value
+                .unwrap()
+                .parse()
+                .map_err(|_| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this value for `cid` must be an unsigned integer"),
+                })?;
+            // CIDs 0, 1, and 2 are reserved for the hypervisor, local loopback, and host.
+            if cid < 3 {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("`cid` must be at least 3 (0-2 are reserved)"),
+                });
+            }
+            if cid > u64::from(u32::MAX) {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("`cid` must fit in 32 bits"),
+                });
+            }
+            cfg.cid = Some(cid);
         }
         "shared-dir" => {
             // This is formatted as multiple fields, each separated by ":". The first 2 fields are
@@ -1296,6 +1531,12 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     expected: String::from("missing tag for `shared-dir`"),
                 })?
                 .to_owned();
+            if tag.is_empty() {
+                return Err(argument::Error::InvalidValue {
+                    value: param.to_owned(),
+                    expected: String::from("tag for `shared-dir` must not be empty"),
+                });
+            }
 
             if !src.is_dir() {
                 return Err(argument::Error::InvalidValue {
@@ -1581,7 +1822,14 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             cfg.split_irqchip = true;
         }
         "initrd" => {
-            cfg.initrd_path = Some(PathBuf::from(value.unwrap().to_owned()));
+            let initrd_path = PathBuf::from(value.unwrap());
+            if !initrd_path.exists() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this initrd path does not exist"),
+                });
+            }
+            cfg.initrd_path = Some(initrd_path);
         }
         "bios" => {
             if cfg.executable_path.is_some() {
@@ -1651,6 +1899,9 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                 })?;
             cfg.gdb = Some(port);
         }
+        "no-balloon" => {
+            cfg.balloon = false;
+        }
         "balloon_bias_mib" => {
             cfg.balloon_bias =
                 value
@@ -1663,6 +1914,14 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     * 1024
                     * 1024; // cfg.balloon_bias is in bytes.
         }
+        "balloon-size" => {
+            if cfg.balloon_size.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`balloon-size` already given".to_owned(),
+                ));
+            }
+            cfg.balloon_size = Some(parse_balloon_size(value.unwrap())?);
+        }
         "vhost-user-blk" => cfg.vhost_user_blk.push(VhostUserOption {
             socket: PathBuf::from(value.unwrap()),
         }),
@@ -1757,11 +2016,51 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
     Ok(())
 }
 
+// Makes a locally administered unicast MAC address for when `mac` isn't given. The address is
+// derived from the vsock CID when there is one, so a VM keeps the same address across runs.
+fn generate_mac_address(cid: Option<u64>) -> std::result::Result<MacAddress, argument::Error> {
+    let mut octets = [0u8; 6];
+    match cid {
+        // The low 40 bits of the CID fill the octets after the first, which holds the flag bits.
+        Some(cid) => octets[1..].copy_from_slice(&cid.to_be_bytes()[3..]),
+        None => File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(&mut octets))
+            .map_err(|e| {
+                argument::Error::ExpectedArgument(format!(
+                    "`mac` missing and a random one couldn't be generated: {}",
+                    e
+                ))
+            })?,
+    }
+    Ok(MacAddress::local_unicast(octets))
+}
+
 fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Error> {
     if cfg.executable_path.is_none() {
-        return Err(argument::Error::ExpectedArgument("`KERNEL`".to_owned()));
+        return Err(argument::Error::ExpectedArgument(
+            "`KERNEL` or `kernel-fd`".to_owned(),
+        ));
+    }
+    if let Some(topology) = cfg.cpu_topology {
+        match cfg.vcpu_count {
+            Some(count) if count != topology.vcpu_count() => {
+                return Err(argument::Error::InvalidValue {
+                    value: count.to_string(),
+                    expected: format!(
+                        "`cpus` must match the {} vcpus given by `cpu-topology`",
+                        topology.vcpu_count()
+                    ),
+                });
+            }
+            _ => cfg.vcpu_count = Some(topology.vcpu_count()),
+        }
     }
     if cfg.host_ip.is_some() || cfg.netmask.is_some() || cfg.mac_address.is_some() {
+        if !cfg.tap_fd.is_empty() {
+            return Err(argument::Error::TooManyArguments(
+                "`tap-fd` can't be combined with `host_ip`, `netmask`, or `mac`".to_owned(),
+            ));
+        }
         if cfg.host_ip.is_none() {
             return Err(argument::Error::ExpectedArgument(
                 "`host_ip` missing from network config".to_owned(),
@@ -1773,9 +2072,26 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
             ));
         }
         if cfg.mac_address.is_none() {
-            return Err(argument::Error::ExpectedArgument(
-                "`mac` missing from network config".to_owned(),
-            ));
+            cfg.mac_address = Some(generate_mac_address(cfg.cid)?);
+        }
+    }
+    if !cfg.balloon && cfg.balloon_size.is_some() {
+        return Err(argument::Error::InvalidValue {
+            value: "no-balloon".to_owned(),
+            expected: "`balloon-size` can't be combined with `no-balloon`".to_owned(),
+        });
+    }
+    if let Some(balloon_size) = cfg.balloon_size {
+        // Guest memory defaults to 256 MiB when `mem` isn't given.
+        let memory_size = cfg.memory.unwrap_or(256).saturating_mul(1 << 20);
+        if balloon_size >= memory_size {
+            return Err(argument::Error::InvalidValue {
+                value: balloon_size.to_string(),
+                expected: format!(
+                    "`balloon-size` must be less than the {} bytes of guest memory",
+                    memory_size
+                ),
+            });
         }
     }
     if cfg.plugin_root.is_some() && !executable_is_plugin(&cfg.executable_path) {
@@ -1783,6 +2099,19 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
             "`plugin-root` requires `plugin`".to_owned(),
         ));
     }
+    // `root` and `rwroot` add a root= parameter of their own, which would conflict with one given
+    // in `params`.
+    let root_params = cfg
+        .params
+        .iter()
+        .flat_map(|p| p.split_whitespace())
+        .filter(|p| p.starts_with("root="))
+        .count();
+    if root_params > 1 {
+        return Err(argument::Error::TooManyArguments(
+            "`root=` given more than once by `root`, `rwroot`, or `params`".to_owned(),
+        ));
+    }
     #[cfg(feature = "gpu")]
     {
         if let Some(gpu_parameters) = cfg.gpu_parameters.as_ref() {
@@ -1810,6 +2139,8 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
 fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
     let arguments =
         &[Argument::positional("KERNEL", "bzImage of kernel to run"),
+          Argument::value("kernel-fd", "FD", "File descriptor of an already open kernel image to run instead of `KERNEL`."),
+          Argument::value("cfg", "PATH", "Path to a file of `key=value` arguments, one per line. Arguments given on the command line take precedence."),
           Argument::value("kvm-device", "PATH", "Path to the KVM device. (default /dev/kvm)"),
           Argument::value("vhost-vsock-device", "PATH", "Path to the vhost-vsock device. (default /dev/vhost-vsock)"),
           Argument::value("vhost-net-device", "PATH", "Path to the vhost-net device. (default /dev/vhost-net)"),
@@ -1819,7 +2150,9 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                                 "params",
                                 "PARAMS",
                                 "Extra kernel or plugin command line arguments. Can be given more than once."),
+          Argument::value("cmdline-file", "PATH", "File of extra kernel command line arguments, separated by whitespace. Lines starting with '#' are ignored. The arguments come before any given by `params`."),
           Argument::short_value('c', "cpus", "N", "Number of VCPUs. (default: 1)"),
+          Argument::value("cpu-topology", "sockets=S,cores=C,threads=T", "Sockets, cores per socket, and threads per core of the VCPUs. Omitted values default to 1. Sets the number of VCPUs, which must match `cpus` if also given."),
           Argument::value("cpu-affinity", "CPUSET", "Comma-separated list of CPUs or CPU ranges to run VCPUs on (e.g. 0,1-3,5)
                               or colon-separated list of assignments of guest to host CPU assignments (e.g. 0=0:1=1:2=2) (default: no mask)"),
           Argument::flag("no-smt", "Don't use SMT in the guest"),
@@ -1827,7 +2160,7 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
           Argument::short_value('m',
                                 "mem",
                                 "N",
-                                "Amount of guest memory in MiB. (default: 256)"),
+                                "Amount of guest memory in MiB, or in bytes if followed by K, M, G, or T (e.g. 2G). (default: 256)"),
           Argument::flag("hugepages", "Advise the kernel to use Huge Pages for guest memory mappings."),
           Argument::short_value('r',
                                 "root",
@@ -1840,8 +2173,10 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
           Argument::short_value('d', "disk", "PATH[,key=value[,key=value[,...]]", "Path to a disk image followed by optional comma-separated options.
                               Valid keys:
                               sparse=BOOL - Indicates whether the disk should support the discard operation (default: true)
-                              block_size=BYTES - Set the reported block size of the disk (default: 512)
-                              id=STRING - Set the block device identifier to an ASCII string, up to 20 characters (default: no ID)"),
+                              block_size=BYTES - Set the reported block size of the disk, must be a power of two (default: 512)
+                              id=STRING - Set the block device identifier to an ASCII string, up to 20 characters (default: no ID)
+                              direct=BOOL - Open a raw disk image with O_DIRECT, bypassing the host page cache (default: false)
+                              on_error=(report,stop) - On a host I/O error, either fail the request in the guest or suspend the VM (default: report)"),
           Argument::value("rwdisk", "PATH[,key=value[,key=value[,...]]", "Path to a writable disk image followed by optional comma-separated options.
                               See --disk for valid options."),
           Argument::value("rw-pmem-device", "PATH", "Path to a writable disk image."),
@@ -1851,7 +2186,7 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                           "IP",
                           "IP address to assign to host tap interface."),
           Argument::value("netmask", "NETMASK", "Netmask for VM subnet."),
-          Argument::value("mac", "MAC", "MAC address for VM."),
+          Argument::value("mac", "MAC", "MAC address for VM. A locally administered address derived from the CID, or random without one, is used if omitted."),
           Argument::value("net-vq-pairs", "N", "virtio net virtual queue paris. (default: 1)"),
           #[cfg(feature = "audio")]
           Argument::value("ac97",
@@ -1958,9 +2293,12 @@ writeback=BOOL - Indicates whether the VM can use writeback caching (default: fa
                                   "Comma separated key=value pairs for setting up battery device
                                   Possible key values:
                                   type=goldfish - type of battery emulation, defaults to goldfish
+                                  type=acpi - ACPI battery and AC adapter, for guests without a goldfish battery driver
                                   "),
           Argument::value("gdb", "PORT", "(EXPERIMENTAL) gdb on the given port"),
+          Argument::flag("no-balloon", "Don't create a virtio-balloon device. The `balloon` and `balloon_stats` commands fail for this VM."),
           Argument::value("balloon_bias_mib", "N", "Amount to bias balance of memory between host and guest as the balloon inflates, in MiB."),
+          Argument::value("balloon-size", "N", "Initial size of the balloon, which the guest inflates to at boot. Given in 4 KiB pages, or in bytes with a K, M, or G suffix. Must be less than `mem`."),
           Argument::value("vhost-user-blk", "SOCKET_PATH", "Path to a socket for vhost-user block"),
           Argument::value("vhost-user-net", "SOCKET_PATH", "Path to a socket for vhost-user net"),
           Argument::value("vhost-user-fs", "SOCKET_PATH:TAG",
@@ -1974,9 +2312,27 @@ writeback=BOOL - Indicates whether the VM can use writeback caching (default: fa
           Argument::value("dmi", "DIR", "Directory with smbios_entry_point/DMI files"),
           Argument::short_flag('h', "help", "Print help message.")];
 
+    let args: Vec<String> = args.collect();
     let mut cfg = Config::default();
-    let match_res = set_arguments(args, &arguments[..], |name, value| {
-        set_argument(&mut cfg, name, value)
+    // Find the config file and the arguments given on the command line first, so the config file
+    // can be applied without overriding any of them.
+    let mut config_path = None;
+    let mut cli_names = Vec::new();
+    let match_res = set_arguments(args.iter(), &arguments[..], |name, value| {
+        if name == "cfg" {
+            config_path = value.map(PathBuf::from);
+        }
+        cli_names.push(name.to_owned());
+        Ok(())
+    })
+    .and_then(|_| match config_path.as_ref() {
+        Some(path) => apply_config_file(&mut cfg, path, &arguments[..], &cli_names),
+        None => Ok(()),
+    })
+    .and_then(|_| {
+        set_arguments(args.iter(), &arguments[..], |name, value| {
+            set_argument(&mut cfg, name, value)
+        })
     })
     .and_then(|_| validate_arguments(&mut cfg));
 
@@ -2015,15 +2371,92 @@ writeback=BOOL - Indicates whether the VM can use writeback caching (default: fa
     }
 }
 
-fn stop_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
-    if args.len() == 0 {
-        print_help("crosvm stop", "VM_SOCKET...", &[]);
+fn stop_vms(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::positional("VM_SOCKET", "control socket of the crosvm instance to stop"),
+        Argument::value(
+            "timeout",
+            "SECONDS",
+            "wait up to SECONDS for each crosvm instance to confirm it exited",
+        ),
+    ];
+    let mut socket_paths = Vec::new();
+    let mut timeout: Option<Duration> = None;
+    set_arguments(args, &arguments[..], |name, value| {
+        match name {
+            "" => socket_paths.push(PathBuf::from(value.unwrap())),
+            "timeout" => {
+                let seconds =
+                    value
+                        .unwrap()
+                        .parse()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from("`timeout` must be an integer"),
+                        })?;
+                timeout = Some(Duration::from_secs(seconds));
+            }
+            _ => unreachable!(),
+        };
+        Ok(())
+    })
+    .map_err(|e| {
+        error!("Unable to parse command line arguments: {}", e);
+    })?;
+    if socket_paths.is_empty() {
+        print_help("crosvm stop", "VM_SOCKET...", &arguments);
         println!("Stops the crosvm instance listening on each `VM_SOCKET` given.");
         return Err(());
     }
-    let socket_path = &args.next().unwrap();
-    let socket_path = Path::new(&socket_path);
-    vms_request(&VmRequest::Exit, socket_path)
+
+    let mut result = Ok(());
+    for socket_path in &socket_paths {
+        let stopped = match timeout {
+            Some(timeout) => do_stop_with_timeout(socket_path, timeout),
+            None => vms_request(&VmRequest::Exit, socket_path),
+        };
+        if stopped.is_err() {
+            result = Err(());
+        }
+    }
+    result
+}
+
+// Returns the PID in the name of a control socket created by `--socket DIR`, which is of the form
+// "crosvm-PID.sock".
+fn socket_file_pid(file_name: &str) -> Option<u32> {
+    file_name
+        .strip_prefix("crosvm-")?
+        .strip_suffix(".sock")?
+        .parse()
+        .ok()
+}
+
+fn list_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm list", "DIR", &[]);
+        println!("Lists the crosvm instances with control sockets in `DIR`, as created by `--socket DIR`.");
+        return Err(());
+    }
+    let dir = PathBuf::from(args.next().unwrap());
+    let entries = std::fs::read_dir(&dir).map_err(|e| {
+        error!("failed to read directory {}: {}", dir.display(), e);
+    })?;
+
+    let mut sockets: Vec<(u32, PathBuf)> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let pid = socket_file_pid(path.file_name()?.to_str()?)?;
+            Some((pid, path))
+        })
+        .collect();
+    sockets.sort();
+
+    for (pid, path) in sockets {
+        let state = if do_ping(&path) { "alive" } else { "stale" };
+        println!("{}\t{}\t{}", pid, state, path.display());
+    }
+    Ok(())
 }
 
 fn suspend_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
@@ -2065,7 +2498,9 @@ fn balloon_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
     let command = BalloonControlCommand::Adjust { num_bytes };
     let socket_path = &args.next().unwrap();
     let socket_path = Path::new(&socket_path);
-    vms_request(&VmRequest::BalloonCommand(command), socket_path)
+    let response = balloon_request(command, socket_path)?;
+    info!("request response was {}", response);
+    Ok(())
 }
 
 fn balloon_stats(mut args: std::env::Args) -> std::result::Result<(), ()> {
@@ -2075,18 +2510,37 @@ fn balloon_stats(mut args: std::env::Args) -> std::result::Result<(), ()> {
         return Err(());
     }
     let command = BalloonControlCommand::Stats {};
-    let request = &VmRequest::BalloonCommand(command);
     let socket_path = &args.next().unwrap();
     let socket_path = Path::new(&socket_path);
-    let response = handle_request(request, socket_path)?;
+    let response = balloon_request(command, socket_path)?;
     println!("{}", response);
     Ok(())
 }
 
+// Sends `command` to the VM's balloon device, failing if the VM was started with `no-balloon`.
+fn balloon_request(
+    command: BalloonControlCommand,
+    socket_path: &Path,
+) -> std::result::Result<VmResponse, ()> {
+    match handle_request(&VmRequest::BalloonCommand(command), socket_path)? {
+        VmResponse::Err(e) if e.errno() == libc::ENODEV => {
+            error!(
+                "crosvm instance at '{:?}' has no balloon device",
+                socket_path
+            );
+            Err(())
+        }
+        response => Ok(response),
+    }
+}
+
 fn create_qcow2(args: std::env::Args) -> std::result::Result<(), ()> {
     let arguments = [
         Argument::positional("PATH", "where to create the qcow2 image"),
-        Argument::positional("[SIZE]", "the expanded size of the image"),
+        Argument::positional(
+            "[SIZE]",
+            "the expanded size of the image, optionally followed by K, M, G, or T",
+        ),
         Argument::value(
             "backing_file",
             "path/to/file",
@@ -2107,12 +2561,14 @@ fn create_qcow2(args: std::env::Args) -> std::result::Result<(), ()> {
             ("", 1) => {
                 // [SIZE]
                 positional_index += 1;
-                size = Some(value.unwrap().parse::<u64>().map_err(|_| {
-                    argument::Error::InvalidValue {
+                let parsed_size = parse_size(value.unwrap())?;
+                if parsed_size == 0 {
+                    return Err(argument::Error::InvalidValue {
                         value: value.unwrap().to_owned(),
-                        expected: String::from("SIZE should be a nonnegative integer"),
-                    }
-                })?);
+                        expected: String::from("SIZE must be greater than zero"),
+                    });
+                }
+                size = Some(parsed_size);
             }
             ("", _) => {
                 return Err(argument::Error::TooManyArguments(
@@ -2133,16 +2589,15 @@ fn create_qcow2(args: std::env::Args) -> std::result::Result<(), ()> {
         print_help("crosvm create_qcow2", "PATH [SIZE]", &arguments);
         println!(
             "Create a new QCOW2 image at `PATH` of either the specified `SIZE` in bytes or
-with a '--backing_file'."
+with a '--backing_file'. `PATH` must not already exist."
         );
         return Err(());
     }
 
     let file = OpenOptions::new()
-        .create(true)
+        .create_new(true)
         .read(true)
         .write(true)
-        .truncate(true)
         .open(&file_path)
         .map_err(|e| {
             error!("Failed opening qcow file at '{}': {}", file_path, e);
@@ -2162,19 +2617,118 @@ with a '--backing_file'."
     Ok(())
 }
 
+// Summary of a disk image, as printed by `crosvm disk info`.
+#[derive(Debug)]
+struct DiskInfo {
+    format: &'static str,
+    virtual_size: u64,
+    cluster_size: Option<u64>,
+    allocated_size: u64,
+    snapshots: usize,
+    backing_file: Option<String>,
+}
+
+impl Display for DiskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "format: {}", self.format)?;
+        writeln!(f, "virtual size: {}", self.virtual_size)?;
+        if let Some(cluster_size) = self.cluster_size {
+            writeln!(f, "cluster size: {}", cluster_size)?;
+        }
+        writeln!(f, "allocated size: {}", self.allocated_size)?;
+        writeln!(f, "snapshots: {}", self.snapshots)?;
+        match &self.backing_file {
+            Some(path) => write!(f, "backing file: {}", path),
+            None => write!(f, "backing file: none"),
+        }
+    }
+}
+
+// Reads the summary of the raw or qcow2 image at `path`.
+fn disk_info(path: &Path) -> std::result::Result<DiskInfo, ()> {
+    let file = File::open(path).map_err(|e| {
+        error!("Failed to open disk image '{}': {}", path.display(), e);
+    })?;
+    let metadata = file.metadata().map_err(|e| {
+        error!("Failed to stat disk image '{}': {}", path.display(), e);
+    })?;
+    // st_blocks is always in units of 512 bytes.
+    let allocated_size = metadata.blocks() * 512;
+
+    let image_type = disk::detect_image_type(&file).map_err(|e| {
+        error!("Failed to detect image type of '{}': {}", path.display(), e);
+    })?;
+    match image_type {
+        ImageType::Raw => Ok(DiskInfo {
+            format: "raw",
+            virtual_size: metadata.len(),
+            cluster_size: None,
+            allocated_size,
+            snapshots: 0,
+            backing_file: None,
+        }),
+        ImageType::Qcow2 => {
+            let qcow = QcowFile::from(file).map_err(|e| {
+                error!("'{}' is not a valid qcow2 image: {}", path.display(), e);
+            })?;
+            let header = qcow.header();
+            Ok(DiskInfo {
+                format: "qcow2",
+                virtual_size: header.size,
+                cluster_size: Some(1 << header.cluster_bits),
+                allocated_size,
+                snapshots: qcow.snapshots().len(),
+                backing_file: header.backing_file_path.clone(),
+            })
+        }
+        t => {
+            error!("Unsupported image type of '{}': {:?}", path.display(), t);
+            Err(())
+        }
+    }
+}
+
 fn disk_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
     if args.len() < 2 {
         print_help("crosvm disk", "SUBCOMMAND VM_SOCKET...", &[]);
         println!("Manage attached virtual disk devices.");
         println!("Subcommands:");
+        println!("  info PATH");
         println!("  resize DISK_INDEX NEW_SIZE VM_SOCKET");
         return Err(());
     }
     let subcommand: &str = &args.next().unwrap();
+    let args: Vec<String> = args.collect();
+    if subcommand == "info" {
+        if args.len() != 1 {
+            error!("Expected PATH for disk info");
+            return Err(());
+        }
+        println!("{}", disk_info(Path::new(&args[0]))?);
+        return Ok(());
+    }
+    let (socket_path, args) = match args.split_last() {
+        Some(split) => split,
+        None => {
+            error!("Missing VM_SOCKET for disk subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+
+    let request = parse_disk_command(subcommand, args)?;
+    vms_request(&request, Path::new(socket_path))
+}
 
-    let request = match subcommand {
+// Parses the arguments of a `crosvm disk` subcommand, not including the trailing VM_SOCKET.
+fn parse_disk_command(subcommand: &str, args: &[String]) -> std::result::Result<VmRequest, ()> {
+    match subcommand {
         "resize" => {
-            let disk_index = match args.next().unwrap().parse::<usize>() {
+            if args.len() != 2 {
+                error!("Expected DISK_INDEX and NEW_SIZE for disk resize");
+                return Err(());
+            }
+
+            let disk_index = match args[0].parse::<usize>() {
                 Ok(n) => n,
                 Err(_) => {
                     error!("Failed to parse disk index");
@@ -2182,7 +2736,7 @@ fn disk_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
                 }
             };
 
-            let new_size = match args.next().unwrap().parse::<u64>() {
+            let new_size = match args[1].parse::<u64>() {
                 Ok(n) => n,
                 Err(_) => {
                     error!("Failed to parse disk size");
@@ -2190,29 +2744,60 @@ fn disk_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
                 }
             };
 
-            VmRequest::DiskCommand {
+            Ok(VmRequest::DiskCommand {
                 disk_index,
                 command: DiskControlCommand::Resize { new_size },
-            }
+            })
         }
         _ => {
             error!("Unknown disk subcommand '{}'", subcommand);
-            return Err(());
+            Err(())
         }
-    };
-
-    let socket_path = &args.next().unwrap();
-    let socket_path = Path::new(&socket_path);
-    vms_request(&request, socket_path)
+    }
 }
 
-fn parse_bus_id_addr(v: &str) -> ModifyUsbResult<(u8, u8, u16, u16)> {
-    debug!("parse_bus_id_addr: {}", v);
-    let mut ids = v.split(':');
-    match (ids.next(), ids.next(), ids.next(), ids.next()) {
-        (Some(bus_id), Some(addr), Some(vid), Some(pid)) => {
-            let bus_id = bus_id
-                .parse::<u8>()
+fn debug_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm debug", "SUBCOMMAND VM_SOCKET...", &[]);
+        println!("Debug a running crosvm instance.");
+        println!("Subcommands:");
+        println!("  dump PATH VM_SOCKET");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+    if subcommand != "dump" {
+        error!("Unknown debug subcommand '{}'", subcommand);
+        return Err(());
+    }
+    let path = PathBuf::from(args.next().unwrap());
+    let socket_path = args.next().unwrap();
+
+    // Open the file here and pass it along, since the sandboxed VM process may not be able to
+    // create `path` itself.
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .map_err(|e| {
+            error!("Failed to open {}: {}", path.display(), e);
+        })?;
+    vms_request(
+        &VmRequest::DumpState {
+            path,
+            file: Some(DumpStateFile(file)),
+        },
+        Path::new(&socket_path),
+    )
+}
+
+fn parse_bus_id_addr(v: &str) -> ModifyUsbResult<(u8, u8, u16, u16)> {
+    debug!("parse_bus_id_addr: {}", v);
+    let mut ids = v.split(':');
+    match (ids.next(), ids.next(), ids.next(), ids.next()) {
+        (Some(bus_id), Some(addr), Some(vid), Some(pid)) => {
+            let bus_id = bus_id
+                .parse::<u8>()
                 .map_err(|e| ModifyUsbError::ArgParseInt("bus_id", bus_id.to_owned(), e))?;
             let addr = addr
                 .parse::<u8>()
@@ -2304,7 +2889,9 @@ fn print_usage() {
     println!("    balloon_stats - Prints virtio balloon statistics.");
     println!("    battery - Modify battery.");
     println!("    create_qcow2  - Create a new qcow2 disk image file.");
+    println!("    debug - Debug a running crosvm instance.");
     println!("    disk - Manage attached virtual disk devices.");
+    println!("    list - Lists crosvm instances by their control sockets in a directory.");
     println!("    resume - Resumes the crosvm instance.");
     println!("    run - Start a new crosvm instance.");
     println!("    stop - Stops crosvm instances via their control sockets.");
@@ -2365,12 +2952,14 @@ fn crosvm_main() -> std::result::Result<(), ()> {
             Ok(())
         }
         Some("stop") => stop_vms(args),
+        Some("list") => list_vms(args),
         Some("suspend") => suspend_vms(args),
         Some("resume") => resume_vms(args),
         Some("run") => run_vm(args),
         Some("balloon") => balloon_vms(args),
         Some("balloon_stats") => balloon_stats(args),
         Some("create_qcow2") => create_qcow2(args),
+        Some("debug") => debug_cmd(args),
         Some("disk") => disk_cmd(args),
         Some("usb") => modify_usb(args),
         Some("version") => pkg_version(),
@@ -2406,7 +2995,8 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crosvm::{DEFAULT_TOUCH_DEVICE_HEIGHT, DEFAULT_TOUCH_DEVICE_WIDTH};
+    use crosvm::{SharedDirKind, DEFAULT_TOUCH_DEVICE_HEIGHT, DEFAULT_TOUCH_DEVICE_WIDTH};
+    use std::io::Write;
 
     #[test]
     fn parse_cpu_set_single() {
@@ -2465,6 +3055,66 @@ mod tests {
         parse_cpu_set("0,1,2,").expect_err("parse should have failed");
     }
 
+    #[test]
+    fn parse_mem_sizes() {
+        assert_eq!(parse_mem_size("512").expect("parse failed"), 512);
+        assert_eq!(parse_mem_size("2G").expect("parse failed"), 2048);
+        assert_eq!(parse_mem_size("1536M").expect("parse failed"), 1536);
+        parse_mem_size("10X").expect_err("parse should have failed");
+        parse_mem_size("512K").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_balloon_size_arg() {
+        let mut config = Config::default();
+        set_argument(&mut config, "balloon-size", Some("256")).expect("parse should succeed");
+        assert_eq!(config.balloon_size, Some(256 * 4096));
+        set_argument(&mut config, "balloon-size", Some("1G")).expect_err("duplicate should fail");
+
+        let mut config = Config::default();
+        set_argument(&mut config, "balloon-size", Some("64M")).expect("parse should succeed");
+        assert_eq!(config.balloon_size, Some(64 << 20));
+        set_argument(&mut config, "balloon-size", Some("64X")).expect_err("parse should fail");
+    }
+
+    #[test]
+    fn parse_no_balloon() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        assert!(config.balloon);
+        set_argument(&mut config, "no-balloon", None).expect("parse should succeed");
+        assert!(!config.balloon);
+        validate_arguments(&mut config).expect("validation should succeed");
+
+        set_argument(&mut config, "balloon-size", Some("64M")).expect("parse should succeed");
+        validate_arguments(&mut config).expect_err("validation should fail");
+    }
+
+    #[test]
+    fn balloon_size_exceeds_memory() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "mem", Some("512")).expect("parse should succeed");
+        set_argument(&mut config, "balloon-size", Some("512M")).expect("parse should succeed");
+        validate_arguments(&mut config).expect_err("validation should fail");
+
+        config.balloon_size = Some(511 << 20);
+        validate_arguments(&mut config).expect("validation should succeed");
+    }
+
+    #[test]
+    fn socket_file_pids() {
+        assert_eq!(socket_file_pid("crosvm-1234.sock"), Some(1234));
+        assert_eq!(socket_file_pid("crosvm-.sock"), None);
+        assert_eq!(socket_file_pid("crosvm-12x.sock"), None);
+        assert_eq!(socket_file_pid("crosvm-1234.sock.bak"), None);
+        assert_eq!(socket_file_pid("other-1234.sock"), None);
+    }
+
     #[test]
     fn parse_cpu_affinity_global() {
         assert_eq!(
@@ -2558,6 +3208,19 @@ mod tests {
         assert_eq!(parsed.path, Some(PathBuf::from("foo=bar==.log")));
     }
 
+    #[test]
+    fn parse_serial_file_valid() {
+        let parsed = parse_serial_options("type=file,path=/tmp/log,console=true")
+            .expect("parse should have succeded");
+        assert_eq!(parsed.path, Some(PathBuf::from("/tmp/log")));
+        assert!(parsed.console);
+    }
+
+    #[test]
+    fn parse_serial_file_missing_path() {
+        parse_serial_options("type=file,console=true").expect_err("parse should have failed");
+    }
+
     #[test]
     fn parse_serial_invalid_type() {
         parse_serial_options("type=wormhole,num=1").expect_err("parse should have failed");
@@ -2598,6 +3261,523 @@ mod tests {
             .expect_err("should fail to parse a second serial port connected to stdin");
     }
 
+    #[test]
+    fn parse_cid_reserved() {
+        let mut config = Config::default();
+        match set_argument(&mut config, "cid", Some("2")) {
+            Err(argument::Error::InvalidValue { .. }) => {}
+            r => panic!("expected InvalidValue, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_cid_overflow() {
+        let mut config = Config::default();
+        match set_argument(&mut config, "cid", Some("4294967296")) {
+            Err(argument::Error::InvalidValue { .. }) => {}
+            r => panic!("expected InvalidValue, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_cid_valid() {
+        let mut config = Config::default();
+        set_argument(&mut config, "cid", Some("3")).expect("parse should succeed");
+        assert_eq!(config.cid, Some(3));
+    }
+
+    #[test]
+    fn initrd_nonexistent_path() {
+        let mut config = Config::default();
+        match set_argument(&mut config, "initrd", Some("/nonexistent/initrd.img")) {
+            Err(argument::Error::InvalidValue { .. }) => {}
+            r => panic!("expected InvalidValue, got {:?}", r),
+        }
+        assert!(config.initrd_path.is_none());
+    }
+
+    #[test]
+    fn parse_multiple_tap_fds() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "tap-fd", Some("3")).expect("parse should succeed");
+        set_argument(&mut config, "tap-fd", Some("4")).expect("parse should succeed");
+        assert_eq!(config.tap_fd, vec![3, 4]);
+        validate_arguments(&mut config).expect("validation should succeed");
+    }
+
+    #[test]
+    fn parse_kernel_fd() {
+        let mut config = Config::default();
+        set_argument(&mut config, "kernel-fd", Some("5")).expect("parse should succeed");
+        assert!(matches!(
+            config.executable_path,
+            Some(Executable::KernelFd(5))
+        ));
+        validate_arguments(&mut config).expect("validation should succeed");
+
+        let mut config = Config::default();
+        set_argument(&mut config, "kernel-fd", Some("kernel")).expect_err("parse should fail");
+        assert!(config.executable_path.is_none());
+        validate_arguments(&mut config).expect_err("validation should fail without a kernel");
+    }
+
+    #[test]
+    fn parse_kernel_fd_negative() {
+        let mut config = Config::default();
+        match set_argument(&mut config, "kernel-fd", Some("-1")) {
+            Err(argument::Error::InvalidValue { .. }) => {}
+            r => panic!("expected InvalidValue, got {:?}", r),
+        }
+        assert!(config.executable_path.is_none());
+    }
+
+    #[test]
+    fn kernel_fd_with_kernel_path() {
+        let mut config = Config::default();
+        set_argument(&mut config, "", Some("/dev/null")).expect("parse should succeed");
+        match set_argument(&mut config, "kernel-fd", Some("5")) {
+            Err(argument::Error::TooManyArguments(_)) => {}
+            r => panic!("expected TooManyArguments, got {:?}", r),
+        }
+
+        let mut config = Config::default();
+        set_argument(&mut config, "kernel-fd", Some("5")).expect("parse should succeed");
+        match set_argument(&mut config, "", Some("/dev/null")) {
+            Err(argument::Error::TooManyArguments(_)) => {}
+            r => panic!("expected TooManyArguments, got {:?}", r),
+        }
+        assert!(matches!(
+            config.executable_path,
+            Some(Executable::KernelFd(5))
+        ));
+    }
+
+    #[test]
+    fn tap_fd_with_host_ip() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "tap-fd", Some("3")).expect("parse should succeed");
+        set_argument(&mut config, "host_ip", Some("192.168.10.1")).expect("parse should succeed");
+        set_argument(&mut config, "netmask", Some("255.255.255.0")).expect("parse should succeed");
+        set_argument(&mut config, "mac", Some("3d:70:eb:61:1a:91")).expect("parse should succeed");
+        validate_arguments(&mut config).expect_err("validation should fail");
+    }
+
+    #[test]
+    fn net_without_mac() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "host_ip", Some("192.168.10.1")).expect("parse should succeed");
+        validate_arguments(&mut config).expect_err("validation should fail without netmask");
+
+        set_argument(&mut config, "netmask", Some("255.255.255.0")).expect("parse should succeed");
+        set_argument(&mut config, "cid", Some("4660")).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert_eq!(
+            config.mac_address.unwrap().octets(),
+            [0x02, 0, 0, 0, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn generated_mac_address_bits() {
+        for cid in &[None, Some(3), Some(u64::MAX)] {
+            let high = generate_mac_address(*cid).unwrap().octets()[0];
+            // Locally administered and unicast.
+            assert_eq!(high & 0x03, 0x02);
+        }
+    }
+
+    #[test]
+    fn parse_disk_options() {
+        let mut config = Config::default();
+        set_argument(
+            &mut config,
+            "rwdisk",
+            Some("/dev/null,sparse=false,block_size=4096,id=DISK"),
+        )
+        .expect("parse should succeed");
+        let disk = &config.disks[0];
+        assert!(!disk.read_only);
+        assert!(!disk.sparse);
+        assert_eq!(disk.block_size, 4096);
+        assert_eq!(&disk.id.unwrap()[..4], b"DISK");
+        assert!(!disk.direct);
+
+        set_argument(&mut config, "disk", Some("/dev/null")).expect("parse should succeed");
+        let disk = &config.disks[1];
+        assert!(disk.read_only);
+        assert!(disk.sparse);
+        assert_eq!(disk.block_size, 512);
+        assert!(disk.id.is_none());
+        assert!(!disk.direct);
+
+        set_argument(&mut config, "rwdisk", Some("/dev/null,direct=true"))
+            .expect("parse should succeed");
+        assert!(config.disks[2].direct);
+        set_argument(&mut config, "rwdisk", Some("/dev/null,direct=yes"))
+            .expect_err("parse should fail");
+    }
+
+    #[test]
+    fn parse_disk_on_error() {
+        let mut config = Config::default();
+        set_argument(&mut config, "rwdisk", Some("/dev/null")).expect("parse should succeed");
+        assert_eq!(config.disks[0].error_policy, DiskErrorPolicy::Report);
+        set_argument(&mut config, "rwdisk", Some("/dev/null,on_error=stop"))
+            .expect("parse should succeed");
+        assert_eq!(config.disks[1].error_policy, DiskErrorPolicy::Stop);
+        set_argument(&mut config, "rwdisk", Some("/dev/null,on_error=report"))
+            .expect("parse should succeed");
+        assert_eq!(config.disks[2].error_policy, DiskErrorPolicy::Report);
+        set_argument(&mut config, "rwdisk", Some("/dev/null,on_error=ignore"))
+            .expect_err("parse should fail");
+    }
+
+    #[test]
+    fn parse_disk_block_size_not_power_of_two() {
+        let mut config = Config::default();
+        set_argument(&mut config, "rwdisk", Some("/dev/null,block_size=3000"))
+            .expect_err("parse should fail");
+    }
+
+    #[test]
+    fn parse_disk_unknown_option() {
+        let mut config = Config::default();
+        set_argument(&mut config, "disk", Some("/dev/null,trim=true"))
+            .expect_err("parse should fail");
+    }
+
+    #[test]
+    fn parse_cpu_topology_consistent() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "cpus", Some("8")).expect("parse should succeed");
+        set_argument(
+            &mut config,
+            "cpu-topology",
+            Some("sockets=2,cores=2,threads=2"),
+        )
+        .expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert_eq!(
+            config.cpu_topology,
+            Some(CpuTopology {
+                sockets: 2,
+                cores: 2,
+                threads: 2,
+            })
+        );
+        assert_eq!(config.vcpu_count, Some(8));
+    }
+
+    #[test]
+    fn parse_cpu_topology_conflicts_with_cpus() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "cpus", Some("4")).expect("parse should succeed");
+        set_argument(&mut config, "cpu-topology", Some("sockets=2,cores=4"))
+            .expect("parse should succeed");
+        validate_arguments(&mut config).expect_err("validation should fail");
+    }
+
+    #[test]
+    fn parse_cpu_topology_defaults() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "cpu-topology", Some("cores=4")).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert_eq!(
+            config.cpu_topology,
+            Some(CpuTopology {
+                sockets: 1,
+                cores: 4,
+                threads: 1,
+            })
+        );
+        assert_eq!(config.vcpu_count, Some(4));
+
+        set_argument(&mut Config::default(), "cpu-topology", Some("cores=0"))
+            .expect_err("parse should fail");
+    }
+
+    #[test]
+    fn root_disk_letters() {
+        assert_eq!(virtio_blk_letters(0), "a");
+        assert_eq!(virtio_blk_letters(25), "z");
+        assert_eq!(virtio_blk_letters(26), "aa");
+        assert_eq!(virtio_blk_letters(51), "az");
+        assert_eq!(virtio_blk_letters(52), "ba");
+        assert_eq!(virtio_blk_letters(701), "zz");
+        assert_eq!(virtio_blk_letters(702), "aaa");
+
+        let mut config = Config::default();
+        for _ in 0..26 {
+            set_argument(&mut config, "disk", Some("/dev/null")).expect("parse should succeed");
+        }
+        set_argument(&mut config, "root", Some("/dev/null")).expect("parse should succeed");
+        assert_eq!(config.params.last().unwrap(), "root=/dev/vdaa ro");
+
+        let mut config = Config::default();
+        for _ in 0..52 {
+            set_argument(&mut config, "disk", Some("/dev/null")).expect("parse should succeed");
+        }
+        set_argument(&mut config, "rwroot", Some("/dev/null")).expect("parse should succeed");
+        assert_eq!(config.params.last().unwrap(), "root=/dev/vdba rw");
+    }
+
+    #[test]
+    fn parse_cmdline_file() {
+        let file = write_config_file(
+            "# kernel arguments\nconsole=ttyS0 earlyprintk\n\n  # indented comment\nquiet\n",
+        );
+        let path = file.path().to_str().unwrap();
+
+        let mut config = Config::default();
+        set_argument(&mut config, "params", Some("init=/bin/sh")).expect("parse should succeed");
+        set_argument(&mut config, "cmdline-file", Some(path)).expect("parse should succeed");
+        assert_eq!(
+            config.params,
+            vec!["console=ttyS0", "earlyprintk", "quiet", "init=/bin/sh"]
+        );
+        set_argument(&mut config, "cmdline-file", Some(path))
+            .expect_err("second cmdline file should fail");
+
+        let empty = write_config_file("");
+        let mut config = Config::default();
+        set_argument(
+            &mut config,
+            "cmdline-file",
+            Some(empty.path().to_str().unwrap()),
+        )
+        .expect("parse should succeed");
+        assert!(config.params.is_empty());
+
+        set_argument(
+            &mut Config::default(),
+            "cmdline-file",
+            Some("/nonexistent/cmdline"),
+        )
+        .expect_err("missing file should fail");
+    }
+
+    #[test]
+    fn root_param_conflict() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "root", Some("/dev/null")).expect("parse should succeed");
+        set_argument(&mut config, "params", Some("console=ttyS0 root=/dev/sda1"))
+            .expect("parse should succeed");
+        match validate_arguments(&mut config) {
+            Err(argument::Error::TooManyArguments(_)) => {}
+            r => panic!("expected TooManyArguments, got {:?}", r),
+        }
+
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "root", Some("/dev/null")).expect("parse should succeed");
+        set_argument(&mut config, "params", Some("console=ttyS0 noroot=1"))
+            .expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "disk", Some("/dev/null")).expect("parse should succeed");
+        set_argument(&mut config, "params", Some("root=/dev/vda")).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+    }
+
+    #[test]
+    fn parse_disk_resize() {
+        let args = |v: &[&str]| -> Vec<String> { v.iter().map(|s| s.to_string()).collect() };
+        match parse_disk_command("resize", &args(&["1", "4096"])) {
+            Ok(VmRequest::DiskCommand {
+                disk_index: 1,
+                command: DiskControlCommand::Resize { new_size: 4096 },
+            }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        parse_disk_command("resize", &args(&["1"])).expect_err("missing size should fail");
+        parse_disk_command("resize", &args(&["-1", "4096"]))
+            .expect_err("negative index should fail");
+        parse_disk_command("resize", &args(&["0", "4096", "extra"]))
+            .expect_err("extra arguments should fail");
+        parse_disk_command("grow", &args(&["0", "4096"])).expect_err("unknown subcommand");
+    }
+
+    #[test]
+    fn disk_info_qcow() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        QcowFile::new(file, 0x10_0000).unwrap();
+
+        let info = disk_info(&path).expect("failed to read disk info");
+        assert_eq!(info.format, "qcow2");
+        assert_eq!(info.virtual_size, 0x10_0000);
+        assert_eq!(info.cluster_size, Some(0x1_0000));
+        assert_eq!(info.snapshots, 0);
+        assert!(info.backing_file.is_none());
+
+        // A corrupt qcow2 image is rejected.
+        let path = dir.path().join("corrupt.qcow2");
+        std::fs::write(&path, &[0x51, 0x46, 0x49, 0xfb, 0x00, 0x00, 0x00, 0x09]).unwrap();
+        disk_info(&path).expect_err("corrupt image should fail");
+    }
+
+    #[test]
+    fn parse_pmem_device() {
+        let mut config = Config::default();
+        set_argument(&mut config, "pmem-device", Some("/dev/null")).expect("parse should succeed");
+        set_argument(&mut config, "rw-pmem-device", Some("/dev/null"))
+            .expect("parse should succeed");
+        assert_eq!(config.pmem_devices.len(), 2);
+        assert_eq!(config.pmem_devices[0].path, PathBuf::from("/dev/null"));
+        assert!(config.pmem_devices[0].read_only);
+        assert!(!config.pmem_devices[1].read_only);
+    }
+
+    #[test]
+    fn parse_pmem_device_nonexistent_path() {
+        let mut config = Config::default();
+        match set_argument(&mut config, "pmem-device", Some("/nonexistent/pmem.img")) {
+            Err(argument::Error::InvalidValue { .. }) => {}
+            r => panic!("expected InvalidValue, got {:?}", r),
+        }
+        assert!(config.pmem_devices.is_empty());
+    }
+
+    #[test]
+    fn parse_shared_dir() {
+        let mut config = Config::default();
+        set_argument(
+            &mut config,
+            "shared-dir",
+            Some("/:rootfs:type=fs:cache=always"),
+        )
+        .expect("parse should succeed");
+        let shared_dir = &config.shared_dirs[0];
+        assert_eq!(shared_dir.src, PathBuf::from("/"));
+        assert_eq!(shared_dir.tag, "rootfs");
+        assert!(shared_dir.kind == SharedDirKind::FS);
+    }
+
+    #[test]
+    fn parse_shared_dir_missing_tag() {
+        let mut config = Config::default();
+        set_argument(&mut config, "shared-dir", Some("/"))
+            .expect_err("parse should fail without a tag");
+        set_argument(&mut config, "shared-dir", Some("/:"))
+            .expect_err("parse should fail with an empty tag");
+        assert!(config.shared_dirs.is_empty());
+    }
+
+    #[test]
+    fn parse_shared_dir_not_a_directory() {
+        let mut config = Config::default();
+        match set_argument(&mut config, "shared-dir", Some("/dev/null:tag")) {
+            Err(argument::Error::InvalidValue { .. }) => {}
+            r => panic!("expected InvalidValue, got {:?}", r),
+        }
+    }
+
+    fn write_config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.as_file_mut().write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn equals_and_space_separated_values() {
+        let arguments = [
+            Argument::short_value('m', "mem", "N", "Amount of guest memory in MiB."),
+            Argument::value("disk", "PATH", "Path to a disk image."),
+        ];
+        let parse = |args: &[&str]| {
+            let mut config = Config::default();
+            set_arguments(args.iter(), &arguments, |name, value| {
+                set_argument(&mut config, name, value)
+            })
+            .expect("parse should succeed");
+            config
+        };
+
+        for config in &[
+            parse(&["--mem=256", "--disk=/dev/null"]),
+            parse(&["--mem", "256", "--disk", "/dev/null"]),
+            parse(&["-m=256", "--disk=/dev/null"]),
+        ] {
+            assert_eq!(config.memory, Some(256));
+            assert_eq!(config.disks.len(), 1);
+            assert_eq!(config.disks[0].path, PathBuf::from("/dev/null"));
+        }
+    }
+
+    #[test]
+    fn config_file_arguments() {
+        let arguments = [
+            Argument::value("cpus", "N", "Number of VCPUs."),
+            Argument::flag("hugepages", "Use Huge Pages."),
+            Argument::value("params", "PARAMS", "Extra kernel command line arguments."),
+        ];
+        let file = write_config_file(
+            "# A comment\n\n  cpus = 2  \nhugepages # trailing comment\nparams=a=b\n",
+        );
+        let mut config = Config::default();
+        apply_config_file(&mut config, file.path(), &arguments, &[])
+            .expect("config file should apply");
+        assert_eq!(config.vcpu_count, Some(2));
+        assert!(config.hugepages);
+        assert_eq!(config.params, vec!["a=b".to_owned()]);
+    }
+
+    #[test]
+    fn config_file_command_line_takes_precedence() {
+        let arguments = [Argument::value("cpus", "N", "Number of VCPUs.")];
+        let file = write_config_file("cpus=2\n");
+        let mut config = Config::default();
+        apply_config_file(&mut config, file.path(), &arguments, &["cpus".to_owned()])
+            .expect("config file should apply");
+        assert_eq!(config.vcpu_count, None);
+        set_argument(&mut config, "cpus", Some("4")).expect("parse should succeed");
+        assert_eq!(config.vcpu_count, Some(4));
+    }
+
+    #[test]
+    fn config_file_unknown_argument() {
+        let arguments = [Argument::value("cpus", "N", "Number of VCPUs.")];
+        let file = write_config_file("bogus=1\n");
+        let mut config = Config::default();
+        match apply_config_file(&mut config, file.path(), &arguments, &[]) {
+            Err(argument::Error::UnknownArgument(name)) => assert_eq!(name, "bogus"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
     #[test]
     fn parse_plugin_mount_valid() {
         let mut config = Config::default();
@@ -2891,6 +4071,14 @@ mod tests {
         parse_battery_options(Some("type=goldfish")).expect("parse should have succeded");
     }
 
+    #[test]
+    fn parse_battery_acpi() {
+        assert_eq!(
+            parse_battery_options(Some("type=acpi")).expect("parse should have succeded"),
+            BatteryType::Acpi
+        );
+    }
+
     #[test]
     fn parse_battery_vaild_no_type() {
         parse_battery_options(None).expect("parse should have succeded");
@@ -0,0 +1,272 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Safe wrappers around the Linux native asynchronous I/O interface (man 2 io_setup).
+
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::Duration;
+
+use libc::{c_long, syscall, SYS_io_destroy, SYS_io_getevents, SYS_io_setup, SYS_io_submit};
+
+use crate::aio_abi_bindings::{
+    aio_context_t, io_event, iocb, IOCB_CMD_POLL, IOCB_CMD_PREAD, IOCB_CMD_PWRITE, IOCB_FLAG_RESFD,
+};
+use crate::{errno_result, Result};
+
+/// A single AIO operation, ready to be submitted to an `AioContext`.
+#[repr(transparent)]
+#[derive(Clone, Copy, Default)]
+pub struct Iocb(iocb);
+
+impl Iocb {
+    fn new(opcode: u32, fd: RawFd, ptr: u64, len: u64, offset: u64, data: u64) -> Iocb {
+        Iocb(iocb {
+            aio_data: data,
+            aio_lio_opcode: opcode as u16,
+            aio_fildes: fd as u32,
+            aio_buf: ptr,
+            aio_nbytes: len,
+            aio_offset: offset as i64,
+            ..Default::default()
+        })
+    }
+
+    /// Creates an operation that reads `len` bytes from `fd` at `offset` into the address given
+    /// by `ptr`. `data` is returned in the completion event to identify the operation.
+    /// # Safety
+    /// The kernel will write up to `len` bytes to `ptr` once the operation is submitted. This is
+    /// only safe if the caller guarantees that the memory lives until the completion has been
+    /// returned from `get_events` and that there are no other references to it until then. Ensure
+    /// that the fd remains open until the op completes as well.
+    pub unsafe fn pread(fd: RawFd, ptr: *mut u8, len: usize, offset: u64, data: u64) -> Iocb {
+        Iocb::new(IOCB_CMD_PREAD, fd, ptr as u64, len as u64, offset, data)
+    }
+
+    /// Creates an operation that writes `len` bytes from the address given by `ptr` to `fd` at
+    /// `offset`. `data` is returned in the completion event to identify the operation.
+    /// # Safety
+    /// The kernel will read up to `len` bytes from `ptr` once the operation is submitted. This is
+    /// only safe if the caller guarantees that the memory lives until the completion has been
+    /// returned from `get_events` and that there are no mutable references to it until then.
+    /// Ensure that the fd remains open until the op completes as well.
+    pub unsafe fn pwrite(fd: RawFd, ptr: *const u8, len: usize, offset: u64, data: u64) -> Iocb {
+        Iocb::new(IOCB_CMD_PWRITE, fd, ptr as u64, len as u64, offset, data)
+    }
+
+    /// Creates an operation that completes once `fd` is ready for any of the poll `events`, e.g.
+    /// `libc::POLLIN`. The completion's `res` holds the ready events. `data` is returned in the
+    /// completion event to identify the operation.
+    pub fn poll(fd: RawFd, events: libc::c_short, data: u64) -> Iocb {
+        // The requested poll mask is passed in place of the buffer address.
+        Iocb::new(IOCB_CMD_POLL, fd, events as u16 as u64, 0, 0, data)
+    }
+
+    /// Has the kernel signal the eventfd `fd` when this operation completes, so completions can be
+    /// waited for alongside other fds instead of blocking in `get_events`. The completion must
+    /// still be collected with `get_events`.
+    pub fn set_eventfd(&mut self, fd: RawFd) -> &mut Self {
+        self.0.aio_flags |= IOCB_FLAG_RESFD;
+        self.0.aio_resfd = fd as u32;
+        self
+    }
+
+    /// Returns the user data that will be reported when this operation completes.
+    pub fn data(&self) -> u64 {
+        self.0.aio_data
+    }
+}
+
+/// The completion of an operation submitted to an `AioContext`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoEvent {
+    /// The `data` the operation was created with.
+    pub data: u64,
+    /// The result of the operation, e.g. the number of bytes transferred or a negative errno.
+    pub res: i64,
+    /// A secondary, operation specific result.
+    pub res2: i64,
+}
+
+impl From<io_event> for IoEvent {
+    fn from(ev: io_event) -> IoEvent {
+        IoEvent {
+            data: ev.data,
+            res: ev.res,
+            res2: ev.res2,
+        }
+    }
+}
+
+/// An AIO context that can have up to a fixed number of operations in flight. The context is
+/// destroyed, cancelling any outstanding operations, when dropped.
+pub struct AioContext {
+    ctx: aio_context_t,
+}
+
+impl AioContext {
+    /// Creates an `AioContext` able to hold at least `nr_events` in-flight operations.
+    pub fn new(nr_events: u32) -> Result<AioContext> {
+        let mut ctx: aio_context_t = 0;
+        // Safe because the kernel only writes to `ctx` and we check the return value.
+        let ret = unsafe { syscall(SYS_io_setup, nr_events as c_long, &mut ctx) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(AioContext { ctx })
+    }
+
+    /// Submits `iocbs` to the kernel and returns the number that were accepted, which may be
+    /// fewer than `iocbs.len()`.
+    pub fn submit(&self, iocbs: &mut [Iocb]) -> Result<usize> {
+        let mut ptrs: Vec<*mut iocb> = iocbs.iter_mut().map(|i| &mut i.0 as *mut iocb).collect();
+        // Safe because `ptrs` and the iocbs it points to outlive the call and we check the return
+        // value. The kernel only writes to the `aio_key` field of each iocb. The caller of the
+        // unsafe `Iocb` constructors has guaranteed the validity of the buffers.
+        let ret = unsafe {
+            syscall(
+                SYS_io_submit,
+                self.ctx,
+                ptrs.len() as c_long,
+                ptrs.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(ret as usize)
+    }
+
+    /// Waits for at least `min_nr` and at most `max_nr` operations to complete, returning their
+    /// completions. If `timeout` is `None`, waits indefinitely for `min_nr` completions.
+    pub fn get_events(
+        &self,
+        min_nr: usize,
+        max_nr: usize,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<IoEvent>> {
+        let mut events = vec![io_event::default(); max_nr];
+        let ts = timeout.map(|t| libc::timespec {
+            tv_sec: t.as_secs() as libc::time_t,
+            tv_nsec: t.subsec_nanos() as libc::c_long,
+        });
+        let ts_ptr = ts
+            .as_ref()
+            .map_or(ptr::null(), |t| t as *const libc::timespec);
+        // Safe because the kernel writes at most `max_nr` events to `events`, only reads `ts`, and
+        // we check the return value.
+        let ret = unsafe {
+            syscall(
+                SYS_io_getevents,
+                self.ctx,
+                min_nr as c_long,
+                max_nr as c_long,
+                events.as_mut_ptr(),
+                ts_ptr,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        events.truncate(ret as usize);
+        Ok(events.into_iter().map(IoEvent::from).collect())
+    }
+}
+
+impl Drop for AioContext {
+    fn drop(&mut self) {
+        // Safe because we own the context and it is not used after this.
+        unsafe { syscall(SYS_io_destroy, self.ctx) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    use tempfile::tempfile;
+
+    use crate::{pipe, EventFd};
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn iocb_layout() {
+        assert_eq!(std::mem::size_of::<iocb>(), 64);
+        assert_eq!(std::mem::size_of::<io_event>(), 32);
+
+        let cb = iocb::default();
+        let base = &cb as *const iocb as usize;
+        assert_eq!(&cb.aio_lio_opcode as *const _ as usize - base, 16);
+        assert_eq!(&cb.aio_fildes as *const _ as usize - base, 20);
+        assert_eq!(&cb.aio_buf as *const _ as usize - base, 24);
+        assert_eq!(&cb.aio_flags as *const _ as usize - base, 56);
+        assert_eq!(&cb.aio_resfd as *const _ as usize - base, 60);
+    }
+
+    #[test]
+    fn pread() {
+        let mut f = tempfile().unwrap();
+        f.write_all(b"hello aio world").unwrap();
+
+        let ctx = AioContext::new(4).unwrap();
+        let mut buf = [0u8; 5];
+        // Safe because `buf` and `f` outlive the completion collected below.
+        let mut iocbs = [unsafe { Iocb::pread(f.as_raw_fd(), buf.as_mut_ptr(), buf.len(), 6, 42) }];
+        assert_eq!(ctx.submit(&mut iocbs).unwrap(), 1);
+
+        let events = ctx.get_events(1, 1, None).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, 42);
+        assert_eq!(events[0].res, 5);
+        assert_eq!(&buf, b"aio w");
+    }
+
+    #[test]
+    fn pread_eventfd() {
+        let mut f = tempfile().unwrap();
+        f.write_all(b"hello aio world").unwrap();
+        let evt = EventFd::new().unwrap();
+
+        let ctx = AioContext::new(4).unwrap();
+        let mut buf = [0u8; 5];
+        // Safe because `buf` and `f` outlive the completion collected below.
+        let mut cb = unsafe { Iocb::pread(f.as_raw_fd(), buf.as_mut_ptr(), buf.len(), 0, 3) };
+        cb.set_eventfd(evt.as_raw_fd());
+        assert_eq!(ctx.submit(&mut [cb]).unwrap(), 1);
+
+        // Blocks until the kernel signals the completion.
+        assert_eq!(evt.read().unwrap(), 1);
+        let events = ctx
+            .get_events(0, 1, Some(Duration::from_millis(0)))
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, 3);
+        assert_eq!(events[0].res, 5);
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn poll_pipe() {
+        let (rx, mut tx) = pipe(true).unwrap();
+
+        let ctx = AioContext::new(4).unwrap();
+        let mut iocbs = [Iocb::poll(rx.as_raw_fd(), libc::POLLIN, 7)];
+        assert_eq!(ctx.submit(&mut iocbs).unwrap(), 1);
+
+        // Nothing has been written yet so the poll must still be pending.
+        let events = ctx
+            .get_events(0, 1, Some(Duration::from_millis(0)))
+            .unwrap();
+        assert!(events.is_empty());
+
+        tx.write_all(&[1]).unwrap();
+        let events = ctx.get_events(1, 1, None).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, 7);
+        assert_ne!(events[0].res & libc::POLLIN as i64, 0);
+    }
+}
@@ -0,0 +1,77 @@
+/* Originally generated by rust-bindgen with
+ *
+ * bindgen --with-derive-default include/uapi/linux/aio_abi.h
+ *
+ * and then edited by hand, so don't regenerate over it. bindgen only emits the layout for the
+ * target it runs on. aio_abi.h declares aio_key and aio_rw_flags through its PADDED(x, y) macro,
+ * which expands to "x, y" on little-endian targets and to "y, x" on big-endian ones. That swap is
+ * the only architecture-dependent part of these definitions, so the little-endian bindgen output
+ * of iocb is kept as is and the big-endian variant is written out with the two fields swapped.
+ * Both are selected with cfg(target_endian) and shared by x86_64, arm, and aarch64.
+ */
+
+#![allow(non_upper_case_globals)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+pub const IOCB_FLAG_RESFD: u32 = 1;
+pub const IOCB_FLAG_IOPRIO: u32 = 2;
+pub type __s16 = ::std::os::raw::c_short;
+pub type __u16 = ::std::os::raw::c_ushort;
+pub type __u32 = ::std::os::raw::c_uint;
+pub type __s64 = ::std::os::raw::c_longlong;
+pub type __u64 = ::std::os::raw::c_ulonglong;
+pub type __kernel_rwf_t = ::std::os::raw::c_int;
+pub type aio_context_t = ::std::os::raw::c_ulong;
+pub const IOCB_CMD_PREAD: ::std::os::raw::c_uint = 0;
+pub const IOCB_CMD_PWRITE: ::std::os::raw::c_uint = 1;
+pub const IOCB_CMD_FSYNC: ::std::os::raw::c_uint = 2;
+pub const IOCB_CMD_FDSYNC: ::std::os::raw::c_uint = 3;
+pub const IOCB_CMD_POLL: ::std::os::raw::c_uint = 5;
+pub const IOCB_CMD_NOOP: ::std::os::raw::c_uint = 6;
+pub const IOCB_CMD_PREADV: ::std::os::raw::c_uint = 7;
+pub const IOCB_CMD_PWRITEV: ::std::os::raw::c_uint = 8;
+pub type _bindgen_ty_1 = ::std::os::raw::c_uint;
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct io_event {
+    pub data: __u64,
+    pub obj: __u64,
+    pub res: __s64,
+    pub res2: __s64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+#[cfg(target_endian = "little")]
+pub struct iocb {
+    pub aio_data: __u64,
+    pub aio_key: __u32,
+    pub aio_rw_flags: __kernel_rwf_t,
+    pub aio_lio_opcode: __u16,
+    pub aio_reqprio: __s16,
+    pub aio_fildes: __u32,
+    pub aio_buf: __u64,
+    pub aio_nbytes: __u64,
+    pub aio_offset: __s64,
+    pub aio_reserved2: __u64,
+    pub aio_flags: __u32,
+    pub aio_resfd: __u32,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+#[cfg(target_endian = "big")]
+pub struct iocb {
+    pub aio_data: __u64,
+    pub aio_rw_flags: __kernel_rwf_t,
+    pub aio_key: __u32,
+    pub aio_lio_opcode: __u16,
+    pub aio_reqprio: __s16,
+    pub aio_fildes: __u32,
+    pub aio_buf: __u64,
+    pub aio_nbytes: __u64,
+    pub aio_offset: __s64,
+    pub aio_reserved2: __u64,
+    pub aio_flags: __u32,
+    pub aio_resfd: __u32,
+}
@@ -4,6 +4,8 @@
 
 //! Small system utility modules for usage by other modules.
 
+mod aio;
+pub mod aio_abi_bindings;
 mod alloc;
 #[cfg(target_os = "android")]
 mod android;
@@ -51,6 +53,7 @@ mod timerfd;
 pub mod vsock;
 mod write_zeroes;
 
+pub use crate::aio::*;
 pub use crate::alloc::LayoutAllocation;
 pub use crate::capabilities::drop_capabilities;
 pub use crate::clock::{Clock, FakeClock};
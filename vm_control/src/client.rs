@@ -7,6 +7,8 @@ use base::{info, net::UnixSeqpacket, validate_raw_descriptor, RawDescriptor, Tub
 use std::fs::OpenOptions;
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
 enum ModifyBatError {
     BatControlErr(BatControlResult),
@@ -89,6 +91,50 @@ pub fn vms_request(request: &VmRequest, socket_path: &Path) -> VmsRequestResult
     Ok(())
 }
 
+/// Sends `VmRequest::Exit` to the crosvm instance listening on `socket_path` and waits up to
+/// `timeout` for it to stop accepting connections on that socket. Returns an error if the instance
+/// does not acknowledge the request or is still running when `timeout` elapses.
+pub fn do_stop_with_timeout(socket_path: &Path, timeout: Duration) -> VmsRequestResult {
+    match handle_request(&VmRequest::Exit, socket_path)? {
+        VmResponse::Exiting => {}
+        r => {
+            error!(
+                "unexpected response to exit request from '{:?}': {}",
+                socket_path, r
+            );
+            return Err(());
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    while UnixSeqpacket::connect(&socket_path).is_ok() {
+        if Instant::now() >= deadline {
+            error!(
+                "crosvm instance at '{:?}' did not exit within {:?}",
+                socket_path, timeout
+            );
+            return Err(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    info!("crosvm instance at '{:?}' exited", socket_path);
+    Ok(())
+}
+
+/// Sends `VmRequest::Ping` to the crosvm instance listening on `socket_path`. Returns true if the
+/// instance answered. Unlike `handle_request`, failures aren't logged, since they're expected for
+/// sockets left behind by instances that have exited.
+pub fn do_ping(socket_path: &Path) -> bool {
+    let socket = match UnixSeqpacket::connect(&socket_path) {
+        Ok(s) => Tube::new(s),
+        Err(_) => return false,
+    };
+    if socket.send(&VmRequest::Ping).is_err() {
+        return false;
+    }
+    matches!(socket.recv(), Ok(VmResponse::Ok))
+}
+
 pub fn do_usb_attach(
     socket_path: &Path,
     bus: u8,
@@ -18,9 +18,10 @@ pub mod client;
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::os::raw::c_int;
+use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 
 use libc::{EINVAL, EIO, ENODEV};
 use serde::{Deserialize, Serialize};
@@ -61,6 +62,8 @@ pub use hypervisor::MemSlot;
 pub enum VcpuControl {
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     Debug(VcpuDebug),
+    /// Send the vCPU's id and a text dump of its registers on the given channel.
+    DumpState(mpsc::Sender<(usize, String)>),
     RunState(VmRunMode),
 }
 
@@ -616,7 +619,7 @@ impl Display for BatControlResult {
             NoSuchHealth => write!(f, "Invalid Battery health setting. Only support: unknown/good/overheat/dead/overvoltage/unexpectedfailure/cold/watchdogtimerexpire/safetytimerexpire/overcurrent"),
             NoSuchProperty => write!(f, "Battery doesn't have such property. Only support: status/health/present/capacity/aconline"),
             NoSuchStatus => write!(f, "Invalid Battery status setting. Only support: unknown/charging/discharging/notcharging/full"),
-            NoSuchBatType => write!(f, "Invalid Battery type setting. Only support: goldfish"),
+            NoSuchBatType => write!(f, "Invalid Battery type setting. Only support: goldfish/acpi"),
             StringParseIntErr => write!(f, "Battery property target ParseInt error"),
         }
     }
@@ -625,6 +628,8 @@ impl Display for BatControlResult {
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub enum BatteryType {
     Goldfish,
+    /// A goldfish battery presented to the guest as an ACPI battery and AC adapter.
+    Acpi,
 }
 
 impl Default for BatteryType {
@@ -639,6 +644,7 @@ impl FromStr for BatteryType {
     fn from_str(s: &str) -> StdResult<Self, Self::Err> {
         match s {
             "goldfish" => Ok(BatteryType::Goldfish),
+            "acpi" => Ok(BatteryType::Acpi),
             _ => Err(BatControlResult::NoSuchBatType),
         }
     }
@@ -901,11 +907,12 @@ impl FsMappingRequest {
 /// Unless otherwise noted, each request should expect a `VmResponse::Ok` to be received on success.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum VmRequest {
-    /// Break the VM's run loop and exit.
+    /// Break the VM's run loop and exit. Responds with `VmResponse::Exiting`.
     Exit,
-    /// Suspend the VM's VCPUs until resume.
+    /// Suspend the VM's VCPUs until resume. Suspending a VM that is already suspended has no
+    /// effect and still responds with `VmResponse::Ok`.
     Suspend,
-    /// Resume the VM's VCPUs that were previously suspended.
+    /// Resume the VM's VCPUs that were previously suspended. Resuming a running VM has no effect.
     Resume,
     /// Command for balloon driver.
     BalloonCommand(BalloonControlCommand),
@@ -919,6 +926,45 @@ pub enum VmRequest {
     UsbCommand(UsbControlCommand),
     /// Command to set battery.
     BatCommand(BatteryType, BatControlCommand),
+    /// Check that the VM is still serving requests. Responds with `VmResponse::Ok`.
+    Ping,
+    /// Write the registers of each vCPU and a summary of the guest memory map to `path`, for
+    /// post-mortem debugging. The VM process creates `path` itself unless `file` holds it already
+    /// opened by the requester, which is needed when the sandboxed VM process can't write there.
+    DumpState {
+        path: PathBuf,
+        file: Option<DumpStateFile>,
+    },
+}
+
+/// A file opened for writing by the sender of a `VmRequest::DumpState`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DumpStateFile(#[serde(with = "with_as_descriptor")] pub File);
+
+// Forwards `command` to the balloon device and returns its result.
+fn execute_balloon_command(
+    command: &BalloonControlCommand,
+    balloon_host_tube: &Tube,
+) -> VmResponse {
+    if balloon_host_tube.send(command).is_err() {
+        return VmResponse::Err(SysError::last());
+    }
+    match command {
+        BalloonControlCommand::Adjust { .. } => VmResponse::Ok,
+        BalloonControlCommand::Stats => match balloon_host_tube.recv() {
+            Ok(BalloonControlResult::Stats {
+                stats,
+                balloon_actual,
+            }) => VmResponse::BalloonStats {
+                stats,
+                balloon_actual,
+            },
+            Err(e) => {
+                error!("balloon socket recv failed: {}", e);
+                VmResponse::Err(SysError::last())
+            }
+        },
+    }
 }
 
 fn register_memory(
@@ -976,11 +1022,12 @@ impl VmRequest {
     ///
     /// This does not return a result, instead encapsulating the success or failure in a
     /// `VmResponse` with the intended purpose of sending the response back over the  socket that
-    /// received this `VmRequest`.
+    /// received this `VmRequest`. Balloon commands fail with `ENODEV` if `balloon_host_tube` is
+    /// `None` because the VM has no balloon device.
     pub fn execute(
         &self,
         run_mode: &mut Option<VmRunMode>,
-        balloon_host_tube: &Tube,
+        balloon_host_tube: Option<&Tube>,
         disk_host_tubes: &[Tube],
         usb_control_tube: &Tube,
         bat_control: &mut Option<BatControl>,
@@ -988,7 +1035,7 @@ impl VmRequest {
         match *self {
             VmRequest::Exit => {
                 *run_mode = Some(VmRunMode::Exiting);
-                VmResponse::Ok
+                VmResponse::Exiting
             }
             VmRequest::Suspend => {
                 *run_mode = Some(VmRunMode::Suspending);
@@ -998,30 +1045,11 @@ impl VmRequest {
                 *run_mode = Some(VmRunMode::Running);
                 VmResponse::Ok
             }
-            VmRequest::BalloonCommand(BalloonControlCommand::Adjust { num_bytes }) => {
-                match balloon_host_tube.send(&BalloonControlCommand::Adjust { num_bytes }) {
-                    Ok(_) => VmResponse::Ok,
-                    Err(_) => VmResponse::Err(SysError::last()),
-                }
-            }
-            VmRequest::BalloonCommand(BalloonControlCommand::Stats) => {
-                match balloon_host_tube.send(&BalloonControlCommand::Stats {}) {
-                    Ok(_) => match balloon_host_tube.recv() {
-                        Ok(BalloonControlResult::Stats {
-                            stats,
-                            balloon_actual,
-                        }) => VmResponse::BalloonStats {
-                            stats,
-                            balloon_actual,
-                        },
-                        Err(e) => {
-                            error!("balloon socket recv failed: {}", e);
-                            VmResponse::Err(SysError::last())
-                        }
-                    },
-                    Err(_) => VmResponse::Err(SysError::last()),
-                }
-            }
+            VmRequest::Ping => VmResponse::Ok,
+            VmRequest::BalloonCommand(ref command) => match balloon_host_tube {
+                Some(tube) => execute_balloon_command(command, tube),
+                None => VmResponse::Err(SysError::new(ENODEV)),
+            },
             VmRequest::DiskCommand {
                 disk_index,
                 ref command,
@@ -1084,6 +1112,8 @@ impl VmRequest {
                     None => VmResponse::BatResponse(BatControlResult::NoBatDevice),
                 }
             }
+            // Dumping state needs the vCPUs, so the run loop handles it without calling `execute`.
+            VmRequest::DumpState { .. } => VmResponse::Err(SysError::new(EINVAL)),
         }
     }
 }
@@ -1097,6 +1127,8 @@ pub enum VmResponse {
     Ok,
     /// Indicates the request encountered some error during execution.
     Err(SysError),
+    /// Acknowledges a `VmRequest::Exit`; the VM's run loop will exit after this response is sent.
+    Exiting,
     /// The request to register memory into guest address space was successfully done at page frame
     /// number `pfn` and memory slot number `slot`.
     RegisterMemory { pfn: u64, slot: u32 },
@@ -1126,6 +1158,7 @@ impl Display for VmResponse {
         match self {
             Ok => write!(f, "ok"),
             Err(e) => write!(f, "error: {}", e),
+            Exiting => write!(f, "exiting"),
             RegisterMemory { pfn, slot } => write!(
                 f,
                 "memory registered to page frame number {:#x} and memory slot {}",
@@ -1149,3 +1182,165 @@ impl Display for VmResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::Tube;
+
+    #[test]
+    fn suspend_resume_round_trip() {
+        let (host, vm) = Tube::pair().unwrap();
+
+        host.send(&VmRequest::Suspend).unwrap();
+        match vm.recv::<VmRequest>().unwrap() {
+            VmRequest::Suspend => {}
+            r => panic!("expected Suspend, got {:?}", r),
+        }
+
+        host.send(&VmRequest::Resume).unwrap();
+        match vm.recv::<VmRequest>().unwrap() {
+            VmRequest::Resume => {}
+            r => panic!("expected Resume, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn dump_state_round_trip() {
+        let (host, vm) = Tube::pair().unwrap();
+
+        host.send(&VmRequest::DumpState {
+            path: PathBuf::from("/tmp/crosvm_state"),
+            file: None,
+        })
+        .unwrap();
+        match vm.recv::<VmRequest>().unwrap() {
+            VmRequest::DumpState { path, file: None } => {
+                assert_eq!(path, PathBuf::from("/tmp/crosvm_state"));
+            }
+            r => panic!("expected DumpState, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn dump_state_passes_file() {
+        use std::io::{Read, Write};
+
+        let (host, vm) = Tube::pair().unwrap();
+        let (mut read_end, write_end) = base::pipe(true).unwrap();
+
+        host.send(&VmRequest::DumpState {
+            path: PathBuf::from("/unwritable/by/vm"),
+            file: Some(DumpStateFile(write_end)),
+        })
+        .unwrap();
+        let mut received = match vm.recv::<VmRequest>().unwrap() {
+            VmRequest::DumpState {
+                path,
+                file: Some(DumpStateFile(received)),
+            } => {
+                assert_eq!(path, PathBuf::from("/unwritable/by/vm"));
+                received
+            }
+            r => panic!("expected DumpState with a file, got {:?}", r),
+        };
+
+        // Writes through the received descriptor reach the sender's end of the pipe.
+        received.write_all(b"vcpu 0").unwrap();
+        drop(received);
+        let mut contents = String::new();
+        read_end.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "vcpu 0");
+    }
+
+    #[test]
+    fn balloon_command_without_balloon() {
+        let (usb_control_tube, _usb_device_tube) = Tube::pair().unwrap();
+        let mut run_mode = None;
+        let response = VmRequest::BalloonCommand(BalloonControlCommand::Stats).execute(
+            &mut run_mode,
+            None,
+            &[],
+            &usb_control_tube,
+            &mut None,
+        );
+        match response {
+            VmResponse::Err(e) => assert_eq!(e.errno(), ENODEV),
+            r => panic!("expected ENODEV, got {}", r),
+        }
+        assert!(run_mode.is_none());
+    }
+
+    #[test]
+    fn balloon_stats_request_round_trip() {
+        let (host, vm) = Tube::pair().unwrap();
+
+        host.send(&VmRequest::BalloonCommand(BalloonControlCommand::Stats))
+            .unwrap();
+        match vm.recv::<VmRequest>().unwrap() {
+            VmRequest::BalloonCommand(BalloonControlCommand::Stats) => {}
+            r => panic!("expected BalloonCommand(Stats), got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn balloon_stats_response_round_trip() {
+        let (host, vm) = Tube::pair().unwrap();
+
+        vm.send(&VmResponse::BalloonStats {
+            stats: BalloonStats {
+                free_memory: Some(0x1000_0000),
+                total_memory: Some(0x4000_0000),
+                ..Default::default()
+            },
+            balloon_actual: 0x800_0000,
+        })
+        .unwrap();
+        match host.recv::<VmResponse>().unwrap() {
+            VmResponse::BalloonStats {
+                stats,
+                balloon_actual,
+            } => {
+                assert_eq!(stats.free_memory, Some(0x1000_0000));
+                assert_eq!(stats.total_memory, Some(0x4000_0000));
+                assert_eq!(stats.swap_in, None);
+                assert_eq!(balloon_actual, 0x800_0000);
+            }
+            r => panic!("expected BalloonStats, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn ping_round_trip() {
+        let (host, vm) = Tube::pair().unwrap();
+
+        host.send(&VmRequest::Ping).unwrap();
+        match vm.recv::<VmRequest>().unwrap() {
+            VmRequest::Ping => {}
+            r => panic!("expected Ping, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn disk_resize_round_trip() {
+        let (host, vm) = Tube::pair().unwrap();
+
+        host.send(&VmRequest::DiskCommand {
+            disk_index: 2,
+            command: DiskControlCommand::Resize {
+                new_size: 0x1_0000_0000,
+            },
+        })
+        .unwrap();
+        match vm.recv::<VmRequest>().unwrap() {
+            VmRequest::DiskCommand {
+                disk_index: 2,
+                command:
+                    DiskControlCommand::Resize {
+                        new_size: 0x1_0000_0000,
+                    },
+            } => {}
+            r => panic!("expected DiskCommand, got {:?}", r),
+        }
+    }
+}
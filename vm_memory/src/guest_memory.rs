@@ -721,6 +721,12 @@ unsafe impl BackingMemory for GuestMemory {
         &self,
         mem_range: cros_async::MemRegion,
     ) -> mem::Result<VolatileSlice<'_>> {
+        // Reject ranges that wrap around the address space before looking up the region so a huge
+        // `len` can't be used to bypass the region bounds check.
+        mem_range
+            .offset
+            .checked_add(mem_range.len as u64)
+            .ok_or(mem::Error::InvalidOffset(mem_range.offset, mem_range.len))?;
         self.get_slice_at_addr(GuestAddress(mem_range.offset as u64), mem_range.len)
             .map_err(|_| mem::Error::InvalidOffset(mem_range.offset, mem_range.len))
     }
@@ -781,6 +787,31 @@ mod tests {
         assert!(gm.checked_offset(GuestAddress(0x5000), 0x1000).is_none());
     }
 
+    #[test]
+    fn backing_memory_overflowing_range() {
+        let start_addr = GuestAddress(0x1000);
+        let gm = GuestMemory::new(&[(start_addr, 0x1000)]).unwrap();
+
+        assert!(gm
+            .get_volatile_slice(cros_async::MemRegion {
+                offset: 0x1000,
+                len: 0x100
+            })
+            .is_ok());
+        assert!(gm
+            .get_volatile_slice(cros_async::MemRegion {
+                offset: 0x1800,
+                len: usize::MAX
+            })
+            .is_err());
+        assert!(gm
+            .get_volatile_slice(cros_async::MemRegion {
+                offset: u64::MAX,
+                len: 2
+            })
+            .is_err());
+    }
+
     #[test]
     fn test_read_u64() {
         let start_addr1 = GuestAddress(0x0);
@@ -6,6 +6,7 @@ use std::arch::x86_64::{__cpuid, __cpuid_count};
 use std::fmt::{self, Display};
 use std::result;
 
+use arch::CpuTopology;
 use devices::{IrqChipCap, IrqChipX86_64};
 use hypervisor::{HypervisorX86_64, VcpuX86_64};
 
@@ -44,13 +45,21 @@ const ECX_TOPO_SMT_TYPE: u32 = 1; // SMT type.
 const ECX_TOPO_CORE_TYPE: u32 = 2; // CORE type.
 const EAX_CPU_CORES_SHIFT: u32 = 26; // Index of cpu cores in the same physical package.
 
+// Returns the number of bits needed to number `count` items.
+fn index_bits(count: usize) -> u32 {
+    32 - ((count - 1) as u32).leading_zeros()
+}
+
 fn filter_cpuid(
     vcpu_id: usize,
     cpu_count: usize,
     cpuid: &mut hypervisor::CpuId,
     irq_chip: &dyn IrqChipX86_64,
     no_smt: bool,
+    cpu_topology: Option<CpuTopology>,
 ) {
+    // Without a topology, all vcpus are in one package.
+    let package_cpu_count = cpu_topology.map_or(cpu_count, |t| t.cores * t.threads);
     let entries = &mut cpuid.cpu_id_entries;
 
     for entry in entries {
@@ -70,8 +79,8 @@ fn filter_cpuid(
                 }
                 entry.ebx = (vcpu_id << EBX_CPUID_SHIFT) as u32
                     | (EBX_CLFLUSH_CACHELINE << EBX_CLFLUSH_SIZE_SHIFT);
-                if cpu_count > 1 {
-                    entry.ebx |= (cpu_count as u32) << EBX_CPU_COUNT_SHIFT;
+                if package_cpu_count > 1 {
+                    entry.ebx |= (package_cpu_count as u32) << EBX_CPU_COUNT_SHIFT;
                     entry.edx |= 1 << EDX_HTT_SHIFT;
                 }
             }
@@ -91,7 +100,9 @@ fn filter_cpuid(
                     entry.edx = result.edx;
                 }
                 entry.eax &= !0xFC000000;
-                if cpu_count > 1 {
+                if let Some(topology) = cpu_topology {
+                    entry.eax |= ((topology.cores - 1) as u32) << EAX_CPU_CORES_SHIFT;
+                } else if cpu_count > 1 {
                     let cpu_cores = if no_smt {
                         cpu_count as u32
                     } else if cpu_count % 2 == 0 {
@@ -111,7 +122,22 @@ fn filter_cpuid(
                 // NOTE: these will need to be split if any of the fields that differ between
                 // the two versions are to be set.
                 entry.edx = vcpu_id as u32; // x2APIC ID
-                if entry.index == 0 {
+                if let Some(topology) = cpu_topology {
+                    let thread_bits = index_bits(topology.threads);
+                    if entry.index == 0 {
+                        entry.eax = thread_bits; // Shift to get id of next level
+                        entry.ebx = topology.threads as u32; // Number of logical cpus at this level
+                        entry.ecx = (ECX_TOPO_SMT_TYPE << ECX_TOPO_TYPE_SHIFT) | entry.index;
+                    } else if entry.index == 1 {
+                        entry.eax = thread_bits + index_bits(topology.cores);
+                        entry.ebx = (package_cpu_count as u32) & 0xffff;
+                        entry.ecx = (ECX_TOPO_CORE_TYPE << ECX_TOPO_TYPE_SHIFT) | entry.index;
+                    } else {
+                        entry.eax = 0;
+                        entry.ebx = 0;
+                        entry.ecx = 0;
+                    }
+                } else if entry.index == 0 {
                     if no_smt || (cpu_count == 1) {
                         // Make it so that all VCPUs appear as different,
                         // non-hyperthreaded cores on the same package.
@@ -153,6 +179,8 @@ fn filter_cpuid(
 /// * `vcpu` - `VcpuX86_64` for setting CPU ID.
 /// * `vcpu_id` - The vcpu index of `vcpu`.
 /// * `nrcpus` - The number of vcpus being used by this VM.
+/// * `no_smt` - Whether to present all vcpus as cores without hyperthreads.
+/// * `cpu_topology` - The sockets, cores, and threads to present the vcpus as, if given.
 pub fn setup_cpuid(
     hypervisor: &dyn HypervisorX86_64,
     irq_chip: &dyn IrqChipX86_64,
@@ -160,12 +188,13 @@ pub fn setup_cpuid(
     vcpu_id: usize,
     nrcpus: usize,
     no_smt: bool,
+    cpu_topology: Option<CpuTopology>,
 ) -> Result<()> {
     let mut cpuid = hypervisor
         .get_supported_cpuid()
         .map_err(Error::GetSupportedCpusFailed)?;
 
-    filter_cpuid(vcpu_id, nrcpus, &mut cpuid, irq_chip, no_smt);
+    filter_cpuid(vcpu_id, nrcpus, &mut cpuid, irq_chip, no_smt, cpu_topology);
 
     vcpu.set_cpuid(&cpuid)
         .map_err(Error::SetSupportedCpusFailed)
@@ -209,7 +238,7 @@ mod tests {
             edx: 0,
             ..Default::default()
         });
-        filter_cpuid(1, 2, &mut cpuid, &irq_chip, false);
+        filter_cpuid(1, 2, &mut cpuid, &irq_chip, false, None);
 
         let entries = &mut cpuid.cpu_id_entries;
         assert_eq!(entries[0].function, 0);
@@ -222,4 +251,49 @@ mod tests {
         assert_ne!(0, entries[1].ecx & (1 << ECX_HYPERVISOR_SHIFT));
         assert_ne!(0, entries[1].edx & (1 << EDX_HTT_SHIFT));
     }
+
+    #[test]
+    fn cpu_topology() {
+        let mut cpuid = hypervisor::CpuId::new(4);
+        let guest_mem =
+            vm_memory::GuestMemory::new(&[(vm_memory::GuestAddress(0), 0x10000)]).unwrap();
+        let kvm = hypervisor::kvm::Kvm::new().unwrap();
+        let vm = hypervisor::kvm::KvmVm::new(&kvm, guest_mem).unwrap();
+        let irq_chip = devices::KvmKernelIrqChip::new(vm, 1).unwrap();
+
+        let entries = &mut cpuid.cpu_id_entries;
+        entries.push(CpuIdEntry {
+            function: 1,
+            ..Default::default()
+        });
+        entries.push(CpuIdEntry {
+            function: 4,
+            ..Default::default()
+        });
+        entries.push(CpuIdEntry {
+            function: 0xB,
+            index: 0,
+            ..Default::default()
+        });
+        entries.push(CpuIdEntry {
+            function: 0xB,
+            index: 1,
+            ..Default::default()
+        });
+        let topology = CpuTopology {
+            sockets: 2,
+            cores: 2,
+            threads: 2,
+        };
+        filter_cpuid(5, 8, &mut cpuid, &irq_chip, false, Some(topology));
+
+        let entries = &mut cpuid.cpu_id_entries;
+        // Four logical cpus per package, in two cores.
+        assert_eq!(4, (entries[0].ebx >> EBX_CPU_COUNT_SHIFT) & 0x000000ff);
+        assert_eq!(1, entries[1].eax >> EAX_CPU_CORES_SHIFT);
+        // Two threads per core, four logical cpus per package.
+        assert_eq!((1, 2), (entries[2].eax, entries[2].ebx));
+        assert_eq!((2, 4), (entries[3].eax, entries[3].ebx));
+        assert_eq!(5, entries[2].edx);
+    }
 }
@@ -57,8 +57,8 @@ use crate::bootparam::boot_params;
 use acpi_tables::aml::Aml;
 use acpi_tables::sdt::SDT;
 use arch::{
-    get_serial_cmdline, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware, SerialParameters,
-    VmComponents, VmImage,
+    get_serial_cmdline, CpuTopology, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware,
+    SerialParameters, VmComponents, VmImage,
 };
 use base::Event;
 use devices::{IrqChip, IrqChipX86_64, PciConfigIo, PciDevice, ProtectionType};
@@ -523,6 +523,7 @@ impl arch::LinuxArch for X8664arch {
             vcpus: None,
             vcpu_affinity: components.vcpu_affinity,
             no_smt: components.no_smt,
+            cpu_topology: components.cpu_topology,
             irq_chip,
             has_bios: matches!(components.vm_image, VmImage::Bios(_)),
             io_bus,
@@ -545,9 +546,18 @@ impl arch::LinuxArch for X8664arch {
         num_cpus: usize,
         has_bios: bool,
         no_smt: bool,
+        cpu_topology: Option<CpuTopology>,
     ) -> Result<()> {
-        cpuid::setup_cpuid(hypervisor, irq_chip, vcpu, vcpu_id, num_cpus, no_smt)
-            .map_err(Error::SetupCpuid)?;
+        cpuid::setup_cpuid(
+            hypervisor,
+            irq_chip,
+            vcpu,
+            vcpu_id,
+            num_cpus,
+            no_smt,
+            cpu_topology,
+        )
+        .map_err(Error::SetupCpuid)?;
 
         if has_bios {
             return Ok(());
@@ -572,6 +582,12 @@ impl arch::LinuxArch for X8664arch {
         Ok(())
     }
 
+    fn dump_vcpu_registers<T: VcpuX86_64>(vcpu: &T) -> Result<String> {
+        let regs = vcpu.get_regs().map_err(Error::ReadRegs)?;
+        let sregs = vcpu.get_sregs().map_err(Error::ReadRegs)?;
+        Ok(format!("{:#x?}\n{:#x?}", regs, sregs))
+    }
+
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     fn debug_read_registers<T: VcpuX86_64>(vcpu: &T) -> Result<X86_64CoreRegs> {
         // General registers: RAX, RBX, RCX, RDX, RSI, RDI, RBP, RSP, r8-r15
@@ -1083,7 +1099,7 @@ impl X8664arch {
 
         let bat_control = if let Some(battery_type) = battery.0 {
             match battery_type {
-                BatteryType::Goldfish => {
+                BatteryType::Goldfish | BatteryType::Acpi => {
                     let control_tube = arch::add_goldfish_battery(
                         &mut amls,
                         battery.1,
@@ -1091,10 +1107,11 @@ impl X8664arch {
                         irq_chip,
                         X86_64_SCI_IRQ,
                         resources,
+                        *battery_type == BatteryType::Acpi,
                     )
                     .map_err(Error::CreateBatDevices)?;
                     Some(BatControl {
-                        type_: BatteryType::Goldfish,
+                        type_: *battery_type,
                         control_tube,
                     })
                 }